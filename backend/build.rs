@@ -0,0 +1,23 @@
+//! Build script: records the git commit reported by `GET /api/version`
+
+use std::process::Command;
+
+fn main() {
+    // Paths are relative to this crate; a missing path just means we rerun
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+    
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty());
+    
+    // Builds from a source tarball have no git; the API reports null then
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=VIBE_GIT_COMMIT={}", commit);
+    }
+}
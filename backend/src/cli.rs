@@ -0,0 +1,115 @@
+//! Command-line options
+
+use std::path::PathBuf;
+
+/// Shown for `--help` and after a bad argument
+pub const USAGE: &str = "\
+Usage: vibe-downloader [OPTIONS]
+
+Options:
+  --headless, --no-tray  Run without the system tray, e.g. on a server
+                         without a display or under systemd
+  --port <PORT>          Serve on this port instead of the configured one
+                         (for this run only)
+  --db-path <PATH>       Keep the download history in this SQLite file
+                         (also VIBE_DB_PATH; defaults to the config folder)
+  -h, --help             Show this help";
+
+/// Environment variable naming the database file, when `--db-path` isn't given
+pub const DB_PATH_ENV: &str = "VIBE_DB_PATH";
+
+/// Options given on the command line
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Cli {
+    /// Skip the system tray and just run the server
+    pub headless: bool,
+    /// Overrides `server.port` without saving it
+    pub port: Option<u16>,
+    /// Database file to use instead of the default
+    pub db_path: Option<PathBuf>,
+    pub help: bool,
+}
+
+impl Cli {
+    /// Parse the arguments after the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut cli = Cli::default();
+        let mut args = args.into_iter();
+        
+        while let Some(arg) = args.next() {
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            
+            match name.as_str() {
+                "--headless" | "--no-tray" => cli.headless = true,
+                "-h" | "--help" => cli.help = true,
+                "--port" => {
+                    let value = inline_value
+                        .or_else(|| args.next())
+                        .ok_or("--port needs a value")?;
+                    let port = value
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|&port| port != 0)
+                        .ok_or_else(|| format!("Invalid port {:?}", value))?;
+                    cli.port = Some(port);
+                }
+                "--db-path" => {
+                    let value = inline_value
+                        .or_else(|| args.next())
+                        .filter(|value| !value.is_empty())
+                        .ok_or("--db-path needs a value")?;
+                    cli.db_path = Some(PathBuf::from(value));
+                }
+                _ => return Err(format!("Unknown argument {:?}", name)),
+            }
+        }
+        
+        Ok(cli)
+    }
+    
+    /// Database file to use: `--db-path`, else `VIBE_DB_PATH`, else `None`
+    /// for the default location
+    pub fn db_path(&self) -> Option<PathBuf> {
+        self.db_path.clone().or_else(|| {
+            std::env::var_os(DB_PATH_ENV)
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn parse(args: &[&str]) -> Result<Cli, String> {
+        Cli::parse(args.iter().map(|a| a.to_string()))
+    }
+    
+    #[test]
+    fn parses_flags_and_port_forms() {
+        assert_eq!(parse(&[]).unwrap(), Cli::default());
+        assert_eq!(
+            parse(&["--no-tray", "--port", "9000"]).unwrap(),
+            Cli { headless: true, port: Some(9000), ..Cli::default() }
+        );
+        assert_eq!(
+            parse(&["--db-path", "/data/vibe.db"]).unwrap().db_path,
+            Some(PathBuf::from("/data/vibe.db"))
+        );
+        assert_eq!(parse(&["--port=8080"]).unwrap().port, Some(8080));
+        assert!(parse(&["--headless"]).unwrap().headless);
+    }
+    
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&["--port"]).is_err());
+        assert!(parse(&["--port", "0"]).is_err());
+        assert!(parse(&["--port", "70000"]).is_err());
+        assert!(parse(&["--db-path="]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+    }
+}
@@ -4,7 +4,7 @@ mod settings;
 
 pub use settings::*;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use std::fs;
 use std::path::PathBuf;
 
@@ -26,8 +26,7 @@ pub fn load_or_create_default() -> Result<Settings> {
     
     if path.exists() {
         let content = fs::read_to_string(&path)?;
-        let settings: Settings = toml::from_str(&content)?;
-        Ok(settings)
+        parse(&content).with_context(|| format!("Invalid config {}", path.display()))
     } else {
         let settings = Settings::default();
         save(&settings)?;
@@ -35,6 +34,19 @@ pub fn load_or_create_default() -> Result<Settings> {
     }
 }
 
+/// Parse a config file's contents, refusing settings the app can't start with
+pub fn parse(content: &str) -> Result<Settings> {
+    let mut settings: Settings = toml::from_str(content)?;
+    settings.number_unordered_file_types();
+    if parse_host(&settings.server.host).is_none() {
+        anyhow::bail!("server.host {}", SERVER_HOST_RULE);
+    }
+    if let Some((field, problem)) = settings.routing_rule_problems().into_iter().next() {
+        anyhow::bail!("{} {}", field, problem);
+    }
+    Ok(settings)
+}
+
 /// Save configuration to file
 pub fn save(settings: &Settings) -> Result<()> {
     let path = config_path();
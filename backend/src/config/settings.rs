@@ -2,7 +2,8 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 
 /// Main application settings
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,12 +17,385 @@ pub struct Settings {
     /// File type to destination folder mappings
     pub file_types: HashMap<String, FileTypeConfig>,
     
+    /// Rules giving downloads from matching URLs a file type regardless of
+    /// their extension. The first match wins; without one the extension
+    /// decides.
+    #[serde(default)]
+    pub routing_rules: Vec<RoutingRule>,
+    
     /// Whether to start on system login
     pub start_on_login: bool,
     
     /// Whether to start on boot without login (Linux systemd service)
     #[serde(default)]
     pub start_on_boot: bool,
+    
+    /// How to handle servers that compress responses with `Content-Encoding`
+    #[serde(default)]
+    pub content_encoding: ContentEncodingMode,
+    
+    /// Command to run after a download completes, e.g. `unzip -o {path}`.
+    /// `{path}`, `{filename}` and `{url}` are substituted. Only honoured when
+    /// `allow_command_hooks` is set, and only editable in the config file.
+    #[serde(default)]
+    pub post_complete_command: Option<String>,
+    
+    /// Opt-in for `post_complete_command`. Filenames and URLs come from remote
+    /// servers, so a hook that hands them to a shell can be abused to run
+    /// arbitrary commands; keep this off unless you trust your sources.
+    #[serde(default)]
+    pub allow_command_hooks: bool,
+    
+    /// URL that gets a JSON POST whenever a download completes or fails
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    
+    /// ffmpeg executable used for file types' `post_download_transcode`.
+    /// Transcoding is skipped while unset. Only editable in the config file.
+    #[serde(default)]
+    pub ffmpeg_path: Option<PathBuf>,
+    
+    /// Hosts whose TLS certificates aren't validated, e.g. an internal server
+    /// with a self-signed certificate. This makes connections to them open
+    /// to interception, so list only hosts you control. Config file only.
+    #[serde(default)]
+    pub allow_invalid_certs_for_hosts: Vec<String>,
+    
+    /// Proxy all downloads go through, e.g. `http://proxy.lan:3128`. Takes
+    /// precedence over the proxy environment variables.
+    #[serde(default)]
+    pub proxy: Option<String>,
+    
+    /// Without a `proxy`, use the ones named by the `HTTP_PROXY`,
+    /// `HTTPS_PROXY` and `ALL_PROXY` environment variables (or their
+    /// lowercase forms), skipping the hosts listed in `NO_PROXY`
+    #[serde(default = "default_use_system_proxy")]
+    pub use_system_proxy: bool,
+    
+    /// PEM certificate presented to servers that ask for one (mutual TLS),
+    /// with its private key in `client_key_path`. Startup fails if they
+    /// can't be loaded. Config file only.
+    #[serde(default)]
+    pub client_cert_path: Option<PathBuf>,
+    
+    /// PEM private key of `client_cert_path`
+    #[serde(default)]
+    pub client_key_path: Option<PathBuf>,
+    
+    /// PEM bundle of extra root certificates to trust, such as a company's
+    /// internal CA, on top of the built-in ones. Startup fails if it can't
+    /// be loaded. Config file only.
+    #[serde(default)]
+    pub custom_ca_cert_path: Option<PathBuf>,
+    
+    /// Suffix appended to files while they are downloading
+    #[serde(default = "default_partial_suffix")]
+    pub partial_suffix: String,
+    
+    /// Prefix partial files with a dot so file managers hide them (Unix)
+    #[serde(default)]
+    pub hide_partial_files: bool,
+    
+    /// Minutes the partial file of a download cancelled by the user is kept
+    /// in a `.trash` folder beside it, so the cancellation can be undone and
+    /// the download resumed. 0 deletes it at once.
+    #[serde(default)]
+    pub cancelled_partial_retention_minutes: u64,
+    
+    /// Flush finished files to disk before they get their final name, so a
+    /// power cut right after completion can't leave an empty or truncated
+    /// file behind. Costs a full write-out per download, which is slow on
+    /// spinning disks.
+    #[serde(default)]
+    pub fsync_on_complete: bool,
+    
+    /// Replace a finished file with a hard link to an earlier completed
+    /// download's file when their checksums match, so identical files only
+    /// take up space once. Each download keeps its own link, so removing one
+    /// file leaves the other intact. Only downloads with a checksum are
+    /// compared, and files on different filesystems are left as they are.
+    #[serde(default)]
+    pub dedupe_by_checksum: bool,
+    
+    /// Hash every download as it arrives and record its SHA-256 digest,
+    /// even when no checksum was given to verify against
+    #[serde(default)]
+    pub compute_checksums: bool,
+    
+    /// Whether downloads left paused re-enter the queue on startup
+    #[serde(default)]
+    pub auto_resume_paused_on_start: bool,
+    
+    /// Milliseconds between starting each download resumed on startup, so
+    /// they don't all connect at once (0 = no delay, at most 60000).
+    /// Downloads added meanwhile start right away.
+    #[serde(default)]
+    pub startup_stagger_ms: u64,
+    
+    /// Pause the queue while the connection is metered (a phone hotspot, a
+    /// capped plan) and pick up again once it isn't. Only has an effect
+    /// where the OS reports it: Windows, and Linux with NetworkManager.
+    #[serde(default)]
+    pub pause_on_metered: bool,
+    
+    /// Bytes re-fetched before resuming a partial file and compared with its
+    /// end, to catch a partial corrupted by a crash mid-write. 0 skips the check.
+    #[serde(default)]
+    pub resume_verify_bytes: u64,
+    
+    /// Maximum simultaneous downloads from the same host, on top of the
+    /// global limit (0 = no per-host limit)
+    #[serde(default)]
+    pub max_connections_per_host: usize,
+    
+    /// Most downloads allowed to wait in the queue; more are refused until
+    /// it drains (0 = no limit)
+    #[serde(default = "default_max_queue_size")]
+    pub max_queue_size: usize,
+    
+    /// Combined speed of all downloads, in bytes per second, while the
+    /// alternative speed limit is switched on from the tray. At least 1.
+    #[serde(default = "default_alt_speed_bytes_per_sec")]
+    pub alt_speed_bytes_per_sec: u64,
+    
+    /// Most completed, failed and cancelled records kept; older ones are
+    /// deleted as downloads finish. Files on disk are kept. (0 = keep all)
+    #[serde(default)]
+    pub max_history_records: usize,
+    
+    /// Retries after a transient failure: a timeout, a dropped connection,
+    /// or a 408, 429 or 5xx response
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    
+    /// Retries after the server couldn't be reached at all (DNS or connect
+    /// failure), e.g. while the network is still coming up
+    #[serde(default = "default_connect_retries")]
+    pub connect_retries: u32,
+    
+    /// First wait before retrying a transient failure; doubles each attempt
+    #[serde(default = "default_retry_backoff_secs")]
+    pub retry_backoff_secs: u64,
+    
+    /// First wait before retrying a DNS or connect failure; doubles each attempt
+    #[serde(default = "default_connect_retry_backoff_secs")]
+    pub connect_retry_backoff_secs: u64,
+    
+    /// Largest completed file the preview endpoint will return inline
+    #[serde(default = "default_preview_max_bytes")]
+    pub preview_max_bytes: u64,
+    
+    /// What to do when a finished download's name is already taken. File
+    /// types can override this with their own `on_conflict`.
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+    
+    /// Exit after this many minutes with no downloads running and no API
+    /// requests (0 = never)
+    #[serde(default)]
+    pub idle_shutdown_minutes: u64,
+    
+    /// Downloads that completed or failed this many seconds ago or less are
+    /// sent to progress WebSocket clients when they connect, so one that
+    /// was away still learns how they ended (0 = off, at most 3600)
+    #[serde(default = "default_finished_replay_secs")]
+    pub finished_replay_secs: u64,
+    
+    /// How often running downloads' progress is written to the database,
+    /// all in one transaction. Longer means less disk activity, but after a
+    /// crash the listed progress can be further behind (the partial files
+    /// themselves are checked on startup). At least 1.
+    #[serde(default = "default_progress_save_interval_secs")]
+    pub progress_save_interval_secs: u64,
+    
+    /// Log verbosity when `RUST_LOG` isn't set: a level such as `debug`, or
+    /// `RUST_LOG`-style directives like `info,vibe_downloader=trace`.
+    /// Read at startup.
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    
+    /// SQLite tuning
+    #[serde(default)]
+    pub database: DatabaseSettings,
+}
+
+fn default_partial_suffix() -> String {
+    ".part".to_string()
+}
+
+fn default_use_system_proxy() -> bool {
+    true
+}
+
+fn default_finished_replay_secs() -> u64 {
+    300
+}
+
+fn default_progress_save_interval_secs() -> u64 {
+    2
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_max_queue_size() -> usize {
+    10_000
+}
+
+fn default_alt_speed_bytes_per_sec() -> u64 {
+    100 * 1024
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_connect_retries() -> u32 {
+    10
+}
+
+fn default_retry_backoff_secs() -> u64 {
+    2
+}
+
+fn default_connect_retry_backoff_secs() -> u64 {
+    10
+}
+
+fn default_preview_max_bytes() -> u64 {
+    1024 * 1024
+}
+
+impl Settings {
+    /// Pick the file type of the first routing rule matching `url`, or else
+    /// the one `filename`'s extension belongs to
+    pub fn file_type_for(&self, url: &str, filename: &str) -> String {
+        self.routing_rules
+            .iter()
+            .find(|rule| self.file_types.contains_key(&rule.file_type) && rule.matches(url))
+            .map(|rule| rule.file_type.clone())
+            .unwrap_or_else(|| self.file_type_for_filename(filename))
+    }
+    
+    /// Problems with the routing rules, as the field and what's wrong with it
+    pub fn routing_rule_problems(&self) -> Vec<(String, &'static str)> {
+        let mut problems = Vec::new();
+        for (i, rule) in self.routing_rules.iter().enumerate() {
+            if rule.url_pattern.trim().is_empty() {
+                problems.push((format!("routing_rules.{}.url_pattern", i), "must not be empty"));
+            }
+            if !self.file_types.contains_key(&rule.file_type) {
+                problems.push((format!("routing_rules.{}.file_type", i), "must be an existing file type"));
+            }
+        }
+        problems
+    }
+    
+    /// Remove file type `id` along with the routing rules that send
+    /// downloads to it, which would otherwise make the config invalid
+    pub fn remove_file_type(&mut self, id: &str) -> Option<FileTypeConfig> {
+        let removed = self.file_types.remove(id)?;
+        self.routing_rules.retain(|rule| rule.file_type != id);
+        Some(removed)
+    }
+    
+    /// File types with their ids, in display order
+    pub fn file_types_in_order(&self) -> Vec<(&String, &FileTypeConfig)> {
+        let mut file_types: Vec<_> = self.file_types.iter().collect();
+        file_types.sort_by_key(|(id, file_type)| (file_type.order, *id));
+        file_types
+    }
+    
+    /// Put the file types listed in `ids` first, in that order, followed by
+    /// the rest in their current order. Fails on an unknown or repeated id.
+    pub fn reorder_file_types(&mut self, ids: &[String]) -> Result<(), String> {
+        let mut seen = std::collections::HashSet::new();
+        for id in ids {
+            if !self.file_types.contains_key(id) {
+                return Err(format!("Unknown file type: {}", id));
+            }
+            if !seen.insert(id) {
+                return Err(format!("File type {} is listed twice", id));
+            }
+        }
+        
+        let rest: Vec<String> = self
+            .file_types_in_order()
+            .into_iter()
+            .map(|(id, _)| id.clone())
+            .filter(|id| !seen.contains(id))
+            .collect();
+        for (position, id) in ids.iter().chain(&rest).enumerate() {
+            if let Some(file_type) = self.file_types.get_mut(id) {
+                file_type.order = position as i32;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Number the file types of a config saved before they had an order,
+    /// where every `order` reads as 0: the default ones keep their default
+    /// order and any others follow by id
+    pub fn number_unordered_file_types(&mut self) {
+        if self.file_types.values().any(|file_type| file_type.order != 0) {
+            return;
+        }
+        let defaults = Settings::default().file_types;
+        let mut ids: Vec<String> = self.file_types.keys().cloned().collect();
+        ids.sort_by_cached_key(|id| (defaults.get(id).map_or(i32::MAX, |file_type| file_type.order), id.clone()));
+        for (position, id) in ids.iter().enumerate() {
+            if let Some(file_type) = self.file_types.get_mut(id) {
+                file_type.order = position as i32;
+            }
+        }
+    }
+    
+    /// Pick the file type whose extensions match `filename`, falling back to "general"
+    pub fn file_type_for_filename(&self, filename: &str) -> String {
+        let extension = match Path::new(filename).extension() {
+            Some(ext) => ext.to_string_lossy().to_lowercase(),
+            None => return "general".to_string(),
+        };
+        
+        // Sort ids so overlapping extensions resolve the same way every time
+        let mut ids: Vec<&String> = self.file_types.keys().collect();
+        ids.sort();
+        
+        ids.into_iter()
+            .find(|id| {
+                self.file_types[*id]
+                    .extensions
+                    .iter()
+                    .any(|e| e != "*" && e.trim_start_matches('.').eq_ignore_ascii_case(&extension))
+            })
+            .cloned()
+            .unwrap_or_else(|| "general".to_string())
+    }
+    
+    /// Conflict policy for a file type: its own override if it has one,
+    /// otherwise the global `on_conflict`
+    pub fn conflict_policy(&self, file_type: &str) -> ConflictPolicy {
+        self.file_types
+            .get(file_type)
+            .and_then(|t| t.on_conflict)
+            .unwrap_or(self.on_conflict)
+    }
+    
+    /// Path of the partial file used while downloading `filename` into `dir`.
+    /// Every code path that writes or looks for partial files goes through here.
+    pub fn partial_path(&self, dir: &Path, filename: &str) -> PathBuf {
+        // An empty suffix would make the partial file clobber the final one,
+        // and separators would move it out of the destination folder
+        let suffix = match self.partial_suffix.as_str() {
+            "" => ".part",
+            s if s.contains(['/', '\\']) => ".part",
+            s => s,
+        };
+        let prefix = if self.hide_partial_files { "." } else { "" };
+        
+        dir.join(format!("{}{}{}", prefix, filename, suffix))
+    }
 }
 
 impl Default for Settings {
@@ -38,6 +412,11 @@ impl Default for Settings {
                 name: "General".to_string(),
                 extensions: vec!["*".to_string()],
                 destination: downloads_dir.clone(),
+                auto_extract: false,
+                on_conflict: None,
+                post_download_transcode: None,
+                date_subfolder: None,
+                order: 0,
             },
         );
         
@@ -53,6 +432,11 @@ impl Default for Settings {
                     "webm".to_string(),
                 ],
                 destination: downloads_dir.join("Videos"),
+                auto_extract: false,
+                on_conflict: None,
+                post_download_transcode: None,
+                date_subfolder: None,
+                order: 1,
             },
         );
         
@@ -68,6 +452,11 @@ impl Default for Settings {
                     "ogg".to_string(),
                 ],
                 destination: downloads_dir.join("Audio"),
+                auto_extract: false,
+                on_conflict: None,
+                post_download_transcode: None,
+                date_subfolder: None,
+                order: 2,
             },
         );
         
@@ -83,6 +472,11 @@ impl Default for Settings {
                     "xlsx".to_string(),
                 ],
                 destination: downloads_dir.join("Documents"),
+                auto_extract: false,
+                on_conflict: None,
+                post_download_transcode: None,
+                date_subfolder: None,
+                order: 3,
             },
         );
         
@@ -99,6 +493,11 @@ impl Default for Settings {
                     "svg".to_string(),
                 ],
                 destination: downloads_dir.join("Images"),
+                auto_extract: false,
+                on_conflict: None,
+                post_download_transcode: None,
+                date_subfolder: None,
+                order: 4,
             },
         );
         
@@ -114,27 +513,168 @@ impl Default for Settings {
                     "gz".to_string(),
                 ],
                 destination: downloads_dir.join("Archives"),
+                auto_extract: false,
+                on_conflict: None,
+                post_download_transcode: None,
+                date_subfolder: None,
+                order: 5,
             },
         );
-
+        
         Self {
             server: ServerSettings::default(),
             max_concurrent_downloads: 3,
             file_types,
+            routing_rules: Vec::new(),
             start_on_login: false,
             start_on_boot: false,
+            content_encoding: ContentEncodingMode::default(),
+            post_complete_command: None,
+            allow_command_hooks: false,
+            webhook_url: None,
+            ffmpeg_path: None,
+            allow_invalid_certs_for_hosts: Vec::new(),
+            proxy: None,
+            use_system_proxy: default_use_system_proxy(),
+            client_cert_path: None,
+            client_key_path: None,
+            custom_ca_cert_path: None,
+            partial_suffix: default_partial_suffix(),
+            hide_partial_files: false,
+            cancelled_partial_retention_minutes: 0,
+            fsync_on_complete: false,
+            dedupe_by_checksum: false,
+            compute_checksums: false,
+            auto_resume_paused_on_start: false,
+            startup_stagger_ms: 0,
+            pause_on_metered: false,
+            resume_verify_bytes: 0,
+            max_connections_per_host: 0,
+            max_queue_size: default_max_queue_size(),
+            alt_speed_bytes_per_sec: default_alt_speed_bytes_per_sec(),
+            max_history_records: 0,
+            max_retries: default_max_retries(),
+            connect_retries: default_connect_retries(),
+            retry_backoff_secs: default_retry_backoff_secs(),
+            connect_retry_backoff_secs: default_connect_retry_backoff_secs(),
+            preview_max_bytes: default_preview_max_bytes(),
+            on_conflict: ConflictPolicy::default(),
+            idle_shutdown_minutes: 0,
+            finished_replay_secs: default_finished_replay_secs(),
+            progress_save_interval_secs: default_progress_save_interval_secs(),
+            log_level: default_log_level(),
+            database: DatabaseSettings::default(),
         }
     }
 }
 
+/// How compressed (`Content-Encoding: gzip`/`br`) responses are handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentEncodingMode {
+    /// Ask for `identity` encoding and store whatever bytes arrive untouched.
+    /// Progress is measured against `Content-Length`, which matches the bytes
+    /// written, so percentages and byte offsets for resume stay exact.
+    #[default]
+    Original,
+    
+    /// Accept gzip/br and store the decompressed body. The compressed length
+    /// says nothing about the decoded size, so such downloads report an
+    /// unknown total, and byte offsets can't be used to resume them. Files
+    /// that are compressed themselves (`.gz`, `.tgz`, `.br`) and byte ranges
+    /// are still fetched as-is.
+    Decompress,
+}
+
+/// What happens when a finished download would replace an existing file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Replace the existing file
+    #[default]
+    Overwrite,
+    
+    /// Keep the existing file and save the new one as `name (2).ext`
+    Rename,
+}
+
 /// Server configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerSettings {
-    /// Host to bind to (0.0.0.0 for LAN access)
+    /// IP address to bind to while `lan_access` is on: 0.0.0.0 (or ::) for
+    /// every interface, or one interface's address to only be reachable
+    /// through it, e.g. a VPN's
     pub host: String,
     
     /// Port to listen on
     pub port: u16,
+    
+    /// Whether the web UI is reachable from other devices on the LAN.
+    /// When false the server only listens on 127.0.0.1, whatever `host` says.
+    #[serde(default = "default_lan_access")]
+    pub lan_access: bool,
+    
+    /// How many pending connections the OS queues before refusing new ones
+    #[serde(default = "default_listen_backlog")]
+    pub listen_backlog: u32,
+    
+    /// Refuse API requests that change anything (anything but GET/HEAD),
+    /// unless they carry `write_token`. For sharing the UI with people who
+    /// should only watch.
+    #[serde(default)]
+    pub read_only: bool,
+    
+    /// Token that still allows changes in read-only mode, sent as
+    /// `Authorization: Bearer <token>`. Without one nothing can be changed
+    /// through the API until `read_only` is turned off in the config file.
+    #[serde(default)]
+    pub write_token: Option<String>,
+}
+
+fn default_lan_access() -> bool {
+    true
+}
+
+fn default_listen_backlog() -> u32 {
+    1024
+}
+
+impl ServerSettings {
+    /// Address the server actually binds to. A `host` that isn't an IP
+    /// address (refused when the config is loaded) means every interface.
+    pub fn bind_host(&self) -> IpAddr {
+        if self.lan_access {
+            parse_host(&self.host).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        } else {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        }
+    }
+    
+    /// URL for opening the web UI from this machine
+    pub fn local_url(&self) -> String {
+        match self.bind_host() {
+            host if host.is_unspecified() => format!("http://localhost:{}", self.port),
+            IpAddr::V4(host) => format!("http://{}:{}", host, self.port),
+            IpAddr::V6(host) => format!("http://[{}]:{}", host, self.port),
+        }
+    }
+    
+    /// Whether a request that changes something may go ahead, given the
+    /// `Authorization` header it came with
+    pub fn allows_write(&self, authorization: Option<&str>) -> bool {
+        if !self.read_only {
+            return true;
+        }
+        let (Some(token), Some(expected)) = (
+            authorization.and_then(|value| value.strip_prefix("Bearer ")),
+            self.write_token.as_deref().filter(|token| !token.is_empty()),
+        ) else {
+            return false;
+        };
+        // Compare in constant time so the token can't be guessed byte by byte
+        token.len() == expected.len()
+            && token.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
 }
 
 impl Default for ServerSettings {
@@ -142,6 +682,40 @@ impl Default for ServerSettings {
         Self {
             host: "0.0.0.0".to_string(),
             port: 8787,
+            lan_access: default_lan_access(),
+            listen_backlog: default_listen_backlog(),
+            read_only: false,
+            write_token: None,
+        }
+    }
+}
+
+/// SQLite connection settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatabaseSettings {
+    /// Use write-ahead logging so reads don't block on writes
+    pub wal: bool,
+    
+    /// How long a connection waits on a locked database before erroring
+    pub busy_timeout_ms: u64,
+    
+    /// Number of read-only connections (0 = share the write connection)
+    pub read_connections: usize,
+    
+    /// Folder that download locations under it are stored relative to, so
+    /// moving it (and pointing this at the new place) keeps the history and
+    /// partial files working. Locations elsewhere stay absolute.
+    pub data_root: Option<PathBuf>,
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        Self {
+            wal: true,
+            busy_timeout_ms: 5000,
+            read_connections: 2,
+            data_root: None,
         }
     }
 }
@@ -157,4 +731,298 @@ pub struct FileTypeConfig {
     
     /// Destination folder for downloads of this type
     pub destination: PathBuf,
+    
+    /// Extract completed `.zip`/`.tar.gz` downloads into a folder beside them
+    #[serde(default)]
+    pub auto_extract: bool,
+    
+    /// Overrides the global `on_conflict` for this file type
+    #[serde(default)]
+    pub on_conflict: Option<ConflictPolicy>,
+    
+    /// Convert completed downloads with ffmpeg, e.g. to mp3
+    #[serde(default)]
+    pub post_download_transcode: Option<TranscodeConfig>,
+    
+    /// Subfolder of `destination` named after the day a download starts,
+    /// as a `chrono` format such as `%Y/%m/%d`
+    #[serde(default)]
+    pub date_subfolder: Option<String>,
+    
+    /// Position in lists of file types, lowest first; ties go by id
+    #[serde(default)]
+    pub order: i32,
+}
+
+impl FileTypeConfig {
+    /// Folder a download starting at `now` is saved in: `destination`, or
+    /// the `date_subfolder` below it. A pattern that doesn't expand to a
+    /// plain relative path is ignored.
+    pub fn destination_at(&self, now: &chrono::DateTime<chrono::Local>) -> PathBuf {
+        match self.date_subfolder.as_deref().and_then(|pattern| date_subfolder(pattern, now)) {
+            Some(subfolder) => self.destination.join(subfolder),
+            None => self.destination.clone(),
+        }
+    }
+}
+
+/// Expand a `date_subfolder` pattern for `now`. `None` if the pattern is
+/// malformed, or would leave the destination: an absolute path or `..`.
+pub fn date_subfolder(pattern: &str, now: &chrono::DateTime<chrono::Local>) -> Option<PathBuf> {
+    use chrono::format::{Item, StrftimeItems};
+    
+    let items: Vec<Item> = StrftimeItems::new(pattern).collect();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return None;
+    }
+    let subfolder = PathBuf::from(now.format_with_items(items.into_iter()).to_string());
+    let plain = subfolder
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)));
+    (plain && subfolder.components().next().is_some()).then_some(subfolder)
+}
+
+/// What a `server.host` must be
+pub const SERVER_HOST_RULE: &str = "must be an IP address, e.g. 0.0.0.0 for every interface or 10.8.0.2 for one";
+
+/// The IP address a `server.host` names, ignoring surrounding whitespace
+pub fn parse_host(host: &str) -> Option<IpAddr> {
+    host.trim().parse().ok()
+}
+
+/// Whether a `date_subfolder` pattern can be used
+pub fn valid_date_subfolder(pattern: &str) -> bool {
+    date_subfolder(pattern, &chrono::Local::now()).is_some()
+}
+
+/// Gives downloads whose URL matches `url_pattern` the file type `file_type`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoutingRule {
+    /// Glob matched against the whole URL or just its host, ignoring case:
+    /// `*` matches any run of characters and `?` any one, so
+    /// `*.youtube.com` and `https://example.com/videos/*` both work
+    pub url_pattern: String,
+    
+    pub file_type: String,
+}
+
+impl RoutingRule {
+    pub fn matches(&self, url: &str) -> bool {
+        let pattern = self.url_pattern.trim().to_lowercase();
+        if pattern.is_empty() {
+            return false;
+        }
+        let host = url::Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_lowercase));
+        glob_match(&pattern, &url.to_lowercase()) || host.is_some_and(|host| glob_match(&pattern, &host))
+    }
+}
+
+/// Match `text` against a glob where `*` is any run of characters and `?`
+/// any one character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and the text position it's now standing in for
+    let mut star: Option<(usize, usize)> = None;
+    
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the `*` take one more character and try again
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// What completed downloads of a file type are transcoded to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TranscodeConfig {
+    /// Target format, as the extension ffmpeg picks the container by (`mp3`)
+    pub format: String,
+    
+    /// Delete the download once it's converted, leaving only the new file
+    #[serde(default)]
+    pub delete_original: bool,
+}
+
+impl TranscodeConfig {
+    /// Whether `format` is a plain extension, which is all that's ever put
+    /// on ffmpeg's command line from it
+    pub fn is_valid(&self) -> bool {
+        (1..=10).contains(&self.format.len()) && self.format.bytes().all(|b| b.is_ascii_alphanumeric())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn file_types_are_reordered() {
+        let mut settings = Settings::default();
+        let ids = |settings: &Settings| -> Vec<String> {
+            settings.file_types_in_order().into_iter().map(|(id, _)| id.clone()).collect()
+        };
+        assert_eq!(ids(&settings), ["general", "video", "audio", "documents", "images", "archives"]);
+        
+        settings.reorder_file_types(&["archives".into(), "video".into()]).unwrap();
+        let order = ["archives", "video", "general", "audio", "documents", "images"];
+        assert_eq!(ids(&settings), order);
+        
+        assert!(settings.reorder_file_types(&["missing".into()]).is_err());
+        assert!(settings.reorder_file_types(&["video".into(), "video".into()]).is_err());
+        assert_eq!(ids(&settings), order);
+        
+        // Ties go by id
+        for file_type in settings.file_types.values_mut() {
+            file_type.order = 0;
+        }
+        assert_eq!(ids(&settings), ["archives", "audio", "documents", "general", "images", "video"]);
+        
+        // A config from before file types had an order gets the default one
+        let mut extra = settings.file_types["general"].clone();
+        extra.name = "Disk images".into();
+        settings.file_types.insert("disk-images".into(), extra);
+        settings.number_unordered_file_types();
+        let numbered = ["general", "video", "audio", "documents", "images", "archives", "disk-images"];
+        assert_eq!(ids(&settings), numbered);
+        let orders: Vec<i32> = settings.file_types_in_order().into_iter().map(|(_, t)| t.order).collect();
+        assert_eq!(orders, [0, 1, 2, 3, 4, 5, 6]);
+        
+        // An order that was set is kept
+        settings.reorder_file_types(&["disk-images".into()]).unwrap();
+        settings.number_unordered_file_types();
+        assert_eq!(ids(&settings)[0], "disk-images");
+    }
+    
+    #[test]
+    fn date_subfolder_stays_inside_the_destination() {
+        use chrono::TimeZone;
+        let now = chrono::Local.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap();
+        let mut file_type = Settings::default().file_types.remove("general").unwrap();
+        
+        file_type.date_subfolder = Some("%Y/%m/%d".into());
+        assert_eq!(file_type.destination_at(&now), file_type.destination.join("2024").join("03").join("09"));
+        
+        for pattern in ["/%Y", "%Y/../..", "..", "", "%Q"] {
+            assert!(date_subfolder(pattern, &now).is_none(), "{}", pattern);
+            file_type.date_subfolder = Some(pattern.into());
+            assert_eq!(file_type.destination_at(&now), file_type.destination);
+        }
+    }
+    
+    #[test]
+    fn file_type_conflict_policy_overrides_global() {
+        let mut settings = Settings { on_conflict: ConflictPolicy::Rename, ..Settings::default() };
+        settings.file_types.get_mut("documents").unwrap().on_conflict = Some(ConflictPolicy::Overwrite);
+        
+        assert_eq!(settings.conflict_policy("documents"), ConflictPolicy::Overwrite);
+        assert_eq!(settings.conflict_policy("archives"), ConflictPolicy::Rename);
+        // Unknown types use the global policy too
+        assert_eq!(settings.conflict_policy("nope"), ConflictPolicy::Rename);
+        
+        settings.on_conflict = ConflictPolicy::Overwrite;
+        settings.file_types.get_mut("archives").unwrap().on_conflict = Some(ConflictPolicy::Rename);
+        assert_eq!(settings.conflict_policy("archives"), ConflictPolicy::Rename);
+        assert_eq!(settings.conflict_policy("general"), ConflictPolicy::Overwrite);
+    }
+    
+    #[test]
+    fn routing_rules_win_over_extensions() {
+        let settings = Settings {
+            routing_rules: vec![
+                RoutingRule { url_pattern: "*.youtube.com".into(), file_type: "video".into() },
+                RoutingRule { url_pattern: "https://example.com/docs/*".into(), file_type: "documents".into() },
+                RoutingRule { url_pattern: "*".into(), file_type: "missing".into() },
+            ],
+            ..Settings::default()
+        };
+        
+        assert_eq!(settings.file_type_for("https://www.YouTube.com/watch?v=1", "watch"), "video");
+        assert_eq!(settings.file_type_for("https://example.com/docs/a.zip", "a.zip"), "documents");
+        // No rule for an existing type matches, so the extension decides
+        assert_eq!(settings.file_type_for("https://example.com/a.zip", "a.zip"), "archives");
+        
+        let problems: Vec<String> = settings.routing_rule_problems().into_iter().map(|(field, _)| field).collect();
+        assert_eq!(problems, ["routing_rules.2.file_type"]);
+        
+        let saved = toml::to_string_pretty(&settings).unwrap();
+        assert_eq!(toml::from_str::<Settings>(&saved).unwrap().routing_rules, settings.routing_rules);
+    }
+    
+    #[test]
+    fn removing_a_file_type_drops_its_routing_rules() {
+        let mut settings = Settings {
+            routing_rules: vec![
+                RoutingRule { url_pattern: "*.youtube.com".into(), file_type: "video".into() },
+                RoutingRule { url_pattern: "*.pdf".into(), file_type: "documents".into() },
+            ],
+            ..Settings::default()
+        };
+        
+        assert!(settings.remove_file_type("video").is_some());
+        assert!(settings.remove_file_type("video").is_none());
+        assert_eq!(settings.routing_rules.len(), 1);
+        
+        // What's saved loads again
+        let saved = toml::to_string_pretty(&settings).unwrap();
+        assert!(crate::config::parse(&saved).is_ok());
+    }
+    
+    #[test]
+    fn globs_match_any_runs() {
+        assert!(glob_match("*.example.com", "cdn.example.com"));
+        assert!(!glob_match("*.example.com", "example.com"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(glob_match("file?.bin", "file1.bin"));
+        assert!(!glob_match("file?.bin", "file.bin"));
+        assert!(glob_match("*", ""));
+    }
+    
+    #[test]
+    fn server_binds_to_its_host_only_with_lan_access() {
+        let mut server = ServerSettings::default();
+        assert_eq!(server.bind_host().to_string(), "0.0.0.0");
+        assert_eq!(server.local_url(), "http://localhost:8787");
+        
+        server.host = " 10.8.0.2 ".into();
+        assert_eq!(server.local_url(), "http://10.8.0.2:8787");
+        server.host = "fd00::2".into();
+        assert_eq!(server.local_url(), "http://[fd00::2]:8787");
+        
+        server.lan_access = false;
+        assert_eq!(server.bind_host().to_string(), "127.0.0.1");
+        
+        assert!(parse_host("localhost").is_none());
+        assert!(parse_host("10.8.0.256").is_none());
+    }
+    
+    #[test]
+    fn read_only_mode_needs_the_write_token() {
+        let mut server = ServerSettings::default();
+        assert!(server.allows_write(None));
+        
+        server.read_only = true;
+        assert!(!server.allows_write(None));
+        assert!(!server.allows_write(Some("Bearer anything")));
+        
+        server.write_token = Some("s3cret".into());
+        assert!(server.allows_write(Some("Bearer s3cret")));
+        assert!(!server.allows_write(Some("Bearer s3cre")));
+        assert!(!server.allows_write(Some("s3cret")));
+        
+        // An empty token would let any empty bearer through
+        server.write_token = Some(String::new());
+        assert!(!server.allows_write(Some("Bearer ")));
+    }
 }
@@ -4,33 +4,230 @@ mod schema;
 
 pub use schema::*;
 
+use crate::config::DatabaseSettings;
 use anyhow::Result;
-use rusqlite::Connection;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use rusqlite::{Connection, OpenFlags};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Columns selected for a `DownloadRecord`, in the order `row_to_record` reads them
+const RECORD_COLUMNS: &str = "id, url, filename, file_type, destination, \
+    total_size, downloaded_size, status, error_message, \
+    created_at, started_at, completed_at, \
+    accept, query_params, cancel_reason, error_kind, extracted_path, \
+    speed_limit, mirrors, checksum, final_url, batch_id, \
+    skip_if_unmodified, etag, last_modified, not_modified, range_start, range_end, \
+    link_file_types, linked_from, transcoded_path, active_secs, method, body, content_type, \
+    max_duration_secs, shares_file_with, headers, rename_to";
+
+/// Maximum error history entries kept per download
+const MAX_ERRORS_PER_DOWNLOAD: usize = 20;
+
+/// Map a row selected with `RECORD_COLUMNS` to a `DownloadRecord`, with
+/// locations resolved against `data_root`
+fn row_to_record(row: &rusqlite::Row, data_root: Option<&Path>) -> rusqlite::Result<DownloadRecord> {
+    let record = DownloadRecord {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        filename: row.get(2)?,
+        file_type: row.get(3)?,
+        destination: load_path(data_root, row.get(4)?),
+        total_size: row.get(5)?,
+        downloaded_size: row.get(6)?,
+        status: DownloadStatus::from_str(&row.get::<_, String>(7)?),
+        error_message: row.get(8)?,
+        created_at: parse_timestamp(&row.get::<_, String>(9)?)
+            .unwrap_or_else(chrono::Utc::now),
+        started_at: row.get::<_, Option<String>>(10)?
+            .and_then(|s| parse_timestamp(&s)),
+        completed_at: row.get::<_, Option<String>>(11)?
+            .and_then(|s| parse_timestamp(&s)),
+        accept: row.get(12)?,
+        query_params: row.get::<_, Option<String>>(13)?
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default(),
+        cancel_reason: row.get::<_, Option<String>>(14)?
+            .and_then(|s| CancelReason::from_str(&s)),
+        error_kind: row.get::<_, Option<String>>(15)?
+            .and_then(|s| DownloadErrorKind::from_str(&s)),
+        extracted_path: row.get::<_, Option<String>>(16)?.map(|s| load_path(data_root, s)),
+        speed_limit: row.get(17)?,
+        mirrors: row.get::<_, Option<String>>(18)?
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default(),
+        checksum: row.get(19)?,
+        final_url: row.get(20)?,
+        batch_id: row.get(21)?,
+        skip_if_unmodified: row.get(22)?,
+        etag: row.get(23)?,
+        last_modified: row.get(24)?,
+        not_modified: row.get(25)?,
+        range_start: row.get(26)?,
+        range_end: row.get(27)?,
+        link_file_types: row.get::<_, Option<String>>(28)?
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default(),
+        linked_from: row.get(29)?,
+        transcoded_path: row.get::<_, Option<String>>(30)?.map(|s| load_path(data_root, s)),
+        active_secs: row.get(31)?,
+        method: row.get::<_, Option<String>>(32)?
+            .map(|s| RequestMethod::from_str(&s))
+            .unwrap_or_default(),
+        body: row.get(33)?,
+        content_type: row.get(34)?,
+        max_duration_secs: row.get(35)?,
+        shares_file_with: row.get(36)?,
+        headers: row.get::<_, Option<String>>(37)?
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default(),
+        rename_to: row.get(38)?,
+        duration_secs: None,
+    };
+    Ok(record.with_duration())
+}
+
+/// How a location is stored: relative to `data_root` when it's under it,
+/// absolute otherwise
+fn store_path(data_root: Option<&Path>, path: &Path) -> String {
+    match data_root.and_then(|root| path.strip_prefix(root).ok()) {
+        Some(relative) => relative.to_string_lossy().into_owned(),
+        None => path.to_string_lossy().into_owned(),
+    }
+}
+
+/// The location a stored path stands for. Relative ones are under `data_root`.
+fn load_path(data_root: Option<&Path>, stored: String) -> PathBuf {
+    let path = PathBuf::from(stored);
+    match data_root {
+        Some(root) if path.as_os_str().is_empty() => root.to_path_buf(),
+        Some(root) if path.is_relative() => root.join(path),
+        _ => path,
+    }
+}
+
+/// Store the absolute locations under `data_root` relative to it, e.g.
+/// those saved before it was set
+fn relativize_paths(conn: &Connection, data_root: &Path) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT id, destination, extracted_path, transcoded_path FROM downloads")?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    
+    let relative = |stored: &str| {
+        let path = Path::new(stored);
+        path.is_absolute().then(|| store_path(Some(data_root), path))
+    };
+    for (id, destination, extracted, transcoded) in rows {
+        let changed = [Some(&destination), extracted.as_ref(), transcoded.as_ref()]
+            .into_iter()
+            .flatten()
+            .any(|stored| relative(stored).is_some_and(|r| &r != stored));
+        if !changed {
+            continue;
+        }
+        let convert = |stored: &str| relative(stored).unwrap_or_else(|| stored.to_string());
+        conn.execute(
+            "UPDATE downloads SET destination = ?1, extracted_path = ?2, transcoded_path = ?3 WHERE id = ?4",
+            rusqlite::params![
+                convert(&destination),
+                extracted.as_deref().map(convert),
+                transcoded.as_deref().map(convert),
+                id
+            ],
+        )?;
+    }
+    
+    Ok(())
+}
+
+/// Add a column to an existing table unless it's already there.
+/// Used for columns introduced after the table was first created.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|r| r.ok())
+        .any(|name| name == column);
+    
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    
+    Ok(())
+}
+
+/// Parse an RFC 3339 timestamp stored in the database
+fn parse_timestamp(s: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::parse_from_rfc3339(s)
+        .ok()
+        .map(|dt| dt.with_timezone(&chrono::Utc))
+}
 
 /// Database wrapper for SQLite operations
 #[derive(Clone)]
 pub struct Database {
+    /// Connection used for all writes
     conn: Arc<Mutex<Connection>>,
+    
+    /// Read-only connections so listings don't wait behind progress writes
+    readers: Arc<Vec<Mutex<Connection>>>,
+    
+    /// Round-robin cursor for picking a reader when all are busy
+    next_reader: Arc<AtomicUsize>,
+    
+    /// Folder that locations under it are stored relative to
+    data_root: Arc<Option<PathBuf>>,
 }
 
 impl Database {
-    /// Create a new database connection
-    pub fn new() -> Result<Self> {
-        let path = Self::db_path();
-        
+    /// Open the database at `path`, or at the default location in the
+    /// config folder when `None`
+    pub fn new(path: Option<&Path>, settings: &DatabaseSettings) -> Result<Self> {
+        match path {
+            Some(path) => Self::open(path, settings),
+            None => Self::open(&Self::db_path(), settings),
+        }
+    }
+    
+    /// Open (or create) the database at `path`
+    pub(crate) fn open(path: &Path, settings: &DatabaseSettings) -> Result<Self> {
         // Ensure parent directory exists
         if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent)?;
         }
         
-        let conn = Connection::open(&path)?;
-        let db = Self {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_millis(settings.busy_timeout_ms))?;
+        
+        let mut db = Self {
             conn: Arc::new(Mutex::new(conn)),
+            readers: Arc::new(Vec::new()),
+            next_reader: Arc::new(AtomicUsize::new(0)),
+            data_root: Arc::new(settings.data_root.clone()),
         };
         
-        db.initialize_schema()?;
+        db.initialize_schema(settings)?;
+        
+        // Readers are opened after the schema exists
+        let mut readers = Vec::with_capacity(settings.read_connections);
+        for _ in 0..settings.read_connections {
+            let reader = Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            reader.busy_timeout(Duration::from_millis(settings.busy_timeout_ms))?;
+            readers.push(Mutex::new(reader));
+        }
+        db.readers = Arc::new(readers);
         
         Ok(db)
     }
@@ -40,10 +237,36 @@ impl Database {
         crate::config::config_dir().join("downloads.db")
     }
     
+    fn data_root(&self) -> Option<&Path> {
+        self.data_root.as_deref()
+    }
+    
+    /// Get a connection for read-only queries, preferring an idle reader.
+    /// Falls back to the write connection when no readers are configured.
+    fn reader(&self) -> MutexGuard<'_, Connection> {
+        if self.readers.is_empty() {
+            return self.conn.lock().unwrap();
+        }
+        
+        for reader in self.readers.iter() {
+            if let Ok(conn) = reader.try_lock() {
+                return conn;
+            }
+        }
+        
+        let index = self.next_reader.fetch_add(1, Ordering::Relaxed) % self.readers.len();
+        self.readers[index].lock().unwrap()
+    }
+    
     /// Initialize the database schema
-    fn initialize_schema(&self) -> Result<()> {
+    fn initialize_schema(&self, settings: &DatabaseSettings) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         
+        // WAL lets the readers run alongside a writer instead of blocking on it.
+        // The pragma reports the resulting mode, so it's read as a query.
+        let mode = if settings.wal { "WAL" } else { "DELETE" };
+        let _: String = conn.query_row(&format!("PRAGMA journal_mode={}", mode), [], |row| row.get(0))?;
+        
         conn.execute_batch(
             r#"
             CREATE TABLE IF NOT EXISTS downloads (
@@ -63,9 +286,58 @@ impl Database {
             
             CREATE INDEX IF NOT EXISTS idx_downloads_status ON downloads(status);
             CREATE INDEX IF NOT EXISTS idx_downloads_created_at ON downloads(created_at);
+            
+            CREATE TABLE IF NOT EXISTS download_errors (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                download_id TEXT NOT NULL,
+                message TEXT NOT NULL,
+                occurred_at TEXT NOT NULL
+            );
+            
+            CREATE INDEX IF NOT EXISTS idx_download_errors_download_id ON download_errors(download_id);
             "#,
         )?;
         
+        // Columns added since the original schema
+        add_column_if_missing(&conn, "downloads", "accept", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "query_params", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "queue_order", "INTEGER")?;
+        add_column_if_missing(&conn, "downloads", "cancel_reason", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "error_kind", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "extracted_path", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "speed_limit", "INTEGER")?;
+        add_column_if_missing(&conn, "downloads", "mirrors", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "checksum", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "final_url", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "batch_id", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "skip_if_unmodified", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column_if_missing(&conn, "downloads", "etag", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "last_modified", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "not_modified", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column_if_missing(&conn, "downloads", "range_start", "INTEGER")?;
+        add_column_if_missing(&conn, "downloads", "range_end", "INTEGER")?;
+        add_column_if_missing(&conn, "downloads", "link_file_types", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "linked_from", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "transcoded_path", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "active_secs", "REAL NOT NULL DEFAULT 0")?;
+        add_column_if_missing(&conn, "downloads", "method", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "body", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "content_type", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "max_duration_secs", "INTEGER")?;
+        add_column_if_missing(&conn, "downloads", "shares_file_with", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "headers", "TEXT")?;
+        add_column_if_missing(&conn, "downloads", "rename_to", "TEXT")?;
+        
+        if let Some(root) = &settings.data_root {
+            relativize_paths(&conn, root)?;
+        }
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_downloads_batch_id ON downloads(batch_id)",
+            [],
+        )?;
+        // Looked up by URL for conditional re-downloads
+        conn.execute("CREATE INDEX IF NOT EXISTS idx_downloads_url ON downloads(url)", [])?;
+        
         Ok(())
     }
     
@@ -77,25 +349,252 @@ impl Database {
             r#"
             INSERT INTO downloads (
                 id, url, filename, file_type, destination, 
-                total_size, downloaded_size, status, created_at
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                total_size, downloaded_size, status, created_at,
+                accept, query_params, speed_limit, mirrors, checksum, batch_id,
+                skip_if_unmodified, etag, last_modified, range_start, range_end,
+                link_file_types, linked_from, final_url, method, body, content_type,
+                max_duration_secs, headers
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20,
+                ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28)
             "#,
             rusqlite::params![
                 download.id,
                 download.url,
                 download.filename,
                 download.file_type,
-                download.destination.to_string_lossy(),
+                store_path(self.data_root(), &download.destination),
                 download.total_size,
                 download.downloaded_size,
                 download.status.as_str(),
                 download.created_at.to_rfc3339(),
+                download.accept,
+                serde_json::to_string(&download.query_params)?,
+                download.speed_limit,
+                serde_json::to_string(&download.mirrors)?,
+                download.checksum,
+                download.batch_id,
+                download.skip_if_unmodified,
+                download.etag,
+                download.last_modified,
+                download.range_start,
+                download.range_end,
+                serde_json::to_string(&download.link_file_types)?,
+                download.linked_from,
+                download.final_url,
+                download.method.as_str(),
+                download.body,
+                download.content_type,
+                download.max_duration_secs,
+                serde_json::to_string(&download.headers)?,
             ],
         )?;
         
         Ok(())
     }
     
+    /// Change the filename a download is saved under, dropping any pending
+    /// `rename_to`
+    pub fn update_filename(&self, id: &str, filename: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET filename = ?1, rename_to = NULL WHERE id = ?2",
+            rusqlite::params![filename, id],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Record the name a started download is to be saved under once finished
+    pub fn set_rename_to(&self, id: &str, filename: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET rename_to = ?1 WHERE id = ?2",
+            rusqlite::params![filename, id],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Record that a download's file now lives at `destination/filename`
+    pub fn update_location(&self, id: &str, destination: &Path, filename: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET destination = ?1, filename = ?2 WHERE id = ?3",
+            rusqlite::params![store_path(self.data_root(), destination), filename, id],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Change a download's speed cap (`None` = unlimited)
+    pub fn update_speed_limit(&self, id: &str, limit: Option<u64>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET speed_limit = ?1 WHERE id = ?2",
+            rusqlite::params![limit, id],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Record the folder a completed archive was extracted into
+    pub fn set_extracted_path(&self, id: &str, path: &Path) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET extracted_path = ?1 WHERE id = ?2",
+            rusqlite::params![store_path(self.data_root(), path), id],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Record the file a completed download was transcoded into
+    pub fn set_transcoded_path(&self, id: &str, path: &Path) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET transcoded_path = ?1 WHERE id = ?2",
+            rusqlite::params![store_path(self.data_root(), path), id],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Add the length of one run to a download's total active time
+    pub fn add_active_time(&self, id: &str, elapsed: Duration) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET active_secs = active_secs + ?1 WHERE id = ?2",
+            rusqlite::params![elapsed.as_secs_f64(), id],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Record the URL a download's content finally came from, after redirects
+    pub fn set_final_url(&self, id: &str, url: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET final_url = ?1 WHERE id = ?2",
+            rusqlite::params![url, id],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Record the digest computed for a finished download
+    pub fn set_checksum(&self, id: &str, checksum: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET checksum = ?1 WHERE id = ?2",
+            rusqlite::params![checksum, id],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Record the `ETag` and `Last-Modified` the content came with
+    pub fn set_validators(&self, id: &str, etag: Option<&str>, last_modified: Option<&str>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET etag = ?1, last_modified = ?2 WHERE id = ?3",
+            rusqlite::params![etag, last_modified, id],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Note that a download was skipped because the content hadn't changed
+    pub fn set_not_modified(&self, id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET not_modified = 1 WHERE id = ?1",
+            rusqlite::params![id],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// The most recently completed download of `url` that recorded an
+    /// `ETag` or `Last-Modified`, to make a conditional request against.
+    /// Its `etag` and `last_modified` are the validators to send; a
+    /// download that came back not modified carries them on too.
+    pub fn find_latest_completed_by_url(&self, url: &str) -> Result<Option<DownloadRecord>> {
+        let conn = self.reader();
+        
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM downloads \
+             WHERE url = ?1 AND status = 'completed' \
+             AND (etag IS NOT NULL OR last_modified IS NOT NULL) \
+             ORDER BY completed_at DESC LIMIT 1",
+            RECORD_COLUMNS
+        ))?;
+        
+        let mut rows = stmt.query_map([url], |row| row_to_record(row, self.data_root()))?;
+        Ok(rows.next().transpose()?)
+    }
+    
+    /// Completed downloads other than `exclude_id` with `checksum`, oldest
+    /// first, as candidates to share a file with
+    pub fn find_completed_by_checksum(&self, checksum: &str, exclude_id: &str) -> Result<Vec<DownloadRecord>> {
+        let conn = self.reader();
+        
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM downloads \
+             WHERE checksum = ?1 AND id != ?2 AND status = 'completed' \
+             ORDER BY completed_at ASC LIMIT 10",
+            RECORD_COLUMNS
+        ))?;
+        
+        let rows = stmt.query_map(rusqlite::params![checksum, exclude_id], |row| {
+            row_to_record(row, self.data_root())
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+    
+    /// Folders of cancelled downloads, whose trash may hold their partial files
+    pub fn cancelled_destinations(&self) -> Result<Vec<PathBuf>> {
+        let conn = self.reader();
+        
+        let mut stmt = conn.prepare("SELECT DISTINCT destination FROM downloads WHERE status = 'cancelled'")?;
+        let rows = stmt.query_map([], |row| Ok(load_path(self.data_root(), row.get(0)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+    
+    /// Total size of the completed downloads in each destination folder
+    pub fn completed_size_by_destination(&self) -> Result<Vec<(PathBuf, u64)>> {
+        let conn = self.reader();
+        
+        let mut stmt = conn.prepare(
+            "SELECT destination, COALESCE(SUM(COALESCE(total_size, downloaded_size)), 0) \
+             FROM downloads WHERE status = 'completed' GROUP BY destination",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((load_path(self.data_root(), row.get(0)?), row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+    
+    /// Record that a download's file is a hard link to another download's
+    pub fn set_shares_file_with(&self, id: &str, original_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            "UPDATE downloads SET shares_file_with = ?1 WHERE id = ?2",
+            rusqlite::params![original_id, id],
+        )?;
+        
+        Ok(())
+    }
+    
     /// Update download progress
     pub fn update_progress(&self, id: &str, downloaded: u64, total: Option<u64>) -> Result<()> {
         let conn = self.conn.lock().unwrap();
@@ -108,6 +607,31 @@ impl Database {
         Ok(())
     }
     
+    /// Save the progress of running downloads, given as id, downloaded and
+    /// total bytes, in one transaction. Unlike `update_progress` this leaves
+    /// downloads that have since stopped alone, so a late update can't
+    /// overwrite the final size.
+    pub fn save_running_progress<'a>(
+        &self,
+        progress: impl IntoIterator<Item = (&'a str, u64, Option<u64>)>,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        
+        {
+            let mut stmt = tx.prepare(
+                "UPDATE downloads SET downloaded_size = ?1, total_size = ?2 \
+                 WHERE id = ?3 AND status = 'downloading'",
+            )?;
+            for (id, downloaded, total) in progress {
+                stmt.execute(rusqlite::params![downloaded, total, id])?;
+            }
+        }
+        
+        tx.commit()?;
+        Ok(())
+    }
+    
     /// Update download status
     pub fn update_status(&self, id: &str, status: DownloadStatus, error: Option<&str>) -> Result<()> {
         let conn = self.conn.lock().unwrap();
@@ -117,19 +641,31 @@ impl Database {
         match status {
             DownloadStatus::Downloading => {
                 conn.execute(
-                    "UPDATE downloads SET status = ?1, started_at = ?2 WHERE id = ?3",
+                    r#"
+                    UPDATE downloads
+                    SET status = ?1, started_at = ?2, cancel_reason = NULL, error_kind = NULL
+                    WHERE id = ?3
+                    "#,
                     rusqlite::params![status.as_str(), now, id],
                 )?;
             }
             DownloadStatus::Completed | DownloadStatus::Failed => {
                 conn.execute(
-                    "UPDATE downloads SET status = ?1, completed_at = ?2, error_message = ?3 WHERE id = ?4",
+                    "UPDATE downloads SET status = ?1, completed_at = ?2, error_message = ?3, error_kind = NULL WHERE id = ?4",
                     rusqlite::params![status.as_str(), now, error, id],
                 )?;
+                
+                if let (DownloadStatus::Failed, Some(message)) = (status, error) {
+                    Self::insert_error(&conn, id, message)?;
+                }
             }
             _ => {
                 conn.execute(
-                    "UPDATE downloads SET status = ?1, error_message = ?2 WHERE id = ?3",
+                    r#"
+                    UPDATE downloads
+                    SET status = ?1, error_message = ?2, cancel_reason = NULL, error_kind = NULL
+                    WHERE id = ?3
+                    "#,
                     rusqlite::params![status.as_str(), error, id],
                 )?;
             }
@@ -138,97 +674,704 @@ impl Database {
         Ok(())
     }
     
-    /// Get all downloads
-    pub fn get_all_downloads(&self) -> Result<Vec<DownloadRecord>> {
+    /// Mark a download cancelled, recording why
+    pub fn mark_cancelled(&self, id: &str, reason: CancelReason) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         
-        let mut stmt = conn.prepare(
+        conn.execute(
             r#"
-            SELECT id, url, filename, file_type, destination, 
-                   total_size, downloaded_size, status, error_message,
-                   created_at, started_at, completed_at
-            FROM downloads
-            ORDER BY created_at DESC
+            UPDATE downloads
+            SET status = 'cancelled', completed_at = ?1, error_message = ?2,
+                cancel_reason = ?3, error_kind = ?4
+            WHERE id = ?5
+            "#,
+            rusqlite::params![
+                chrono::Utc::now().to_rfc3339(),
+                format!("Download {}", reason),
+                reason.as_str(),
+                DownloadErrorKind::from(reason).as_str(),
+                id,
+            ],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Mark a download failed, recording the message and its category
+    pub fn mark_failed(&self, id: &str, kind: DownloadErrorKind, message: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        
+        conn.execute(
+            r#"
+            UPDATE downloads
+            SET status = 'failed', completed_at = ?1, error_message = ?2, error_kind = ?3
+            WHERE id = ?4
             "#,
+            rusqlite::params![chrono::Utc::now().to_rfc3339(), message, kind.as_str(), id],
         )?;
+        Self::insert_error(&conn, id, message)?;
+        
+        Ok(())
+    }
+    
+    /// Get all downloads, newest first
+    pub fn get_all_downloads(&self) -> Result<Vec<DownloadRecord>> {
+        self.list_downloads(&DownloadListQuery::default())
+    }
+    
+    /// Get the downloads created within a time range, sorted as asked.
+    /// Downloads without a value in the sort column (e.g. not completed yet)
+    /// go last either way.
+    pub fn list_downloads(&self, query: &DownloadListQuery) -> Result<Vec<DownloadRecord>> {
+        let conn = self.reader();
+        
+        // Timestamps are stored as RFC 3339 in UTC, which sorts as text
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        if let Some(from) = query.created_from {
+            params.push(from.to_rfc3339());
+            conditions.push(format!("created_at >= ?{}", params.len()));
+        }
+        if let Some(to) = query.created_to {
+            params.push(to.to_rfc3339());
+            conditions.push(format!("created_at <= ?{}", params.len()));
+        }
+        let filter = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        
+        let column = query.sort.column();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM downloads {} ORDER BY {} IS NULL, {} {}, created_at DESC",
+            RECORD_COLUMNS,
+            filter,
+            column,
+            column,
+            query.order.as_sql()
+        ))?;
         
         let downloads = stmt
-            .query_map([], |row| {
-                Ok(DownloadRecord {
-                    id: row.get(0)?,
-                    url: row.get(1)?,
-                    filename: row.get(2)?,
-                    file_type: row.get(3)?,
-                    destination: PathBuf::from(row.get::<_, String>(4)?),
-                    total_size: row.get(5)?,
-                    downloaded_size: row.get(6)?,
-                    status: DownloadStatus::from_str(&row.get::<_, String>(7)?),
-                    error_message: row.get(8)?,
-                    created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(9)?)
-                        .map(|dt| dt.with_timezone(&chrono::Utc))
-                        .unwrap_or_else(|_| chrono::Utc::now()),
-                    started_at: row.get::<_, Option<String>>(10)?
-                        .and_then(|s| chrono::DateTime::parse_from_rfc3339(&s).ok())
-                        .map(|dt| dt.with_timezone(&chrono::Utc)),
-                    completed_at: row.get::<_, Option<String>>(11)?
-                        .and_then(|s| chrono::DateTime::parse_from_rfc3339(&s).ok())
-                        .map(|dt| dt.with_timezone(&chrono::Utc)),
-                })
-            })?
+            .query_map(rusqlite::params_from_iter(&params), |row| row_to_record(row, self.data_root()))?
             .filter_map(|r| r.ok())
             .collect();
         
         Ok(downloads)
     }
     
+    /// Get a single download by id
+    pub fn get_download(&self, id: &str) -> Result<Option<DownloadRecord>> {
+        let conn = self.reader();
+        
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM downloads WHERE id = ?1",
+            RECORD_COLUMNS
+        ))?;
+        
+        let mut rows = stmt.query_map([id], |row| row_to_record(row, self.data_root()))?;
+        Ok(rows.next().transpose()?)
+    }
+    
     /// Delete a download record
     pub fn delete_download(&self, id: &str) -> Result<()> {
+        self.delete_downloads(&[id])
+    }
+    
+    /// Delete several downloads and their error history in one transaction
+    pub fn delete_downloads(&self, ids: &[&str]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        
+        for id in ids {
+            tx.execute("DELETE FROM downloads WHERE id = ?1", [id])?;
+            tx.execute("DELETE FROM download_errors WHERE download_id = ?1", [id])?;
+            // Their files are links of their own, so they're unaffected
+            tx.execute("UPDATE downloads SET shares_file_with = NULL WHERE shares_file_with = ?1", [id])?;
+        }
+        
+        tx.commit()?;
+        Ok(())
+    }
+    
+    /// Persist where a download sits in the queue: lower positions are next
+    /// in line, and `None` means it's no longer queued. Positions only need
+    /// to keep their order, so moving one download writes just its row.
+    pub fn set_queue_order(&self, id: &str, position: Option<i64>) -> Result<()> {
         let conn = self.conn.lock().unwrap();
-        conn.execute("DELETE FROM downloads WHERE id = ?1", [id])?;
+        conn.execute("UPDATE downloads SET queue_order = ?1 WHERE id = ?2", rusqlite::params![position, id])?;
         Ok(())
     }
     
-    /// Get pending downloads (for resuming on startup)
-    pub fn get_pending_downloads(&self) -> Result<Vec<DownloadRecord>> {
+    /// Lowest and highest saved queue positions, for new ones to go around
+    pub fn queue_order_bounds(&self) -> Result<(Option<i64>, Option<i64>)> {
+        let conn = self.reader();
+        let bounds = conn.query_row("SELECT MIN(queue_order), MAX(queue_order) FROM downloads", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        Ok(bounds)
+    }
+    
+    /// Fold the WAL back into the database file, e.g. before exiting
+    pub fn checkpoint(&self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        // The pragma reports its outcome as a row; outside WAL mode it's a no-op
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok(())
+    }
+    
+    /// Delete all completed (and optionally cancelled) records, returning how many were removed.
+    /// Files on disk are left alone.
+    pub fn clear_completed(&self, include_cancelled: bool) -> Result<usize> {
         let conn = self.conn.lock().unwrap();
         
+        let removed = conn.execute(
+            "DELETE FROM downloads WHERE status = 'completed' OR (?1 AND status = 'cancelled')",
+            [include_cancelled],
+        )?;
+        
+        conn.execute(
+            "DELETE FROM download_errors WHERE download_id NOT IN (SELECT id FROM downloads)",
+            [],
+        )?;
+        
+        Ok(removed)
+    }
+    
+    /// Delete all but the `keep` most recently finished completed, failed
+    /// and cancelled records, returning how many were removed. Downloads
+    /// cancelled by a shutdown aren't finished, they resume on the next
+    /// start, so they're kept. Files on disk are left alone.
+    pub fn trim_history(&self, keep: usize) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        
+        let removed = conn.execute(
+            r#"
+            DELETE FROM downloads WHERE id IN (
+                SELECT id FROM downloads
+                WHERE status IN ('completed', 'failed', 'cancelled')
+                  AND NOT (status = 'cancelled' AND cancel_reason IS 'shutdown')
+                ORDER BY COALESCE(completed_at, created_at) DESC
+                LIMIT -1 OFFSET ?1
+            )
+            "#,
+            [i64::try_from(keep).unwrap_or(i64::MAX)],
+        )?;
+        
+        if removed > 0 {
+            conn.execute(
+                "DELETE FROM download_errors WHERE download_id NOT IN (SELECT id FROM downloads)",
+                [],
+            )?;
+            conn.execute(
+                "UPDATE downloads SET shares_file_with = NULL \
+                 WHERE shares_file_with NOT IN (SELECT id FROM downloads)",
+                [],
+            )?;
+        }
+        
+        Ok(removed)
+    }
+    
+    /// Summarize the download history by status.
+    /// Records removed from the history no longer count.
+    pub fn lifetime_stats(&self) -> Result<LifetimeStats> {
+        let conn = self.reader();
+        
         let mut stmt = conn.prepare(
             r#"
-            SELECT id, url, filename, file_type, destination, 
-                   total_size, downloaded_size, status, error_message,
-                   created_at, started_at, completed_at
+            SELECT status, COUNT(*), COALESCE(SUM(downloaded_size), 0)
             FROM downloads
-            WHERE status IN ('pending', 'queued', 'downloading')
-            ORDER BY created_at ASC
+            GROUP BY status
             "#,
         )?;
         
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                DownloadStatus::from_str(&row.get::<_, String>(0)?),
+                row.get::<_, u64>(1)?,
+                row.get::<_, u64>(2)?,
+            ))
+        })?;
+        
+        let mut stats = LifetimeStats::default();
+        for row in rows {
+            let (status, count, bytes) = row?;
+            stats.total_downloads += count;
+            stats.total_bytes += bytes;
+            
+            let totals = stats.by_status.entry(status).or_default();
+            totals.count += count;
+            totals.bytes += bytes;
+        }
+        
+        Ok(stats)
+    }
+    
+    /// Downloads that completed or failed at or after `since`, the most
+    /// recent `limit` of them, oldest first
+    pub fn finished_since(&self, since: chrono::DateTime<chrono::Utc>, limit: usize) -> Result<Vec<DownloadRecord>> {
+        let conn = self.reader();
+        
+        let mut stmt = conn.prepare(&format!(
+            r#"
+            SELECT {} FROM (
+                SELECT * FROM downloads
+                WHERE status IN ('completed', 'failed') AND completed_at >= ?1
+                ORDER BY completed_at DESC
+                LIMIT ?2
+            )
+            ORDER BY completed_at ASC
+            "#,
+            RECORD_COLUMNS
+        ))?;
+        
         let downloads = stmt
-            .query_map([], |row| {
-                Ok(DownloadRecord {
-                    id: row.get(0)?,
-                    url: row.get(1)?,
-                    filename: row.get(2)?,
-                    file_type: row.get(3)?,
-                    destination: PathBuf::from(row.get::<_, String>(4)?),
-                    total_size: row.get(5)?,
-                    downloaded_size: row.get(6)?,
-                    status: DownloadStatus::from_str(&row.get::<_, String>(7)?),
-                    error_message: row.get(8)?,
-                    created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(9)?)
-                        .map(|dt| dt.with_timezone(&chrono::Utc))
-                        .unwrap_or_else(|_| chrono::Utc::now()),
-                    started_at: row.get::<_, Option<String>>(10)?
-                        .and_then(|s| chrono::DateTime::parse_from_rfc3339(&s).ok())
-                        .map(|dt| dt.with_timezone(&chrono::Utc)),
-                    completed_at: row.get::<_, Option<String>>(11)?
-                        .and_then(|s| chrono::DateTime::parse_from_rfc3339(&s).ok())
-                        .map(|dt| dt.with_timezone(&chrono::Utc)),
+            .query_map(rusqlite::params![since.to_rfc3339(), limit as i64], |row| {
+                row_to_record(row, self.data_root())
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        
+        Ok(downloads)
+    }
+    
+    /// Aggregate progress of a batch, or `None` if no download belongs to it
+    pub fn get_batch(&self, batch_id: &str) -> Result<Option<BatchProgress>> {
+        let conn = self.reader();
+        
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT status, COUNT(*), COALESCE(SUM(downloaded_size), 0),
+                   COALESCE(SUM(total_size), 0), SUM(total_size IS NULL)
+            FROM downloads
+            WHERE batch_id = ?1
+            GROUP BY status
+            "#,
+        )?;
+        
+        let rows = stmt.query_map([batch_id], |row| {
+            Ok((
+                DownloadStatus::from_str(&row.get::<_, String>(0)?),
+                row.get::<_, u64>(1)?,
+                row.get::<_, u64>(2)?,
+                row.get::<_, u64>(3)?,
+                row.get::<_, u64>(4)?,
+            ))
+        })?;
+        
+        let mut batch = BatchProgress {
+            batch_id: batch_id.to_string(),
+            ..Default::default()
+        };
+        for row in rows {
+            let (status, count, downloaded, total, unknown) = row?;
+            batch.total_downloads += count;
+            batch.downloaded_bytes += downloaded;
+            batch.total_bytes += total;
+            batch.unknown_sizes += unknown;
+            *batch.by_status.entry(status).or_default() += count;
+        }
+        
+        Ok((batch.total_downloads > 0).then_some(batch))
+    }
+    
+    /// Record an error for a download (e.g. a failed retry attempt)
+    pub fn record_error(&self, id: &str, message: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        Self::insert_error(&conn, id, message)
+    }
+    
+    /// Insert an error history entry, pruning the oldest beyond the cap
+    fn insert_error(conn: &Connection, id: &str, message: &str) -> Result<()> {
+        conn.execute(
+            "INSERT INTO download_errors (download_id, message, occurred_at) VALUES (?1, ?2, ?3)",
+            rusqlite::params![id, message, chrono::Utc::now().to_rfc3339()],
+        )?;
+        
+        conn.execute(
+            r#"
+            DELETE FROM download_errors
+            WHERE download_id = ?1 AND id NOT IN (
+                SELECT id FROM download_errors
+                WHERE download_id = ?1
+                ORDER BY id DESC
+                LIMIT ?2
+            )
+            "#,
+            rusqlite::params![id, MAX_ERRORS_PER_DOWNLOAD],
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Get the error history for a download, oldest first
+    pub fn get_download_errors(&self, id: &str) -> Result<Vec<DownloadError>> {
+        let conn = self.reader();
+        
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT message, occurred_at
+            FROM download_errors
+            WHERE download_id = ?1
+            ORDER BY id ASC
+            "#,
+        )?;
+        
+        let errors = stmt
+            .query_map([id], |row| {
+                Ok(DownloadError {
+                    message: row.get(0)?,
+                    occurred_at: parse_timestamp(&row.get::<_, String>(1)?)
+                        .unwrap_or_else(chrono::Utc::now),
                 })
             })?
             .filter_map(|r| r.ok())
             .collect();
         
+        Ok(errors)
+    }
+    
+    /// Get pending downloads (for resuming on startup), optionally including paused ones.
+    /// Downloads interrupted by a shutdown count as pending.
+    pub fn get_pending_downloads(&self, include_paused: bool) -> Result<Vec<DownloadRecord>> {
+        let conn = self.reader();
+        
+        let mut stmt = conn.prepare(&format!(
+            r#"
+            SELECT {} FROM downloads
+            WHERE status IN ('pending', 'queued', 'downloading')
+               OR (?1 AND status = 'paused')
+               OR (status = 'cancelled' AND cancel_reason = 'shutdown')
+            ORDER BY
+                CASE status WHEN 'downloading' THEN 0 WHEN 'cancelled' THEN 0 WHEN 'pending' THEN 1 ELSE 2 END,
+                queue_order IS NULL,
+                queue_order ASC,
+                created_at ASC
+            "#,
+            RECORD_COLUMNS
+        ))?;
+        
+        let downloads = stmt
+            .query_map([include_paused], |row| row_to_record(row, self.data_root()))?
+            .filter_map(|r| r.ok())
+            .collect();
+        
         Ok(downloads)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    
+    fn record(n: usize) -> DownloadRecord {
+        DownloadRecord::new(
+            format!("https://example.com/file-{}.bin", n),
+            format!("file-{}.bin", n),
+            "general".into(),
+            PathBuf::from("/tmp"),
+        )
+    }
+    
+    #[test]
+    fn batch_progress_sums_its_downloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db"), &DatabaseSettings::default()).unwrap();
+        
+        let ids: Vec<String> = [(0, Some(1000)), (1, Some(500)), (2, None)]
+            .into_iter()
+            .map(|(n, total)| {
+                let mut record = record(n);
+                record.batch_id = Some("batch".into());
+                record.total_size = total;
+                db.insert_download(&record).unwrap();
+                record.id
+            })
+            .collect();
+        db.insert_download(&record(3)).unwrap();
+        
+        db.update_progress(&ids[0], 1000, Some(1000)).unwrap();
+        db.update_status(&ids[0], DownloadStatus::Completed, None).unwrap();
+        db.update_status(&ids[1], DownloadStatus::Downloading, None).unwrap();
+        // The second isn't running, so it's ignored
+        db.save_running_progress([(ids[1].as_str(), 200, Some(500)), (ids[2].as_str(), 300, None)]).unwrap();
+        
+        let batch = db.get_batch("batch").unwrap().unwrap();
+        assert_eq!(batch.total_downloads, 3);
+        assert_eq!(batch.downloaded_bytes, 1200);
+        assert_eq!(batch.total_bytes, 1500);
+        assert_eq!(batch.unknown_sizes, 1);
+        assert_eq!(batch.by_status[&DownloadStatus::Completed], 1);
+        assert_eq!(batch.by_status[&DownloadStatus::Downloading], 1);
+        assert_eq!(batch.by_status[&DownloadStatus::Pending], 1);
+        
+        assert!(db.get_batch("missing").unwrap().is_none());
+    }
+    
+    #[test]
+    fn latest_completed_download_of_a_url_has_its_validators() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db"), &DatabaseSettings::default()).unwrap();
+        let url = record(0).url;
+        
+        let completed = |etag: Option<&str>| {
+            let record = record(0);
+            db.insert_download(&record).unwrap();
+            db.set_validators(&record.id, etag, None).unwrap();
+            db.update_status(&record.id, DownloadStatus::Completed, None).unwrap();
+            record.id
+        };
+        assert!(db.find_latest_completed_by_url(&url).unwrap().is_none());
+        completed(Some("\"v1\""));
+        let latest = completed(Some("\"v2\""));
+        // Nothing to send, so it's passed over
+        completed(None);
+        // Not completed
+        let failed = record(0);
+        db.insert_download(&failed).unwrap();
+        db.set_validators(&failed.id, Some("\"v3\""), None).unwrap();
+        
+        let found = db.find_latest_completed_by_url(&url).unwrap().unwrap();
+        assert_eq!(found.id, latest);
+        assert_eq!(found.etag.as_deref(), Some("\"v2\""));
+        assert!(db.find_latest_completed_by_url("https://example.com/other").unwrap().is_none());
+    }
+    
+    #[test]
+    fn active_time_adds_up_and_duration_is_derived() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db"), &DatabaseSettings::default()).unwrap();
+        let record = record(0);
+        db.insert_download(&record).unwrap();
+        
+        db.update_status(&record.id, DownloadStatus::Downloading, None).unwrap();
+        db.add_active_time(&record.id, Duration::from_millis(1500)).unwrap();
+        let running = db.get_download(&record.id).unwrap().unwrap();
+        assert_eq!(running.duration_secs, None);
+        
+        // Resumed in a later session
+        db.update_status(&record.id, DownloadStatus::Downloading, None).unwrap();
+        db.add_active_time(&record.id, Duration::from_secs(2)).unwrap();
+        db.update_status(&record.id, DownloadStatus::Completed, None).unwrap();
+        
+        let done = db.get_download(&record.id).unwrap().unwrap();
+        assert_eq!(done.active_secs, 3.5);
+        let duration = done.duration_secs.unwrap();
+        assert!((0.0..60.0).contains(&duration), "{}", duration);
+        assert_eq!(db.get_all_downloads().unwrap()[0].duration_secs, Some(duration));
+    }
+    
+    #[test]
+    fn history_is_trimmed_to_the_newest_finished_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db"), &DatabaseSettings::default()).unwrap();
+        
+        let ids: Vec<String> = (0..5)
+            .map(|n| {
+                let record = record(n);
+                db.insert_download(&record).unwrap();
+                record.id
+            })
+            .collect();
+        // Cancelled before any of those finish: one by the user, which
+        // counts as history, and one by a shutdown, which resumes instead
+        let cancelled = |n, reason| {
+            let record = record(n);
+            db.insert_download(&record).unwrap();
+            db.mark_cancelled(&record.id, reason).unwrap();
+            record.id
+        };
+        cancelled(5, CancelReason::UserRequested);
+        let interrupted = cancelled(6, CancelReason::Shutdown);
+        std::thread::sleep(Duration::from_millis(5));
+        // Finished in reverse order of creation, so 0 is the newest
+        for (status, id) in [
+            (DownloadStatus::Completed, &ids[3]),
+            (DownloadStatus::Failed, &ids[2]),
+            (DownloadStatus::Completed, &ids[0]),
+        ] {
+            db.update_status(id, status, Some("error").filter(|_| status == DownloadStatus::Failed)).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        db.update_status(&ids[1], DownloadStatus::Downloading, None).unwrap();
+        
+        assert_eq!(db.trim_history(2).unwrap(), 2);
+        let mut left: Vec<String> = db.get_all_downloads().unwrap().into_iter().map(|r| r.id).collect();
+        left.sort();
+        let mut expected = vec![ids[0].clone(), ids[1].clone(), ids[2].clone(), ids[4].clone(), interrupted];
+        expected.sort();
+        assert_eq!(left, expected);
+        assert_eq!(db.get_download_errors(&ids[2]).unwrap().len(), 1);
+        
+        assert_eq!(db.trim_history(2).unwrap(), 0);
+    }
+    
+    #[test]
+    fn locations_under_the_data_root_survive_moving_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let old_root = dir.path().join("old");
+        let new_root = dir.path().join("new");
+        let db_path = dir.path().join("test.db");
+        
+        let mut inside = record(0);
+        inside.destination = old_root.join("downloads");
+        inside.extracted_path = Some(old_root.join("downloads/file-0"));
+        let outside = record(1);
+        {
+            // Saved before a root was configured
+            let db = Database::open(&db_path, &DatabaseSettings::default()).unwrap();
+            db.insert_download(&inside).unwrap();
+            db.set_extracted_path(&inside.id, inside.extracted_path.as_ref().unwrap()).unwrap();
+            db.insert_download(&outside).unwrap();
+        }
+        
+        let stored = |db: &Database, id: &str| -> String {
+            db.conn
+                .lock()
+                .unwrap()
+                .query_row("SELECT destination FROM downloads WHERE id = ?1", [id], |row| row.get(0))
+                .unwrap()
+        };
+        let settings = |root: &Path| DatabaseSettings {
+            data_root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        {
+            let db = Database::open(&db_path, &settings(&old_root)).unwrap();
+            assert_eq!(stored(&db, &inside.id), "downloads");
+            assert_eq!(stored(&db, &outside.id), "/tmp");
+            assert_eq!(db.get_download(&inside.id).unwrap().unwrap().destination, inside.destination);
+        }
+        
+        // The folder moved and the setting followed it
+        let db = Database::open(&db_path, &settings(&new_root)).unwrap();
+        let moved = db.get_download(&inside.id).unwrap().unwrap();
+        assert_eq!(moved.destination, new_root.join("downloads"));
+        assert_eq!(moved.extracted_path, Some(new_root.join("downloads/file-0")));
+        assert_eq!(db.get_download(&outside.id).unwrap().unwrap().destination, PathBuf::from("/tmp"));
+        
+        db.update_location(&inside.id, &new_root.join("sorted"), "file-0.bin").unwrap();
+        assert_eq!(stored(&db, &inside.id), "sorted");
+    }
+    
+    #[test]
+    fn recently_finished_are_listed_oldest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db"), &DatabaseSettings::default()).unwrap();
+        
+        let ids: Vec<String> = (0..4)
+            .map(|n| {
+                let record = record(n);
+                db.insert_download(&record).unwrap();
+                record.id
+            })
+            .collect();
+        db.update_status(&ids[0], DownloadStatus::Completed, None).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let since = chrono::Utc::now();
+        for (id, status) in [
+            (&ids[1], DownloadStatus::Completed),
+            (&ids[2], DownloadStatus::Failed),
+            (&ids[3], DownloadStatus::Downloading),
+        ] {
+            std::thread::sleep(Duration::from_millis(5));
+            db.update_status(id, status, Some("error").filter(|_| status == DownloadStatus::Failed)).unwrap();
+        }
+        
+        let finished: Vec<String> = db.finished_since(since, 10).unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(finished, vec![ids[1].clone(), ids[2].clone()]);
+        let latest = db.finished_since(since, 1).unwrap();
+        assert_eq!(latest[0].id, ids[2]);
+        assert_eq!(latest[0].error_message.as_deref(), Some("error"));
+    }
+    
+    #[test]
+    fn list_filters_by_creation_time_and_sorts() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db"), &DatabaseSettings::default()).unwrap();
+        let day = |d: u32| {
+            chrono::NaiveDate::from_ymd_opt(2024, 1, d)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap()
+                .and_utc()
+        };
+        
+        for (n, created, size) in [(0, day(1), 30), (1, day(5), 10), (2, day(9), 20)] {
+            let mut record = record(n);
+            record.created_at = created;
+            record.total_size = Some(size);
+            db.insert_download(&record).unwrap();
+        }
+        
+        let names = |query: DownloadListQuery| -> Vec<String> {
+            db.list_downloads(&query).unwrap().into_iter().map(|d| d.filename).collect()
+        };
+        
+        assert_eq!(
+            names(DownloadListQuery::default()),
+            ["file-2.bin", "file-1.bin", "file-0.bin"]
+        );
+        assert_eq!(
+            names(DownloadListQuery {
+                created_from: Some(day(1)),
+                created_to: Some(day(5)),
+                sort: SortColumn::TotalSize,
+                order: SortOrder::Asc,
+            }),
+            ["file-1.bin", "file-0.bin"]
+        );
+        assert_eq!(
+            names(DownloadListQuery {
+                created_from: Some(day(2)),
+                sort: SortColumn::Filename,
+                order: SortOrder::Asc,
+                ..Default::default()
+            }),
+            ["file-1.bin", "file-2.bin"]
+        );
+    }
+    
+    #[test]
+    fn concurrent_reads_and_writes_do_not_deadlock() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db"), &DatabaseSettings::default()).unwrap();
+        let (done_tx, done_rx) = mpsc::channel();
+        
+        for writer in 0..4 {
+            let db = db.clone();
+            let done_tx = done_tx.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    let record = record(writer * 100 + i);
+                    db.insert_download(&record).unwrap();
+                    db.update_progress(&record.id, 512, Some(1024)).unwrap();
+                    db.update_status(&record.id, DownloadStatus::Completed, None).unwrap();
+                }
+                done_tx.send(()).unwrap();
+            });
+        }
+        
+        for _ in 0..4 {
+            let db = db.clone();
+            let done_tx = done_tx.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    db.get_all_downloads().unwrap();
+                    db.lifetime_stats().unwrap();
+                }
+                done_tx.send(()).unwrap();
+            });
+        }
+        
+        for _ in 0..8 {
+            done_rx
+                .recv_timeout(Duration::from_secs(30))
+                .expect("database access deadlocked");
+        }
+        
+        assert_eq!(db.get_all_downloads().unwrap().len(), 200);
+    }
+}
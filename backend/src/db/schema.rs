@@ -2,10 +2,11 @@
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 /// Download status enum
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum DownloadStatus {
     Pending,
@@ -18,6 +19,11 @@ pub enum DownloadStatus {
 }
 
 impl DownloadStatus {
+    /// Whether the download has stopped for good: completed, failed or cancelled
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Completed | Self::Failed | Self::Cancelled)
+    }
+    
     pub fn as_str(&self) -> &'static str {
         match self {
             Self::Pending => "pending",
@@ -44,6 +50,160 @@ impl DownloadStatus {
     }
 }
 
+/// HTTP method a download is fetched with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RequestMethod {
+    #[default]
+    Get,
+    /// For sources that want a request body, e.g. a JSON or form query
+    Post,
+}
+
+impl RequestMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Post => "POST",
+        }
+    }
+    
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "POST" => Self::Post,
+            _ => Self::Get,
+        }
+    }
+}
+
+/// Why a download ended up `Cancelled`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason {
+    /// Cancelled or removed by the user
+    UserRequested,
+    /// Interrupted because the application was quitting
+    Shutdown,
+    /// Stopped because the destination disk ran out of space
+    DiskFull,
+    /// Paused because the connection became metered; goes back in the queue
+    /// rather than ending up `Cancelled`
+    Metered,
+    /// Stopped for running longer than its `max_duration_secs`
+    Timeout,
+    /// Paused by the user; keeps its partial file and ends up `Paused`
+    /// rather than `Cancelled`
+    Paused,
+}
+
+impl CancelReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UserRequested => "user_requested",
+            Self::Shutdown => "shutdown",
+            Self::DiskFull => "disk_full",
+            Self::Metered => "metered",
+            Self::Timeout => "timeout",
+            Self::Paused => "paused",
+        }
+    }
+    
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "user_requested" => Some(Self::UserRequested),
+            "shutdown" => Some(Self::Shutdown),
+            "disk_full" => Some(Self::DiskFull),
+            "metered" => Some(Self::Metered),
+            "timeout" => Some(Self::Timeout),
+            "paused" => Some(Self::Paused),
+            _ => None,
+        }
+    }
+    
+    /// Whether the partial file stays so the download can pick up from it
+    pub fn keeps_partial(&self) -> bool {
+        matches!(self, Self::Shutdown | Self::Metered | Self::Paused)
+    }
+}
+
+impl std::fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::UserRequested => "cancelled by user",
+            Self::Shutdown => "cancelled during shutdown",
+            Self::DiskFull => "cancelled: disk full",
+            Self::Metered => "paused: metered connection",
+            Self::Timeout => "cancelled: took longer than its time limit",
+            Self::Paused => "paused by user",
+        })
+    }
+}
+
+/// Broad category of why a download stopped, for clients to act on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadErrorKind {
+    /// Connection failed or dropped
+    Network,
+    /// Connecting or reading took too long
+    Timeout,
+    /// The server answered with an error status
+    HttpStatus,
+    /// The destination disk ran out of space
+    DiskFull,
+    /// The destination, or a folder above it, is an existing file
+    NotADirectory,
+    /// The finished file didn't match its expected checksum
+    ChecksumMismatch,
+    /// Ran past its `max_duration_secs`; retrying would likely hang again
+    DeadlineExceeded,
+    /// Stopped on purpose; see `cancel_reason`
+    Cancelled,
+    /// Anything else, e.g. a local file error
+    Other,
+}
+
+impl DownloadErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Network => "network",
+            Self::Timeout => "timeout",
+            Self::HttpStatus => "http_status",
+            Self::DiskFull => "disk_full",
+            Self::NotADirectory => "not_a_directory",
+            Self::ChecksumMismatch => "checksum_mismatch",
+            Self::DeadlineExceeded => "deadline_exceeded",
+            Self::Cancelled => "cancelled",
+            Self::Other => "other",
+        }
+    }
+    
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "network" => Some(Self::Network),
+            "timeout" => Some(Self::Timeout),
+            "http_status" => Some(Self::HttpStatus),
+            "disk_full" => Some(Self::DiskFull),
+            "not_a_directory" => Some(Self::NotADirectory),
+            "checksum_mismatch" => Some(Self::ChecksumMismatch),
+            "deadline_exceeded" => Some(Self::DeadlineExceeded),
+            "cancelled" => Some(Self::Cancelled),
+            "other" => Some(Self::Other),
+            _ => None,
+        }
+    }
+}
+
+impl From<CancelReason> for DownloadErrorKind {
+    fn from(reason: CancelReason) -> Self {
+        match reason {
+            CancelReason::DiskFull => Self::DiskFull,
+            CancelReason::Timeout => Self::DeadlineExceeded,
+            _ => Self::Cancelled,
+        }
+    }
+}
+
 /// A download record stored in the database
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DownloadRecord {
@@ -59,6 +219,84 @@ pub struct DownloadRecord {
     pub created_at: DateTime<Utc>,
     pub started_at: Option<DateTime<Utc>>,
     pub completed_at: Option<DateTime<Utc>>,
+    /// `Accept` header to send instead of the default
+    pub accept: Option<String>,
+    /// Extra query parameters appended to the URL's own
+    #[serde(default)]
+    pub query_params: Vec<(String, String)>,
+    /// Extra request headers. Values may hold `${VAR}` placeholders, which
+    /// are expanded from the environment when the request is sent and never
+    /// stored resolved.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// Set when the download was cancelled
+    pub cancel_reason: Option<CancelReason>,
+    /// Set when the download failed or was cancelled
+    pub error_kind: Option<DownloadErrorKind>,
+    /// Folder the downloaded archive was extracted into
+    pub extracted_path: Option<PathBuf>,
+    /// Speed cap in bytes per second
+    pub speed_limit: Option<u64>,
+    /// Fallback URLs tried in order when `url` fails
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    /// Expected digest as `algorithm:hex`, checked before the file is finalized
+    pub checksum: Option<String>,
+    /// URL the content finally came from, after any redirects
+    pub final_url: Option<String>,
+    /// Batch this download was added with, if any
+    pub batch_id: Option<String>,
+    /// Ask the server whether the content changed since the last completed
+    /// download of this URL, and skip the transfer if it didn't
+    #[serde(default)]
+    pub skip_if_unmodified: bool,
+    /// `ETag` the server sent with the content
+    pub etag: Option<String>,
+    /// `Last-Modified` the server sent with the content
+    pub last_modified: Option<String>,
+    /// Completed without transferring anything because the server said the
+    /// content hadn't changed; the file is the earlier download's
+    #[serde(default)]
+    pub not_modified: bool,
+    /// First byte of the requested slice, when only part of the file is
+    /// downloaded
+    pub range_start: Option<u64>,
+    /// Last byte (inclusive) of the requested slice; `None` runs to the end
+    pub range_end: Option<u64>,
+    /// File types whose folders also get the finished file, as a hard link
+    /// or, across filesystems, a copy
+    #[serde(default)]
+    pub link_file_types: Vec<String>,
+    /// Set on a record that was never downloaded itself: its file was
+    /// linked or copied from the download with this id
+    pub linked_from: Option<String>,
+    /// Name to save the file under once finished, set when the download was
+    /// renamed after it started. Its partial file keeps `filename` until then,
+    /// so it's still found after a restart.
+    pub rename_to: Option<String>,
+    /// Set on a completed download whose file was replaced with a hard link
+    /// to the identical file of the download with this id
+    pub shares_file_with: Option<String>,
+    /// File the download was transcoded into by its file type's
+    /// `post_download_transcode`
+    pub transcoded_path: Option<PathBuf>,
+    /// Seconds spent transferring, summed over every run, so a download
+    /// resumed across sessions doesn't count the time it sat paused
+    #[serde(default)]
+    pub active_secs: f64,
+    /// Method the download is requested with
+    #[serde(default)]
+    pub method: RequestMethod,
+    /// Body sent with a `POST` download
+    pub body: Option<String>,
+    /// `Content-Type` of `body`
+    pub content_type: Option<String>,
+    /// Longest a run of the download may take before it's cancelled
+    pub max_duration_secs: Option<u64>,
+    /// Seconds from the last start to completion, for completed downloads.
+    /// Derived from `started_at` and `completed_at`, never stored.
+    #[serde(default)]
+    pub duration_secs: Option<f64>,
 }
 
 impl DownloadRecord {
@@ -82,9 +320,48 @@ impl DownloadRecord {
             created_at: Utc::now(),
             started_at: None,
             completed_at: None,
+            accept: None,
+            query_params: Vec::new(),
+            headers: Vec::new(),
+            rename_to: None,
+            cancel_reason: None,
+            error_kind: None,
+            extracted_path: None,
+            speed_limit: None,
+            mirrors: Vec::new(),
+            checksum: None,
+            final_url: None,
+            batch_id: None,
+            skip_if_unmodified: false,
+            etag: None,
+            last_modified: None,
+            not_modified: false,
+            range_start: None,
+            range_end: None,
+            link_file_types: Vec::new(),
+            linked_from: None,
+            shares_file_with: None,
+            transcoded_path: None,
+            active_secs: 0.0,
+            method: RequestMethod::Get,
+            body: None,
+            content_type: None,
+            max_duration_secs: None,
+            duration_secs: None,
         }
     }
     
+    /// Fill in `duration_secs` from the timestamps
+    pub fn with_duration(mut self) -> Self {
+        self.duration_secs = match (self.status, self.started_at, self.completed_at) {
+            (DownloadStatus::Completed, Some(started), Some(completed)) if completed >= started => {
+                Some((completed - started).num_milliseconds() as f64 / 1000.0)
+            }
+            _ => None,
+        };
+        self
+    }
+    
     /// Get progress as a percentage (0.0 - 100.0)
     pub fn progress(&self) -> f64 {
         match self.total_size {
@@ -93,3 +370,95 @@ impl DownloadRecord {
         }
     }
 }
+
+/// An entry in a download's error history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadError {
+    pub message: String,
+    pub occurred_at: DateTime<Utc>,
+}
+
+/// Count and bytes for one download status
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatusTotals {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Aggregate statistics over the download history
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LifetimeStats {
+    pub total_downloads: u64,
+    pub total_bytes: u64,
+    pub by_status: HashMap<DownloadStatus, StatusTotals>,
+}
+
+/// Columns the download list can be sorted by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortColumn {
+    #[default]
+    CreatedAt,
+    StartedAt,
+    CompletedAt,
+    Filename,
+    TotalSize,
+    Status,
+}
+
+impl SortColumn {
+    /// Column name to put in `ORDER BY`. Only these fixed names ever reach
+    /// the SQL, never the raw query parameter.
+    pub fn column(&self) -> &'static str {
+        match self {
+            Self::CreatedAt => "created_at",
+            Self::StartedAt => "started_at",
+            Self::CompletedAt => "completed_at",
+            Self::Filename => "filename",
+            Self::TotalSize => "total_size",
+            Self::Status => "status",
+        }
+    }
+}
+
+/// Sort direction of the download list
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            Self::Asc => "ASC",
+            Self::Desc => "DESC",
+        }
+    }
+}
+
+/// Which downloads to list, and in what order
+#[derive(Debug, Clone, Default)]
+pub struct DownloadListQuery {
+    /// Only downloads created at or after this time
+    pub created_from: Option<DateTime<Utc>>,
+    /// Only downloads created at or before this time
+    pub created_to: Option<DateTime<Utc>>,
+    pub sort: SortColumn,
+    pub order: SortOrder,
+}
+
+/// Aggregate progress of the downloads added together as one batch
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BatchProgress {
+    pub batch_id: String,
+    pub total_downloads: u64,
+    pub downloaded_bytes: u64,
+    /// Sum of the known sizes
+    pub total_bytes: u64,
+    /// Downloads whose size isn't known yet, so `total_bytes` may grow
+    pub unknown_sizes: u64,
+    pub by_status: HashMap<DownloadStatus, u64>,
+}
@@ -0,0 +1,138 @@
+//! Checksum verification of finished downloads
+
+use sha2::{Digest, Sha256, Sha512};
+use std::io::Read;
+use std::path::Path;
+
+/// Hash algorithms a download can be verified with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        }
+    }
+    
+    /// Parse an algorithm name, accepting both `sha256` and the
+    /// `sha-256` spelling used by Metalink
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" | "sha-256" => Some(Self::Sha256),
+            "sha512" | "sha-512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+    
+    /// Length of the hex digest
+    fn hex_len(&self) -> usize {
+        match self {
+            Self::Sha256 => 64,
+            Self::Sha512 => 128,
+        }
+    }
+}
+
+/// An expected digest, written as `algorithm:hex`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    pub digest: String,
+}
+
+impl Checksum {
+    /// Parse `sha256:<hex>` or `sha512:<hex>`. A bare hex digest is taken
+    /// as SHA-256 or SHA-512 by its length.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (algorithm, digest) = match s.split_once(':') {
+            Some((algorithm, digest)) => (HashAlgorithm::from_str(algorithm)?, digest),
+            None => match s.len() {
+                64 => (HashAlgorithm::Sha256, s),
+                128 => (HashAlgorithm::Sha512, s),
+                _ => return None,
+            },
+        };
+        
+        if digest.len() != algorithm.hex_len() || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        
+        Some(Self {
+            algorithm,
+            digest: digest.to_ascii_lowercase(),
+        })
+    }
+}
+
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm.as_str(), self.digest)
+    }
+}
+
+/// Hash a file's contents, returning the lowercase hex digest.
+/// Blocking; run it off the async runtime.
+pub fn file_digest(path: &Path, algorithm: HashAlgorithm) -> std::io::Result<String> {
+    match algorithm {
+        HashAlgorithm::Sha256 => hash_file::<Sha256>(path),
+        HashAlgorithm::Sha512 => hash_file::<Sha512>(path),
+    }
+}
+
+fn hash_file<D: Digest>(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = D::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    
+    Ok(to_hex(&hasher.finalize()))
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hashes a download chunk by chunk as it arrives, so the file needn't be
+/// read again once it's complete
+pub enum StreamHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl StreamHasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Self::Sha512(Sha512::new()),
+        }
+    }
+    
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(bytes),
+            Self::Sha512(hasher) => hasher.update(bytes),
+        }
+    }
+    
+    /// The digest of everything hashed, as a `Checksum`
+    pub fn finish(self) -> Checksum {
+        let (algorithm, digest) = match self {
+            Self::Sha256(hasher) => (HashAlgorithm::Sha256, to_hex(&hasher.finalize())),
+            Self::Sha512(hasher) => (HashAlgorithm::Sha512, to_hex(&hasher.finalize())),
+        };
+        Checksum { algorithm, digest }
+    }
+}
@@ -0,0 +1,111 @@
+//! Expanding `${VAR}` placeholders in request headers from the environment,
+//! so secrets can stay out of the database
+
+/// Start of the name of every variable a placeholder may name. Anyone who can
+/// add a download chooses where its headers go, so without it they could
+/// read any of the server's environment.
+pub const ENV_VAR_PREFIX: &str = "VIBE_";
+
+/// A `${VAR}` placeholder that can't be filled in
+#[derive(Debug, Clone)]
+pub enum EnvVarError {
+    /// The variable isn't set
+    Unresolved(String),
+    /// The variable's name lacks `ENV_VAR_PREFIX`
+    NotAllowed(String),
+}
+
+impl std::fmt::Display for EnvVarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvVarError::Unresolved(name) => write!(f, "unresolved env var ${{{}}}", name),
+            EnvVarError::NotAllowed(name) => {
+                write!(f, "env var ${{{}}} can't be used; only names starting with {} are", name, ENV_VAR_PREFIX)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvVarError {}
+
+/// Replace each `${VAR}` in `value` with the variable's value from the
+/// process environment. Anything else, including a lone `$`, is kept as is.
+pub fn expand_env(value: &str) -> Result<String, EnvVarError> {
+    expand_with(value, |name| std::env::var(name).ok())
+}
+
+/// Check that every `${VAR}` in `value` names a variable that may be used,
+/// whether or not it's set yet
+pub fn check_env_vars(value: &str) -> Result<(), EnvVarError> {
+    expand_with(value, |_| Some(String::new())).map(|_| ())
+}
+
+/// `expand_env` with the variables looked up by `lookup`
+fn expand_with(
+    value: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, EnvVarError> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find('}').filter(|&end| is_var_name(&after[..end])) {
+            Some(end) => {
+                let name = &after[..end];
+                if !name.starts_with(ENV_VAR_PREFIX) {
+                    return Err(EnvVarError::NotAllowed(name.to_string()));
+                }
+                let resolved = lookup(name).ok_or_else(|| EnvVarError::Unresolved(name.to_string()))?;
+                expanded.push_str(&resolved);
+                rest = &after[end + 1..];
+            }
+            None => {
+                expanded.push_str("${");
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    
+    Ok(expanded)
+}
+
+/// Letters, digits and underscores, not starting with a digit
+fn is_var_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn lookup(name: &str) -> Option<String> {
+        (name == "VIBE_TOKEN").then(|| "s3cret".to_string())
+    }
+    
+    #[test]
+    fn placeholders_are_expanded() {
+        assert_eq!(expand_with("Bearer ${VIBE_TOKEN}", lookup).unwrap(), "Bearer s3cret");
+        assert_eq!(expand_with("${VIBE_TOKEN}:${VIBE_TOKEN}", lookup).unwrap(), "s3cret:s3cret");
+        // Not placeholders
+        assert_eq!(expand_with("$VIBE_TOKEN ${1X} ${", lookup).unwrap(), "$VIBE_TOKEN ${1X} ${");
+    }
+    
+    #[test]
+    fn missing_variables_are_an_error() {
+        let err = expand_with("Bearer ${VIBE_MISSING}", lookup).unwrap_err();
+        assert_eq!(err.to_string(), "unresolved env var ${VIBE_MISSING}");
+    }
+    
+    #[test]
+    fn only_prefixed_variables_are_used() {
+        let err = expand_with("${AWS_SECRET_ACCESS_KEY}", |_| Some("leaked".into())).unwrap_err();
+        assert!(matches!(err, EnvVarError::NotAllowed(name) if name == "AWS_SECRET_ACCESS_KEY"));
+        assert!(check_env_vars("Bearer ${HOME}").is_err());
+        assert!(check_env_vars("Bearer ${VIBE_UNSET_TOKEN} $HOME").is_ok());
+    }
+}
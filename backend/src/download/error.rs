@@ -0,0 +1,164 @@
+//! Typed download errors and their classification
+
+use crate::db::{CancelReason, DownloadErrorKind};
+
+/// Error returned by a download that was stopped on purpose rather than failing
+#[derive(Debug, Clone, Copy)]
+pub struct Cancelled(pub CancelReason);
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Download {}", self.0)
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// The server answered a download request with a non-success status
+#[derive(Debug, Clone, Copy)]
+pub struct HttpStatusError(pub reqwest::StatusCode);
+
+impl std::fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP error: {}", self.0)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// The finished file's digest didn't match the one it was expected to have
+#[derive(Debug, Clone)]
+pub struct ChecksumMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Checksum mismatch: expected {}, got {}", self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// The queue already holds its maximum number of downloads
+#[derive(Debug, Clone, Copy)]
+pub struct QueueFull(pub usize);
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Download queue is full ({} waiting), try again later", self.0)
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// Work out what kind of failure ended a download from the error itself,
+/// looking through any context added on the way up
+pub fn classify_error(err: &anyhow::Error) -> DownloadErrorKind {
+    for cause in err.chain() {
+        if let Some(Cancelled(reason)) = cause.downcast_ref() {
+            return DownloadErrorKind::from(*reason);
+        }
+        if cause.is::<ChecksumMismatch>() {
+            return DownloadErrorKind::ChecksumMismatch;
+        }
+        if cause.is::<HttpStatusError>() {
+            return DownloadErrorKind::HttpStatus;
+        }
+        if cause.is::<crate::fs::NotADirectory>() {
+            return DownloadErrorKind::NotADirectory;
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return if e.is_timeout() {
+                DownloadErrorKind::Timeout
+            } else if e.is_status() {
+                DownloadErrorKind::HttpStatus
+            } else {
+                DownloadErrorKind::Network
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            match e.kind() {
+                std::io::ErrorKind::StorageFull => return DownloadErrorKind::DiskFull,
+                std::io::ErrorKind::TimedOut => return DownloadErrorKind::Timeout,
+                std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted => {
+                    return DownloadErrorKind::Network
+                }
+                _ => {}
+            }
+        }
+    }
+    DownloadErrorKind::Other
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use std::io::{Error as IoError, ErrorKind};
+    
+    fn status(code: u16) -> anyhow::Error {
+        HttpStatusError(reqwest::StatusCode::from_u16(code).unwrap()).into()
+    }
+    
+    #[test]
+    fn statuses_are_http_errors() {
+        for code in [400, 404, 429, 500, 503] {
+            assert_eq!(classify_error(&status(code)), DownloadErrorKind::HttpStatus, "{}", code);
+        }
+        // Found below added context too
+        let err = Err::<(), _>(status(429)).context("Mirror 2 failed").unwrap_err();
+        assert_eq!(classify_error(&err), DownloadErrorKind::HttpStatus);
+    }
+    
+    #[test]
+    fn io_errors_are_told_apart() {
+        let io = |kind: ErrorKind| anyhow::Error::from(IoError::from(kind));
+        assert_eq!(classify_error(&io(ErrorKind::StorageFull)), DownloadErrorKind::DiskFull);
+        assert_eq!(classify_error(&io(ErrorKind::TimedOut)), DownloadErrorKind::Timeout);
+        assert_eq!(classify_error(&io(ErrorKind::ConnectionReset)), DownloadErrorKind::Network);
+        assert_eq!(classify_error(&io(ErrorKind::PermissionDenied)), DownloadErrorKind::Other);
+        
+        let err = Err::<(), _>(io(ErrorKind::StorageFull)).context("Failed to write chunk").unwrap_err();
+        assert_eq!(classify_error(&err), DownloadErrorKind::DiskFull);
+    }
+    
+    #[test]
+    fn typed_errors_keep_their_kind() {
+        let blocked = anyhow::Error::from(crate::fs::NotADirectory {
+            destination: "/srv/file/sub".into(),
+            file: "/srv/file".into(),
+        });
+        assert_eq!(classify_error(&blocked), DownloadErrorKind::NotADirectory);
+        
+        let mismatch = anyhow::Error::from(ChecksumMismatch { expected: "a".into(), actual: "b".into() });
+        assert_eq!(classify_error(&mismatch), DownloadErrorKind::ChecksumMismatch);
+        
+        let cancelled = anyhow::Error::from(Cancelled(CancelReason::UserRequested));
+        assert_eq!(classify_error(&cancelled), DownloadErrorKind::Cancelled);
+        
+        assert_eq!(classify_error(&anyhow::anyhow!("something else")), DownloadErrorKind::Other);
+    }
+    
+    #[tokio::test]
+    async fn request_failures_are_network_errors_or_timeouts() {
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent = format!("http://{}/", listener.local_addr().unwrap());
+        let client = reqwest::Client::new();
+        
+        let timed_out = client
+            .get(&silent)
+            .timeout(std::time::Duration::from_millis(100))
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(classify_error(&timed_out.into()), DownloadErrorKind::Timeout);
+        
+        // Nothing listens there once the listener is gone
+        drop(listener);
+        let refused = client.get(&silent).send().await.unwrap_err();
+        assert_eq!(classify_error(&refused.into()), DownloadErrorKind::Network);
+    }
+}
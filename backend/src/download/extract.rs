@@ -0,0 +1,168 @@
+//! Built-in extraction of downloaded archives
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Archive formats we know how to extract
+enum ArchiveFormat {
+    Zip,
+    TarGz,
+}
+
+/// Detect the archive format from the filename, returning it with the name
+/// minus the archive extension
+fn archive_format(filename: &str) -> Option<(ArchiveFormat, &str)> {
+    let lower = filename.to_ascii_lowercase();
+    let (format, ext_len) = if lower.ends_with(".zip") {
+        (ArchiveFormat::Zip, 4)
+    } else if lower.ends_with(".tar.gz") {
+        (ArchiveFormat::TarGz, 7)
+    } else if lower.ends_with(".tgz") {
+        (ArchiveFormat::TarGz, 4)
+    } else {
+        return None;
+    };
+    
+    let stem = &filename[..filename.len() - ext_len];
+    (!stem.is_empty()).then_some((format, stem))
+}
+
+/// Whether `filename` is an archive `extract_archive` can handle
+pub fn is_extractable(filename: &str) -> bool {
+    archive_format(filename).is_some()
+}
+
+/// Extract an archive into a new folder next to it, named after the archive,
+/// and return that folder. Entries that would land outside the folder abort
+/// the extraction. Blocking; run it off the async runtime.
+pub fn extract_archive(archive: &Path) -> Result<PathBuf> {
+    let filename = archive
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("Archive path has no filename")?;
+    let (format, stem) = archive_format(filename).context("Not a supported archive")?;
+    let parent = archive.parent().context("Archive path has no parent folder")?;
+    
+    // Don't merge into a folder that's already there
+    let mut dest = parent.join(stem);
+    let mut n = 2;
+    while dest.exists() {
+        dest = parent.join(format!("{} ({})", stem, n));
+        n += 1;
+    }
+    std::fs::create_dir_all(&dest)?;
+    
+    let result = match format {
+        ArchiveFormat::Zip => extract_zip(archive, &dest),
+        ArchiveFormat::TarGz => extract_tar_gz(archive, &dest),
+    };
+    
+    match result {
+        Ok(()) => Ok(dest),
+        Err(e) => {
+            // Don't leave half an archive behind
+            let _ = std::fs::remove_dir_all(&dest);
+            Err(e)
+        }
+    }
+}
+
+fn extract_zip(archive: &Path, dest: &Path) -> Result<()> {
+    let mut zip = zip::ZipArchive::new(File::open(archive)?)?;
+    
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        
+        // Rejects absolute paths and `..` components (zip-slip)
+        let relative = entry.enclosed_name().with_context(|| {
+            format!("Archive entry {} would extract outside the target folder", entry.name())
+        })?;
+        let out = dest.join(relative);
+        
+        if entry.is_dir() {
+            std::fs::create_dir_all(&out)?;
+        } else {
+            if let Some(parent) = out.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::io::copy(&mut entry, &mut File::create(&out)?)?;
+        }
+    }
+    
+    Ok(())
+}
+
+fn extract_tar_gz(archive: &Path, dest: &Path) -> Result<()> {
+    let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(File::open(archive)?));
+    
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        // `unpack_in` refuses `..` paths and links that escape `dest` (zip-slip)
+        if !entry.unpack_in(dest)? {
+            anyhow::bail!(
+                "Archive entry {} would extract outside the target folder",
+                entry.path()?.display()
+            );
+        }
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, data) in entries {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+    
+    #[test]
+    fn extracts_zip_into_sibling_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("bundle.zip");
+        write_zip(&archive, &[("a.txt", b"alpha"), ("nested/b.txt", b"beta")]);
+        
+        let out = extract_archive(&archive).unwrap();
+        
+        assert_eq!(out, dir.path().join("bundle"));
+        assert_eq!(std::fs::read(out.join("a.txt")).unwrap(), b"alpha");
+        assert_eq!(std::fs::read(out.join("nested/b.txt")).unwrap(), b"beta");
+    }
+    
+    #[test]
+    fn rejects_zip_slip_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("evil.zip");
+        write_zip(&archive, &[("ok.txt", b"fine"), ("../escaped.txt", b"gotcha")]);
+        
+        assert!(extract_archive(&archive).is_err());
+        assert!(!dir.path().join("escaped.txt").exists());
+        assert!(!dir.path().join("evil").exists());
+    }
+    
+    #[test]
+    fn extracts_tar_gz() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("logs.tar.gz");
+        let gz = flate2::write::GzEncoder::new(File::create(&archive).unwrap(), Default::default());
+        let mut tar = tar::Builder::new(gz);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_cksum();
+        tar.append_data(&mut header, "day1.log", &b"hello"[..]).unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+        
+        let out = extract_archive(&archive).unwrap();
+        
+        assert_eq!(out, dir.path().join("logs"));
+        assert_eq!(std::fs::read(out.join("day1.log")).unwrap(), b"hello");
+    }
+}
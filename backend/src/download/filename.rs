@@ -0,0 +1,244 @@
+//! Choosing and checking the names downloads are saved under
+
+use crate::config::ConflictPolicy;
+use std::path::Path;
+
+/// Check a user-supplied filename, returning it trimmed. Names that are empty,
+/// contain path separators or would refer to a directory are rejected so a
+/// download can't be written outside its destination folder.
+pub fn sanitize_filename(name: &str) -> Option<String> {
+    let name = name.trim();
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\\'])
+        || name.chars().any(char::is_control)
+        // A drive prefix like `C:` would make `join` leave the folder on Windows
+        || (cfg!(windows) && name.contains(':'))
+    {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Reduce a name suggested by a server to its last path component and
+/// sanitize it
+fn clean_remote_name(name: &str) -> Option<String> {
+    name.rsplit(['/', '\\']).next().and_then(sanitize_filename)
+}
+
+/// Read a parameter of a `Content-Disposition`-style header. Quoted values
+/// may contain `;` and backslash escapes; bare values end at the next `;`.
+fn header_param(header: &str, name: &str) -> Option<String> {
+    // ASCII lowercasing keeps byte offsets valid for `header`
+    let lower = header.to_ascii_lowercase();
+    let needle = format!("{}=", name);
+    let mut from = 0;
+    
+    while let Some(pos) = lower[from..].find(&needle) {
+        let start = from + pos;
+        from = start + needle.len();
+        
+        // Parameters follow a `;`, which rules out matches like `xfilename=`
+        let before = lower[..start].trim_end();
+        if !before.is_empty() && !before.ends_with(';') {
+            continue;
+        }
+        
+        let rest = header[from..].trim_start();
+        let value = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next()),
+                        '"' => break,
+                        c => value.push(c),
+                    }
+                }
+                value
+            }
+            None => rest
+                .split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .trim_matches('\'')
+                .to_string(),
+        };
+        return Some(value);
+    }
+    
+    None
+}
+
+/// Decode an RFC 5987 extended value (`charset'language'percent-encoded`)
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+    
+    let bytes = urlencoding::decode_binary(encoded.as_bytes());
+    if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes.into_owned()).ok()
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        Some(bytes.iter().map(|&b| b as char).collect())
+    } else {
+        None
+    }
+}
+
+/// Filename from a `Content-Disposition` header. `filename*=` (RFC 5987) wins
+/// over `filename=`; either is reduced to a bare, sanitized name.
+pub fn parse_content_disposition(header: &str) -> Option<String> {
+    header_param(header, "filename*")
+        .and_then(|v| decode_ext_value(&v))
+        .and_then(|name| clean_remote_name(&name))
+        .or_else(|| header_param(header, "filename").and_then(|name| clean_remote_name(&name)))
+}
+
+/// Filename from the last segment of a URL's path, percent-decoded and sanitized
+pub fn filename_from_url(url: &str) -> Option<String> {
+    let parsed = url::Url::parse(url).ok()?;
+    let last = parsed.path_segments()?.next_back()?;
+    let decoded = urlencoding::decode(last).ok()?;
+    clean_remote_name(&decoded)
+}
+
+/// Pick the name to save a download under: the `Content-Disposition`
+/// filename, then the URL's last path segment, then a generated name
+pub fn extract_filename(url: &str, content_disposition: Option<&str>) -> String {
+    content_disposition
+        .and_then(parse_content_disposition)
+        .or_else(|| filename_from_url(url))
+        .unwrap_or_else(|| format!("download_{}", uuid::Uuid::new_v4().simple()))
+}
+
+/// Split off the extension, keeping compound `.tar.*` ones whole
+fn split_extension(filename: &str) -> (&str, &str) {
+    let lower = filename.to_ascii_lowercase();
+    match lower.rfind(".tar.").or_else(|| lower.rfind('.')) {
+        // A leading dot marks a hidden file, not an extension
+        Some(pos) if pos > 0 => filename.split_at(pos),
+        _ => (filename, ""),
+    }
+}
+
+/// Whether `filename` names gzip or Brotli data, which servers often label
+/// with a matching `Content-Encoding` as though they'd compressed it in transit
+pub fn is_precompressed(filename: &str) -> bool {
+    let lower = filename.to_ascii_lowercase();
+    [".gz", ".tgz", ".svgz", ".br"].iter().any(|ext| lower.ends_with(ext))
+}
+
+/// Name for a download of only part of `filename`, marked with the byte
+/// range, e.g. `video (bytes 0-1023).mp4`
+pub fn range_filename(filename: &str, start: u64, end: Option<u64>) -> String {
+    let (stem, extension) = split_extension(filename);
+    let end = end.map(|end| end.to_string()).unwrap_or_default();
+    format!("{} (bytes {}-{}){}", stem, start, end, extension)
+}
+
+/// Name to save `filename` under in `dir`. With `Rename`, a taken name
+/// becomes the first free `name (2).ext`, `name (3).ext`, ... Names for
+/// which `reserved` is true count as taken even if nothing is on disk yet.
+pub fn resolve_conflict(
+    dir: &Path,
+    filename: &str,
+    policy: ConflictPolicy,
+    reserved: impl Fn(&Path) -> bool,
+) -> String {
+    let taken = |name: &str| {
+        let path = dir.join(name);
+        reserved(&path) || path.exists()
+    };
+    if policy == ConflictPolicy::Overwrite || !taken(filename) {
+        return filename.to_string();
+    }
+    
+    let (stem, extension) = split_extension(filename);
+    (2..)
+        .map(|n| format!("{} ({}){}", stem, n, extension))
+        .find(|candidate| !taken(candidate))
+        .expect("ran out of candidate names")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn content_disposition_variants() {
+        let cases = [
+            ("attachment; filename=\"file.zip\"", Some("file.zip")),
+            ("attachment; filename=file.zip; size=10", Some("file.zip")),
+            ("attachment; FILENAME=\"a;b.zip\"", Some("a;b.zip")),
+            ("attachment; filename=\"say \\\"hi\\\".txt\"", Some("say \"hi\".txt")),
+            ("attachment; filename*=UTF-8''na%C3%AFve%20file.txt", Some("naïve file.txt")),
+            ("attachment; filename*=iso-8859-1'en'caf%E9.txt", Some("café.txt")),
+            // The extended form wins regardless of order
+            ("attachment; filename=\"plain.txt\"; filename*=UTF-8''fancy.txt", Some("fancy.txt")),
+            // A broken extended value falls back to the plain one
+            ("attachment; filename*=bogus; filename=plain.txt", Some("plain.txt")),
+            ("attachment; filename=\"../../etc/passwd\"", Some("passwd")),
+            ("attachment; filename=\"..\"", None),
+            ("attachment; filename=\"\"", None),
+            ("attachment; xfilename=nope.txt", None),
+            ("inline", None),
+        ];
+        
+        for (header, expected) in cases {
+            assert_eq!(parse_content_disposition(header).as_deref(), expected, "{}", header);
+        }
+    }
+    
+    #[test]
+    fn url_fallbacks() {
+        assert_eq!(filename_from_url("https://x.org/a/file%20name.iso?dl=1").as_deref(), Some("file name.iso"));
+        assert_eq!(filename_from_url("https://x.org/a/..%2F..%2Fevil.sh").as_deref(), Some("evil.sh"));
+        assert_eq!(filename_from_url("https://x.org/dir/"), None);
+        assert_eq!(filename_from_url("https://x.org"), None);
+        assert_eq!(filename_from_url("not a url"), None);
+    }
+    
+    #[test]
+    fn extract_filename_prefers_header_then_url_then_generated() {
+        assert_eq!(extract_filename("https://x.org/url.bin", Some("attachment; filename=cd.bin")), "cd.bin");
+        assert_eq!(extract_filename("https://x.org/url.bin", Some("attachment")), "url.bin");
+        assert!(extract_filename("https://x.org/", None).starts_with("download_"));
+        assert_ne!(extract_filename("https://x.org/", None), extract_filename("https://x.org/", None));
+    }
+    
+    #[test]
+    fn conflicts_follow_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        for name in ["report.pdf", "backup.tar.gz", "backup (2).tar.gz", ".bashrc"] {
+            std::fs::write(dir.join(name), b"x").unwrap();
+        }
+        
+        assert_eq!(resolve_conflict(dir, "report.pdf", ConflictPolicy::Overwrite, |_| false), "report.pdf");
+        assert_eq!(resolve_conflict(dir, "report.pdf", ConflictPolicy::Rename, |_| false), "report (2).pdf");
+        assert_eq!(resolve_conflict(dir, "backup.tar.gz", ConflictPolicy::Rename, |_| false), "backup (3).tar.gz");
+        assert_eq!(resolve_conflict(dir, ".bashrc", ConflictPolicy::Rename, |_| false), ".bashrc (2)");
+        assert_eq!(resolve_conflict(dir, "new.txt", ConflictPolicy::Rename, |_| false), "new.txt");
+        let reserved = |path: &Path| path.ends_with("new.txt");
+        assert_eq!(resolve_conflict(dir, "new.txt", ConflictPolicy::Rename, reserved), "new (2).txt");
+    }
+    
+    #[test]
+    fn range_names_mark_the_slice() {
+        assert_eq!(range_filename("movie.mkv", 0, Some(1023)), "movie (bytes 0-1023).mkv");
+        assert_eq!(range_filename("dump.tar.gz", 512, None), "dump (bytes 512-).tar.gz");
+    }
+    
+    #[test]
+    fn compressed_files_are_recognized() {
+        assert!(is_precompressed("backup.tar.gz"));
+        assert!(is_precompressed("BACKUP.TGZ"));
+        assert!(!is_precompressed("report.pdf"));
+        assert!(!is_precompressed("gz"));
+    }
+}
@@ -0,0 +1,156 @@
+//! Post-download command hooks
+
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Run the configured post-download command for a completed file.
+///
+/// The command is split into arguments (honouring single and double quotes)
+/// and executed directly, not through a shell, so substituted values can't
+/// inject extra commands. Returns an error message if the command couldn't
+/// be started or exited unsuccessfully.
+pub async fn run_post_complete_command(
+    template: &str,
+    path: &Path,
+    filename: &str,
+    url: &str,
+) -> Result<(), String> {
+    let path = path.to_string_lossy();
+    let placeholders = [("{path}", path.as_ref()), ("{filename}", filename), ("{url}", url)];
+    
+    let args: Vec<String> = split_command(template)
+        .iter()
+        .map(|arg| substitute(arg, &placeholders))
+        .collect();
+    
+    let Some((program, rest)) = args.split_first() else {
+        return Err("Post-download command is empty".to_string());
+    };
+    
+    info!("Running post-download command for {}: {}", filename, program);
+    
+    let output = tokio::process::Command::new(program)
+        .args(rest)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run post-download command: {}", e))?;
+    
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stdout.trim().is_empty() {
+        info!("Post-download command output: {}", stdout.trim());
+    }
+    if !stderr.trim().is_empty() {
+        warn!("Post-download command stderr: {}", stderr.trim());
+    }
+    
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("Post-download command exited with {}", output.status))
+    }
+}
+
+/// Replace placeholders in a single pass, so a value that happens to contain
+/// `{filename}` (e.g. a URL) isn't expanded again
+fn substitute(arg: &str, placeholders: &[(&str, &str)]) -> String {
+    let mut result = String::with_capacity(arg.len());
+    let mut rest = arg;
+    
+    'outer: while !rest.is_empty() {
+        for (key, value) in placeholders {
+            if let Some(after) = rest.strip_prefix(key) {
+                result.push_str(value);
+                rest = after;
+                continue 'outer;
+            }
+        }
+        let mut chars = rest.chars();
+        result.extend(chars.next());
+        rest = chars.as_str();
+    }
+    
+    result
+}
+
+/// Split a command line into arguments on whitespace, keeping quoted sections together
+fn split_command(command: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote: Option<char> = None;
+    
+    for c in command.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            None if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    
+    if in_arg {
+        args.push(current);
+    }
+    
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Arguments `template` runs with for the given placeholder values
+    fn args(template: &str, path: &str, filename: &str, url: &str) -> Vec<String> {
+        let placeholders = [("{path}", path), ("{filename}", filename), ("{url}", url)];
+        split_command(template).iter().map(|arg| substitute(arg, &placeholders)).collect()
+    }
+    
+    #[test]
+    fn quoted_sections_stay_one_argument() {
+        assert_eq!(split_command("  unzip -o  file.zip "), ["unzip", "-o", "file.zip"]);
+        assert_eq!(split_command(r#"cp "my file.txt" 'other dir/'"#), ["cp", "my file.txt", "other dir/"]);
+        assert_eq!(split_command(r#"echo pre"fix suf"fix ''"#), ["echo", "prefix suffix", ""]);
+        // Each kind of quote is literal inside the other
+        assert_eq!(split_command(r#"echo "it's" '"x"'"#), ["echo", "it's", "\"x\""]);
+        assert!(split_command("   ").is_empty());
+    }
+    
+    #[test]
+    fn unterminated_quotes_run_to_the_end() {
+        assert_eq!(split_command(r#"echo "a b"#), ["echo", "a b"]);
+        assert_eq!(split_command("echo 'a \"b"), ["echo", "a \"b"]);
+    }
+    
+    #[test]
+    fn placeholders_are_filled_in_inside_quotes() {
+        assert_eq!(
+            args(r#"mv "{path}" '/srv/{filename}.done'"#, "/tmp/a.iso", "a.iso", "http://x"),
+            ["mv", "/tmp/a.iso", "/srv/a.iso.done"]
+        );
+        assert_eq!(args("echo {unknown} {url}", "p", "f", "u"), ["echo", "{unknown}", "u"]);
+    }
+    
+    #[test]
+    fn values_are_never_split_or_expanded_again() {
+        // A value with spaces, quotes or a `;` stays a single argument
+        let filename = r#"a b"; rm -rf ~ 'c.txt"#;
+        assert_eq!(args("touch {filename}", "p", filename, "u"), ["touch", filename]);
+        
+        // A value holding a placeholder is left as it is
+        let url = "http://example.com/{path}?f={filename}";
+        assert_eq!(args("fetch {url} {path}", "/tmp/x", "x", url), ["fetch", url, "/tmp/x"]);
+    }
+}
@@ -1,10 +1,14 @@
 //! Download manager for handling concurrent downloads with queue
 
-use crate::db::{DownloadRecord, DownloadStatus};
-use parking_lot::RwLock;
-use std::collections::{HashMap, VecDeque};
+use super::{Cancelled, HttpClients, QueueFull, SharedThrottle, SpeedHistory, SpeedLimit, SpeedStats};
+use crate::db::{CancelReason, Database, DownloadRecord, DownloadStatus};
+use crate::config::ConflictPolicy;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::sync::{broadcast, mpsc, OwnedSemaphorePermit, Semaphore};
 
 /// Progress update sent to clients
 #[derive(Debug, Clone, serde::Serialize)]
@@ -12,21 +16,250 @@ pub struct ProgressUpdate {
     pub id: String,
     pub downloaded: u64,
     pub total: Option<u64>,
+    /// True while the total size is unknown, so clients should show a
+    /// running byte count rather than a percentage
+    pub indeterminate: bool,
     pub speed: u64, // bytes per second
     pub status: DownloadStatus,
     pub error: Option<String>,
+    /// Set only on the single update sent when a download actually starts
+    pub started_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// What happened to a download, as far as a client that just refetches the
+/// list is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    StatusChanged,
+    /// Several downloads changed at once, or some changes were missed;
+    /// refetch everything
+    Resync,
+}
+
+/// Lightweight notice that the download list changed, for clients that
+/// don't render live progress
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DownloadChange {
+    /// Absent for `Resync`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub change: ChangeKind,
+}
+
+impl DownloadChange {
+    pub fn new(id: &str, change: ChangeKind) -> Self {
+        Self {
+            id: Some(id.to_string()),
+            change,
+        }
+    }
+    
+    pub fn resync() -> Self {
+        Self {
+            id: None,
+            change: ChangeKind::Resync,
+        }
+    }
+    
+    /// The change a progress update amounts to: status transitions count,
+    /// bytes moving while downloading don't. The `Pending` update of a new
+    /// download is left out, as it's already reported as `Added`.
+    pub fn from_progress(update: &ProgressUpdate) -> Option<Self> {
+        let transition = match update.status {
+            DownloadStatus::Pending => false,
+            DownloadStatus::Downloading => update.started_at.is_some(),
+            _ => true,
+        };
+        transition.then(|| Self::new(&update.id, ChangeKind::StatusChanged))
+    }
+}
+
+/// The queue's order, sent to clients following it whenever it changes
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct QueueSnapshot {
+    /// Next in line first
+    pub queue: Vec<QueuedPosition>,
+}
+
+/// Where a queued download stands
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct QueuedPosition {
+    pub id: String,
+    /// 1-based, like `AddDownloadResponse::queue_position`
+    pub position: usize,
+    /// Rough seconds until it starts; see `DownloadManager::estimated_wait_secs`
+    pub eta_secs: Option<u64>,
+}
+
+/// A download that is currently running
+struct ActiveDownload {
+    cancel_tx: mpsc::Sender<CancelReason>,
+    /// File type of the download, for acting on all downloads of a type
+    file_type: String,
+    /// Speed cap in bytes per second (0 = unlimited), read by the chunk loop
+    speed_limit: Arc<AtomicU64>,
+    /// New filename requested while it was running
+    rename: Option<String>,
+    /// Set once the final filename has been chosen; too late to rename
+    finalized: bool,
+}
+
+/// Slots held per host, for the per-host limit
+type HostSlots = Arc<Mutex<HashMap<String, usize>>>;
+
+/// A running download's claim on one of the global slots, and on one of its
+/// host's. Dropping it frees them, unless the limit was lowered meanwhile
+/// and the global slot is one of those to retire.
+pub struct DownloadSlot {
+    permit: Option<OwnedSemaphorePermit>,
+    retiring: Arc<AtomicUsize>,
+    host: Option<(String, HostSlots)>,
+}
+
+impl Drop for DownloadSlot {
+    fn drop(&mut self) {
+        if let Some((host, host_slots)) = self.host.take() {
+            let mut host_slots = host_slots.lock();
+            if let Some(count) = host_slots.get_mut(&host) {
+                *count -= 1;
+                if *count == 0 {
+                    host_slots.remove(&host);
+                }
+            }
+        }
+        
+        let retire = self
+            .retiring
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if let Some(permit) = self.permit.take().filter(|_| retire) {
+            permit.forget();
+        }
+    }
+}
+
+/// Final paths that finishing downloads have picked but not created yet.
+/// Picking a name and creating the file are separate steps, so without
+/// this two downloads finishing together could settle on the same free
+/// name and one would replace the other.
+#[derive(Clone, Default)]
+pub struct FilenameReservations {
+    paths: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl FilenameReservations {
+    /// Pick the name to save `filename` under in `dir` with
+    /// `resolve_conflict`, steering clear of names reserved by others, and
+    /// hold it until the returned reservation is dropped.
+    /// With `Overwrite` the name is used as is and nothing is held.
+    pub fn reserve(&self, dir: &Path, filename: &str, policy: ConflictPolicy) -> ReservedFilename {
+        let mut paths = self.paths.lock();
+        let filename = super::resolve_conflict(dir, filename, policy, |path| paths.contains(path));
+        let path = (policy != ConflictPolicy::Overwrite).then(|| {
+            let path = dir.join(&filename);
+            paths.insert(path.clone());
+            path
+        });
+        ReservedFilename {
+            filename,
+            path,
+            paths: Arc::clone(&self.paths),
+        }
+    }
+}
+
+/// A name held by `FilenameReservations::reserve`. Keep it until the file
+/// exists under that name; dropping it releases the name.
+pub struct ReservedFilename {
+    filename: String,
+    path: Option<PathBuf>,
+    paths: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl ReservedFilename {
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+}
+
+impl Drop for ReservedFilename {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            self.paths.lock().remove(path);
+        }
+    }
 }
 
 /// Inner state that cannot be cloned directly
 struct DownloadManagerInner {
     /// Maximum concurrent downloads
-    max_concurrent: RwLock<usize>,
+    max_concurrent: AtomicUsize,
+    
+    /// Maximum simultaneous downloads from one host (0 = no limit)
+    max_per_host: RwLock<usize>,
     
-    /// Active downloads (id -> cancel sender)
-    active: RwLock<HashMap<String, mpsc::Sender<()>>>,
+    /// Maximum downloads waiting in the queue (0 = no limit)
+    max_queue: RwLock<usize>,
+    
+    /// Active downloads by id
+    active: RwLock<HashMap<String, ActiveDownload>>,
     
     /// Queued downloads waiting to start
     queue: RwLock<VecDeque<DownloadRecord>>,
+    
+    /// Lengths of `active` and `queue`, stored whenever they change (with
+    /// the lock still held) so stats can be read without locking
+    active_count: AtomicUsize,
+    queued_count: AtomicUsize,
+    
+    /// Next queue positions to save for a download put at the front and at
+    /// the back, so a change only writes the download that moved
+    queue_front: AtomicI64,
+    queue_back: AtomicI64,
+    
+    /// Bytes saved by downloads completed since startup
+    total_bytes: AtomicU64,
+    
+    /// Set while nothing may start, e.g. on a metered connection
+    queue_paused: AtomicBool,
+    
+    /// Set by the user to stop the queue advancing, independent of
+    /// `queue_paused`
+    paused: AtomicBool,
+    
+    /// Whether the alternative speed limit is on
+    alt_speed: AtomicBool,
+    
+    /// The alternative speed limit, in bytes per second, applied to all
+    /// downloads together while it's on
+    alt_speed_limit: AtomicU64,
+    
+    /// Bucket all downloads share; its limit is `alt_speed_limit` while the
+    /// alternative speed limit is on, otherwise 0 (unlimited)
+    shared_limit: Arc<AtomicU64>,
+    shared_throttle: SharedThrottle,
+    
+    /// Recent speed samples of each download
+    speed_history: Mutex<SpeedHistory>,
+    
+    /// Latest downloaded and total bytes of running downloads, waiting to
+    /// be written to the database together
+    staged_progress: Mutex<HashMap<String, (u64, Option<u64>)>>,
+    
+    /// Moving average of how long a download takes, in seconds
+    avg_duration_secs: RwLock<Option<f64>>,
+    
+    /// HTTP clients shared by all downloads
+    client: RwLock<HttpClients>,
+    
+    /// Names picked by downloads that are about to be saved
+    filenames: FilenameReservations,
+    
+    /// Database the queue order is persisted to
+    db: Database,
 }
 
 /// Download manager that handles concurrent downloads and queuing
@@ -35,29 +268,75 @@ pub struct DownloadManager {
     /// Semaphore to limit concurrent downloads
     semaphore: Arc<Semaphore>,
     
+    /// Slots to retire as they are released, after the limit was lowered
+    /// below the number of running downloads
+    retiring: Arc<AtomicUsize>,
+    
+    /// Slots held by each host's downloads
+    host_slots: HostSlots,
+    
     /// Inner state wrapped in Arc
     inner: Arc<DownloadManagerInner>,
     
     /// Broadcast channel for progress updates
     progress_tx: broadcast::Sender<ProgressUpdate>,
+    
+    /// Broadcast channel for downloads being added or removed
+    changes_tx: broadcast::Sender<DownloadChange>,
+    
+    /// Broadcast channel for the queue's order changing
+    queue_tx: broadcast::Sender<QueueSnapshot>,
 }
 
 impl DownloadManager {
     /// Create a new download manager
-    pub fn new(max_concurrent: usize) -> Self {
+    pub fn new(max_concurrent: usize, client: HttpClients, db: Database) -> Self {
         let (progress_tx, _) = broadcast::channel(1000);
+        let (changes_tx, _) = broadcast::channel(100);
+        let (queue_tx, _) = broadcast::channel(16);
+        let shared_limit = Arc::new(AtomicU64::new(0));
+        // Carry on around the positions saved before a restart
+        let (first, last) = db.queue_order_bounds().unwrap_or_default();
         
         Self {
             semaphore: Arc::new(Semaphore::new(max_concurrent)),
+            retiring: Arc::new(AtomicUsize::new(0)),
+            host_slots: HostSlots::default(),
             inner: Arc::new(DownloadManagerInner {
-                max_concurrent: RwLock::new(max_concurrent),
+                max_concurrent: AtomicUsize::new(max_concurrent),
+                max_per_host: RwLock::new(0),
+                max_queue: RwLock::new(0),
                 active: RwLock::new(HashMap::new()),
                 queue: RwLock::new(VecDeque::new()),
+                active_count: AtomicUsize::new(0),
+                queued_count: AtomicUsize::new(0),
+                queue_front: AtomicI64::new(first.unwrap_or(0) - 1),
+                queue_back: AtomicI64::new(last.map_or(0, |last| last + 1)),
+                total_bytes: AtomicU64::new(0),
+                queue_paused: AtomicBool::new(false),
+                paused: AtomicBool::new(false),
+                alt_speed: AtomicBool::new(false),
+                alt_speed_limit: AtomicU64::new(0),
+                shared_limit: Arc::clone(&shared_limit),
+                shared_throttle: SharedThrottle::new(shared_limit),
+                speed_history: Mutex::new(SpeedHistory::default()),
+                staged_progress: Mutex::new(HashMap::new()),
+                avg_duration_secs: RwLock::new(None),
+                client: RwLock::new(client),
+                filenames: FilenameReservations::default(),
+                db,
             }),
             progress_tx,
+            changes_tx,
+            queue_tx,
         }
     }
     
+    /// Names reserved for downloads and copies about to be saved
+    pub fn filenames(&self) -> &FilenameReservations {
+        &self.inner.filenames
+    }
+    
     /// Subscribe to progress updates
     pub fn subscribe(&self) -> broadcast::Receiver<ProgressUpdate> {
         self.progress_tx.subscribe()
@@ -68,19 +347,117 @@ impl DownloadManager {
         self.progress_tx.clone()
     }
     
-    /// Get the semaphore for limiting concurrency
-    pub fn semaphore(&self) -> Arc<Semaphore> {
-        Arc::clone(&self.semaphore)
+    /// Subscribe to downloads being added or removed. Status changes come
+    /// with the progress updates; see `DownloadChange::from_progress`.
+    pub fn subscribe_changes(&self) -> broadcast::Receiver<DownloadChange> {
+        self.changes_tx.subscribe()
+    }
+    
+    /// Subscribe to the queue's order, sent whenever it changes. A
+    /// subscriber that lags should take a fresh `queue_snapshot`.
+    pub fn subscribe_queue(&self) -> broadcast::Receiver<QueueSnapshot> {
+        self.queue_tx.subscribe()
+    }
+    
+    /// Tell change subscribers that downloads were added or removed
+    pub fn notify_change(&self, change: DownloadChange) {
+        let _ = self.changes_tx.send(change);
+    }
+    
+    /// Tell clients about a download that was just added, before it's
+    /// started or queued, so lists following progress gain it at once
+    pub fn announce(&self, download: &DownloadRecord) {
+        self.notify_change(DownloadChange::new(&download.id, ChangeKind::Added));
+        let _ = self.progress_tx.send(ProgressUpdate {
+            id: download.id.clone(),
+            downloaded: 0,
+            total: download.total_size,
+            indeterminate: download.total_size.is_none(),
+            speed: 0,
+            status: DownloadStatus::Pending,
+            error: None,
+            started_at: None,
+        });
+    }
+    
+    /// Get the shared HTTP clients (cheap to clone, shares the connection pools)
+    pub fn http_client(&self) -> HttpClients {
+        self.inner.client.read().clone()
     }
     
-    /// Add a download to the active set
-    pub fn add_active(&self, id: String, cancel_tx: mpsc::Sender<()>) {
-        self.inner.active.write().insert(id, cancel_tx);
+    /// Replace the shared HTTP clients, e.g. after settings changed.
+    /// Downloads already running keep the clients they started with.
+    pub fn set_http_client(&self, client: HttpClients) {
+        *self.inner.client.write() = client;
+    }
+    
+    /// Add a download to the active set, returning the speed limit handle
+    /// its chunk loop should read
+    pub fn add_active(
+        &self,
+        record: &DownloadRecord,
+        cancel_tx: mpsc::Sender<CancelReason>,
+    ) -> SpeedLimit {
+        let speed_limit = SpeedLimit {
+            shared: Some(self.inner.shared_throttle.clone()),
+            ..SpeedLimit::new(record.speed_limit.unwrap_or(0))
+        };
+        let mut active = self.inner.active.write();
+        active.insert(record.id.clone(), ActiveDownload {
+            cancel_tx,
+            file_type: record.file_type.clone(),
+            speed_limit: Arc::clone(&speed_limit.own),
+            rename: record.rename_to.clone(),
+            finalized: false,
+        });
+        self.inner.active_count.store(active.len(), Ordering::SeqCst);
+        speed_limit
+    }
+    
+    /// Change the speed cap of a running or queued download (`None` =
+    /// unlimited). A running download picks it up on its next chunk.
+    pub fn set_speed_limit(&self, id: &str, limit: Option<u64>) {
+        if let Some(active) = self.inner.active.read().get(id) {
+            active.speed_limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+            return;
+        }
+        
+        if let Some(queued) = self.inner.queue.write().iter_mut().find(|d| d.id == id) {
+            queued.speed_limit = limit;
+        }
     }
     
     /// Remove a download from the active set
     pub fn remove_active(&self, id: &str) {
-        self.inner.active.write().remove(id);
+        let mut active = self.inner.active.write();
+        active.remove(id);
+        self.inner.active_count.store(active.len(), Ordering::SeqCst);
+    }
+    
+    /// Add a speed sample, in bytes per second, for a running download
+    pub fn record_speed(&self, id: &str, speed: u64) {
+        self.inner.speed_history.lock().record(id, speed);
+    }
+    
+    /// Spread of a download's sampled speeds
+    pub fn speed_stats(&self, id: &str) -> SpeedStats {
+        self.inner.speed_history.lock().stats(id)
+    }
+    
+    /// Forget a removed download's speed samples
+    pub fn forget_speeds(&self, id: &str) {
+        self.inner.speed_history.lock().remove(id);
+    }
+    
+    /// Note a running download's progress for the next database write,
+    /// replacing whatever it had staged before
+    pub fn stage_progress(&self, id: &str, downloaded: u64, total: Option<u64>) {
+        self.inner.staged_progress.lock().insert(id.to_string(), (downloaded, total));
+    }
+    
+    /// Take the progress staged since the last call
+    pub fn take_staged_progress(&self) -> HashMap<String, (u64, Option<u64>)> {
+        std::mem::take(&mut *self.inner.staged_progress.lock())
     }
     
     /// Check if a download is active
@@ -88,64 +465,407 @@ impl DownloadManager {
         self.inner.active.read().contains_key(id)
     }
     
+    /// Claim a slot for a download of `url` if it can start now without
+    /// going over the global limit or the per-host limit, and the queue
+    /// isn't paused. The download holds the returned permit for as long as
+    /// it runs.
+    pub fn try_start(&self, url: &str) -> Option<DownloadSlot> {
+        if self.is_queue_paused() || self.is_paused() {
+            return None;
+        }
+        
+        let per_host = *self.inner.max_per_host.read();
+        let host = host_key(url);
+        // Checked and taken under one lock, so two downloads from a host
+        // starting together can't both get its last slot
+        let mut host_slots = self.host_slots.lock();
+        let held = host.as_ref().and_then(|host| host_slots.get(host)).copied().unwrap_or(0);
+        if per_host > 0 && host.is_some() && held >= per_host {
+            return None;
+        }
+        
+        let permit = Arc::clone(&self.semaphore).try_acquire_owned().ok()?;
+        if let Some(host) = &host {
+            *host_slots.entry(host.clone()).or_default() += 1;
+        }
+        Some(DownloadSlot {
+            permit: Some(permit),
+            retiring: Arc::clone(&self.retiring),
+            host: host.map(|host| (host, Arc::clone(&self.host_slots))),
+        })
+    }
+    
     /// Cancel a download
-    pub async fn cancel(&self, id: &str) -> bool {
+    pub async fn cancel(&self, id: &str, reason: CancelReason) -> bool {
         // Clone the sender if found to avoid holding the lock across await
-        let cancel_tx = self.inner.active.read().get(id).cloned();
+        let cancel_tx = self.inner.active.read().get(id).map(|a| a.cancel_tx.clone());
         
         if let Some(tx) = cancel_tx {
-            let _ = tx.send(()).await;
+            let _ = tx.send(reason).await;
             true
         } else {
             // Check if it's in the queue
             let mut queue = self.inner.queue.write();
             if let Some(pos) = queue.iter().position(|d| d.id == id) {
                 queue.remove(pos);
+                self.persist_queue_change(&queue, id, None);
+                drop(queue);
+                
+                // Never started, so there's no task to record the cancellation
+                if let Err(e) = self.inner.db.mark_cancelled(id, reason) {
+                    tracing::warn!("Failed to record cancellation: {}", e);
+                }
+                let _ = self.progress_tx.send(ProgressUpdate {
+                    id: id.to_string(),
+                    downloaded: 0,
+                    total: None,
+                    indeterminate: false,
+                    speed: 0,
+                    status: DownloadStatus::Cancelled,
+                    error: Some(Cancelled(reason).to_string()),
+                    started_at: None,
+                });
                 return true;
             }
             false
         }
     }
     
-    /// Add a download to the queue
-    pub fn enqueue(&self, download: DownloadRecord) {
-        self.inner.queue.write().push_back(download);
+    /// Pause a running or queued download, keeping its partial file. A
+    /// running one records the pause itself once it stops; a queued one
+    /// leaves the queue and is marked `Paused` here. Returns whether the
+    /// download was found.
+    pub async fn pause(&self, id: &str) -> bool {
+        let cancel_tx = self.inner.active.read().get(id).map(|a| a.cancel_tx.clone());
+        if let Some(tx) = cancel_tx {
+            let _ = tx.send(CancelReason::Paused).await;
+            return true;
+        }
+        
+        let removed = {
+            let mut queue = self.inner.queue.write();
+            let removed = queue.iter().position(|d| d.id == id).and_then(|pos| queue.remove(pos));
+            if removed.is_some() {
+                self.persist_queue_change(&queue, id, None);
+            }
+            removed
+        };
+        let Some(download) = removed else { return false };
+        if let Err(e) = self.inner.db.update_status(id, DownloadStatus::Paused, None) {
+            tracing::warn!("Failed to record pause: {}", e);
+        }
+        let _ = self.progress_tx.send(ProgressUpdate {
+            id: id.to_string(),
+            downloaded: download.downloaded_size,
+            total: download.total_size,
+            indeterminate: download.total_size.is_none(),
+            speed: 0,
+            status: DownloadStatus::Paused,
+            error: None,
+            started_at: None,
+        });
+        true
+    }
+    
+    /// Ids of the running and the queued downloads of `file_type`
+    pub fn ids_of_file_type(&self, file_type: &str) -> (Vec<String>, Vec<String>) {
+        let active = self
+            .inner
+            .active
+            .read()
+            .iter()
+            .filter(|(_, a)| a.file_type == file_type)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let queued = self
+            .inner
+            .queue
+            .read()
+            .iter()
+            .filter(|d| d.file_type == file_type)
+            .map(|d| d.id.clone())
+            .collect();
+        (active, queued)
+    }
+    
+    /// Signal every running download to stop. Usable from non-async code;
+    /// a download that already has a cancellation pending is left alone.
+    pub fn cancel_all_active(&self, reason: CancelReason) {
+        for active in self.inner.active.read().values() {
+            let _ = active.cancel_tx.try_send(reason);
+        }
+    }
+    
+    /// Change the filename a download will be saved under. A running download
+    /// keeps its partial file and picks the new name up when it finishes, as
+    /// does a queued one when `started` (it may have a partial file already).
+    /// Returns false if the download has already been saved under its old name.
+    pub fn rename(&self, id: &str, filename: &str, started: bool) -> bool {
+        if let Some(active) = self.inner.active.write().get_mut(id) {
+            if active.finalized {
+                return false;
+            }
+            active.rename = Some(filename.to_string());
+            return true;
+        }
+        
+        if let Some(queued) = self.inner.queue.write().iter_mut().find(|d| d.id == id) {
+            if started {
+                queued.rename_to = Some(filename.to_string());
+            } else {
+                queued.filename = filename.to_string();
+            }
+        }
+        true
+    }
+    
+    /// Fix the filename of a finishing download, returning the name it was
+    /// renamed to while running, if any. Later renames are refused.
+    pub fn finalize_filename(&self, id: &str) -> Option<String> {
+        let mut active = self.inner.active.write();
+        let entry = active.get_mut(id)?;
+        entry.finalized = true;
+        entry.rename.take()
+    }
+    
+    /// Add a download to the queue, returning its 1-based position, or
+    /// `QueueFull` if `max_queue_size` downloads are already waiting.
+    /// Broadcasts a `Queued` update so clients see it waiting.
+    pub fn enqueue(&self, download: DownloadRecord) -> Result<usize, QueueFull> {
+        self.push_queue(download, *self.inner.max_queue.read(), false)
     }
     
-    /// Get next download from queue
-    pub fn dequeue(&self) -> Option<DownloadRecord> {
-        self.inner.queue.write().pop_front()
+    /// Put a download that was already accepted (e.g. one restored at
+    /// startup) back in the queue, regardless of the queue limit
+    pub fn requeue(&self, download: DownloadRecord) -> usize {
+        self.push_queue(download, 0, false).unwrap_or_default()
+    }
+    
+    /// Put a download that was paused mid-transfer back at the head of the
+    /// queue, so it's the first to pick up again
+    pub fn requeue_paused(&self, download: DownloadRecord) {
+        let _ = self.push_queue(download, 0, true);
+    }
+    
+    fn push_queue(&self, download: DownloadRecord, max: usize, front: bool) -> Result<usize, QueueFull> {
+        let update = ProgressUpdate {
+            id: download.id.clone(),
+            downloaded: download.downloaded_size,
+            total: download.total_size,
+            indeterminate: download.total_size.is_none(),
+            speed: 0,
+            status: DownloadStatus::Queued,
+            error: None,
+            started_at: None,
+        };
+        
+        let position = {
+            let mut queue = self.inner.queue.write();
+            if max > 0 && queue.len() >= max {
+                return Err(QueueFull(max));
+            }
+            let position = if front {
+                queue.push_front(download);
+                self.inner.queue_front.fetch_sub(1, Ordering::SeqCst)
+            } else {
+                queue.push_back(download);
+                self.inner.queue_back.fetch_add(1, Ordering::SeqCst)
+            };
+            self.persist_queue_change(&queue, &update.id, Some(position));
+            if front { 1 } else { queue.len() }
+        };
+        
+        let _ = self.progress_tx.send(update);
+        Ok(position)
+    }
+    
+    /// Take the first queued download that can start now, with its slot.
+    /// Downloads whose host is at its connection limit are skipped but keep
+    /// their place.
+    pub fn dequeue(&self) -> Option<(DownloadRecord, DownloadSlot)> {
+        let mut queue = self.inner.queue.write();
+        let (position, slot) = queue
+            .iter()
+            .enumerate()
+            .find_map(|(i, d)| self.try_start(&d.url).map(|slot| (i, slot)))?;
+        let next = queue.remove(position)?;
+        self.persist_queue_change(&queue, &next.id, None);
+        Some((next, slot))
+    }
+    
+    /// Write the queue position of `id`, which just joined or left the queue,
+    /// to the database so the order survives a restart, update the queued
+    /// count and tell queue subscribers. Called with the queue lock held so
+    /// concurrent changes persist in order.
+    fn persist_queue_change(&self, queue: &VecDeque<DownloadRecord>, id: &str, position: Option<i64>) {
+        self.inner.queued_count.store(queue.len(), Ordering::SeqCst);
+        // Only built when someone follows the queue
+        if self.queue_tx.receiver_count() > 0 {
+            let _ = self.queue_tx.send(self.snapshot_of(queue));
+        }
+        if let Err(e) = self.inner.db.set_queue_order(id, position) {
+            tracing::warn!("Failed to persist queue order: {}", e);
+        }
     }
     
     /// Get queue length
     pub fn queue_len(&self) -> usize {
-        self.inner.queue.read().len()
+        self.inner.queued_count.load(Ordering::SeqCst)
+    }
+    
+    /// Snapshot of the queue, next in line first
+    pub fn queued(&self) -> Vec<DownloadRecord> {
+        self.inner.queue.read().iter().cloned().collect()
+    }
+    
+    /// Positions and estimated waits of the queued downloads
+    pub fn queue_snapshot(&self) -> QueueSnapshot {
+        self.snapshot_of(&self.inner.queue.read())
+    }
+    
+    fn snapshot_of(&self, queue: &VecDeque<DownloadRecord>) -> QueueSnapshot {
+        let queue = queue
+            .iter()
+            .enumerate()
+            .map(|(i, download)| QueuedPosition {
+                id: download.id.clone(),
+                position: i + 1,
+                eta_secs: self.estimated_wait_secs(i + 1),
+            })
+            .collect();
+        QueueSnapshot { queue }
     }
     
     /// Get active download count
     pub fn active_count(&self) -> usize {
-        self.inner.active.read().len()
+        self.inner.active_count.load(Ordering::SeqCst)
+    }
+    
+    /// Global slots held by running downloads, including those to be
+    /// retired after the limit was lowered
+    pub fn slots_in_use(&self) -> usize {
+        let slots = self.inner.max_concurrent.load(Ordering::SeqCst) + self.retiring.load(Ordering::SeqCst);
+        slots.saturating_sub(self.semaphore.available_permits())
+    }
+    
+    /// Count the bytes a completed download saved
+    pub fn record_completed_bytes(&self, bytes: u64) {
+        self.inner.total_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+    
+    /// Record how long a successful download took
+    pub fn record_duration(&self, duration: std::time::Duration) {
+        // Exponential moving average so recent downloads weigh more
+        const ALPHA: f64 = 0.3;
+        
+        let secs = duration.as_secs_f64();
+        let mut avg = self.inner.avg_duration_secs.write();
+        *avg = Some(match *avg {
+            Some(prev) => prev + ALPHA * (secs - prev),
+            None => secs,
+        });
+    }
+    
+    /// Estimate seconds until the download at `position` in the queue starts.
+    /// Returns `None` until at least one download has completed.
+    pub fn estimated_wait_secs(&self, position: usize) -> Option<u64> {
+        let avg = (*self.inner.avg_duration_secs.read())?;
+        let slots = self.inner.max_concurrent.load(Ordering::SeqCst).max(1);
+        
+        // Every `slots` completions move the queue forward by `slots` places
+        let rounds = position.div_ceil(slots);
+        Some((rounds as f64 * avg).round() as u64)
     }
     
     /// Update max concurrent downloads
     pub fn set_max_concurrent(&self, max: usize) {
-        let mut current_max = self.inner.max_concurrent.write();
-        let old_max = *current_max;
-        *current_max = max;
+        let old_max = self.inner.max_concurrent.swap(max, Ordering::SeqCst);
         
-        // If increasing, add permits
         if max > old_max {
-            self.semaphore.add_permits(max - old_max);
+            // Slots still waiting to be retired are simply kept instead
+            let mut added = max - old_max;
+            let retiring = self
+                .retiring
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| Some(n.saturating_sub(added)))
+                .unwrap_or_default();
+            added -= retiring.min(added);
+            self.semaphore.add_permits(added);
+        } else if max < old_max {
+            // Free slots go now; the rest are retired as running downloads
+            // finish, so the active count drains down to the new limit
+            let excess = old_max - max;
+            let pending = excess - self.semaphore.forget_permits(excess);
+            self.retiring.fetch_add(pending, Ordering::SeqCst);
         }
-        // Note: Decreasing is handled naturally as permits are released
     }
     
-    /// Get current statistics
+    /// Update the per-host connection limit (0 = no limit)
+    pub fn set_max_connections_per_host(&self, max: usize) {
+        *self.inner.max_per_host.write() = max;
+    }
+    
+    /// Pause or unpause the queue. While paused, nothing starts and new
+    /// downloads wait in the queue; running downloads aren't touched.
+    pub fn set_queue_paused(&self, paused: bool) {
+        self.inner.queue_paused.store(paused, Ordering::SeqCst);
+    }
+    
+    pub fn is_queue_paused(&self) -> bool {
+        self.inner.queue_paused.load(Ordering::SeqCst)
+    }
+    
+    /// Stop or let the queue advance at the user's request. Like
+    /// `set_queue_paused` it leaves running downloads alone, but the two are
+    /// kept apart so the connection becoming unmetered doesn't undo it.
+    pub fn set_paused(&self, paused: bool) {
+        self.inner.paused.store(paused, Ordering::SeqCst);
+    }
+    
+    pub fn is_paused(&self) -> bool {
+        self.inner.paused.load(Ordering::SeqCst)
+    }
+    
+    /// Turn the alternative speed limit on or off. While it's on, running
+    /// downloads together go no faster than `alt_speed_limit`.
+    pub fn set_alt_speed(&self, enabled: bool) {
+        self.inner.alt_speed.store(enabled, Ordering::SeqCst);
+        self.apply_alt_speed();
+    }
+    
+    pub fn is_alt_speed(&self) -> bool {
+        self.inner.alt_speed.load(Ordering::SeqCst)
+    }
+    
+    /// Change the alternative speed limit, in bytes per second. Takes
+    /// effect at once if it's on.
+    pub fn set_alt_speed_limit(&self, limit: u64) {
+        self.inner.alt_speed_limit.store(limit, Ordering::SeqCst);
+        self.apply_alt_speed();
+    }
+    
+    fn apply_alt_speed(&self) {
+        let limit = if self.is_alt_speed() {
+            self.inner.alt_speed_limit.load(Ordering::SeqCst)
+        } else {
+            0
+        };
+        self.inner.shared_limit.store(limit, Ordering::Relaxed);
+    }
+    
+    /// Update the queue limit. Downloads already queued stay queued.
+    pub fn set_max_queue_size(&self, max: usize) {
+        *self.inner.max_queue.write() = max;
+    }
+    
+    /// Get current statistics. Only reads counters, so it's cheap to poll.
     pub fn stats(&self) -> DownloadStats {
         DownloadStats {
             active: self.active_count(),
             queued: self.queue_len(),
-            max_concurrent: *self.inner.max_concurrent.read(),
+            max_concurrent: self.inner.max_concurrent.load(Ordering::SeqCst),
+            queue_paused: self.is_queue_paused() || self.is_paused(),
+            paused: self.is_paused(),
+            total_bytes: self.inner.total_bytes.load(Ordering::Relaxed),
         }
     }
 }
@@ -156,34 +876,401 @@ pub struct DownloadStats {
     pub active: usize,
     pub queued: usize,
     pub max_concurrent: usize,
+    /// Nothing starts, whether the user paused the queue or the connection
+    /// is metered
+    pub queue_paused: bool,
+    /// The user paused the queue
+    pub paused: bool,
+    /// Bytes saved by downloads completed since startup
+    pub total_bytes: u64,
 }
 
-/// Extract filename from URL
-pub fn extract_filename(url: &str, content_disposition: Option<&str>) -> String {
-    // Try Content-Disposition header first
-    if let Some(cd) = content_disposition {
-        if let Some(start) = cd.find("filename=") {
-            let start = start + 9;
-            let filename = &cd[start..];
-            let filename = filename.trim_matches('"').trim_matches('\'');
-            if !filename.is_empty() {
-                return filename.to_string();
-            }
+/// Key used to group downloads by host: the lowercased domain without a
+/// trailing dot, or the IP address in canonical form for IP-literal URLs
+fn host_key(url: &str) -> Option<String> {
+    match url::Url::parse(url).ok()?.host()? {
+        url::Host::Domain(domain) => Some(domain.trim_end_matches('.').to_ascii_lowercase()),
+        url::Host::Ipv4(ip) => Some(ip.to_string()),
+        url::Host::Ipv6(ip) => Some(ip.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseSettings;
+    use crate::download::extract_filename;
+    
+    fn manager(dir: &std::path::Path) -> DownloadManager {
+        let db = Database::open(&dir.join("test.db"), &DatabaseSettings::default()).unwrap();
+        let client = crate::download::build_http_client(&crate::config::Settings::default()).unwrap();
+        DownloadManager::new(3, client, db)
+    }
+    
+    fn record(url: &str) -> DownloadRecord {
+        DownloadRecord::new(url.into(), extract_filename(url, None), "general".into(), "/tmp".into())
+    }
+    
+    #[test]
+    fn per_host_limit_holds_back_same_host_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        manager.set_max_connections_per_host(1);
+        
+        let first = record("https://files.example.com/a.bin");
+        let second = record("https://FILES.example.com./b.bin");
+        let other = record("https://mirror.example.org/c.bin");
+        
+        let running = manager.try_start(&first.url).unwrap();
+        
+        // A global slot is free, but the host is at its limit
+        assert!(manager.try_start(&second.url).is_none());
+        assert!(manager.try_start(&other.url).is_some());
+        
+        manager.enqueue(second.clone()).unwrap();
+        manager.enqueue(other.clone()).unwrap();
+        assert_eq!(manager.dequeue().unwrap().0.id, other.id);
+        assert!(manager.dequeue().is_none());
+        
+        drop(running);
+        assert_eq!(manager.dequeue().unwrap().0.id, second.id);
+    }
+    
+    #[test]
+    fn per_host_limit_holds_for_downloads_starting_together() {
+        const THREADS: usize = 16;
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        manager.set_max_concurrent(THREADS);
+        manager.set_max_connections_per_host(2);
+        
+        let barrier = std::sync::Barrier::new(THREADS);
+        let slots: Vec<_> = std::thread::scope(|scope| {
+            let starts: Vec<_> = (0..THREADS)
+                .map(|i| {
+                    let (manager, barrier) = (&manager, &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        manager.try_start(&format!("https://files.example.com/{}", i))
+                    })
+                })
+                .collect();
+            starts.into_iter().filter_map(|start| start.join().unwrap()).collect()
+        });
+        assert_eq!(slots.len(), 2);
+        
+        // Each finished one hands its place on
+        drop(slots);
+        assert!(manager.try_start("https://files.example.com/next").is_some());
+    }
+    
+    #[test]
+    fn paused_queue_starts_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let waiting = record("https://a.example.com/1");
+        let paused = record("https://b.example.com/2");
+        
+        manager.set_queue_paused(true);
+        assert!(manager.try_start(&waiting.url).is_none());
+        manager.enqueue(waiting.clone()).unwrap();
+        manager.requeue_paused(paused.clone());
+        assert!(manager.dequeue().is_none());
+        assert!(manager.stats().queue_paused);
+        
+        // Downloads paused mid-transfer pick up first
+        manager.set_queue_paused(false);
+        assert_eq!(manager.dequeue().unwrap().0.id, paused.id);
+        assert_eq!(manager.dequeue().unwrap().0.id, waiting.id);
+    }
+    
+    #[test]
+    fn queue_order_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let [a, b, c, d, e] = ["a", "b", "c", "d", "e"].map(|name| record(&format!("https://{}.example.com/1", name)));
+        for download in [&a, &b, &c, &d, &e] {
+            manager.inner.db.insert_download(download).unwrap();
         }
+        
+        manager.enqueue(a.clone()).unwrap();
+        manager.enqueue(b.clone()).unwrap();
+        manager.enqueue(c.clone()).unwrap();
+        manager.requeue_paused(d.clone());
+        assert_eq!(manager.dequeue().unwrap().0.id, d.id);
+        drop(manager);
+        
+        // Added after the restart, so it goes after what was saved
+        let manager = self::manager(dir.path());
+        manager.enqueue(e.clone()).unwrap();
+        
+        let order: Vec<String> = manager.inner.db.get_pending_downloads(false).unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(order, [a.id, b.id, c.id, e.id, d.id]);
     }
     
-    // Fall back to URL path
-    if let Ok(parsed) = url::Url::parse(url) {
-        if let Some(segments) = parsed.path_segments() {
-            if let Some(last) = segments.last() {
-                let decoded = urlencoding::decode(last).unwrap_or_else(|_| last.into());
-                if !decoded.is_empty() && decoded != "/" {
-                    return decoded.to_string();
-                }
-            }
+    #[test]
+    fn staged_progress_keeps_the_latest_until_taken() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        
+        manager.stage_progress("a", 100, None);
+        manager.stage_progress("a", 250, Some(1000));
+        manager.stage_progress("b", 10, Some(20));
+        
+        let staged = manager.take_staged_progress();
+        assert_eq!(staged.len(), 2);
+        assert_eq!(staged["a"], (250, Some(1000)));
+        assert!(manager.take_staged_progress().is_empty());
+    }
+    
+    #[test]
+    fn user_pause_outlasts_the_metered_pause() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let waiting = record("https://a.example.com/1");
+        
+        manager.set_paused(true);
+        manager.set_queue_paused(true);
+        manager.enqueue(waiting.clone()).unwrap();
+        manager.set_queue_paused(false);
+        assert!(manager.dequeue().is_none());
+        assert!(manager.stats().queue_paused);
+        assert!(manager.stats().paused);
+        
+        manager.set_paused(false);
+        assert_eq!(manager.dequeue().unwrap().0.id, waiting.id);
+    }
+    
+    #[tokio::test]
+    async fn updates_of_unknown_size_downloads_are_indeterminate() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let mut rx = manager.subscribe();
+        let unsized_download = record("https://a.example.com/1");
+        let mut sized = record("https://b.example.com/2");
+        sized.total_size = Some(100);
+        for download in [&unsized_download, &sized] {
+            manager.inner.db.insert_download(download).unwrap();
+        }
+        
+        for download in [&unsized_download, &sized] {
+            manager.announce(download);
+            manager.enqueue(download.clone()).unwrap();
+            assert!(manager.pause(&download.id).await);
+        }
+        
+        let mut updates = Vec::new();
+        while let Ok(update) = rx.try_recv() {
+            updates.push((update.id == unsized_download.id, update.status, update.indeterminate));
         }
+        let expected: Vec<_> = [true, false]
+            .into_iter()
+            .flat_map(|unknown| {
+                [DownloadStatus::Pending, DownloadStatus::Queued, DownloadStatus::Paused].map(|status| (unknown, status, unknown))
+            })
+            .collect();
+        assert_eq!(updates, expected);
     }
     
-    // Last resort: generate a name
-    format!("download_{}", chrono::Utc::now().timestamp())
+    #[tokio::test]
+    async fn downloads_are_found_and_paused_by_file_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let running = record("https://a.example.com/1");
+        let mut waiting = record("https://b.example.com/2");
+        let mut other = record("https://c.example.com/3");
+        waiting.file_type = running.file_type.clone();
+        other.file_type = "video".into();
+        for download in [&running, &waiting, &other] {
+            manager.inner.db.insert_download(download).unwrap();
+        }
+        
+        let (cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        manager.add_active(&running, cancel_tx);
+        manager.enqueue(waiting.clone()).unwrap();
+        manager.enqueue(other.clone()).unwrap();
+        
+        let (active, queued) = manager.ids_of_file_type(&running.file_type);
+        assert_eq!((active, queued), (vec![running.id.clone()], vec![waiting.id.clone()]));
+        
+        assert!(manager.pause(&running.id).await);
+        assert_eq!(cancel_rx.recv().await, Some(CancelReason::Paused));
+        assert!(manager.pause(&waiting.id).await);
+        assert_eq!(manager.queued().len(), 1);
+        let paused = manager.inner.db.get_download(&waiting.id).unwrap().unwrap();
+        assert_eq!(paused.status, DownloadStatus::Paused);
+        assert!(!manager.pause("missing").await);
+    }
+    
+    #[test]
+    fn enqueue_rejects_beyond_queue_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        manager.set_max_queue_size(2);
+        
+        assert_eq!(manager.enqueue(record("https://a.example.com/1")).unwrap(), 1);
+        assert_eq!(manager.enqueue(record("https://a.example.com/2")).unwrap(), 2);
+        assert!(manager.enqueue(record("https://a.example.com/3")).is_err());
+        // Already-accepted downloads restored at startup aren't turned away
+        assert_eq!(manager.requeue(record("https://a.example.com/4")), 3);
+        
+        manager.dequeue();
+        manager.dequeue();
+        assert!(manager.enqueue(record("https://a.example.com/5")).is_ok());
+    }
+    
+    #[test]
+    fn lowering_max_concurrent_drains_running_slots() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let url = "https://a.example.com/file";
+        
+        let mut running: Vec<_> = (0..3).map(|_| manager.try_start(url).unwrap()).collect();
+        manager.set_max_concurrent(1);
+        
+        // The first two to finish are retired rather than handed on
+        running.pop();
+        assert!(manager.try_start(url).is_none());
+        running.pop();
+        assert!(manager.try_start(url).is_none());
+        running.pop();
+        let slot = manager.try_start(url).unwrap();
+        assert!(manager.try_start(url).is_none());
+        
+        // Raising it again while nothing is pending just adds slots
+        manager.set_max_concurrent(2);
+        assert!(manager.try_start(url).is_some());
+        drop(slot);
+    }
+    
+    #[test]
+    fn raising_max_concurrent_cancels_pending_retirements() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let url = "https://a.example.com/file";
+        
+        let mut running: Vec<_> = (0..3).map(|_| manager.try_start(url).unwrap()).collect();
+        manager.set_max_concurrent(1);
+        manager.set_max_concurrent(2);
+        
+        // Only one slot is retired, so one finishing download still frees
+        // nothing but the next does
+        running.pop();
+        assert!(manager.try_start(url).is_none());
+        running.pop();
+        assert!(manager.try_start(url).is_some());
+    }
+    
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn counters_match_contents_under_concurrent_changes() {
+        const TASKS: usize = 8;
+        const ROUNDS: usize = 50;
+        
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|task| {
+                let manager = manager.clone();
+                tokio::spawn(async move {
+                    for round in 0..ROUNDS {
+                        let running = record(&format!("https://{}.example.com/{}", task, round));
+                        let (cancel_tx, _cancel_rx) = mpsc::channel(1);
+                        manager.add_active(&running, cancel_tx);
+                        
+                        let kept = record(&format!("https://{}.example.com/kept-{}", task, round));
+                        let cancelled = record(&format!("https://{}.example.com/cancelled-{}", task, round));
+                        manager.enqueue(kept).unwrap();
+                        manager.enqueue(cancelled.clone()).unwrap();
+                        manager.cancel(&cancelled.id, CancelReason::UserRequested).await;
+                        
+                        let stats = manager.stats();
+                        assert!(stats.active <= TASKS, "{:?}", stats);
+                        assert!(stats.queued <= 2 * TASKS * ROUNDS, "{:?}", stats);
+                        
+                        drop(manager.dequeue());
+                        manager.remove_active(&running.id);
+                        manager.record_completed_bytes(10);
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        
+        let stats = manager.stats();
+        assert_eq!(stats.active, 0);
+        assert_eq!(stats.active, manager.inner.active.read().len());
+        assert_eq!(stats.queued, manager.queued().len());
+        assert_eq!(stats.total_bytes, (TASKS * ROUNDS * 10) as u64);
+    }
+    
+    #[test]
+    fn queue_subscribers_get_a_snapshot_per_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let mut snapshots = manager.subscribe_queue();
+        manager.record_duration(std::time::Duration::from_secs(10));
+        manager.set_max_concurrent(1);
+        
+        // Held back by the running download, so both wait
+        let running = manager.try_start("https://a.example.com/0").unwrap();
+        let (first, second) = (record("https://a.example.com/1"), record("https://a.example.com/2"));
+        manager.enqueue(first.clone()).unwrap();
+        manager.enqueue(second.clone()).unwrap();
+        assert_eq!(snapshots.try_recv().unwrap().queue.len(), 1);
+        let both = snapshots.try_recv().unwrap();
+        assert_eq!(
+            both.queue,
+            vec![
+                QueuedPosition { id: first.id.clone(), position: 1, eta_secs: Some(10) },
+                QueuedPosition { id: second.id.clone(), position: 2, eta_secs: Some(20) },
+            ]
+        );
+        assert_eq!(manager.queue_snapshot(), both);
+        
+        // Not a change in order
+        manager.set_speed_limit(&first.id, Some(1000));
+        assert!(snapshots.try_recv().is_err());
+        
+        drop(running);
+        assert_eq!(manager.dequeue().unwrap().0.id, first.id);
+        let after = snapshots.try_recv().unwrap();
+        assert_eq!(after.queue, vec![QueuedPosition { id: second.id, position: 1, eta_secs: Some(10) }]);
+    }
+    
+    #[test]
+    fn only_status_transitions_count_as_changes() {
+        let update = |status, started_at| ProgressUpdate {
+            id: "a".into(),
+            downloaded: 0,
+            total: None,
+            indeterminate: true,
+            speed: 0,
+            status,
+            error: None,
+            started_at,
+        };
+        
+        assert!(DownloadChange::from_progress(&update(DownloadStatus::Downloading, None)).is_none());
+        // Announced as `Added` instead
+        assert!(DownloadChange::from_progress(&update(DownloadStatus::Pending, None)).is_none());
+        for started in [
+            update(DownloadStatus::Downloading, Some(chrono::Utc::now())),
+            update(DownloadStatus::Queued, None),
+            update(DownloadStatus::Completed, None),
+        ] {
+            let change = DownloadChange::from_progress(&started).unwrap();
+            assert_eq!(change.change, ChangeKind::StatusChanged);
+            assert_eq!(change.id.as_deref(), Some("a"));
+        }
+    }
+    
+    #[test]
+    fn host_key_normalizes_ip_literals() {
+        assert_eq!(host_key("http://[0:0::1]:8080/a"), host_key("http://[::1]/b"));
+        assert_eq!(host_key("http://127.0.0.1:8000/a").as_deref(), Some("127.0.0.1"));
+        assert_ne!(host_key("http://127.0.0.1/a"), host_key("http://127.0.0.2/a"));
+    }
 }
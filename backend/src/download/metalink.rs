@@ -0,0 +1,237 @@
+//! Metalink (RFC 5854 `.meta4` and the older v3 `.metalink`) parsing
+
+use super::{sanitize_filename, Checksum, HashAlgorithm};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+/// What a Metalink says about the single file it describes
+#[derive(Debug, Clone)]
+pub struct Metalink {
+    pub filename: String,
+    pub size: Option<u64>,
+    /// HTTP(S) sources, most preferred first
+    pub urls: Vec<String>,
+    /// Strongest digest we know how to verify, if any is listed
+    pub checksum: Option<Checksum>,
+}
+
+/// Whether a URL points at a Metalink document, judged by its extension
+pub fn is_metalink_url(url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|url| {
+        let path = url.path().to_ascii_lowercase();
+        path.ends_with(".meta4") || path.ends_with(".metalink")
+    })
+}
+
+#[derive(Deserialize)]
+struct MetalinkXml {
+    /// v4 lists files directly under the root
+    #[serde(default)]
+    file: Vec<FileXml>,
+    /// v3 wraps them in `<files>`
+    files: Option<FilesXml>,
+}
+
+#[derive(Deserialize)]
+struct FilesXml {
+    #[serde(default)]
+    file: Vec<FileXml>,
+}
+
+#[derive(Deserialize)]
+struct FileXml {
+    #[serde(rename = "@name")]
+    name: Option<String>,
+    size: Option<u64>,
+    #[serde(default)]
+    hash: Vec<HashXml>,
+    #[serde(default)]
+    url: Vec<UrlXml>,
+    /// v3 keeps hashes in `<verification>`
+    verification: Option<VerificationXml>,
+    /// v3 keeps urls in `<resources>`
+    resources: Option<ResourcesXml>,
+}
+
+#[derive(Deserialize)]
+struct VerificationXml {
+    #[serde(default)]
+    hash: Vec<HashXml>,
+}
+
+#[derive(Deserialize)]
+struct ResourcesXml {
+    #[serde(default)]
+    url: Vec<UrlXml>,
+}
+
+#[derive(Deserialize)]
+struct HashXml {
+    #[serde(rename = "@type")]
+    kind: String,
+    #[serde(rename = "$text")]
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct UrlXml {
+    /// v4: 1 is the most preferred
+    #[serde(rename = "@priority")]
+    priority: Option<u32>,
+    /// v3: 100 is the most preferred
+    #[serde(rename = "@preference")]
+    preference: Option<u32>,
+    #[serde(rename = "$text")]
+    url: String,
+}
+
+impl UrlXml {
+    /// Sort key where lower is more preferred; unranked urls go last
+    fn rank(&self) -> u32 {
+        match (self.priority, self.preference) {
+            (Some(priority), _) => priority,
+            (None, Some(preference)) => 101 - preference.min(100),
+            (None, None) => u32::MAX,
+        }
+    }
+}
+
+/// Parse a Metalink document describing a single file
+pub fn parse_metalink(xml: &str) -> Result<Metalink> {
+    let doc: MetalinkXml = quick_xml::de::from_str(xml).context("Invalid Metalink XML")?;
+    
+    let mut files = doc.file;
+    if let Some(v3) = doc.files {
+        files.extend(v3.file);
+    }
+    let file = match files.len() {
+        0 => bail!("Metalink lists no files"),
+        1 => files.remove(0),
+        n => bail!("Metalink lists {} files; only single-file Metalinks are supported", n),
+    };
+    
+    // Names may carry a relative directory; only the last part is used
+    let name = file.name.ok_or_else(|| anyhow!("Metalink file has no name"))?;
+    let filename = name
+        .rsplit('/')
+        .next()
+        .and_then(sanitize_filename)
+        .ok_or_else(|| anyhow!("Metalink file name {:?} is not usable", name))?;
+    
+    let mut urls = file.url;
+    urls.extend(file.resources.map(|r| r.url).unwrap_or_default());
+    // Stable, so equally ranked mirrors keep document order
+    urls.sort_by_key(UrlXml::rank);
+    let urls: Vec<String> = urls
+        .into_iter()
+        .map(|u| u.url.trim().to_string())
+        .filter(|u| u.starts_with("http://") || u.starts_with("https://"))
+        .collect();
+    if urls.is_empty() {
+        bail!("Metalink lists no HTTP or HTTPS sources for {}", filename);
+    }
+    
+    let mut hashes = file.hash;
+    hashes.extend(file.verification.map(|v| v.hash).unwrap_or_default());
+    let mut checksums: Vec<Checksum> = hashes
+        .iter()
+        .filter_map(|h| {
+            let algorithm = HashAlgorithm::from_str(h.kind.trim())?;
+            Checksum::parse(&format!("{}:{}", algorithm.as_str(), h.value.trim()))
+        })
+        .collect();
+    checksums.sort_by_key(|c| c.algorithm != HashAlgorithm::Sha512);
+    
+    Ok(Metalink {
+        filename,
+        size: file.size,
+        urls,
+        checksum: checksums.into_iter().next(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const SHA256: &str = "8b1a9953c4611296a827abf8c47804d7e6c49c6b8d1f0e1b5f1a8e29b52f3c1b";
+    
+    #[test]
+    fn parses_v4_with_priorities_and_hash() {
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <metalink xmlns="urn:ietf:params:xml:ns:metalink">
+              <generator>test</generator>
+              <file name="isos/distro.iso">
+                <size>1024</size>
+                <hash type="sha-1">da39a3ee5e6b4b0d3255bfef95601890afd80709</hash>
+                <hash type="sha-256">{}</hash>
+                <url location="de" priority="2">https://b.example.org/distro.iso</url>
+                <metaurl mediatype="torrent">https://a.example.org/distro.torrent</metaurl>
+                <url location="us" priority="1">https://a.example.org/distro.iso</url>
+                <url priority="3">ftp://c.example.org/distro.iso</url>
+              </file>
+            </metalink>"#,
+            SHA256
+        );
+        
+        let metalink = parse_metalink(&xml).unwrap();
+        assert_eq!(metalink.filename, "distro.iso");
+        assert_eq!(metalink.size, Some(1024));
+        assert_eq!(
+            metalink.urls,
+            ["https://a.example.org/distro.iso", "https://b.example.org/distro.iso"]
+        );
+        assert_eq!(metalink.checksum.unwrap().to_string(), format!("sha256:{}", SHA256));
+    }
+    
+    #[test]
+    fn parses_v3_resources_by_preference() {
+        let xml = format!(
+            r#"<metalink version="3.0" xmlns="http://www.metalinker.org/">
+              <files>
+                <file name="tool.tar.gz">
+                  <verification><hash type="sha256">{}</hash></verification>
+                  <resources>
+                    <url type="http" preference="10">http://slow.example.org/tool.tar.gz</url>
+                    <url type="http" preference="100">http://fast.example.org/tool.tar.gz</url>
+                  </resources>
+                </file>
+              </files>
+            </metalink>"#,
+            SHA256
+        );
+        
+        let metalink = parse_metalink(&xml).unwrap();
+        assert_eq!(metalink.filename, "tool.tar.gz");
+        assert_eq!(metalink.size, None);
+        assert_eq!(
+            metalink.urls,
+            ["http://fast.example.org/tool.tar.gz", "http://slow.example.org/tool.tar.gz"]
+        );
+        assert!(metalink.checksum.is_some());
+    }
+    
+    #[test]
+    fn rejects_malformed_and_unusable_documents() {
+        assert!(parse_metalink("<metalink><file name=").is_err());
+        assert!(parse_metalink("<metalink></metalink>").is_err());
+        // No HTTP sources
+        assert!(parse_metalink(
+            r#"<metalink><file name="a.iso"><url>ftp://x/a.iso</url></file></metalink>"#
+        )
+        .is_err());
+        // Unusable file name
+        assert!(parse_metalink(
+            r#"<metalink><file name=".."><url>https://x/a.iso</url></file></metalink>"#
+        )
+        .is_err());
+    }
+    
+    #[test]
+    fn detects_metalink_urls() {
+        assert!(is_metalink_url("https://example.org/distro.iso.meta4"));
+        assert!(is_metalink_url("https://example.org/distro.METALINK?mirror=1"));
+        assert!(!is_metalink_url("https://example.org/distro.iso"));
+    }
+}
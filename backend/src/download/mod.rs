@@ -1,5 +1,31 @@
 //! Download manager module
 
+mod checksum;
+mod client;
+mod env;
+mod error;
+mod extract;
+mod filename;
+mod hooks;
 mod manager;
+mod metalink;
+mod retry;
+mod speed;
+mod throttle;
+mod transcode;
+mod webhook;
 
+pub use checksum::*;
+pub use client::*;
+pub use env::*;
+pub use error::*;
+pub use extract::*;
+pub use filename::*;
+pub use hooks::*;
 pub use manager::*;
+pub use metalink::*;
+pub use retry::*;
+pub use speed::*;
+pub use throttle::*;
+pub use transcode::*;
+pub use webhook::*;
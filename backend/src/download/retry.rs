@@ -0,0 +1,175 @@
+//! Deciding whether a failed download is worth another attempt
+
+use super::{Cancelled, ChecksumMismatch, HttpStatusError};
+use crate::config::Settings;
+use std::time::Duration;
+
+/// Longest wait between two attempts, however many have failed
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How a failure bears on retrying
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryClass {
+    /// The server couldn't be reached at all (DNS or connect failure);
+    /// worth waiting patiently for the network
+    Connect,
+    /// Reached the server but the attempt broke down in a way that may
+    /// not happen again: a timeout, a dropped connection, a 5xx
+    Transient,
+    /// Retrying would fail the same way: a 404, a bad checksum, a full disk
+    Fatal,
+}
+
+/// Whether a response status is worth asking again for
+fn status_is_transient(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Sort a download failure into a `RetryClass`, looking through any
+/// context added on the way up
+pub fn retry_class(err: &anyhow::Error) -> RetryClass {
+    for cause in err.chain() {
+        if cause.is::<Cancelled>() || cause.is::<ChecksumMismatch>() {
+            return RetryClass::Fatal;
+        }
+        if let Some(HttpStatusError(status)) = cause.downcast_ref() {
+            return if status_is_transient(*status) {
+                RetryClass::Transient
+            } else {
+                RetryClass::Fatal
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            // A connect timeout is both; it's still a connect failure
+            return if e.is_connect() {
+                RetryClass::Connect
+            } else if e.is_timeout() || e.is_request() || e.is_body() || (e.is_decode() && !is_corrupt_encoding(e)) {
+                RetryClass::Transient
+            } else if let Some(status) = e.status() {
+                if status_is_transient(status) {
+                    RetryClass::Transient
+                } else {
+                    RetryClass::Fatal
+                }
+            } else {
+                RetryClass::Fatal
+            };
+        }
+    }
+    RetryClass::Fatal
+}
+
+/// Whether a decode error came from bad compressed data, rather than the
+/// connection breaking off mid-body, which reqwest reports the same way
+fn is_corrupt_encoding(err: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                std::io::ErrorKind::InvalidData | std::io::ErrorKind::InvalidInput | std::io::ErrorKind::Other
+            );
+        }
+        source = cause.source();
+    }
+    false
+}
+
+/// How many times, and how long apart, failed downloads are retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub connect_retries: u32,
+    pub backoff: Duration,
+    pub connect_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_retries: settings.max_retries,
+            connect_retries: settings.connect_retries,
+            backoff: Duration::from_secs(settings.retry_backoff_secs),
+            connect_backoff: Duration::from_secs(settings.connect_retry_backoff_secs),
+        }
+    }
+}
+
+/// Counts the retries made so far for one download
+#[derive(Debug, Clone, Default)]
+pub struct RetryState {
+    retries: u32,
+    connect_retries: u32,
+}
+
+impl RetryState {
+    /// How long to wait before retrying after `err`, or `None` if it
+    /// shouldn't be retried. Each class of failure has its own budget.
+    pub fn next_delay(&mut self, policy: &RetryPolicy, err: &anyhow::Error) -> Option<Duration> {
+        let (count, max, base) = match retry_class(err) {
+            RetryClass::Connect => (&mut self.connect_retries, policy.connect_retries, policy.connect_backoff),
+            RetryClass::Transient => (&mut self.retries, policy.max_retries, policy.backoff),
+            RetryClass::Fatal => return None,
+        };
+        if *count >= max {
+            return None;
+        }
+        
+        let delay = base.saturating_mul(2u32.saturating_pow(*count)).min(MAX_BACKOFF);
+        *count += 1;
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            connect_retries: 3,
+            backoff: Duration::from_secs(1),
+            connect_backoff: Duration::from_secs(10),
+        }
+    }
+    
+    #[test]
+    fn statuses_are_classified() {
+        let class = |code: u16| {
+            retry_class(&HttpStatusError(reqwest::StatusCode::from_u16(code).unwrap()).into())
+        };
+        assert_eq!(class(503), RetryClass::Transient);
+        assert_eq!(class(429), RetryClass::Transient);
+        assert_eq!(class(408), RetryClass::Transient);
+        assert_eq!(class(404), RetryClass::Fatal);
+        assert_eq!(class(403), RetryClass::Fatal);
+    }
+    
+    #[test]
+    fn cancellation_and_bad_checksums_are_fatal() {
+        let cancelled = anyhow::Error::from(Cancelled(crate::db::CancelReason::UserRequested));
+        let mismatch = anyhow::Error::from(ChecksumMismatch {
+            expected: "a".into(),
+            actual: "b".into(),
+        });
+        assert_eq!(retry_class(&cancelled), RetryClass::Fatal);
+        assert_eq!(retry_class(&mismatch.context("verifying")), RetryClass::Fatal);
+        assert_eq!(retry_class(&anyhow::anyhow!("something else")), RetryClass::Fatal);
+    }
+    
+    #[test]
+    fn backoff_doubles_within_each_budget() {
+        let policy = policy();
+        let mut state = RetryState::default();
+        let transient = anyhow::Error::from(HttpStatusError(reqwest::StatusCode::BAD_GATEWAY));
+        let fatal = anyhow::Error::from(HttpStatusError(reqwest::StatusCode::NOT_FOUND));
+        
+        assert_eq!(state.next_delay(&policy, &fatal), None);
+        assert_eq!(state.next_delay(&policy, &transient), Some(Duration::from_secs(1)));
+        assert_eq!(state.next_delay(&policy, &transient), Some(Duration::from_secs(2)));
+        assert_eq!(state.next_delay(&policy, &transient), None);
+    }
+}
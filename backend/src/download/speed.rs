@@ -0,0 +1,144 @@
+//! Speed samples of recent downloads, for telling a slow server from a
+//! spiky connection
+
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// How often a download's speed is sampled
+pub const SPEED_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples kept per download; older ones make way for new ones
+const MAX_SAMPLES: usize = 600;
+
+/// Downloads whose samples are kept; the least recently sampled are forgotten
+const MAX_TRACKED: usize = 200;
+
+/// Fewer samples than this say too little to summarize
+const MIN_SAMPLES: usize = 5;
+
+/// Ring buffers of speed samples, in bytes per second, by download id
+#[derive(Default)]
+pub struct SpeedHistory {
+    /// Each download's samples and the tick it was last sampled at
+    samples: HashMap<String, (VecDeque<u64>, u64)>,
+    tick: u64,
+}
+
+impl SpeedHistory {
+    /// Add a sample for download `id`
+    pub fn record(&mut self, id: &str, speed: u64) {
+        if !self.samples.contains_key(id) && self.samples.len() >= MAX_TRACKED {
+            let stalest = self
+                .samples
+                .iter()
+                .min_by_key(|(_, (_, last))| *last)
+                .map(|(id, _)| id.clone());
+            if let Some(stalest) = stalest {
+                self.samples.remove(&stalest);
+            }
+        }
+        
+        let (samples, last) = self.samples.entry(id.to_string()).or_default();
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(speed);
+        self.tick += 1;
+        *last = self.tick;
+    }
+    
+    /// Summary of download `id`'s samples
+    pub fn stats(&self, id: &str) -> SpeedStats {
+        match self.samples.get(id) {
+            Some((samples, _)) => SpeedStats::from_samples(samples.iter().copied().collect()),
+            None => SpeedStats::from_samples(Vec::new()),
+        }
+    }
+    
+    /// Forget download `id`'s samples
+    pub fn remove(&mut self, id: &str) {
+        self.samples.remove(id);
+    }
+}
+
+/// Spread of a download's sampled speeds, in bytes per second. All `None`
+/// while there are too few samples.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SpeedStats {
+    pub samples: usize,
+    pub min: Option<u64>,
+    pub median: Option<u64>,
+    pub p95: Option<u64>,
+    pub max: Option<u64>,
+}
+
+impl SpeedStats {
+    pub fn from_samples(mut samples: Vec<u64>) -> Self {
+        let count = samples.len();
+        if count < MIN_SAMPLES {
+            return Self {
+                samples: count,
+                min: None,
+                median: None,
+                p95: None,
+                max: None,
+            };
+        }
+        
+        samples.sort_unstable();
+        Self {
+            samples: count,
+            min: samples.first().copied(),
+            median: Some(percentile(&samples, 50)),
+            p95: Some(percentile(&samples, 95)),
+            max: samples.last().copied(),
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted, non-empty `samples`
+fn percentile(samples: &[u64], p: usize) -> u64 {
+    let rank = (p * samples.len()).div_ceil(100).max(1);
+    samples[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn percentiles_need_enough_samples() {
+        assert_eq!(SpeedStats::from_samples(vec![100; 4]).median, None);
+        
+        let stats = SpeedStats::from_samples((1..=20).rev().map(|n| n * 100).collect());
+        assert_eq!(
+            stats,
+            SpeedStats {
+                samples: 20,
+                min: Some(100),
+                median: Some(1000),
+                p95: Some(1900),
+                max: Some(2000),
+            }
+        );
+    }
+    
+    #[test]
+    fn history_is_bounded() {
+        let mut history = SpeedHistory::default();
+        for n in 0..MAX_SAMPLES as u64 + 10 {
+            history.record("a", n);
+        }
+        let stats = history.stats("a");
+        assert_eq!(stats.samples, MAX_SAMPLES);
+        assert_eq!(stats.min, Some(10));
+        
+        for n in 0..MAX_TRACKED {
+            history.record(&n.to_string(), 1);
+        }
+        assert_eq!(history.samples.len(), MAX_TRACKED);
+        // The one sampled longest ago made way
+        assert_eq!(history.stats("a").samples, 0);
+    }
+}
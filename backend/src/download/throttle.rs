@@ -0,0 +1,166 @@
+//! Token-bucket pacing for download speed limits
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Longest single sleep, so a raised or removed limit takes effect quickly
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+/// Paces a stream of chunks to a bytes-per-second limit that can be changed
+/// at any time through the shared atomic (0 = unlimited)
+pub struct Throttle {
+    limit: Arc<AtomicU64>,
+    /// Bytes that may be received without waiting; negative when in debt
+    tokens: f64,
+    last_refill: Instant,
+    /// Limit on all downloads together, also waited for
+    shared: Option<SharedThrottle>,
+    /// Bytes received but not yet accounted for with `shared`
+    unshared: u64,
+}
+
+impl Throttle {
+    pub fn new(limit: Arc<AtomicU64>) -> Self {
+        Self {
+            limit,
+            tokens: 0.0,
+            last_refill: Instant::now(),
+            shared: None,
+            unshared: 0,
+        }
+    }
+    
+    /// Throttle for a download with `limit`
+    pub fn for_download(limit: &SpeedLimit) -> Self {
+        Self {
+            shared: limit.shared.clone(),
+            ..Self::new(Arc::clone(&limit.own))
+        }
+    }
+    
+    /// Account for a chunk that was just received
+    pub fn consume(&mut self, bytes: u64) {
+        self.tokens -= bytes as f64;
+        if self.shared.is_some() {
+            self.unshared += bytes;
+        }
+    }
+    
+    /// Wait until the bytes received so far are within the current limit,
+    /// then within the shared one
+    pub async fn wait(&mut self) {
+        self.wait_own().await;
+        if let Some(shared) = &self.shared {
+            shared.pass(std::mem::take(&mut self.unshared)).await;
+        }
+    }
+    
+    /// Wait until the bytes received so far are within this throttle's own limit
+    async fn wait_own(&mut self) {
+        loop {
+            let limit = self.limit.load(Ordering::Relaxed);
+            self.refill(limit);
+            if limit == 0 || self.tokens >= 0.0 {
+                return;
+            }
+            
+            let needed = Duration::from_secs_f64(-self.tokens / limit as f64);
+            tokio::time::sleep(needed.min(MAX_SLEEP)).await;
+        }
+    }
+    
+    /// Add the tokens earned since the last refill, allowing at most one
+    /// second's worth of burst
+    fn refill(&mut self, limit: u64) {
+        let now = Instant::now();
+        if limit == 0 {
+            self.tokens = 0.0;
+        } else {
+            let earned = now.duration_since(self.last_refill).as_secs_f64() * limit as f64;
+            self.tokens = (self.tokens + earned).min(limit as f64);
+        }
+        self.last_refill = now;
+    }
+}
+
+/// A `Throttle` every download passes its chunks through, capping their
+/// combined speed. Cheap to clone; clones share the bucket.
+#[derive(Clone)]
+pub struct SharedThrottle {
+    limit: Arc<AtomicU64>,
+    bucket: Arc<tokio::sync::Mutex<Throttle>>,
+}
+
+impl SharedThrottle {
+    pub fn new(limit: Arc<AtomicU64>) -> Self {
+        Self {
+            bucket: Arc::new(tokio::sync::Mutex::new(Throttle::new(Arc::clone(&limit)))),
+            limit,
+        }
+    }
+    
+    /// Account for `bytes` one download received, then wait until all
+    /// received so far are within the limit. Downloads take turns, so the
+    /// one that's been waiting longest goes first. While there's no limit
+    /// the bucket isn't touched, so downloads don't contend for it.
+    async fn pass(&self, bytes: u64) {
+        if self.limit.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut throttle = self.bucket.lock().await;
+        throttle.consume(bytes);
+        throttle.wait_own().await;
+    }
+}
+
+/// The speed limits a running download's chunk loop reads
+#[derive(Clone, Default)]
+pub struct SpeedLimit {
+    /// Its own limit in bytes per second (0 = unlimited); can be changed
+    /// while it runs
+    pub own: Arc<AtomicU64>,
+    /// Limit on all downloads together
+    pub shared: Option<SharedThrottle>,
+}
+
+impl SpeedLimit {
+    /// Just a download's own limit
+    pub fn new(own: u64) -> Self {
+        Self {
+            own: Arc::new(AtomicU64::new(own)),
+            shared: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn shared_limit_caps_downloads_together() {
+        let shared = SharedThrottle::new(Arc::new(AtomicU64::new(20_000)));
+        let limit = SpeedLimit {
+            shared: Some(shared),
+            ..SpeedLimit::new(0)
+        };
+        let (mut a, mut b) = (Throttle::for_download(&limit), Throttle::for_download(&limit));
+        
+        let started = Instant::now();
+        for _ in 0..5 {
+            a.consume(1000);
+            b.consume(1000);
+            tokio::join!(a.wait(), b.wait());
+        }
+        // 10000 bytes between them at 20000 bytes per second
+        assert!(started.elapsed() >= Duration::from_millis(400), "{:?}", started.elapsed());
+        
+        // Without a shared limit nothing is held back
+        let started = Instant::now();
+        let mut own = Throttle::for_download(&SpeedLimit::new(0));
+        own.consume(1_000_000);
+        own.wait().await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,70 @@
+//! Converting completed downloads with ffmpeg
+
+use std::path::Path;
+use tracing::info;
+
+/// Name of the converted file: `filename` with its extension replaced by
+/// `format`. `None` if it's already in that format.
+pub fn transcoded_filename(filename: &str, format: &str) -> Option<String> {
+    let (stem, ext) = match filename.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, Some(ext)),
+        _ => (filename, None),
+    };
+    if ext.is_some_and(|ext| ext.eq_ignore_ascii_case(format)) {
+        return None;
+    }
+    Some(format!("{}.{}", stem, format))
+}
+
+/// Convert `input` to `output` with the ffmpeg at `ffmpeg`, picking the
+/// format from `output`'s extension. ffmpeg writes to a temporary name
+/// beside `output` that is only renamed into place once it succeeded, so a
+/// failed conversion leaves nothing behind. Returns an error message if
+/// ffmpeg couldn't be started or failed.
+pub async fn transcode(ffmpeg: &Path, input: &Path, output: &Path) -> Result<(), String> {
+    let name = output.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    // Keeps the extension, which is what ffmpeg goes by
+    let temp = output.with_file_name(format!(".transcoding-{}-{}", uuid::Uuid::new_v4().simple(), name));
+    
+    info!("Transcoding {} to {}", input.display(), output.display());
+    
+    let result = tokio::process::Command::new(ffmpeg)
+        .args(["-hide_banner", "-nostdin", "-loglevel", "error", "-y", "-i"])
+        .arg(input)
+        .arg(&temp)
+        .output()
+        .await;
+    
+    let failure = match result {
+        Err(e) => Some(format!("Failed to run ffmpeg: {}", e)),
+        Ok(output) if !output.status.success() => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Some(format!("ffmpeg exited with {}: {}", output.status, stderr.trim()))
+        }
+        Ok(_) => None,
+    };
+    if let Some(message) = failure {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(message);
+    }
+    
+    if let Err(e) = tokio::fs::rename(&temp, output).await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(format!("Failed to save {}: {}", output.display(), e));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn extension_is_replaced_unless_it_already_matches() {
+        assert_eq!(transcoded_filename("song.flac", "mp3").as_deref(), Some("song.mp3"));
+        assert_eq!(transcoded_filename("my.song.wav", "mp3").as_deref(), Some("my.song.mp3"));
+        assert_eq!(transcoded_filename("song", "mp3").as_deref(), Some("song.mp3"));
+        assert_eq!(transcoded_filename(".hidden", "mp3").as_deref(), Some(".hidden.mp3"));
+        assert_eq!(transcoded_filename("song.MP3", "mp3"), None);
+    }
+}
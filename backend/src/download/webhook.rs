@@ -0,0 +1,110 @@
+//! Webhook notifications when downloads finish
+
+use crate::db::{DownloadRecord, DownloadStatus};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long the webhook gets to answer before the notification is given up
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// JSON body POSTed to the webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookEvent {
+    /// `download.completed`, `download.failed` or `test`
+    pub event: &'static str,
+    pub id: String,
+    pub filename: String,
+    pub url: String,
+    pub status: DownloadStatus,
+    /// Where the finished file is
+    pub path: Option<PathBuf>,
+    pub size: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl WebhookEvent {
+    /// Event for a download that just completed or failed
+    pub fn finished(record: &DownloadRecord, status: DownloadStatus, error: Option<String>) -> Self {
+        let completed = status == DownloadStatus::Completed;
+        Self {
+            event: if completed { "download.completed" } else { "download.failed" },
+            id: record.id.clone(),
+            filename: record.filename.clone(),
+            url: record.url.clone(),
+            status,
+            path: completed.then(|| record.destination.join(&record.filename)),
+            size: if completed { record.total_size } else { None },
+            error,
+        }
+    }
+    
+    /// Made-up event for checking the webhook is set up right
+    pub fn test() -> Self {
+        Self {
+            event: "test",
+            id: "00000000-0000-0000-0000-000000000000".to_string(),
+            filename: "example.zip".to_string(),
+            url: "https://example.com/example.zip".to_string(),
+            status: DownloadStatus::Completed,
+            path: None,
+            size: Some(1024),
+            error: None,
+        }
+    }
+}
+
+/// POST `event` to the webhook at `url`. Returns an error message if it
+/// couldn't be reached or didn't answer with a success status.
+pub async fn send_webhook(client: &reqwest::Client, url: &str, event: &WebhookEvent) -> Result<(), String> {
+    let body = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+    let response = client
+        .post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| format!("Webhook request failed: {}", e))?;
+    
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("Webhook answered {}", response.status()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use tokio::sync::mpsc;
+    
+    #[tokio::test]
+    async fn posts_the_event_as_json() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(move |Json(body): Json<serde_json::Value>| async move {
+                    tx.send(body).await.unwrap();
+                    StatusCode::NO_CONTENT
+                }),
+            )
+            .route("/broken", post(|| async { StatusCode::INTERNAL_SERVER_ERROR }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+        
+        send_webhook(&client, &format!("{}/hook", base), &WebhookEvent::test()).await.unwrap();
+        let body = rx.recv().await.unwrap();
+        assert_eq!(body["event"], "test");
+        assert_eq!(body["status"], "completed");
+        
+        let err = send_webhook(&client, &format!("{}/broken", base), &WebhookEvent::test()).await.unwrap_err();
+        assert!(err.contains("500"), "{}", err);
+    }
+}
@@ -0,0 +1,273 @@
+//! Filesystem helpers
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// A destination that exists as a file, or lies below one, so no folder
+/// can be created there
+#[derive(Debug, Clone)]
+pub struct NotADirectory {
+    pub destination: PathBuf,
+    /// The file in the way; the destination itself unless it's further up
+    pub file: PathBuf,
+}
+
+impl std::fmt::Display for NotADirectory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "destination is not a directory: {}", self.destination.display())?;
+        if self.file != self.destination {
+            write!(f, " ({} is a file)", self.file.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NotADirectory {}
+
+/// Create `dir` and any missing parents, failing with `NotADirectory` when
+/// a file is in the way rather than with the OS's vaguer error.
+/// Blocking; run it off the async runtime.
+pub fn create_dir(dir: &Path) -> Result<()> {
+    // The nearest part of the path that exists must be a folder
+    let existing = dir.ancestors().find_map(|path| std::fs::metadata(path).ok().map(|meta| (path, meta)));
+    if let Some((path, meta)) = existing {
+        if !meta.is_dir() {
+            return Err(NotADirectory {
+                destination: dir.to_path_buf(),
+                file: path.to_path_buf(),
+            }
+            .into());
+        }
+    }
+    
+    std::fs::create_dir_all(dir).with_context(|| format!("Cannot create folder {}", dir.display()))
+}
+
+/// Make sure `dir` exists and new files can be written to it: create it if
+/// needed, then write and remove a probe file. Catches destinations on an
+/// unmounted drive or a read-only share before a download is accepted.
+/// Blocking; run it off the async runtime.
+pub fn ensure_writable_dir(dir: &Path) -> Result<()> {
+    create_dir(dir)?;
+    
+    let probe = dir.join(format!(".vibe-write-test-{}", uuid::Uuid::new_v4().simple()));
+    std::fs::write(&probe, b"")
+        .with_context(|| format!("Folder {} is not writable", dir.display()))?;
+    let _ = std::fs::remove_file(&probe);
+    
+    Ok(())
+}
+
+/// Move the file at `from` to `to`, replacing any file there. Across
+/// filesystems, where a rename can't work, it's copied under a temporary
+/// name beside `to`, renamed into place, and only then removed from `from`,
+/// so an interrupted move never leaves a truncated file under the new name.
+/// Blocking; run it off the async runtime.
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    match std::fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("Cannot move {} to {}", from.display(), to.display()));
+        }
+    }
+    
+    let name = to.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let temp = to.with_file_name(format!(".{}.moving-{}", name, uuid::Uuid::new_v4().simple()));
+    let copied = std::fs::copy(from, &temp).and_then(|_| std::fs::rename(&temp, to));
+    if let Err(e) = copied {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e).with_context(|| format!("Cannot copy {} to {}", from.display(), to.display()));
+    }
+    std::fs::remove_file(from)
+        .with_context(|| format!("Copied to {} but cannot remove {}", to.display(), from.display()))
+}
+
+/// Put the file at `from` at `to` as well, replacing any file there: as a
+/// hard link where possible, otherwise (e.g. across filesystems) as a copy.
+/// Returns whether it was linked. Goes through a temporary name beside `to`
+/// like `move_file`. Blocking; run it off the async runtime.
+pub fn link_or_copy(from: &Path, to: &Path) -> Result<bool> {
+    let name = to.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let temp = to.with_file_name(format!(".{}.linking-{}", name, uuid::Uuid::new_v4().simple()));
+    
+    let linked = std::fs::hard_link(from, &temp).is_ok();
+    let placed = if linked { Ok(0) } else { std::fs::copy(from, &temp) }.and_then(|_| std::fs::rename(&temp, to));
+    if let Err(e) = placed {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e).with_context(|| format!("Cannot copy {} to {}", from.display(), to.display()));
+    }
+    // Renaming onto a link to the same file does nothing, leaving the temp
+    let _ = std::fs::remove_file(&temp);
+    Ok(linked)
+}
+
+/// Replace the file at `to` with a hard link to the file at `from`, through
+/// a temporary name beside `to` like `move_file`. Returns false, leaving `to`
+/// alone, when they can't be linked, e.g. across filesystems. Blocking; run
+/// it off the async runtime.
+pub fn replace_with_link(from: &Path, to: &Path) -> Result<bool> {
+    let name = to.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let temp = to.with_file_name(format!(".{}.linking-{}", name, uuid::Uuid::new_v4().simple()));
+    
+    if std::fs::hard_link(from, &temp).is_err() {
+        return Ok(false);
+    }
+    if let Err(e) = std::fs::rename(&temp, to) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e).with_context(|| format!("Cannot replace {} with a link", to.display()));
+    }
+    // Renaming onto a link to the same file does nothing, leaving the temp
+    let _ = std::fs::remove_file(&temp);
+    Ok(true)
+}
+
+/// Total size of the files under `dir`, leaving out the folders in `skip`
+/// and anything that can't be read. Links aren't followed. A folder that
+/// doesn't exist is empty. Blocking; run it off the async runtime.
+pub fn dir_size(dir: &Path, skip: &[PathBuf]) -> u64 {
+    let mut size = 0;
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else { continue };
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else { continue };
+            if metadata.is_dir() {
+                let path = entry.path();
+                if !skip.contains(&path) {
+                    pending.push(path);
+                }
+            } else if metadata.is_file() {
+                size += metadata.len();
+            }
+        }
+    }
+    size
+}
+
+/// Bytes free for new files on the volume holding `dir`. When `dir` doesn't
+/// exist yet, the nearest folder above it that does is asked instead.
+/// Blocking; run it off the async runtime.
+pub fn available_space(dir: &Path) -> std::io::Result<u64> {
+    let existing = dir
+        .ancestors()
+        .find(|path| path.is_dir())
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+    volume_available_space(existing)
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn volume_available_space(dir: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is only read once filled in
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn volume_available_space(dir: &Path) -> std::io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use winapi::shared::ntdef::ULARGE_INTEGER;
+    
+    let path: Vec<u16> = dir.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+    let mut available: ULARGE_INTEGER = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and the totals we don't need may be null
+    let ok = unsafe {
+        winapi::um::fileapi::GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { *available.QuadPart() })
+}
+
+#[cfg(not(any(unix, windows)))]
+fn volume_available_space(_dir: &Path) -> std::io::Result<u64> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn creates_missing_folders_and_rejects_unusable_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("a").join("b");
+        
+        ensure_writable_dir(&nested).unwrap();
+        assert!(nested.is_dir());
+        // The probe file is cleaned up
+        assert_eq!(std::fs::read_dir(&nested).unwrap().count(), 0);
+        
+        // Neither a regular file nor a path below one can be a folder
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"x").unwrap();
+        let err = ensure_writable_dir(&file).unwrap_err();
+        assert_eq!(err.to_string(), format!("destination is not a directory: {}", file.display()));
+        let err = ensure_writable_dir(&file.join("sub")).unwrap_err();
+        let err = err.downcast_ref::<NotADirectory>().unwrap();
+        assert_eq!(err.file, file);
+    }
+    
+    #[test]
+    fn move_replaces_the_target_and_removes_the_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("a.bin");
+        let to = dir.path().join("b.bin");
+        std::fs::write(&from, b"new").unwrap();
+        std::fs::write(&to, b"old").unwrap();
+        
+        move_file(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(std::fs::read(&to).unwrap(), b"new");
+        
+        assert!(move_file(&from, &to).is_err());
+    }
+    
+    #[test]
+    fn link_or_copy_leaves_the_source_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("a.bin");
+        let to = dir.path().join("sub").join("a.bin");
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(&from, b"data").unwrap();
+        std::fs::write(&to, b"old").unwrap();
+        
+        assert!(link_or_copy(&from, &to).unwrap());
+        assert_eq!(std::fs::read(&from).unwrap(), b"data");
+        assert_eq!(std::fs::read(&to).unwrap(), b"data");
+        assert_eq!(std::fs::read_dir(dir.path().join("sub")).unwrap().count(), 1);
+    }
+    
+    #[test]
+    fn dir_size_counts_nested_files_outside_skipped_folders() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("2026").join("10");
+        let skipped = dir.path().join("Videos");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::create_dir(&skipped).unwrap();
+        std::fs::write(dir.path().join("a.bin"), b"abc").unwrap();
+        std::fs::write(nested.join("b.bin"), b"defgh").unwrap();
+        std::fs::write(skipped.join("c.bin"), b"ijklmnop").unwrap();
+        
+        assert_eq!(dir_size(dir.path(), &[skipped]), 8);
+        assert_eq!(dir_size(&dir.path().join("missing"), &[]), 0);
+        // Asked of the folder above one that doesn't exist yet
+        assert!(available_space(&dir.path().join("missing")).unwrap() > 0);
+    }
+}
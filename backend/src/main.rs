@@ -8,19 +8,22 @@
     windows_subsystem = "windows"
 )]
 
+mod cli;
 mod config;
 mod db;
 mod download;
+mod fs;
 mod server;
 mod tray;
 
 use anyhow::Result;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use std::sync::Arc;
-use tracing::{info, Level};
-use tracing_subscriber::FmtSubscriber;
+use std::time::{Duration, Instant};
+use tracing::info;
+use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
-use crate::config::Settings;
+use crate::config::{ServerSettings, Settings};
 use crate::db::Database;
 use crate::download::DownloadManager;
 
@@ -168,44 +171,191 @@ pub struct AppState {
     pub settings: RwLock<Settings>,
     pub db: Database,
     pub download_manager: DownloadManager,
+    /// Signals the web server to stop and rebind with the current settings
+    pub server_restart: tokio::sync::watch::Sender<()>,
+    /// Last API request or running download, for `idle_shutdown_minutes`
+    last_activity: Mutex<Instant>,
+    /// Port given with `--port`, used instead of `server.port`
+    pub port_override: Option<u16>,
+    /// Address the web server is listening on, once it's bound
+    pub bound_server: RwLock<Option<ServerSettings>>,
 }
 
 impl AppState {
-    pub fn new(settings: Settings, db: Database) -> Self {
-        let download_manager = DownloadManager::new(settings.max_concurrent_downloads);
-        Self {
+    pub fn new(settings: Settings, db: Database) -> Result<Self> {
+        let client = download::build_http_client(&settings)?;
+        let download_manager =
+            DownloadManager::new(settings.max_concurrent_downloads, client, db.clone());
+        download_manager.set_max_connections_per_host(settings.max_connections_per_host);
+        download_manager.set_max_queue_size(settings.max_queue_size);
+        download_manager.set_alt_speed_limit(settings.alt_speed_bytes_per_sec);
+        Ok(Self {
             settings: RwLock::new(settings),
             db,
             download_manager,
+            server_restart: tokio::sync::watch::channel(()).0,
+            last_activity: Mutex::new(Instant::now()),
+            port_override: None,
+            bound_server: RwLock::new(None),
+        })
+    }
+    
+    /// Restart the web server, e.g. after the port changed. Requests in
+    /// flight are allowed to finish first.
+    pub fn restart_server(&self) {
+        self.server_restart.send_replace(());
+    }
+    
+    /// Address the web server is listening on, or until it's bound, the one
+    /// it will use: `--port` wins over the configured port
+    pub fn served_at(&self) -> ServerSettings {
+        let server = self.bound_server.read().clone();
+        server.unwrap_or_else(|| {
+            let mut server = self.settings.read().server.clone();
+            server.port = self.port_override.unwrap_or(server.port);
+            server
+        })
+    }
+    
+    /// Note activity, resetting the idle shutdown timer
+    pub fn touch(&self) {
+        *self.last_activity.lock() = Instant::now();
+    }
+}
+
+/// How often the idle shutdown timer is checked
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Exit cleanly once nothing has happened for `idle_shutdown_minutes`.
+/// Running or queued downloads count as activity.
+async fn shutdown_when_idle(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    
+    loop {
+        interval.tick().await;
+        
+        let manager = &state.download_manager;
+        if manager.active_count() > 0 || manager.queue_len() > 0 {
+            state.touch();
+            continue;
+        }
+        
+        // Read each time so a changed setting applies without a restart
+        let minutes = state.settings.read().idle_shutdown_minutes;
+        if minutes == 0 {
+            continue;
+        }
+        
+        let idle = state.last_activity.lock().elapsed();
+        if idle >= Duration::from_secs(minutes.saturating_mul(60)) {
+            info!("Idle for {} minutes, shutting down", minutes);
+            if let Err(e) = state.db.checkpoint() {
+                tracing::warn!("Failed to checkpoint database: {}", e);
+            }
+            std::process::exit(0);
+        }
+    }
+}
+
+/// Build the log filter from `RUST_LOG` (passed in as `rust_log`), else the
+/// `log_level` setting, else `info`. A value that doesn't parse is skipped rather than stopping
+/// startup; the returned warnings say which, to log once logging is up.
+fn log_filter(rust_log: Option<String>, log_level: &str) -> (EnvFilter, Vec<String>) {
+    let mut warnings = Vec::new();
+    
+    if let Some(env) = rust_log {
+        if !env.trim().is_empty() {
+            match EnvFilter::try_new(&env) {
+                Ok(filter) => return (filter, warnings),
+                Err(e) => warnings.push(format!("Ignoring invalid RUST_LOG {:?}: {}", env, e)),
+            }
+        }
+    }
+    
+    // A lone word would parse as a target name, so a misspelt level
+    // would silence everything instead of failing
+    let is_directive = log_level.contains(['=', ',']);
+    let filter = if is_directive {
+        EnvFilter::try_new(log_level).map_err(|e| e.to_string())
+    } else {
+        log_level
+            .trim()
+            .parse::<tracing_subscriber::filter::LevelFilter>()
+            .map(|level| EnvFilter::default().add_directive(level.into()))
+            .map_err(|e| e.to_string())
+    };
+    
+    match filter {
+        Ok(filter) => (filter, warnings),
+        Err(e) => {
+            warnings.push(format!("Invalid log_level {:?} ({}), logging at info", log_level, e));
+            (EnvFilter::new("info"), warnings)
         }
     }
 }
 
 fn main() -> Result<()> {
-    // Check for required dependencies on Linux
-    check_linux_dependencies();
+    let cli = match cli::Cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if cli.help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+    
+    // Check for required dependencies on Linux (only the tray needs them)
+    if !cli.headless {
+        check_linux_dependencies();
+    }
+    
+    // Load configuration first, since it may set the log level
+    let settings = config::load_or_create_default()?;
     
     // Initialize logging
-    let _subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
+    let (filter, filter_warnings) = log_filter(std::env::var(EnvFilter::DEFAULT_ENV).ok(), &settings.log_level);
+    FmtSubscriber::builder()
+        .with_env_filter(filter)
         .with_target(false)
         .init();
+    for warning in filter_warnings {
+        tracing::warn!("{}", warning);
+    }
 
     info!("Starting Vibe Downloader v{}", env!("CARGO_PKG_VERSION"));
-
-    // Load configuration
-    let settings = config::load_or_create_default()?;
     info!("Configuration loaded from {:?}", config::config_path());
     
+    if settings.allow_command_hooks {
+        if let Some(command) = &settings.post_complete_command {
+            tracing::warn!("Command hooks enabled, running after each download: {}", command);
+        }
+    }
+    
+    if !settings.allow_invalid_certs_for_hosts.is_empty() {
+        tracing::warn!(
+            "TLS certificate validation is DISABLED for these hosts: {}",
+            settings.allow_invalid_certs_for_hosts.join(", ")
+        );
+    }
+    
     // Check linger status for start-on-boot (Linux only)
     check_linger_status(&settings);
 
     // Initialize database
-    let db = Database::new()?;
-    info!("Database initialized");
+    let db_path = cli.db_path();
+    let db = Database::new(db_path.as_deref(), &settings.database)?;
+    match &db_path {
+        Some(path) => info!("Database initialized at {}", path.display()),
+        None => info!("Database initialized"),
+    }
 
     // Create shared application state
-    let state = Arc::new(AppState::new(settings.clone(), db));
+    let mut state = AppState::new(settings.clone(), db)?;
+    state.port_override = cli.port;
+    let state = Arc::new(state);
     
     // Sync auto-launch setting with current executable path
     sync_auto_launch(&settings);
@@ -215,18 +365,45 @@ fn main() -> Result<()> {
     let server_handle = std::thread::spawn(move || {
         let rt = tokio::runtime::Runtime::new().expect("Failed to create Tokio runtime");
         rt.block_on(async {
+            tokio::spawn(shutdown_when_idle(Arc::clone(&server_state)));
             if let Err(e) = server::run(server_state).await {
                 tracing::error!("Server error: {}", e);
             }
         });
     });
 
-    // Run the system tray on the main thread (required by most platforms)
-    info!("Starting system tray...");
-    tray::run(Arc::clone(&state))?;
+    if cli.headless {
+        info!("Running headless, without a system tray");
+    } else {
+        // Run the system tray on the main thread (required by most platforms)
+        info!("Starting system tray...");
+        tray::run(Arc::clone(&state))?;
+    }
 
     // Wait for server thread to finish (it won't unless there's an error)
     let _ = server_handle.join();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn bad_log_levels_fall_back_to_info_with_a_warning() {
+        let (filter, warnings) = log_filter(None, "debgu");
+        assert_eq!(filter.to_string(), "info");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("debgu"));
+        
+        let (filter, warnings) = log_filter(Some("not==valid".into()), "warn");
+        assert_eq!(filter.to_string(), "warn");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("RUST_LOG"));
+        
+        let (filter, warnings) = log_filter(None, "debug");
+        assert_eq!(filter.to_string(), "debug");
+        assert!(warnings.is_empty());
+    }
+}
@@ -1,22 +1,38 @@
 //! Web server module
 
+mod network;
 mod routes;
 mod static_files;
 mod websocket;
 
 pub use routes::resume_incomplete_downloads;
 
+use crate::config::ServerSettings;
 use crate::AppState;
+use routes::AppError;
 use anyhow::Result;
+use axum::extract::{Request, State};
+use axum::http::{header, Method};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
 use axum::Router;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::net::{TcpListener, TcpSocket};
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate};
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::{Any, CorsLayer};
 use tracing::info;
 
-/// Run the web server
+/// Run the web server. It keeps serving until the process exits, rebinding
+/// whenever `AppState::restart_server` is called (e.g. after the port
+/// changed). Downloads run on their own tasks and carry on across restarts.
 pub async fn run(state: Arc<AppState>) -> Result<()> {
-    let settings = state.settings.read().clone();
-    let addr = format!("{}:{}", settings.server.host, settings.server.port);
+    // Look at the connection first, so nothing starts on a metered one
+    routes::check_metered(&state).await;
+    tokio::spawn(routes::watch_metered(state.clone()));
+    tokio::spawn(routes::save_progress_periodically(state.clone()));
+    tokio::spawn(routes::sweep_trash_periodically(state.clone()));
     
     // Resume any incomplete downloads from previous session
     resume_incomplete_downloads(state.clone());
@@ -26,17 +42,183 @@ pub async fn run(state: Arc<AppState>) -> Result<()> {
         .allow_methods(Any)
         .allow_headers(Any);
     
+    // Added after the compression layer so the WebSocket upgrade bypasses it
     let app = Router::new()
         .nest("/api", routes::api_routes())
-        .route("/ws", axum::routing::get(websocket::ws_handler))
         .fallback(static_files::static_handler)
+        .layer(compression())
+        .route("/ws", axum::routing::get(websocket::ws_handler))
+        .layer(middleware::from_fn_with_state(state.clone(), enforce_read_only))
+        .layer(middleware::from_fn_with_state(state.clone(), track_activity))
         .layer(cors)
-        .with_state(state);
+        .with_state(state.clone());
+    
+    let mut last_good: Option<ServerSettings> = None;
+    
+    loop {
+        // Subscribe before binding so a restart requested meanwhile isn't missed
+        let mut restart = state.server_restart.subscribe();
+        let mut server = state.settings.read().server.clone();
+        if let Some(port) = state.port_override {
+            server.port = port;
+        }
+        
+        let (listener, server) = match bind(&server).await {
+            Ok(listener) => (listener, server),
+            // Keep serving on the old address rather than going dark
+            Err(e) if last_good.is_some() => {
+                let previous = last_good.clone().unwrap();
+                tracing::error!("{:#}, staying on {}", e, server_addr(&previous));
+                (bind(&previous).await?, previous)
+            }
+            Err(e) => return Err(e),
+        };
+        
+        info!("Starting web server on http://{}", server_addr(&server));
+        if server.lan_access {
+            for url in network::lan_urls(server.bind_host(), server.port) {
+                info!("Reachable on the LAN at {}", url);
+            }
+        } else {
+            info!("LAN access disabled, reachable only at {}", server.local_url());
+        }
+        *state.bound_server.write() = Some(server.clone());
+        last_good = Some(server);
+        
+        axum::serve(listener, app.clone())
+            .with_graceful_shutdown(async move {
+                if restart.changed().await.is_err() {
+                    // The state is gone, so no restart will ever come
+                    std::future::pending::<()>().await;
+                }
+            })
+            .await?;
+        
+        info!("Web server stopped, rebinding");
+    }
+}
+
+/// Gzip or Brotli responses for clients that accept it. Content that is
+/// already compressed (images, archives, fonts) and ranges are sent as is.
+fn compression() -> CompressionLayer<impl Predicate> {
+    let predicate = DefaultPredicate::new()
+        .and(NotForContentType::const_new("application/zip"))
+        .and(NotForContentType::const_new("application/gzip"))
+        .and(NotForContentType::const_new("application/x-7z-compressed"))
+        .and(NotForContentType::const_new("application/x-xz"))
+        .and(NotForContentType::const_new("font/woff2"))
+        .and(NotForContentType::const_new("video/"))
+        .and(NotForContentType::const_new("audio/"));
+    CompressionLayer::new().gzip(true).br(true).compress_when(predicate)
+}
+
+/// Count every request as activity for the idle shutdown
+async fn track_activity(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    state.touch();
+    next.run(request).await
+}
+
+/// In read-only mode, refuse API requests that would change something
+/// unless they carry the write token. Reads, the web UI and the WebSocket
+/// are left alone.
+async fn enforce_read_only(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    let is_api = path == "/api" || path.starts_with("/api/");
+    let reads = matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    
+    if is_api && !reads {
+        let authorization = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        if !state.settings.read().server.allows_write(authorization) {
+            return AppError::Forbidden("The server is read-only".into()).into_response();
+        }
+    }
     
-    info!("Starting web server on http://{}", addr);
+    next.run(request).await
+}
+
+fn server_addr(server: &ServerSettings) -> SocketAddr {
+    SocketAddr::new(server.bind_host(), server.port)
+}
+
+/// Bind the listening socket with the configured backlog
+async fn bind(server: &ServerSettings) -> Result<TcpListener> {
+    let addr = server_addr(server);
+    
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    // Lets a restart rebind the port straight away (what `TcpListener::bind` does too)
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr).map_err(|e| bind_error(addr, e))?;
+    
+    Ok(socket.listen(server.listen_backlog)?)
+}
+
+/// Say what to change when the address can't be listened on, rather than
+/// leaving the OS error to speak for itself
+fn bind_error(addr: SocketAddr, e: std::io::Error) -> anyhow::Error {
+    let hint = match e.kind() {
+        std::io::ErrorKind::AddrNotAvailable => format!(
+            "{} is not an address of this machine; set server.host to one of its IP addresses, or 0.0.0.0 for all",
+            addr.ip()
+        ),
+        std::io::ErrorKind::AddrInUse => format!("port {} is already in use; pick another server.port", addr.port()),
+        std::io::ErrorKind::PermissionDenied => format!("not allowed to use port {}; pick one above 1023", addr.port()),
+        _ => return anyhow::Error::new(e).context(format!("Cannot listen on {}", addr)),
+    };
+    anyhow::Error::new(e).context(format!("Cannot listen on {}: {}", addr, hint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
     
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    #[tokio::test]
+    async fn binding_a_foreign_address_says_what_to_change() {
+        // TEST-NET-1, which no machine's interface has
+        let server = ServerSettings {
+            host: "192.0.2.1".into(),
+            port: 18787,
+            ..Default::default()
+        };
+        let err = bind(&server).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot listen on 192.0.2.1:18787: 192.0.2.1 is not an address of this machine; \
+             set server.host to one of its IP addresses, or 0.0.0.0 for all"
+        );
+    }
     
-    Ok(())
+    #[tokio::test]
+    async fn compresses_text_but_not_archives() {
+        let body = "x".repeat(4096);
+        let app = Router::new()
+            .route("/text", axum::routing::get({
+                let body = body.clone();
+                move || async move { ([(header::CONTENT_TYPE, "application/json")], body) }
+            }))
+            .route("/zip", axum::routing::get(move || async move {
+                ([(header::CONTENT_TYPE, "application/zip")], body)
+            }))
+            .layer(compression());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        
+        let client = reqwest::Client::builder().no_gzip().no_brotli().build().unwrap();
+        let encoding = |path: &'static str, accept: &'static str| {
+            let request = client.get(format!("{}{}", base, path)).header("accept-encoding", accept);
+            async move {
+                let response = request.send().await.unwrap();
+                response.headers().get("content-encoding").map(|v| v.to_str().unwrap().to_string())
+            }
+        };
+        
+        assert_eq!(encoding("/text", "gzip").await.as_deref(), Some("gzip"));
+        assert_eq!(encoding("/text", "br").await.as_deref(), Some("br"));
+        assert_eq!(encoding("/text", "identity").await, None);
+        assert_eq!(encoding("/zip", "gzip").await, None);
+    }
 }
@@ -0,0 +1,156 @@
+//! Network interface discovery for LAN access
+
+use std::net::IpAddr;
+
+/// List this machine's addresses that other LAN devices can reach.
+/// Loopback and link-local addresses are filtered out.
+pub fn lan_addresses() -> Vec<IpAddr> {
+    let interfaces = match local_ip_address::list_afinet_netifas() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            tracing::warn!("Failed to enumerate network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+    
+    let mut addresses: Vec<IpAddr> = interfaces
+        .into_iter()
+        .map(|(_, ip)| ip)
+        .filter(|ip| !ip.is_loopback() && !ip.is_unspecified() && !is_link_local(ip))
+        .collect();
+    
+    // IPv4 first, since that's what people type into a phone
+    addresses.sort_by_key(|ip| ip.is_ipv6());
+    addresses.dedup();
+    addresses
+}
+
+/// Build copy-paste URLs for a server bound to `host`: one for each LAN
+/// address when it listens on every interface, otherwise just its own
+/// (none for a loopback address)
+pub fn lan_urls(host: IpAddr, port: u16) -> Vec<String> {
+    lan_urls_from(host, port, &lan_addresses())
+}
+
+/// `lan_urls` given this machine's LAN `addresses`
+pub fn lan_urls_from(host: IpAddr, port: u16, addresses: &[IpAddr]) -> Vec<String> {
+    if host.is_unspecified() {
+        addresses.iter().map(|ip| url_for(*ip, port)).collect()
+    } else if host.is_loopback() {
+        Vec::new()
+    } else {
+        vec![url_for(host, port)]
+    }
+}
+
+/// URL of the web UI at `ip`, bracketing IPv6 addresses
+pub fn url_for(ip: IpAddr, port: u16) -> String {
+    match ip {
+        IpAddr::V4(v4) => format!("http://{}:{}", v4, port),
+        IpAddr::V6(v6) => format!("http://[{}]:{}", v6, port),
+    }
+}
+
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_link_local(),
+        IpAddr::V6(v6) => (v6.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/// Whether the connection in use is metered (a phone hotspot, a capped
+/// plan), or `None` if that can't be told here
+pub fn is_metered() -> Option<bool> {
+    metered_status()
+}
+
+/// Ask NetworkManager for its overall metered state
+#[cfg(target_os = "linux")]
+fn metered_status() -> Option<bool> {
+    let output = std::process::Command::new("busctl")
+        .args([
+            "get-property",
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.NetworkManager",
+            "Metered",
+        ])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    parse_nm_metered(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Ask Windows for the cost of the internet connection profile. The WinRT
+/// API is reached through PowerShell rather than a binding of its own.
+#[cfg(windows)]
+fn metered_status() -> Option<bool> {
+    use std::os::windows::process::CommandExt;
+    
+    /// Keep a console window from flashing up on every check
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+    const SCRIPT: &str = "$t = [Windows.Networking.Connectivity.NetworkInformation, Windows.Networking.Connectivity, ContentType = WindowsRuntime]; \
+        $p = $t::GetInternetConnectionProfile(); \
+        if ($p) { $p.GetConnectionCost().NetworkCostType }";
+    
+    let output = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", SCRIPT])
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    parse_network_cost(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn metered_status() -> Option<bool> {
+    None
+}
+
+/// Read NetworkManager's `NMMetered` value as printed by busctl ("u 4").
+/// Its guesses count like definite answers.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_nm_metered(output: &str) -> Option<bool> {
+    match output.trim().strip_prefix("u ")?.trim() {
+        "1" | "3" => Some(true),
+        "2" | "4" => Some(false),
+        _ => None,
+    }
+}
+
+/// Read a Windows `NetworkCostType`: `Fixed` and `Variable` plans are metered
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_network_cost(output: &str) -> Option<bool> {
+    match output.trim() {
+        "Unrestricted" => Some(false),
+        "Fixed" | "Variable" => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn urls_bracket_ipv6_addresses() {
+        assert_eq!(url_for("192.168.1.23".parse().unwrap(), 8787), "http://192.168.1.23:8787");
+        assert_eq!(url_for("fd00::23".parse().unwrap(), 8787), "http://[fd00::23]:8787");
+        assert!(!is_link_local(&"fd00::23".parse().unwrap()));
+        assert!(is_link_local(&"fe80::1".parse().unwrap()));
+        assert!(is_link_local(&"169.254.0.9".parse().unwrap()));
+    }
+    
+    #[test]
+    fn metered_states_are_read() {
+        assert_eq!(parse_nm_metered("u 1\n"), Some(true));
+        assert_eq!(parse_nm_metered("u 3"), Some(true));
+        assert_eq!(parse_nm_metered("u 4\n"), Some(false));
+        assert_eq!(parse_nm_metered("u 0"), None);
+        assert_eq!(parse_nm_metered(""), None);
+        
+        assert_eq!(parse_network_cost("Variable\r\n"), Some(true));
+        assert_eq!(parse_network_cost("Unrestricted\r\n"), Some(false));
+        assert_eq!(parse_network_cost("Unknown"), None);
+    }
+}
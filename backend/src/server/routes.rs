@@ -1,25 +1,32 @@
 //! REST API routes
 
-use crate::config::{self, FileTypeConfig};
-use crate::db::{DownloadRecord, DownloadStatus};
+use crate::config::{
+    self, ConflictPolicy, ContentEncodingMode, FileTypeConfig, RoutingRule, Settings, TranscodeConfig,
+};
+use crate::db::{
+    BatchProgress, CancelReason, DownloadError, DownloadListQuery, DownloadRecord, DownloadStatus,
+    LifetimeStats, RequestMethod, SortColumn, SortOrder,
+};
 use crate::download::{self, DownloadStats};
 use crate::AppState;
 use auto_launch::AutoLaunchBuilder;
 use axum::{
-    extract::{Path, State},
+    extract::{
+        multipart::{Multipart, MultipartError},
+        FromRequest, Path, Query, State,
+    },
     http::StatusCode,
     response::{IntoResponse, Response},
     routing::{delete, get, post, put},
     Json, Router,
 };
 use futures_util::StreamExt;
-use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tracing::info;
 
 /// Create API routes
@@ -28,24 +35,57 @@ pub fn api_routes() -> Router<Arc<AppState>> {
         // Downloads
         .route("/downloads", get(list_downloads))
         .route("/downloads", post(add_download))
+        .route("/downloads/batch", post(add_batch))
+        .route("/downloads/from-file", post(add_from_list_file))
+        .route("/downloads/register", post(register_download))
+        .route("/downloads/delete", post(remove_downloads))
         .route("/downloads/{id}", delete(remove_download))
         .route("/downloads/{id}/cancel", post(cancel_download))
+        .route("/downloads/{id}/restore", post(restore_download))
+        .route("/downloads/{id}/filename", put(rename_download))
+        .route("/downloads/{id}/move", post(move_download))
+        .route("/downloads/{id}/speed-limit", put(set_speed_limit))
+        .route("/downloads/{id}/errors", get(download_errors))
+        .route("/downloads/{id}/speed-stats", get(download_speed_stats))
+        .route("/downloads/{id}/preview", get(preview_download))
         .route("/downloads/stats", get(download_stats))
+        .route("/stats/lifetime", get(lifetime_stats))
+        .route("/batches/{batch_id}", get(get_batch))
+        .route("/queue", get(list_queue))
+        .route("/queue/pause", post(pause_queue))
+        .route("/queue/resume", post(resume_queue))
+        .route("/downloads/clear-completed", post(clear_completed))
+        .route("/downloads/validate", post(validate_download))
         // URL utilities
         .route("/url-info", post(get_url_info))
+        // Build info
+        .route("/version", get(version_info))
+        .route("/network-info", get(network_info))
         // Settings
         .route("/settings", get(get_settings))
         .route("/settings", put(update_settings))
+        .route("/config/raw", get(get_raw_config).put(replace_raw_config))
+        .route("/notifications/test", post(test_notifications))
         // File types
         .route("/file-types", get(list_file_types))
         .route("/file-types", post(add_file_type))
+        .route("/file-types/order", put(reorder_file_types))
         .route("/file-types/{id}", put(update_file_type))
         .route("/file-types/{id}", delete(remove_file_type))
+        .route("/file-types/{id}/listing", get(file_type_listing))
+        .route("/file-types/{id}/pause-all", post(pause_file_type))
+        .route("/file-types/{id}/resume-all", post(resume_file_type))
+        .route("/file-types/{id}/cancel-all", post(cancel_file_type))
+        .route("/storage", get(storage_usage))
 }
 
 /// Resume incomplete downloads from previous session
 pub fn resume_incomplete_downloads(state: Arc<AppState>) {
-    let downloads = match state.db.get_all_downloads() {
+    let resume_paused = state.settings.read().auto_resume_paused_on_start;
+    
+    // Paused downloads only come back if the user opted in; otherwise they
+    // stay paused until resumed by hand
+    let downloads = match state.db.get_pending_downloads(resume_paused) {
         Ok(d) => d,
         Err(e) => {
             tracing::error!("Failed to load downloads for resume: {}", e);
@@ -53,59 +93,203 @@ pub fn resume_incomplete_downloads(state: Arc<AppState>) {
         }
     };
     
-    let max_concurrent = state.settings.read().max_concurrent_downloads;
-    let mut started = 0;
-    
-    for download in downloads {
-        match download.status {
-            DownloadStatus::Downloading | DownloadStatus::Pending => {
-                // These were interrupted - restart them
-                if started < max_concurrent {
-                    info!("Resuming download: {}", download.filename);
-                    start_download(state.clone(), download);
-                    started += 1;
-                } else {
-                    // Queue the rest
-                    info!("Queueing download: {}", download.filename);
-                    let _ = state.db.update_status(&download.id, DownloadStatus::Queued, None);
-                    state.download_manager.enqueue(download);
-                }
-            }
-            DownloadStatus::Queued => {
-                // Re-enqueue
-                if started < max_concurrent {
-                    info!("Starting queued download: {}", download.filename);
-                    start_download(state.clone(), download);
-                    started += 1;
-                } else {
-                    state.download_manager.enqueue(download);
-                }
-            }
-            _ => {} // Completed, Failed, Cancelled - leave as is
+    let settings = state.settings.read().clone();
+    
+    // Everything goes back in the queue in order, then starts from there
+    // like any queued download, so resuming respects `max_concurrent` and
+    // the per-host limit exactly
+    let mut resumed = Vec::with_capacity(downloads.len());
+    for mut download in downloads {
+        // Report the bytes really on disk, so the progress bar is right from
+        // the first render and a stale record can't mislead the resume
+        let on_disk = reconcile_partial(&settings, &download);
+        if on_disk != download.downloaded_size {
+            download.downloaded_size = on_disk;
+            let _ = state.db.update_progress(&download.id, on_disk, download.total_size);
+        }
+        
+        // Interrupted (cancelled ones only by a shutdown), still queued, or
+        // paused ones when auto-resume is on
+        if download.status != DownloadStatus::Queued {
+            let _ = state.db.update_status(&download.id, DownloadStatus::Queued, None);
         }
+        resumed.push(download);
+    }
+    
+    if settings.startup_stagger_ms > 0 {
+        let stagger = std::time::Duration::from_millis(settings.startup_stagger_ms);
+        tokio::spawn(requeue_staggered(state, resumed, stagger));
+        return;
+    }
+    for download in resumed {
+        state.download_manager.requeue(download);
     }
+    start_queued(&state);
     
-    if started > 0 {
-        info!("Resumed {} downloads", started);
+    let manager = &state.download_manager;
+    // Nothing else is running yet, so each slot taken is a download just started
+    if manager.active_count() != manager.slots_in_use() {
+        tracing::warn!(
+            "{} downloads running but {} download slots taken",
+            manager.active_count(),
+            manager.slots_in_use()
+        );
+    }
+    if manager.active_count() > 0 || manager.queue_len() > 0 {
+        info!("Resumed {} downloads, {} queued", manager.active_count(), manager.queue_len());
+    }
+}
+
+/// Work out how much of an interrupted download can be resumed from. After a
+/// hard crash the recorded `downloaded_size` can lag behind the partial file
+/// (progress is only saved now and then) or run ahead of it (saved before
+/// the data reached the disk). Only the bytes both agree on are trusted: the
+/// download resumes from the shorter of the two, and a longer partial is
+/// cut back to match. A partial longer than the known total can't be
+/// resumed and is removed so the download starts over.
+fn reconcile_partial(settings: &Settings, download: &DownloadRecord) -> u64 {
+    // Only partials holding the server's bytes as-is are resumed; anything
+    // else starts from zero
+    if !keeps_encoding(settings, download) {
+        return 0;
+    }
+    
+    let partial = settings.partial_path(&download.destination, &download.filename);
+    let on_disk = match std::fs::metadata(&partial) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return 0,
+    };
+    
+    match download.total_size {
+        Some(total) if on_disk > total => {
+            tracing::warn!(
+                "Partial file for {} is larger than the download ({} > {} bytes), starting over",
+                download.filename,
+                on_disk,
+                total
+            );
+            let _ = std::fs::remove_file(&partial);
+            0
+        }
+        _ if on_disk > download.downloaded_size => {
+            let resume_from = download.downloaded_size;
+            let truncated = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&partial)
+                .and_then(|file| file.set_len(resume_from));
+            match truncated {
+                Ok(()) => resume_from,
+                Err(e) => {
+                    tracing::warn!("Cannot truncate the partial file for {}, starting over: {}", download.filename, e);
+                    let _ = std::fs::remove_file(&partial);
+                    0
+                }
+            }
+        }
+        _ => on_disk,
     }
 }
 
 // ============ Download Endpoints ============
 
-/// List all downloads
+/// Query parameters for listing downloads
+#[derive(Debug, Deserialize)]
+pub struct ListDownloadsQuery {
+    /// Earliest creation date (`YYYY-MM-DD`, UTC) or RFC 3339 time
+    pub from: Option<String>,
+    /// Latest creation date, including that whole day, or RFC 3339 time
+    pub to: Option<String>,
+    #[serde(default)]
+    pub sort: SortColumn,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+/// Parse a `from`/`to` bound. A bare date stands for the start of that day,
+/// or its end when `end_of_day` is set, so `to` includes the whole day.
+fn parse_time_bound(value: &str, end_of_day: bool) -> Result<chrono::DateTime<chrono::Utc>, AppError> {
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let time = if end_of_day {
+            chrono::NaiveTime::from_hms_nano_opt(23, 59, 59, 999_999_999)
+        } else {
+            chrono::NaiveTime::from_hms_opt(0, 0, 0)
+        };
+        return Ok(date.and_time(time.unwrap_or_default()).and_utc());
+    }
+    
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.with_timezone(&chrono::Utc))
+        .map_err(|_| AppError::BadRequest(format!("Invalid date {:?}, expected YYYY-MM-DD or RFC 3339", value)))
+}
+
+/// List downloads, optionally only those created in a time range, sorted
+/// by a whitelisted column
 async fn list_downloads(
     State(state): State<Arc<AppState>>,
+    Query(query): Query<ListDownloadsQuery>,
 ) -> Result<Json<Vec<DownloadRecord>>, AppError> {
-    let downloads = state.db.get_all_downloads()?;
+    let created_from = query.from.as_deref().map(|v| parse_time_bound(v, false)).transpose()?;
+    let created_to = query.to.as_deref().map(|v| parse_time_bound(v, true)).transpose()?;
+    if let (Some(from), Some(to)) = (created_from, created_to) {
+        if from > to {
+            return Err(AppError::BadRequest("`from` is after `to`".into()));
+        }
+    }
+    
+    let downloads = state.db.list_downloads(&DownloadListQuery {
+        created_from,
+        created_to,
+        sort: query.sort,
+        order: query.order,
+    })?;
     Ok(Json(downloads))
 }
 
 /// Request to add a new download
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct AddDownloadRequest {
     pub url: String,
+    /// Picked by the routing rules, then the extension, when left out
+    #[serde(default)]
     pub file_type: String,
     pub filename: Option<String>,
+    /// `Accept` header some APIs require
+    pub accept: Option<String>,
+    /// Query parameters (e.g. an auth token) merged into the URL's existing ones
+    pub query_params: Option<Vec<(String, String)>>,
+    /// Extra request headers, e.g. `Authorization`. Values here and in
+    /// `query_params` may hold `${VIBE_...}` placeholders, expanded from the
+    /// environment each time the download is sent.
+    pub headers: Option<Vec<(String, String)>>,
+    /// Speed cap in bytes per second (0 or absent = unlimited)
+    pub speed_limit: Option<u64>,
+    /// Fallback URLs tried in order when the main one fails
+    pub mirrors: Option<Vec<String>>,
+    /// Expected digest as `sha256:<hex>` or `sha512:<hex>`
+    pub checksum: Option<String>,
+    /// Skip the transfer if the content hasn't changed since the last
+    /// completed download of this URL
+    #[serde(default)]
+    pub skip_if_unmodified: bool,
+    /// Download only the bytes from `range_start` (default 0) through
+    /// `range_end` (inclusive, default the end of the file)
+    pub range_start: Option<u64>,
+    pub range_end: Option<u64>,
+    /// Other file types whose folders also get the finished file, linked
+    /// rather than downloaded again
+    pub link_file_types: Option<Vec<String>>,
+    /// `GET` (the default) or `POST`
+    pub method: Option<RequestMethod>,
+    /// Body to send; only allowed with `POST`
+    pub body: Option<String>,
+    /// `Content-Type` of the body, e.g. `application/json`
+    pub content_type: Option<String>,
+    /// Cancel a run of the download that takes longer than this (0 or
+    /// absent = no limit)
+    pub max_duration_secs: Option<u64>,
+    /// Size of the file if known beforehand, for progress when the server
+    /// doesn't send one. Ignored for a range.
+    pub expected_size: Option<u64>,
 }
 
 /// Response after adding a download
@@ -113,6 +297,10 @@ pub struct AddDownloadRequest {
 pub struct AddDownloadResponse {
     pub id: String,
     pub queued: bool,
+    /// 1-based position in the queue, if queued
+    pub queue_position: Option<usize>,
+    /// Rough seconds until the download starts, once average durations are known
+    pub estimated_wait_secs: Option<u64>,
 }
 
 /// Add a new download
@@ -120,8 +308,309 @@ async fn add_download(
     State(state): State<Arc<AppState>>,
     Json(req): Json<AddDownloadRequest>,
 ) -> Result<Json<AddDownloadResponse>, AppError> {
+    create_download(&state, req, None).await.map(Json)
+}
+
+/// Request to add several downloads as one batch
+#[derive(Debug, Deserialize)]
+pub struct AddBatchRequest {
+    pub downloads: Vec<AddDownloadRequest>,
+}
+
+/// A download in a batch that couldn't be added
+#[derive(Debug, Serialize)]
+pub struct BatchItemError {
+    /// Position of the download in the request
+    pub index: usize,
+    pub error: String,
+}
+
+/// Response after adding a batch
+#[derive(Debug, Serialize)]
+pub struct AddBatchResponse {
+    pub batch_id: String,
+    pub added: Vec<AddDownloadResponse>,
+    pub errors: Vec<BatchItemError>,
+}
+
+/// Add several downloads that share a batch id, so their progress can be
+/// followed together. Each is added like a single download; one that is
+/// refused doesn't stop the rest.
+async fn add_batch(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<AddBatchRequest>,
+) -> Result<Json<AddBatchResponse>, AppError> {
+    if req.downloads.is_empty() {
+        return Err(AppError::BadRequest("A batch needs at least one download".into()));
+    }
+    
+    let batch_id = uuid::Uuid::new_v4().to_string();
+    let mut added = Vec::new();
+    let mut errors = Vec::new();
+    
+    for (index, download) in req.downloads.into_iter().enumerate() {
+        match create_download(&state, download, Some(&batch_id)).await {
+            Ok(response) => added.push(response),
+            Err(e) => errors.push(BatchItemError {
+                index,
+                error: e.to_string(),
+            }),
+        }
+    }
+    
+    Ok(Json(AddBatchResponse {
+        batch_id,
+        added,
+        errors,
+    }))
+}
+
+/// Query parameters for importing a list of URLs
+#[derive(Debug, Deserialize)]
+pub struct ListFileQuery {
+    /// File type of every download; by default each gets the one its
+    /// filename matches
+    pub file_type: Option<String>,
+}
+
+/// What became of one URL of an imported list
+#[derive(Debug, Serialize)]
+pub struct ListFileLine {
+    /// 1-based line number in the file
+    pub line: usize,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<AddDownloadResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Response after importing a list of URLs
+#[derive(Debug, Serialize)]
+pub struct ListFileResponse {
+    pub batch_id: String,
+    /// One per URL; blank lines and comments are left out
+    pub lines: Vec<ListFileLine>,
+}
+
+/// Add the downloads listed in a text file, one URL per line, as a batch.
+/// The file is either the whole request body or the file of a
+/// `multipart/form-data` upload. A line that isn't a URL, or whose download
+/// is refused, is reported without stopping the rest.
+async fn add_from_list_file(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ListFileQuery>,
+    request: axum::extract::Request,
+) -> Result<Json<ListFileResponse>, AppError> {
+    let is_multipart = request
+        .headers()
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+    let file = if is_multipart {
+        let multipart = Multipart::from_request(request, &())
+            .await
+            .map_err(|e| AppError::BadRequest(e.body_text()))?;
+        multipart_file(multipart)
+            .await
+            .map_err(|e| AppError::BadRequest(e.body_text()))?
+            .ok_or_else(|| AppError::BadRequest("The upload has no file".into()))?
+    } else {
+        axum::body::Bytes::from_request(request, &())
+            .await
+            .map_err(|e| AppError::BadRequest(e.body_text()))?
+    };
+    let text = std::str::from_utf8(&file).map_err(|_| AppError::BadRequest("The list must be UTF-8 text".into()))?;
+    
+    let entries = parse_url_list(text);
+    if entries.is_empty() {
+        return Err(AppError::BadRequest("The list has no URLs".into()));
+    }
+    if let Some(file_type) = &query.file_type {
+        if !state.settings.read().file_types.contains_key(file_type) {
+            return Err(AppError::BadRequest(format!("Unknown file type: {}", file_type)));
+        }
+    }
+    
+    let batch_id = uuid::Uuid::new_v4().to_string();
+    let mut lines = Vec::with_capacity(entries.len());
+    for (line, url, parsed) in entries {
+        let added = match parsed {
+            Ok(()) => {
+                let file_type = query.file_type.clone().unwrap_or_else(|| {
+                    let filename = download::extract_filename(&url, None);
+                    state.settings.read().file_type_for(&url, &filename)
+                });
+                let req = AddDownloadRequest {
+                    url: url.clone(),
+                    file_type,
+                    ..Default::default()
+                };
+                create_download(&state, req, Some(&batch_id)).await.map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e),
+        };
+        let (download, error) = match added {
+            Ok(download) => (Some(download), None),
+            Err(e) => (None, Some(e)),
+        };
+        lines.push(ListFileLine { line, url, download, error });
+    }
+    
+    Ok(Json(ListFileResponse { batch_id, lines }))
+}
+
+/// The URLs of a list file with their 1-based line numbers, and whether
+/// each can be downloaded. Blank lines and lines starting with `#` are
+/// skipped.
+fn parse_url_list(text: &str) -> Vec<(usize, String, Result<(), String>)> {
+    text.trim_start_matches('\u{feff}')
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line, url)| {
+            let parsed = match url::Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(()),
+                Ok(_) => Err("Only http(s) URLs can be downloaded".to_string()),
+                Err(e) => Err(format!("Invalid URL: {}", e)),
+            };
+            (line, url.to_string(), parsed)
+        })
+        .collect()
+}
+
+/// Contents of the first file in a multipart upload, or of its first part
+/// if none is a file (e.g. a text field)
+async fn multipart_file(mut multipart: Multipart) -> Result<Option<axum::body::Bytes>, MultipartError> {
+    let mut first = None;
+    while let Some(field) = multipart.next_field().await? {
+        let is_file = field.file_name().is_some();
+        let contents = field.bytes().await?;
+        if is_file {
+            return Ok(Some(contents));
+        }
+        first.get_or_insert(contents);
+    }
+    Ok(first)
+}
+
+/// Request to track a file that was downloaded some other way
+#[derive(Debug, Deserialize)]
+pub struct RegisterDownloadRequest {
+    /// Absolute path of the file
+    pub path: PathBuf,
+    /// Where the file came from, for the record
+    pub url: String,
+    /// Defaults to the file type its name matches
+    pub file_type: Option<String>,
+    /// Digest the file must match, as `sha256:<hex>` or `sha512:<hex>`
+    pub checksum: Option<String>,
+}
+
+/// Add an existing file to the history as a completed download, without
+/// downloading anything, once it's confirmed to exist and to match its
+/// checksum if one is given
+async fn register_download(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<RegisterDownloadRequest>,
+) -> Result<Json<DownloadRecord>, AppError> {
+    if !req.path.is_absolute() {
+        return Err(AppError::BadRequest("Path must be absolute".into()));
+    }
+    if url::Url::parse(&req.url).is_err() {
+        return Err(AppError::BadRequest("Invalid URL".into()));
+    }
+    let checksum = match &req.checksum {
+        Some(checksum) => Some(
+            download::Checksum::parse(checksum)
+                .ok_or_else(|| AppError::BadRequest("Invalid checksum, expected sha256:<hex> or sha512:<hex>".into()))?,
+        ),
+        None => None,
+    };
+    let (Some(destination), Some(filename)) = (req.path.parent(), req.path.file_name()) else {
+        return Err(AppError::BadRequest("Path must name a file".into()));
+    };
+    let filename = filename.to_string_lossy().into_owned();
+    let file_type = match req.file_type {
+        Some(file_type) if !state.settings.read().file_types.contains_key(&file_type) => {
+            return Err(AppError::BadRequest(format!("Unknown file type: {}", file_type)));
+        }
+        Some(file_type) => file_type,
+        None => state.settings.read().file_type_for(&req.url, &filename),
+    };
+    
+    let size = match tokio::fs::metadata(&req.path).await {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => return Err(AppError::NotFound(format!("{} is not a file", req.path.display()))),
+    };
+    let tracked = state
+        .db
+        .get_all_downloads()?
+        .into_iter()
+        .any(|d| d.status == DownloadStatus::Completed && d.destination.join(&d.filename) == req.path);
+    if tracked {
+        return Err(AppError::Conflict(format!("{} is already tracked", req.path.display())));
+    }
+    
+    if let Some(expected) = &checksum {
+        let (path, algorithm) = (req.path.clone(), expected.algorithm);
+        let actual = tokio::task::spawn_blocking(move || download::file_digest(&path, algorithm))
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?
+            .map_err(|e| AppError::Internal(format!("Cannot read {}: {}", req.path.display(), e)))?;
+        if actual != expected.digest {
+            let mismatch = download::ChecksumMismatch {
+                expected: expected.to_string(),
+                actual: format!("{}:{}", algorithm.as_str(), actual),
+            };
+            return Err(AppError::BadRequest(mismatch.to_string()));
+        }
+    }
+    
+    let mut record = DownloadRecord::new(req.url, filename, file_type, destination.to_path_buf());
+    record.total_size = Some(size);
+    record.downloaded_size = size;
+    record.checksum = checksum.map(|c| c.to_string());
+    state.db.insert_download(&record)?;
+    state.db.update_status(&record.id, DownloadStatus::Completed, None)?;
+    state
+        .download_manager
+        .notify_change(download::DownloadChange::new(&record.id, download::ChangeKind::Added));
+    
+    let record = state
+        .db
+        .get_download(&record.id)?
+        .ok_or_else(|| AppError::Internal("Registered download disappeared".into()))?;
+    Ok(Json(record))
+}
+
+/// Aggregate progress of a batch
+async fn get_batch(
+    State(state): State<Arc<AppState>>,
+    Path(batch_id): Path<String>,
+) -> Result<Json<BatchProgress>, AppError> {
+    state
+        .db
+        .get_batch(&batch_id)?
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound("Batch not found".into()))
+}
+
+/// Validate and record a download, then start or queue it
+async fn create_download(
+    state: &Arc<AppState>,
+    mut req: AddDownloadRequest,
+    batch_id: Option<&str>,
+) -> Result<AddDownloadResponse, AppError> {
     let settings = state.settings.read().clone();
     
+    // Without a file type, a routing rule or the extension picks one
+    if req.file_type.is_empty() {
+        let filename = req.filename.clone().unwrap_or_else(|| download::extract_filename(&req.url, None));
+        req.file_type = settings.file_type_for(&req.url, &filename);
+    }
+    
     // Get destination folder from file type
     let file_type_config = settings
         .file_types
@@ -129,737 +618,5780 @@ async fn add_download(
         .or_else(|| settings.file_types.get("general"))
         .ok_or_else(|| AppError::BadRequest("Unknown file type".into()))?;
     
+    // Fail now, naming the folder, rather than deep inside the download
+    check_destination(file_type_config.destination.clone()).await?;
+    
+    let mut link_file_types: Vec<String> = Vec::new();
+    for file_type in req.link_file_types.unwrap_or_default() {
+        if file_type == req.file_type || link_file_types.contains(&file_type) {
+            continue;
+        }
+        let config = settings
+            .file_types
+            .get(&file_type)
+            .ok_or_else(|| AppError::BadRequest(format!("Unknown file type: {}", file_type)))?;
+        check_destination(config.destination.clone()).await?;
+        link_file_types.push(file_type);
+    }
+    
+    let mut checksum = match &req.checksum {
+        Some(checksum) => Some(
+            download::Checksum::parse(checksum)
+                .ok_or_else(|| AppError::BadRequest("Invalid checksum, expected sha256:<hex> or sha512:<hex>".into()))?,
+        ),
+        None => None,
+    };
+    
+    let method = req.method.unwrap_or_default();
+    if method != RequestMethod::Post && (req.body.is_some() || req.content_type.is_some()) {
+        return Err(AppError::BadRequest("A body is only allowed with POST".into()));
+    }
+    if let Some(content_type) = &req.content_type {
+        if reqwest::header::HeaderValue::from_str(content_type).is_err() {
+            return Err(AppError::BadRequest("Invalid Content-Type header value".into()));
+        }
+    }
+    
+    let mut url = req.url;
+    let mut mirrors = req.mirrors.unwrap_or_default();
+    let mut filename = req.filename;
+    let mut total_size = None;
+    
+    // A Metalink is expanded into a download of the file it describes,
+    // with its other sources as mirrors and its digest to verify against
+    if method == RequestMethod::Get && download::is_metalink_url(&url) {
+        let clients = state.download_manager.http_client();
+        let metalink = fetch_metalink(clients.for_url(&url), &url).await?;
+        let mut urls = metalink.urls;
+        // parse_metalink guarantees at least one source
+        url = urls.remove(0);
+        urls.append(&mut mirrors);
+        mirrors = urls;
+        filename = filename.or(Some(metalink.filename));
+        checksum = checksum.or(metalink.checksum);
+        total_size = metalink.size;
+    }
+    if let Some(expected) = req.expected_size.filter(|&size| size > 0) {
+        total_size = Some(expected);
+    }
+    
+    let range = match (req.range_start, req.range_end) {
+        (None, None) => None,
+        (start, end) => {
+            let start = start.unwrap_or(0);
+            if end.is_some_and(|end| end < start) {
+                return Err(AppError::BadRequest("range_end must not be before range_start".into()));
+            }
+            // The length has to fit too: 0 to u64::MAX is one byte too many
+            if end.is_some_and(|end| (end - start).checked_add(1).is_none()) {
+                return Err(AppError::BadRequest("The range is too large".into()));
+            }
+            Some((start, end))
+        }
+    };
+    if range.is_some() {
+        // A digest covers the whole file, never a slice of it
+        if req.checksum.is_some() {
+            return Err(AppError::BadRequest("A checksum can't be verified for part of a file".into()));
+        }
+        checksum = None;
+        total_size = range.and_then(|(start, end)| end.and_then(|end| (end - start).checked_add(1)));
+    }
+    
     // Extract filename from URL if not provided
-    let filename = req.filename.unwrap_or_else(|| {
-        download::extract_filename(&req.url, None)
+    let named = filename.is_some();
+    let filename = filename.unwrap_or_else(|| {
+        let filename = download::extract_filename(&url, None);
+        match range {
+            Some((start, end)) => download::range_filename(&filename, start, end),
+            None => filename,
+        }
     });
     
+    if let Some(accept) = &req.accept {
+        if reqwest::header::HeaderValue::from_str(accept).is_err() {
+            return Err(AppError::BadRequest("Invalid Accept header value".into()));
+        }
+    }
+    let headers = req.headers.unwrap_or_default();
+    for (name, value) in &headers {
+        if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(AppError::BadRequest(format!("Invalid header name: {}", name)));
+        }
+        if reqwest::header::HeaderValue::from_str(value).is_err() {
+            return Err(AppError::BadRequest(format!("Invalid value for header {}", name)));
+        }
+    }
+    let query_params = req.query_params.unwrap_or_default();
+    for (_, value) in headers.iter().chain(&query_params) {
+        download::check_env_vars(value).map_err(|e| AppError::BadRequest(e.to_string()))?;
+    }
+    
     // Create download record
-    let record = DownloadRecord::new(
-        req.url.clone(),
+    let mut record = DownloadRecord::new(
+        url,
         filename,
         req.file_type.clone(),
         file_type_config.destination.clone(),
     );
+    record.accept = req.accept;
+    record.query_params = query_params;
+    record.headers = headers;
+    record.speed_limit = req.speed_limit.filter(|&limit| limit > 0);
+    record.mirrors = mirrors;
+    record.checksum = checksum.map(|c| c.to_string());
+    record.total_size = total_size;
+    record.batch_id = batch_id.map(str::to_string);
+    record.range_start = range.map(|(start, _)| start);
+    record.range_end = range.and_then(|(_, end)| end);
+    record.link_file_types = link_file_types;
+    record.method = method;
+    record.body = req.body;
+    record.content_type = req.content_type;
+    record.max_duration_secs = req.max_duration_secs.filter(|&secs| secs > 0);
+    
+    // A conditional request needs the validators of an earlier download
+    // whose file is still there, in the same folder, to fall back on. With a
+    // dated subfolder that's any day's folder; the earlier one is kept.
+    if req.skip_if_unmodified && range.is_none() {
+        record.skip_if_unmodified = true;
+        let dated = file_type_config.date_subfolder.is_some();
+        let previous = state.db.find_latest_completed_by_url(&record.url)?.filter(|previous| {
+            let same_folder = previous.destination == record.destination
+                || (dated && previous.destination.starts_with(&record.destination));
+            same_folder
+                && previous.destination.join(&previous.filename).exists()
+                && (!named || previous.filename == record.filename)
+        });
+        if let Some(previous) = previous {
+            record.destination = previous.destination;
+            record.filename = previous.filename;
+            record.etag = previous.etag;
+            record.last_modified = previous.last_modified;
+        }
+    }
     
     let id = record.id.clone();
     
     // Insert into database
     state.db.insert_download(&record)?;
+    state.download_manager.announce(&record);
     
     // Check if we should queue or start immediately
-    let active_count = state.download_manager.active_count();
-    let max_concurrent = state.settings.read().max_concurrent_downloads;
-    let queued = active_count >= max_concurrent;
+    let slot = state.download_manager.try_start(&record.url);
+    let queued = slot.is_none();
+    
+    let mut queue_position = None;
+    let mut estimated_wait_secs = None;
     
-    if queued {
+    if let Some(slot) = slot {
+        // Start download immediately
+        start_download(state.clone(), record, slot);
+    } else {
         // Update status to queued
         state.db.update_status(&id, DownloadStatus::Queued, None)?;
-        state.download_manager.enqueue(record);
-    } else {
-        // Start download immediately
-        start_download(state.clone(), record);
+        let position = match state.download_manager.enqueue(record) {
+            Ok(position) => position,
+            Err(e) => {
+                // Refused, so don't leave a record behind that will never run
+                state.db.delete_download(&id)?;
+                state
+                    .download_manager
+                    .notify_change(download::DownloadChange::new(&id, download::ChangeKind::Removed));
+                return Err(AppError::ServiceUnavailable(e.to_string()));
+            }
+        };
+        queue_position = Some(position);
+        estimated_wait_secs = state.download_manager.estimated_wait_secs(position);
+    }
+    
+    Ok(AddDownloadResponse {
+        id,
+        queued,
+        queue_position,
+        estimated_wait_secs,
+    })
+}
+
+/// Fetch and parse the Metalink document at `url`
+async fn fetch_metalink(client: &reqwest::Client, url: &str) -> Result<download::Metalink, AppError> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Failed to fetch Metalink: {}", e)))?;
+    
+    if !response.status().is_success() {
+        return Err(AppError::BadRequest(format!("Failed to fetch Metalink: HTTP {}", response.status())));
     }
     
-    Ok(Json(AddDownloadResponse { id, queued }))
+    let xml = response
+        .text()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Failed to fetch Metalink: {}", e)))?;
+    
+    download::parse_metalink(&xml).map_err(|e| AppError::BadRequest(format!("{:#}", e)))
+}
+
+/// Min, median, 95th percentile and max of a download's sampled speeds,
+/// from the samples taken while it ran since the app started
+async fn download_speed_stats(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<download::SpeedStats>, AppError> {
+    if state.db.get_download(&id)?.is_none() {
+        return Err(AppError::NotFound("Download not found".into()));
+    }
+    
+    Ok(Json(state.download_manager.speed_stats(&id)))
+}
+
+/// Request to check a download without adding it
+#[derive(Debug, Deserialize)]
+pub struct ValidateDownloadRequest {
+    pub url: String,
+    pub accept: Option<String>,
+    pub query_params: Option<Vec<(String, String)>>,
+}
+
+/// What a download would look like if it were added
+#[derive(Debug, Serialize)]
+pub struct ValidateDownloadResponse {
+    pub reachable: bool,
+    pub status_code: Option<u16>,
+    pub content_length: Option<u64>,
+    pub suggested_filename: String,
+    pub suggested_file_type: String,
+    pub supports_resume: bool,
+    pub error: Option<String>,
+}
+
+/// Dry run: probe a URL with the download client without recording or starting anything
+async fn validate_download(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ValidateDownloadRequest>,
+) -> Json<ValidateDownloadResponse> {
+    let clients = state.download_manager.http_client();
+    let client = clients.for_url(&req.url);
+    let query = req.query_params.unwrap_or_default();
+    
+    let build = |method: reqwest::Method| {
+        let mut request = client
+            .request(method, &req.url)
+            .timeout(std::time::Duration::from_secs(15))
+            .query(&query);
+        if let Some(accept) = &req.accept {
+            request = request.header(reqwest::header::ACCEPT, accept);
+        }
+        request
+    };
+    
+    // HEAD first; some servers reject it, so fall back to a 1-byte ranged GET
+    let response = match build(reqwest::Method::HEAD).send().await {
+        Ok(response) if response.status().is_success() => Ok(response),
+        _ => build(reqwest::Method::GET).header(reqwest::header::RANGE, "bytes=0-0").send().await,
+    };
+    
+    let (reachable, status_code, content_length, filename, supports_resume, error) = match response {
+        Ok(response) => {
+            let status = response.status();
+            let headers = response.headers();
+            let filename = headers
+                .get(reqwest::header::CONTENT_DISPOSITION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(download::parse_content_disposition);
+            let content_length = response_total_size(status, headers);
+            let supports_resume = status == reqwest::StatusCode::PARTIAL_CONTENT
+                || headers
+                    .get(reqwest::header::ACCEPT_RANGES)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| v.eq_ignore_ascii_case("bytes"));
+            let reachable = status.is_success();
+            let error = (!reachable).then(|| format!("HTTP error: {}", status));
+            
+            (reachable, Some(status.as_u16()), content_length, filename, supports_resume, error)
+        }
+        Err(e) => (false, None, None, None, false, Some(e.to_string())),
+    };
+    
+    let suggested_filename = filename.unwrap_or_else(|| download::extract_filename(&req.url, None));
+    let suggested_file_type = state.settings.read().file_type_for(&req.url, &suggested_filename);
+    
+    Json(ValidateDownloadResponse {
+        reachable,
+        status_code,
+        content_length,
+        suggested_filename,
+        suggested_file_type,
+        supports_resume,
+        error,
+    })
+}
+
+/// Stage a running download's progress for `save_progress_periodically`,
+/// so listings and batch totals don't only move when a download stops, and
+/// sample its speed every `SPEED_SAMPLE_INTERVAL`. Ends with the first
+/// update saying the download is no longer running.
+fn persist_progress(
+    manager: download::DownloadManager,
+    mut updates: tokio::sync::broadcast::Receiver<download::ProgressUpdate>,
+    id: String,
+) {
+    tokio::spawn(async move {
+        // Bytes at the last sample; the speed in updates is an average since
+        // the start, too smooth to show spikes
+        let mut last_sample: Option<(std::time::Instant, u64)> = None;
+        loop {
+            let update = match updates.recv().await {
+                Ok(update) => update,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            };
+            if update.id != id {
+                continue;
+            }
+            if update.status != DownloadStatus::Downloading {
+                break;
+            }
+            manager.stage_progress(&id, update.downloaded, update.total);
+            
+            // A retry starts counting again from wherever it resumes
+            if update.error.is_some() {
+                last_sample = None;
+                continue;
+            }
+            match last_sample {
+                Some((at, bytes)) if at.elapsed() >= download::SPEED_SAMPLE_INTERVAL => {
+                    let speed = update.downloaded.saturating_sub(bytes) as f64 / at.elapsed().as_secs_f64();
+                    manager.record_speed(&id, speed as u64);
+                    last_sample = Some((std::time::Instant::now(), update.downloaded));
+                }
+                Some(_) => {}
+                None => last_sample = Some((std::time::Instant::now(), update.downloaded)),
+            }
+        }
+    });
 }
 
-/// Start a download task
-fn start_download(state: Arc<AppState>, record: DownloadRecord) {
+/// Start a download task in the slot claimed for it, which is given up
+/// once the download is over
+fn start_download(state: Arc<AppState>, mut record: DownloadRecord, slot: download::DownloadSlot) {
     let db = state.db.clone();
     let download_manager = state.download_manager.clone();
     let settings = state.settings.read().clone();
+    let client = download_manager.http_client();
     let progress_tx = download_manager.progress_sender();
     
+    // The dated subfolder is picked when a download first starts and kept
+    // from then on, so a resume the next day still finds its partial file
+    if record.started_at.is_none() {
+        if let Some(file_type) = settings.file_types.get(&record.file_type) {
+            if file_type.date_subfolder.is_some() && record.destination == file_type.destination {
+                record.destination = file_type.destination_at(&chrono::Local::now());
+                let _ = db.update_location(&record.id, &record.destination, &record.filename);
+            }
+        }
+    }
+    
     // Create cancel channel
-    let (cancel_tx, mut cancel_rx) = tokio::sync::mpsc::channel::<()>(1);
+    let (cancel_tx, mut cancel_rx) = tokio::sync::mpsc::channel::<CancelReason>(1);
     
     // Register as active
-    let download_id = record.id.clone();
-    download_manager.add_active(download_id.clone(), cancel_tx);
+    let speed_limit = download_manager.add_active(&record, cancel_tx);
     
     // Update status to downloading
     let _ = db.update_status(&record.id, DownloadStatus::Downloading, None);
     
-    // Send initial progress update
+    // Announce the start; this is the only update carrying `started_at`
     let _ = progress_tx.send(download::ProgressUpdate {
         id: record.id.clone(),
-        downloaded: 0,
+        downloaded: record.downloaded_size,
         total: record.total_size,
+        indeterminate: record.total_size.is_none(),
         speed: 0,
         status: DownloadStatus::Downloading,
         error: None,
+        started_at: Some(chrono::Utc::now()),
     });
     
+    persist_progress(download_manager.clone(), progress_tx.subscribe(), record.id.clone());
+    
     tokio::spawn(async move {
-        // Perform download with cancellation support
-        let result = download_file_with_cancel(&record, &progress_tx, &mut cancel_rx).await;
+        let started = std::time::Instant::now();
         
-        // Remove from active set
-        download_manager.remove_active(&record.id);
+        // Perform download with cancellation support
+        let policy = download::RetryPolicy::from_settings(&settings);
+        let download = download_with_retries(
+            &record,
+            &client,
+            &settings,
+            &policy,
+            &progress_tx,
+            &mut cancel_rx,
+            &speed_limit,
+            download_manager.filenames(),
+            || download_manager.finalize_filename(&record.id),
+            |e, delay| {
+                let message = format!("{:#}; retrying in {}s", e, delay.as_secs());
+                tracing::warn!("{}: {}", record.filename, message);
+                let _ = db.record_error(&record.id, &message);
+                let _ = progress_tx.send(download::ProgressUpdate {
+                    id: record.id.clone(),
+                    downloaded: record.downloaded_size,
+                    total: record.total_size,
+                    indeterminate: record.total_size.is_none(),
+                    speed: 0,
+                    status: DownloadStatus::Downloading,
+                    error: Some(message),
+                    started_at: None,
+                });
+            },
+        );
+        // The deadline covers retries too, and cuts in wherever the download
+        // is stuck, even waiting for a response
+        let result = match record.max_duration_secs {
+            Some(secs) => match tokio::time::timeout(std::time::Duration::from_secs(secs), download).await {
+                Ok(result) => result,
+                Err(_) => {
+                    tracing::warn!("{} took longer than {}s, cancelling", record.filename, secs);
+                    let _ = tokio::fs::remove_file(settings.partial_path(&record.destination, &record.filename)).await;
+                    Err(download::Cancelled(CancelReason::Timeout).into())
+                }
+            },
+            None => download.await,
+        };
+        // Before the outcome is recorded, so it's in the final record
+        let _ = db.add_active_time(&record.id, started.elapsed());
+        // Progress is only saved now and then, so a download stopped partway
+        // records exactly what its partial (kept, or in the trash) holds;
+        // it resumes from no further than the record says
+        if let Err(e) = &result {
+            if e.is::<download::Cancelled>() {
+                let partial = settings.partial_path(&record.destination, &record.filename);
+                let kept = match tokio::fs::metadata(&partial).await {
+                    Ok(meta) => Some(meta),
+                    Err(_) => tokio::fs::metadata(trash_path(&record.destination, &record.id)).await.ok(),
+                };
+                if let (Some(kept), Ok(Some(current))) = (kept, db.get_download(&record.id)) {
+                    let _ = db.update_progress(&record.id, kept.len(), current.total_size);
+                }
+            }
+        }
         
         match result {
-            Ok(_) => {
+            Ok(FinishedDownload { size: downloaded, filename, final_url, validators, not_modified, checksum }) => {
+                if filename != record.filename || record.rename_to.is_some() {
+                    let _ = db.update_filename(&record.id, &filename);
+                    record.filename = filename;
+                    record.rename_to = None;
+                }
+                let _ = db.set_final_url(&record.id, &final_url);
+                record.final_url = Some(final_url);
+                let _ = db.set_validators(&record.id, validators.etag.as_deref(), validators.last_modified.as_deref());
+                // A verified download already has its checksum
+                if let Some(checksum) = checksum.filter(|_| record.checksum.is_none()) {
+                    let _ = db.set_checksum(&record.id, &checksum);
+                    record.checksum = Some(checksum);
+                }
+                if not_modified {
+                    let _ = db.set_not_modified(&record.id);
+                } else {
+                    download_manager.record_duration(started.elapsed());
+                    download_manager.record_completed_bytes(downloaded);
+                }
+                // The size is known now even if the server never reported it
+                let _ = db.update_progress(&record.id, downloaded, Some(downloaded));
                 let _ = db.update_status(&record.id, DownloadStatus::Completed, None);
                 let _ = progress_tx.send(download::ProgressUpdate {
                     id: record.id.clone(),
-                    downloaded: record.total_size.unwrap_or(0),
-                    total: record.total_size,
+                    downloaded,
+                    total: Some(downloaded),
+                    indeterminate: false,
                     speed: 0,
                     status: DownloadStatus::Completed,
                     error: None,
+                    started_at: None,
+                });
+                
+                record.total_size = Some(downloaded);
+                send_webhook(
+                    &settings,
+                    &client,
+                    download::WebhookEvent::finished(&record, DownloadStatus::Completed, None),
+                );
+                // Nothing new arrived, so there's nothing to extract, link,
+                // convert or hand to the hook
+                if !not_modified {
+                    tokio::spawn(process_completed(Arc::clone(&state), record.clone(), settings.clone()));
+                }
+            }
+            Err(e) if matches!(e.downcast_ref(), Some(download::Cancelled(CancelReason::Paused))) => {
+                // Stays out of the queue, partial file and all, until resumed
+                let _ = db.update_status(&record.id, DownloadStatus::Paused, None);
+                let _ = progress_tx.send(download::ProgressUpdate {
+                    id: record.id.clone(),
+                    downloaded: db.get_download(&record.id).ok().flatten().map_or(0, |r| r.downloaded_size),
+                    total: record.total_size,
+                    indeterminate: record.total_size.is_none(),
+                    speed: 0,
+                    status: DownloadStatus::Paused,
+                    error: None,
+                    started_at: None,
                 });
             }
+            Err(e) if matches!(e.downcast_ref(), Some(download::Cancelled(CancelReason::Metered))) => {
+                // Waits at the head of the queue, partial file and all, until
+                // the connection is unmetered
+                let _ = db.update_status(&record.id, DownloadStatus::Queued, None);
+                let record = db.get_download(&record.id).ok().flatten().unwrap_or(record.clone());
+                download_manager.requeue_paused(record);
+            }
             Err(e) => {
                 let error_msg = e.to_string();
-                let status = if error_msg.contains("cancelled") {
+                let status = if let Some(download::Cancelled(reason)) = e.downcast_ref() {
+                    let _ = db.mark_cancelled(&record.id, *reason);
                     DownloadStatus::Cancelled
                 } else {
+                    let _ = db.mark_failed(&record.id, download::classify_error(&e), &error_msg);
+                    send_webhook(
+                        &settings,
+                        &client,
+                        download::WebhookEvent::finished(&record, DownloadStatus::Failed, Some(error_msg.clone())),
+                    );
                     DownloadStatus::Failed
                 };
-                let _ = db.update_status(&record.id, status.clone(), Some(&error_msg));
                 let _ = progress_tx.send(download::ProgressUpdate {
                     id: record.id.clone(),
                     downloaded: 0,
                     total: None,
+                    indeterminate: false,
                     speed: 0,
                     status,
                     error: Some(error_msg),
+                    started_at: None,
                 });
             }
         }
         
+        // Leave the active set only once the outcome is recorded, so a
+        // shutdown waiting on active downloads sees their final status
+        download_manager.remove_active(&record.id);
+        drop(slot);
+        trim_history(&state);
+        
         // Try to start next queued download
-        if let Some(next) = download_manager.dequeue() {
-            // Rebuild a minimal state for the next download
-            let next_state = Arc::new(AppState {
-                settings: RwLock::new(settings),
-                db: db.clone(),
-                download_manager: download_manager.clone(),
-            });
-            start_download(next_state, next);
-        }
+        start_queued(&state);
     });
 }
 
-/// Remove a download
-async fn remove_download(
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<String>,
-) -> Response {
-    // Cancel if active
-    state.download_manager.cancel(&id).await;
-    
-    // Remove from database
-    if let Err(e) = state.db.delete_download(&id) {
-        return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "error": e.to_string() }))).into_response();
+/// Delete the oldest finished records beyond `max_history_records`
+fn trim_history(state: &AppState) {
+    let keep = state.settings.read().max_history_records;
+    if keep == 0 {
+        return;
     }
-    
-    StatusCode::NO_CONTENT.into_response()
-}
-
-/// Cancel an active download
-async fn cancel_download(
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<String>,
-) -> Response {
-    if state.download_manager.cancel(&id).await {
-        (StatusCode::OK, Json(serde_json::json!({ "success": true }))).into_response()
-    } else {
-        (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": "Download not found or already completed" }))).into_response()
+    match state.db.trim_history(keep) {
+        Ok(0) => {}
+        Ok(removed) => {
+            info!("Removed {} old download records", removed);
+            state.download_manager.notify_change(download::DownloadChange::resync());
+        }
+        Err(e) => tracing::error!("Failed to trim download history: {}", e),
     }
 }
 
-/// Get download statistics
-async fn download_stats(
-    State(state): State<Arc<AppState>>,
-) -> Json<DownloadStats> {
-    Json(state.download_manager.stats())
-}
-
-// ============ URL Info Endpoint ============
-
-#[derive(Debug, Deserialize)]
-pub struct UrlInfoRequest {
-    pub url: String,
-}
-
-#[derive(Debug, Serialize)]
-pub struct UrlInfoResponse {
-    pub filename: Option<String>,
-    pub size: Option<u64>,
-    pub content_type: Option<String>,
-}
-
-/// Get file info from URL via HEAD request, falling back to GET with range if needed
-async fn get_url_info(
-    Json(req): Json<UrlInfoRequest>,
-) -> Result<Json<UrlInfoResponse>, AppError> {
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(15))
-        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
-        .redirect(reqwest::redirect::Policy::limited(10))
-        .build()
-        .map_err(|e| AppError::Internal(e.to_string()))?;
+/// Work through what follows a completed download, one step at a time so
+/// none runs on a file another is replacing or deleting: extract it,
+/// share it with a duplicate, link it into other file types, convert it,
+/// then run the hook on the file the download ended up as. A step that
+/// fails is recorded without failing the download.
+async fn process_completed(state: Arc<AppState>, mut record: DownloadRecord, settings: Settings) {
+    let auto_extract = settings
+        .file_types
+        .get(&record.file_type)
+        .is_some_and(|t| t.auto_extract);
+    if auto_extract && download::is_extractable(&record.filename) {
+        let archive = record.destination.join(&record.filename);
+        let result = tokio::task::spawn_blocking(move || download::extract_archive(&archive))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r);
+        match result {
+            Ok(dir) => {
+                info!("Extracted {} to {}", record.filename, dir.display());
+                let _ = state.db.set_extracted_path(&record.id, &dir);
+            }
+            Err(e) => {
+                let message = format!("Failed to extract {}: {}", record.filename, e);
+                tracing::warn!("{}", message);
+                let _ = state.db.record_error(&record.id, &message);
+            }
+        }
+    }
     
-    // Try HEAD request first
-    let head_response = client
-        .head(&req.url)
-        .send()
-        .await;
+    // Linked copies are of the download itself, so they go before a
+    // transcode that may delete it
+    if settings.dedupe_by_checksum {
+        deduplicate(&state, &record).await;
+    }
+    link_copies(&state, &record).await;
+    if let Some(filename) = transcode_download(&state, &record).await {
+        record.filename = filename;
+    }
     
-    let (filename, size, content_type) = match head_response {
-        Ok(response) if response.status().is_success() => {
-            let headers = response.headers();
-            let filename = headers
-                .get("content-disposition")
-                .and_then(|v| v.to_str().ok())
-                .and_then(|v| parse_content_disposition(v));
-            let size = headers
-                .get("content-length")
-                .and_then(|v| v.to_str().ok())
-                .and_then(|v| v.parse().ok());
-            let content_type = headers
-                .get("content-type")
-                .and_then(|v| v.to_str().ok())
-                .map(|s| s.split(';').next().unwrap_or(s).trim().to_string());
-            
-            // If HEAD didn't give us a filename, try GET with range
-            if filename.is_none() {
-                match try_get_with_range(&client, &req.url).await {
-                    Some((get_filename, get_size, get_content_type)) => {
-                        (get_filename, get_size.or(size), get_content_type.or(content_type))
-                    }
-                    None => (filename, size, content_type)
-                }
-            } else {
-                (filename, size, content_type)
+    if settings.allow_command_hooks {
+        if let Some(command) = &settings.post_complete_command {
+            let path = record.destination.join(&record.filename);
+            if let Err(e) = download::run_post_complete_command(command, &path, &record.filename, &record.url).await {
+                tracing::warn!("{}", e);
+                let _ = state.db.record_error(&record.id, &e);
             }
         }
-        _ => {
-            // HEAD failed or returned error, try GET with range
-            try_get_with_range(&client, &req.url).await
-                .unwrap_or((None, None, None))
+    }
+}
+
+/// Replace a completed download's file with a hard link to the file of an
+/// earlier completed download with the same checksum, recording which one it
+/// shares. The earlier file is hashed again first, in case it changed since.
+/// Without a match, or one that can be linked to, the file stays as it is.
+async fn deduplicate(state: &AppState, record: &DownloadRecord) {
+    let Some(checksum) = record.checksum.as_deref().and_then(download::Checksum::parse) else { return };
+    let originals = match state.db.find_completed_by_checksum(&checksum.to_string(), &record.id) {
+        Ok(originals) => originals,
+        Err(e) => {
+            tracing::warn!("Failed to look for duplicates of {}: {}", record.filename, e);
+            return;
         }
     };
     
-    // Fall back to URL path for filename
-    let filename = filename.or_else(|| extract_filename_from_url(&req.url));
-    
-    Ok(Json(UrlInfoResponse {
-        filename,
-        size,
-        content_type,
-    }))
+    let path = record.destination.join(&record.filename);
+    for original in originals {
+        let source = original.destination.join(&original.filename);
+        let (path, checksum) = (path.clone(), checksum.clone());
+        let linked = tokio::task::spawn_blocking(move || {
+            let same_size = match (std::fs::metadata(&source), std::fs::metadata(&path)) {
+                (Ok(a), Ok(b)) => a.len() == b.len(),
+                _ => false,
+            };
+            if !same_size || download::file_digest(&source, checksum.algorithm).ok() != Some(checksum.digest) {
+                return Ok(false);
+            }
+            crate::fs::replace_with_link(&source, &path)
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|r| r);
+        
+        match linked {
+            Ok(false) => continue,
+            Ok(true) => {
+                info!("{} is a duplicate of {}, now sharing its file", record.filename, original.filename);
+                let original_id = original.shares_file_with.unwrap_or(original.id);
+                let _ = state.db.set_shares_file_with(&record.id, &original_id);
+            }
+            Err(e) => {
+                let message = format!("Failed to deduplicate {}: {:#}", record.filename, e);
+                tracing::warn!("{}", message);
+                let _ = state.db.record_error(&record.id, &message);
+            }
+        }
+        return;
+    }
 }
 
-/// Try to get file info using GET request with Range header (downloads minimal data)
-async fn try_get_with_range(
-    client: &reqwest::Client,
-    url: &str,
-) -> Option<(Option<String>, Option<u64>, Option<String>)> {
-    // Request only first byte to minimize data transfer
-    let response = client
-        .get(url)
-        .header("Range", "bytes=0-0")
-        .send()
-        .await
-        .ok()?;
-    
-    if !response.status().is_success() && response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
-        return None;
+/// Link (or copy) a completed download's file into the folder of each of its
+/// `link_file_types`, adding a completed download linked from it for each.
+/// One that fails is recorded on the download without failing it.
+async fn link_copies(state: &AppState, record: &DownloadRecord) {
+    for file_type in &record.link_file_types {
+        if let Err(e) = link_copy(state, record, file_type).await {
+            let message = format!("Failed to link {} into {}: {:#}", record.filename, file_type, e);
+            tracing::warn!("{}", message);
+            let _ = state.db.record_error(&record.id, &message);
+        }
+    }
+}
+
+async fn link_copy(state: &AppState, record: &DownloadRecord, file_type: &str) -> anyhow::Result<()> {
+    let (destination, policy) = {
+        let settings = state.settings.read();
+        let config = settings
+            .file_types
+            .get(file_type)
+            .ok_or_else(|| anyhow::anyhow!("the file type no longer exists"))?;
+        (config.destination.clone(), settings.conflict_policy(file_type))
+    };
+    // The file is already there
+    if same_folder(&record.destination, &destination) {
+        return Ok(());
     }
     
-    let headers = response.headers();
-    
-    let filename = headers
-        .get("content-disposition")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| parse_content_disposition(v));
-    
-    // For range requests, Content-Range header has full size: "bytes 0-0/12345"
-    let size = headers
-        .get("content-range")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.split('/').last())
-        .and_then(|v| v.parse().ok())
-        .or_else(|| {
-            headers
-                .get("content-length")
-                .and_then(|v| v.to_str().ok())
-                .and_then(|v| v.parse().ok())
-        });
+    let reserved = state.download_manager.filenames().reserve(&destination, &record.filename, policy);
+    let filename = reserved.filename().to_string();
+    let (from, to) = (record.destination.join(&record.filename), destination.join(&filename));
+    let linked = tokio::task::spawn_blocking(move || {
+        crate::fs::ensure_writable_dir(to.parent().unwrap_or(&to))?;
+        crate::fs::link_or_copy(&from, &to)
+    })
+    .await??;
     
-    let content_type = headers
-        .get("content-type")
-        .and_then(|v| v.to_str().ok())
-        .map(|s| s.split(';').next().unwrap_or(s).trim().to_string());
+    let mut copy = DownloadRecord::new(record.url.clone(), filename, file_type.to_string(), destination);
+    copy.status = DownloadStatus::Completed;
+    copy.total_size = record.total_size;
+    copy.downloaded_size = record.total_size.unwrap_or_default();
+    copy.checksum = record.checksum.clone();
+    copy.final_url = record.final_url.clone();
+    copy.linked_from = Some(record.id.clone());
+    state.db.insert_download(&copy)?;
+    state.db.update_status(&copy.id, DownloadStatus::Completed, None)?;
+    state
+        .download_manager
+        .notify_change(download::DownloadChange::new(&copy.id, download::ChangeKind::Added));
     
-    Some((filename, size, content_type))
+    info!(
+        "{} {} into {}",
+        if linked { "Linked" } else { "Copied" },
+        record.filename,
+        copy.destination.display()
+    );
+    Ok(())
 }
 
-/// Parse filename from Content-Disposition header
-fn parse_content_disposition(header: &str) -> Option<String> {
-    // Handle formats like:
-    // attachment; filename="file.zip"
-    // attachment; filename*=UTF-8''file%20name.zip
-    // attachment; filename=file.zip
+/// Convert a completed download with ffmpeg if its file type has a
+/// `post_download_transcode` and `ffmpeg_path` is set. The download is
+/// complete either way, so a failed conversion is only recorded on it.
+/// Returns the converted file's name if the download now stands for it,
+/// the original having been deleted.
+async fn transcode_download(state: &AppState, record: &DownloadRecord) -> Option<String> {
+    let (ffmpeg, transcode, policy) = {
+        let settings = state.settings.read();
+        let transcode = settings
+            .file_types
+            .get(&record.file_type)
+            .and_then(|t| t.post_download_transcode.clone())?;
+        let Some(ffmpeg) = settings.ffmpeg_path.clone() else {
+            tracing::debug!("ffmpeg_path isn't set, not transcoding {}", record.filename);
+            return None;
+        };
+        (ffmpeg, transcode, settings.conflict_policy(&record.file_type))
+    };
+    // Checked when set through the API, but the config file can say anything
+    if !transcode.is_valid() {
+        tracing::warn!("Not transcoding {}: invalid format {:?}", record.filename, transcode.format);
+        return None;
+    }
+    let filename = download::transcoded_filename(&record.filename, &transcode.format)?;
     
-    // Try filename*= (RFC 5987) first
-    if let Some(start) = header.find("filename*=") {
-        let value = &header[start + 10..];
-        if let Some(quote_start) = value.find("''") {
-            let encoded = value[quote_start + 2..].split(';').next().unwrap_or("");
-            if let Ok(decoded) = urlencoding::decode(encoded.trim_matches('"').trim()) {
-                return Some(decoded.into_owned());
-            }
-        }
+    let reserved = state.download_manager.filenames().reserve(&record.destination, &filename, policy);
+    let filename = reserved.filename().to_string();
+    let input = record.destination.join(&record.filename);
+    let output = record.destination.join(&filename);
+    if let Err(e) = download::transcode(&ffmpeg, &input, &output).await {
+        let message = format!("Failed to transcode {}: {}", record.filename, e);
+        tracing::warn!("{}", message);
+        let _ = state.db.record_error(&record.id, &message);
+        return None;
     }
+    let _ = state.db.set_transcoded_path(&record.id, &output);
     
-    // Try filename= with quotes
-    if let Some(start) = header.find("filename=\"") {
-        let value = &header[start + 10..];
-        if let Some(end) = value.find('"') {
-            return Some(value[..end].to_string());
+    if transcode.delete_original {
+        if let Err(e) = tokio::fs::remove_file(&input).await {
+            let message = format!("Transcoded {} but failed to delete it: {}", record.filename, e);
+            tracing::warn!("{}", message);
+            let _ = state.db.record_error(&record.id, &message);
+            return None;
+        }
+        // The download now stands for the converted file
+        let _ = state.db.update_location(&record.id, &record.destination, &filename);
+        if let Ok(meta) = tokio::fs::metadata(&output).await {
+            let _ = state.db.update_progress(&record.id, meta.len(), Some(meta.len()));
         }
+        return Some(filename);
     }
-    
-    // Try filename= without quotes
-    if let Some(start) = header.find("filename=") {
-        let value = &header[start + 9..];
-        let filename = value.split(';').next().unwrap_or(value).trim();
-        if !filename.is_empty() {
-            return Some(filename.trim_matches('"').to_string());
+    None
+}
+
+/// Whether two paths name the same existing folder
+fn same_folder(a: &std::path::Path, b: &std::path::Path) -> bool {
+    matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+/// POST `event` to the configured webhook, if any, without waiting for it.
+/// A webhook that can't be reached is only logged.
+fn send_webhook(settings: &Settings, clients: &download::HttpClients, event: download::WebhookEvent) {
+    let Some(url) = settings.webhook_url.clone() else { return };
+    let clients = clients.clone();
+    tokio::spawn(async move {
+        if let Err(e) = download::send_webhook(clients.for_url(&url), &url, &event).await {
+            tracing::warn!("{}", e);
+        }
+    });
+}
+
+/// Start queued downloads for as long as slots are free
+fn start_queued(state: &Arc<AppState>) {
+    while let Some((next, slot)) = state.download_manager.dequeue() {
+        start_download(state.clone(), next, slot);
+    }
+}
+
+/// Put downloads resumed on startup back in the queue one every `stagger`,
+/// starting each as a slot allows, so they don't all connect at once.
+/// Downloads added meanwhile queue and start as usual.
+async fn requeue_staggered(state: Arc<AppState>, resumed: Vec<DownloadRecord>, stagger: std::time::Duration) {
+    let count = resumed.len();
+    for (i, download) in resumed.into_iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(stagger).await;
         }
+        // It may have been cancelled, paused or removed while it waited
+        let still_queued = state
+            .db
+            .get_download(&download.id)
+            .ok()
+            .flatten()
+            .is_some_and(|record| record.status == DownloadStatus::Queued);
+        if still_queued {
+            state.download_manager.requeue(download);
+            start_queued(&state);
+        }
+    }
+    if count > 0 {
+        info!("Resumed {} downloads", count);
     }
+}
+
+/// How often the connection is checked for `pause_on_metered`
+const METERED_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Pause the queue while the connection is metered and `pause_on_metered`
+/// is on: running downloads go back to the head of the queue keeping their
+/// partial files, and nothing starts until the connection is unmetered or
+/// the setting is turned off. A connection whose cost can't be told counts
+/// as unmetered.
+pub async fn check_metered(state: &Arc<AppState>) {
+    let metered = state.settings.read().pause_on_metered
+        && tokio::task::spawn_blocking(super::network::is_metered)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or(false);
     
-    None
+    let manager = &state.download_manager;
+    if metered == manager.is_queue_paused() {
+        return;
+    }
+    manager.set_queue_paused(metered);
+    if metered {
+        info!("Connection is metered, pausing downloads");
+        manager.cancel_all_active(CancelReason::Metered);
+    } else {
+        info!("Resuming downloads");
+        start_queued(state);
+    }
+    manager.notify_change(download::DownloadChange::resync());
 }
 
-/// Extract filename from URL path as fallback
-fn extract_filename_from_url(url_str: &str) -> Option<String> {
-    url::Url::parse(url_str).ok().and_then(|url| {
-        let path = url.path();
-        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
-        segments.last().and_then(|s| {
-            let decoded = urlencoding::decode(s).ok()?.into_owned();
-            // Only return if it looks like a filename (has extension)
-            if decoded.contains('.') && !decoded.starts_with('.') {
-                Some(decoded)
-            } else {
-                None
+/// Keep checking the connection for `pause_on_metered`. The setting is read
+/// each time, so turning it on or off applies without a restart.
+pub async fn watch_metered(state: Arc<AppState>) {
+    loop {
+        tokio::time::sleep(METERED_CHECK_INTERVAL).await;
+        check_metered(&state).await;
+    }
+}
+
+/// Write the progress running downloads staged to the database every
+/// `progress_save_interval_secs`, all in one transaction, rather than each
+/// download writing its own. The setting is read each time.
+pub async fn save_progress_periodically(state: Arc<AppState>) {
+    loop {
+        let interval = state.settings.read().progress_save_interval_secs.max(1);
+        tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+        save_staged_progress(&state);
+    }
+}
+
+fn save_staged_progress(state: &AppState) {
+    let staged = state.download_manager.take_staged_progress();
+    if staged.is_empty() {
+        return;
+    }
+    let progress = staged.iter().map(|(id, &(downloaded, total))| (id.as_str(), downloaded, total));
+    if let Err(e) = state.db.save_running_progress(progress) {
+        tracing::warn!("Failed to save download progress: {}", e);
+    }
+}
+
+/// How long removing a running download waits for it to stop
+const REMOVAL_STOP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Cancel a download about to be removed and clear away its partial file
+async fn stop_for_removal(state: &AppState, id: &str) {
+    // Cancel if active
+    let running = state.download_manager.is_active(id);
+    state.download_manager.cancel(id, CancelReason::UserRequested).await;
+    if running {
+        // The task moves its partial to the trash as it stops, so wait for
+        // that before clearing the trash
+        let stopped = tokio::time::timeout(REMOVAL_STOP_TIMEOUT, async {
+            while state.download_manager.is_active(id) {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
             }
         })
-    })
+        .await;
+        if stopped.is_err() {
+            tracing::warn!("Download {} is still stopping; its partial file is left to the trash sweep", id);
+        }
+    } else {
+        discard_partial(state, id, false).await;
+    }
+    // With the record gone the cancellation can't be undone
+    if let Ok(Some(record)) = state.db.get_download(id) {
+        let _ = tokio::fs::remove_file(trash_path(&record.destination, &record.id)).await;
+    }
 }
 
-// ============ Settings Endpoints ============
-
-/// Settings response (excluding sensitive data)
-#[derive(Debug, Serialize)]
-pub struct SettingsResponse {
-    pub server_port: u16,
-    pub max_concurrent_downloads: usize,
-    pub start_on_login: bool,
-    pub start_on_boot: bool,
-    pub start_on_boot_available: bool,
+/// Tell clients a download is gone
+fn announce_removal(state: &AppState, id: &str) {
+    state.download_manager.forget_speeds(id);
+    state
+        .download_manager
+        .notify_change(download::DownloadChange::new(id, download::ChangeKind::Removed));
 }
 
-/// Get current settings
-async fn get_settings(
+/// Remove a download
+async fn remove_download(
     State(state): State<Arc<AppState>>,
-) -> Json<SettingsResponse> {
-    let settings = state.settings.read();
-    Json(SettingsResponse {
-        server_port: settings.server.port,
-        max_concurrent_downloads: settings.max_concurrent_downloads,
-        start_on_login: settings.start_on_login,
-        start_on_boot: settings.start_on_boot,
-        start_on_boot_available: cfg!(target_os = "linux"),
-    })
+    Path(id): Path<String>,
+) -> Response {
+    stop_for_removal(&state, &id).await;
+    
+    // Remove from database
+    if let Err(e) = state.db.delete_download(&id) {
+        return AppError::Internal(e.to_string()).into_response();
+    }
+    announce_removal(&state, &id);
+    
+    StatusCode::NO_CONTENT.into_response()
 }
 
-/// Update settings request
+/// Request to remove several downloads at once
 #[derive(Debug, Deserialize)]
-pub struct UpdateSettingsRequest {
-    pub max_concurrent_downloads: Option<usize>,
-    pub start_on_login: Option<bool>,
-    pub start_on_boot: Option<bool>,
+pub struct RemoveDownloadsRequest {
+    pub ids: Vec<String>,
+    /// Also delete the files of completed downloads
+    #[serde(default)]
+    pub delete_files: bool,
 }
 
-/// Update settings
-async fn update_settings(
+/// What became of one id of a `RemoveDownloadsRequest`
+#[derive(Debug, Serialize)]
+pub struct RemovedDownload {
+    pub id: String,
+    pub removed: bool,
+    /// Files of the download deleted from disk
+    pub files_deleted: Vec<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Remove several downloads: cancel the active ones, then delete all the
+/// records in one transaction. With `delete_files`, the files of completed
+/// ones go too, except where another remaining download completed to the
+/// same path. An unknown id is reported without stopping the rest.
+async fn remove_downloads(
     State(state): State<Arc<AppState>>,
-    Json(req): Json<UpdateSettingsRequest>,
-) -> Result<Json<SettingsResponse>, AppError> {
-    let mut settings = state.settings.write();
-    
-    if let Some(max) = req.max_concurrent_downloads {
-        settings.max_concurrent_downloads = max;
-        state.download_manager.set_max_concurrent(max);
+    Json(req): Json<RemoveDownloadsRequest>,
+) -> Result<Json<Vec<RemovedDownload>>, AppError> {
+    if req.ids.is_empty() {
+        return Err(AppError::BadRequest("No downloads to remove".into()));
     }
     
-    if let Some(start) = req.start_on_login {
-        settings.start_on_login = start;
-        
-        // Configure auto-launch
-        if let Err(e) = configure_auto_launch(start) {
-            tracing::error!("Failed to configure auto-launch: {}", e);
+    // Position of each id in the request, for answering in the same order
+    let mut order = HashMap::new();
+    let mut results = Vec::with_capacity(req.ids.len());
+    let mut records = Vec::new();
+    for id in req.ids {
+        if order.contains_key(&id) {
+            continue;
+        }
+        order.insert(id.clone(), order.len());
+        match state.db.get_download(&id)? {
+            Some(record) => {
+                stop_for_removal(&state, &id).await;
+                records.push(record);
+            }
+            None => results.push(RemovedDownload {
+                id,
+                removed: false,
+                files_deleted: Vec::new(),
+                error: Some("Download not found".into()),
+            }),
         }
     }
     
-    if let Some(start) = req.start_on_boot {
-        settings.start_on_boot = start;
-        
-        // Configure systemd service (Linux only)
-        #[cfg(target_os = "linux")]
-        if let Err(e) = configure_systemd_service(start) {
-            tracing::error!("Failed to configure systemd service: {}", e);
+    let ids: Vec<&str> = records.iter().map(|record| record.id.as_str()).collect();
+    state.db.delete_downloads(&ids)?;
+    
+    // Paths still in use by a download that stays, such as an earlier
+    // download of a URL that later came back not modified
+    let kept: HashSet<PathBuf> = if req.delete_files {
+        state
+            .db
+            .get_all_downloads()?
+            .into_iter()
+            .filter(|record| record.status == DownloadStatus::Completed)
+            .flat_map(|record| {
+                let path = record.destination.join(&record.filename);
+                std::iter::once(path).chain(record.transcoded_path)
+            })
+            .collect()
+    } else {
+        HashSet::new()
+    };
+    
+    for record in records {
+        let mut files_deleted = Vec::new();
+        if req.delete_files && record.status == DownloadStatus::Completed {
+            let paths = std::iter::once(record.destination.join(&record.filename)).chain(record.transcoded_path);
+            for path in paths {
+                if !kept.contains(&path) && tokio::fs::remove_file(&path).await.is_ok() {
+                    files_deleted.push(path);
+                }
+            }
         }
+        announce_removal(&state, &record.id);
+        results.push(RemovedDownload {
+            id: record.id,
+            removed: true,
+            files_deleted,
+            error: None,
+        });
     }
+    results.sort_by_key(|result| order[&result.id]);
     
-    // Save to file
-    config::save(&settings)?;
-    
-    Ok(Json(SettingsResponse {
-        server_port: settings.server.port,
-        max_concurrent_downloads: settings.max_concurrent_downloads,
-        start_on_login: settings.start_on_login,
-        start_on_boot: settings.start_on_boot,
-        start_on_boot_available: cfg!(target_os = "linux"),
-    }))
+    Ok(Json(results))
 }
 
-/// Configure auto-launch on system startup
-fn configure_auto_launch(enable: bool) -> Result<(), String> {
-    let exe_path = std::env::current_exe()
-        .map_err(|e| format!("Failed to get executable path: {}", e))?;
-    
-    let exe_path_str = exe_path.to_string_lossy().to_string();
-    
-    let auto_launch = AutoLaunchBuilder::new()
-        .set_app_name("Vibe Downloader")
-        .set_app_path(&exe_path_str)
-        .set_use_launch_agent(true) // macOS: use LaunchAgent instead of login items
-        .build()
-        .map_err(|e| format!("Failed to build auto-launch: {}", e))?;
-    
-    if enable {
-        auto_launch.enable().map_err(|e| format!("Failed to enable auto-launch: {}", e))?;
-        info!("Auto-launch enabled");
-    } else {
-        // Only disable if currently enabled
-        if auto_launch.is_enabled().unwrap_or(false) {
-            auto_launch.disable().map_err(|e| format!("Failed to disable auto-launch: {}", e))?;
-            info!("Auto-launch disabled");
+/// Cancel an active download
+async fn cancel_download(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Response {
+    let running = state.download_manager.is_active(&id);
+    if state.download_manager.cancel(&id, CancelReason::UserRequested).await {
+        if !running {
+            discard_partial(&state, &id, true).await;
         }
+        (StatusCode::OK, Json(serde_json::json!({ "success": true }))).into_response()
+    } else if state.db.get_download(&id).ok().flatten().is_some_and(|r| r.status == DownloadStatus::Queued) {
+        // Neither running nor in the queue, so resumed on startup and still
+        // waiting for `requeue_staggered` to put it back
+        let _ = state.db.mark_cancelled(&id, CancelReason::UserRequested);
+        discard_partial(&state, &id, true).await;
+        let _ = state.download_manager.progress_sender().send(download::ProgressUpdate {
+            id: id.clone(),
+            downloaded: 0,
+            total: None,
+            indeterminate: false,
+            speed: 0,
+            status: DownloadStatus::Cancelled,
+            error: Some(download::Cancelled(CancelReason::UserRequested).to_string()),
+            started_at: None,
+        });
+        (StatusCode::OK, Json(serde_json::json!({ "success": true }))).into_response()
+    } else {
+        AppError::NotFound("Download not found or already completed".into()).into_response()
     }
-    
-    Ok(())
 }
 
-/// Configure systemd user service for boot startup (Linux only)
-#[cfg(target_os = "linux")]
-fn configure_systemd_service(enable: bool) -> Result<(), String> {
-    use std::process::Command;
-    
-    let exe_path = std::env::current_exe()
-        .map_err(|e| format!("Failed to get executable path: {}", e))?;
-    
-    let service_dir = dirs::config_dir()
-        .ok_or("Could not find config directory")?
-        .join("systemd/user");
-    
-    let service_path = service_dir.join("vibe-downloader.service");
-    
-    if enable {
-        // Create systemd user service directory
-        std::fs::create_dir_all(&service_dir)
-            .map_err(|e| format!("Failed to create systemd directory: {}", e))?;
-        
-        // Create service file
-        let service_content = format!(
-            r#"[Unit]
-Description=Vibe Downloader - Download Manager with Web UI
-After=network.target
+/// Delete the partial file of a download that isn't running, such as one
+/// queued to resume after a restart, or with `trash` move it to the trash
+/// like a cancelled running download's; a running download does this itself
+/// once it sees the cancellation. Completed downloads are skipped: they have
+/// no partial, and another download may be using the same name by now.
+async fn discard_partial(state: &AppState, id: &str, trash: bool) {
+    let Ok(Some(record)) = state.db.get_download(id) else { return };
+    if record.status == DownloadStatus::Completed {
+        return;
+    }
+    let settings = state.settings.read().clone();
+    if trash {
+        discard_cancelled_partial(&settings, &record, CancelReason::UserRequested).await;
+    } else {
+        let _ = tokio::fs::remove_file(settings.partial_path(&record.destination, &record.filename)).await;
+    }
+}
 
-[Service]
-Type=simple
-ExecStart={}
-Restart=on-failure
-RestartSec=5
-Environment=DISPLAY=:0
+/// Folder beside the partial files that those of cancelled downloads are
+/// kept in for `cancelled_partial_retention_minutes`
+const TRASH_DIR: &str = ".trash";
 
-[Install]
-WantedBy=default.target
-"#,
-            exe_path.display()
-        );
-        
-        std::fs::write(&service_path, service_content)
-            .map_err(|e| format!("Failed to write service file: {}", e))?;
-        
-        // Reload systemd and enable service
-        Command::new("systemctl")
-            .args(["--user", "daemon-reload"])
-            .output()
-            .map_err(|e| format!("Failed to reload systemd: {}", e))?;
-        
-        Command::new("systemctl")
-            .args(["--user", "enable", "vibe-downloader.service"])
-            .output()
-            .map_err(|e| format!("Failed to enable service: {}", e))?;
-        
-        // Enable lingering so service starts at boot without login
-        let user = std::env::var("USER").unwrap_or_else(|_| "root".to_string());
-        let linger_result = Command::new("loginctl")
-            .args(["enable-linger", &user])
-            .output();
-        
-        if let Err(e) = linger_result {
-            tracing::warn!("Failed to enable linger (may need sudo): {}", e);
+/// How often expired partial files are removed from the trash
+const TRASH_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Where the partial file of cancelled download `id` is kept, by id since
+/// its name may be taken by the time it's restored
+fn trash_path(destination: &std::path::Path, id: &str) -> PathBuf {
+    destination.join(TRASH_DIR).join(format!("{}.part", id))
+}
+
+/// Get rid of the partial file of a download cancelled for `reason`. One
+/// the user cancelled goes to the trash while
+/// `cancelled_partial_retention_minutes` is set, so the download can be
+/// restored; anything else, or one that can't be moved, is deleted.
+async fn discard_cancelled_partial(settings: &Settings, record: &DownloadRecord, reason: CancelReason) {
+    let partial = settings.partial_path(&record.destination, &record.filename);
+    if reason == CancelReason::UserRequested && settings.cancelled_partial_retention_minutes > 0 {
+        let trash = trash_path(&record.destination, &record.id);
+        let from = partial.clone();
+        let moved = tokio::task::spawn_blocking(move || -> anyhow::Result<bool> {
+            if !from.exists() {
+                return Ok(false);
+            }
+            std::fs::create_dir_all(trash.parent().unwrap_or(&trash))?;
+            crate::fs::move_file(&from, &trash)?;
+            // The retention counts from now, not from the last write
+            std::fs::File::options().write(true).open(&trash)?.set_modified(std::time::SystemTime::now())?;
+            Ok(true)
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|r| r);
+        match moved {
+            Ok(_) => return,
+            Err(e) => tracing::warn!("Failed to keep the partial file of {}: {:#}", record.filename, e),
         }
-        
-        info!("Systemd service enabled for boot startup");
-    } else {
-        // Disable and remove service
-        let _ = Command::new("systemctl")
-            .args(["--user", "disable", "vibe-downloader.service"])
-            .output();
-        
-        let _ = Command::new("systemctl")
-            .args(["--user", "stop", "vibe-downloader.service"])
-            .output();
-        
-        if service_path.exists() {
-            let _ = std::fs::remove_file(&service_path);
+    }
+    let _ = tokio::fs::remove_file(partial).await;
+}
+
+/// Remove partial files kept in the trash beyond
+/// `cancelled_partial_retention_minutes`, checking the trash folders of the
+/// file types' destinations and their date subfolders, and of cancelled
+/// downloads
+pub async fn sweep_trash_periodically(state: Arc<AppState>) {
+    // Folders found with a trash stay swept until it's empty, so partials
+    // of downloads removed from the history since still expire
+    let mut known: HashSet<PathBuf> = HashSet::new();
+    loop {
+        tokio::time::sleep(TRASH_SWEEP_INTERVAL).await;
+        let (file_types, retention) = {
+            let settings = state.settings.read();
+            let file_types: Vec<(PathBuf, Option<String>)> = settings
+                .file_types
+                .values()
+                .map(|t| (t.destination.clone(), t.date_subfolder.clone()))
+                .collect();
+            (file_types, settings.cancelled_partial_retention_minutes)
+        };
+        known.extend(state.db.cancelled_destinations().unwrap_or_default());
+        let retention = std::time::Duration::from_secs(retention.saturating_mul(60));
+        let mut folders = std::mem::take(&mut known);
+        let (removed, left) = tokio::task::spawn_blocking(move || {
+            for (destination, date_subfolder) in file_types {
+                if let Some(pattern) = date_subfolder {
+                    let depth = std::path::Path::new(&pattern).components().count();
+                    folders.extend(subfolders_at_depth(&destination, depth));
+                }
+                folders.insert(destination);
+            }
+            let folders: Vec<PathBuf> = folders.into_iter().collect();
+            let removed = sweep_trash(&folders, retention);
+            let left = folders.into_iter().filter(|f| f.join(TRASH_DIR).exists()).collect();
+            (removed, left)
+        })
+        .await
+        .unwrap_or_default();
+        known = left;
+        if removed > 0 {
+            info!("Removed {} expired partial files of cancelled downloads", removed);
         }
-        
-        let _ = Command::new("systemctl")
-            .args(["--user", "daemon-reload"])
-            .output();
-        
-        info!("Systemd service disabled");
+    }
+}
+
+/// Folders `depth` levels below `root`, where a `date_subfolder` pattern
+/// that many components long puts downloads. Blocking.
+fn subfolders_at_depth(root: &std::path::Path, depth: usize) -> Vec<PathBuf> {
+    let mut level = vec![root.to_path_buf()];
+    for _ in 0..depth {
+        level = level
+            .iter()
+            .filter_map(|folder| std::fs::read_dir(folder).ok())
+            .flat_map(|entries| entries.flatten())
+            .filter(|entry| entry.file_name() != TRASH_DIR && entry.file_type().is_ok_and(|t| t.is_dir()))
+            .map(|entry| entry.path())
+            .collect();
+    }
+    level
+}
+
+/// Delete the files in the trash folders of `folders` last changed more
+/// than `retention` ago, then the trash folders left empty. Returns how
+/// many files went. Blocking; run it off the async runtime.
+fn sweep_trash(folders: &[PathBuf], retention: std::time::Duration) -> usize {
+    let mut removed = 0;
+    let trash_folders: std::collections::HashSet<PathBuf> = folders.iter().map(|f| f.join(TRASH_DIR)).collect();
+    for trash in trash_folders {
+        let Ok(entries) = std::fs::read_dir(&trash) else { continue };
+        for entry in entries.flatten() {
+            let expired = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| modified.elapsed().unwrap_or_default() >= retention);
+            if expired && std::fs::remove_file(entry.path()).is_ok() {
+                removed += 1;
+            }
+        }
+        // Fails while anything is left
+        let _ = std::fs::remove_dir(&trash);
+    }
+    removed
+}
+
+/// Undo the cancellation of a download whose partial file is still in the
+/// trash: put the partial back and queue the download to resume from it
+async fn restore_download(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<DownloadRecord>, AppError> {
+    let mut record = state
+        .db
+        .get_download(&id)?
+        .ok_or_else(|| AppError::NotFound("Download not found".into()))?;
+    if record.status != DownloadStatus::Cancelled {
+        return Err(AppError::Conflict("Only cancelled downloads can be restored".into()));
+    }
+    let trash = trash_path(&record.destination, &record.id);
+    if !trash.exists() {
+        return Err(AppError::NotFound("The partial file of this download is no longer kept".into()));
     }
     
-    Ok(())
+    let settings = state.settings.read().clone();
+    let partial = settings.partial_path(&record.destination, &record.filename);
+    let (from, to) = (trash.clone(), partial.clone());
+    tokio::task::spawn_blocking(move || crate::fs::move_file(&from, &to))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?
+        .map_err(|e| AppError::Internal(format!("{:#}", e)))?;
+    
+    record.downloaded_size = reconcile_partial(&settings, &record);
+    state.db.update_progress(&record.id, record.downloaded_size, record.total_size)?;
+    state.db.update_status(&record.id, DownloadStatus::Queued, None)?;
+    record.status = DownloadStatus::Queued;
+    if let Err(e) = state.download_manager.enqueue(record.clone()) {
+        // Back as it was, so it can be restored once there's room
+        state.db.update_status(&record.id, DownloadStatus::Cancelled, None)?;
+        let _ = tokio::task::spawn_blocking(move || crate::fs::move_file(&partial, &trash)).await;
+        return Err(AppError::ServiceUnavailable(e.to_string()));
+    }
+    state
+        .download_manager
+        .notify_change(download::DownloadChange::new(&record.id, download::ChangeKind::StatusChanged));
+    info!("Restored cancelled download {}", record.filename);
+    start_queued(&state);
+    
+    Ok(Json(record))
 }
 
-// ============ File Type Endpoints ============
+/// Request to change a download's filename
+#[derive(Debug, Deserialize)]
+pub struct RenameDownloadRequest {
+    pub filename: String,
+}
 
-/// List all file types
-async fn list_file_types(
+/// Change the filename a download will be saved under. Works for downloads
+/// that haven't finished yet. One that has started keeps its partial file
+/// under the old name, recording the new one as `rename_to`, and is saved
+/// under the new name when done.
+async fn rename_download(
     State(state): State<Arc<AppState>>,
-) -> Json<HashMap<String, FileTypeConfig>> {
-    let settings = state.settings.read();
-    Json(settings.file_types.clone())
+    Path(id): Path<String>,
+    Json(req): Json<RenameDownloadRequest>,
+) -> Result<Json<DownloadRecord>, AppError> {
+    let filename = download::sanitize_filename(&req.filename).ok_or_else(|| {
+        AppError::BadRequest("Filename must be a plain file name without path separators".into())
+    })?;
+    
+    let record = state
+        .db
+        .get_download(&id)?
+        .ok_or_else(|| AppError::NotFound("Download not found".into()))?;
+    
+    let started = record.started_at.is_some() || state.download_manager.is_active(&id);
+    if record.status == DownloadStatus::Completed || !state.download_manager.rename(&id, &filename, started) {
+        return Err(AppError::Conflict("Download has already been saved".into()));
+    }
+    
+    if started {
+        state.db.set_rename_to(&id, &filename)?;
+        Ok(Json(DownloadRecord { rename_to: Some(filename), ..record }))
+    } else {
+        state.db.update_filename(&id, &filename)?;
+        Ok(Json(DownloadRecord { filename, ..record }))
+    }
 }
 
-/// Add file type request
+/// Request to move a completed download's file
 #[derive(Debug, Deserialize)]
-pub struct AddFileTypeRequest {
-    pub name: String,
-    pub extensions: Vec<String>,
-    pub destination: String,
+pub struct MoveDownloadRequest {
+    pub destination: PathBuf,
 }
 
-/// Add a new file type
-async fn add_file_type(
+/// Response after moving a download's file
+#[derive(Debug, Serialize)]
+pub struct MoveDownloadResponse {
+    /// Where the file is now
+    pub path: PathBuf,
+    pub download: DownloadRecord,
+}
+
+/// Move a completed download's file to another folder. A file of the same
+/// name there is handled by the conflict policy of the download's file type.
+async fn move_download(
     State(state): State<Arc<AppState>>,
-    Json(req): Json<AddFileTypeRequest>,
-) -> Result<Json<serde_json::Value>, AppError> {
-    let mut settings = state.settings.write();
+    Path(id): Path<String>,
+    Json(req): Json<MoveDownloadRequest>,
+) -> Result<Json<MoveDownloadResponse>, AppError> {
+    if !req.destination.is_absolute() {
+        return Err(AppError::BadRequest("Destination must be an absolute path".into()));
+    }
     
-    // Generate unique ID from name + timestamp to allow multiple categories
-    let base_id = req.name.to_lowercase().replace(|c: char| !c.is_alphanumeric(), "-");
-    let mut id = base_id.clone();
-    let mut counter = 1;
+    let mut record = state
+        .db
+        .get_download(&id)?
+        .ok_or_else(|| AppError::NotFound("Download not found".into()))?;
+    if record.status != DownloadStatus::Completed {
+        return Err(AppError::Conflict("Only completed downloads can be moved".into()));
+    }
     
-    // If ID exists, append a number to make it unique
-    while settings.file_types.contains_key(&id) {
-        id = format!("{}-{}", base_id, counter);
-        counter += 1;
+    let from = record.destination.join(&record.filename);
+    if !from.is_file() {
+        return Err(AppError::NotFound(format!("{} no longer exists", from.display())));
     }
     
-    settings.file_types.insert(
-        id.clone(),
-        FileTypeConfig {
-            name: req.name,
-            extensions: req.extensions,
-            destination: PathBuf::from(req.destination),
-        },
-    );
+    check_destination(req.destination.clone()).await?;
     
-    config::save(&settings)?;
+    // Already there; the conflict policy would otherwise rename it next to itself
+    if same_folder(&record.destination, &req.destination) {
+        return Ok(Json(MoveDownloadResponse { path: from, download: record }));
+    }
     
-    Ok(Json(serde_json::json!({ "id": id })))
+    let to = relocate_file(&state, &mut record, req.destination)
+        .await
+        .map_err(|e| AppError::Internal(format!("{:#}", e)))?;
+    
+    Ok(Json(MoveDownloadResponse { path: to, download: record }))
 }
 
-/// Update file type request
+/// Move a completed download's file into `destination`, under a name its
+/// file type's conflict policy allows, and record the new location.
+/// Returns where the file is now.
+async fn relocate_file(state: &AppState, record: &mut DownloadRecord, destination: PathBuf) -> anyhow::Result<PathBuf> {
+    let from = record.destination.join(&record.filename);
+    let policy = state.settings.read().conflict_policy(&record.file_type);
+    let reserved = state.download_manager.filenames().reserve(&destination, &record.filename, policy);
+    let filename = reserved.filename().to_string();
+    let to = destination.join(&filename);
+    
+    let target = to.clone();
+    tokio::task::spawn_blocking(move || crate::fs::move_file(&from, &target)).await??;
+    
+    state.db.update_location(&record.id, &destination, &filename)?;
+    record.destination = destination;
+    record.filename = filename;
+    Ok(to)
+}
+
+/// Request to change a download's speed cap
 #[derive(Debug, Deserialize)]
-pub struct UpdateFileTypeRequest {
-    pub name: Option<String>,
-    pub extensions: Option<Vec<String>>,
-    pub destination: Option<String>,
+pub struct SpeedLimitRequest {
+    /// Bytes per second; 0 removes the cap
+    pub bytes_per_sec: u64,
 }
 
-/// Update an existing file type
-async fn update_file_type(
+/// Change a download's speed cap. A running download adjusts on its next
+/// chunk without restarting.
+async fn set_speed_limit(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
-    Json(req): Json<UpdateFileTypeRequest>,
-) -> Result<StatusCode, AppError> {
-    let mut settings = state.settings.write();
+    Json(req): Json<SpeedLimitRequest>,
+) -> Result<Json<DownloadRecord>, AppError> {
+    let record = state
+        .db
+        .get_download(&id)?
+        .ok_or_else(|| AppError::NotFound("Download not found".into()))?;
     
-    let file_type = settings
-        .file_types
-        .get_mut(&id)
-        .ok_or_else(|| AppError::NotFound("File type not found".into()))?;
+    let speed_limit = (req.bytes_per_sec > 0).then_some(req.bytes_per_sec);
+    state.download_manager.set_speed_limit(&id, speed_limit);
+    state.db.update_speed_limit(&id, speed_limit)?;
     
-    if let Some(name) = req.name {
-        file_type.name = name;
-    }
-    if let Some(extensions) = req.extensions {
-        file_type.extensions = extensions;
+    Ok(Json(DownloadRecord { speed_limit, ..record }))
+}
+
+/// Query parameters for clearing completed downloads
+#[derive(Debug, Deserialize)]
+pub struct ClearCompletedQuery {
+    #[serde(default)]
+    pub include_cancelled: bool,
+}
+
+/// Remove finished downloads from the list without touching their files
+async fn clear_completed(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ClearCompletedQuery>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let removed = state.db.clear_completed(query.include_cancelled)?;
+    if removed > 0 {
+        state.download_manager.notify_change(download::DownloadChange::resync());
     }
-    if let Some(destination) = req.destination {
-        file_type.destination = PathBuf::from(destination);
+    Ok(Json(serde_json::json!({ "removed": removed })))
+}
+
+/// Get the error history of a download
+async fn download_errors(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<DownloadError>>, AppError> {
+    if state.db.get_download(&id)?.is_none() {
+        return Err(AppError::NotFound("Download not found".into()));
     }
     
-    config::save(&settings)?;
-    
-    Ok(StatusCode::OK)
+    Ok(Json(state.db.get_download_errors(&id)?))
 }
 
-/// Remove a file type
-async fn remove_file_type(
+/// Return a small completed text or image file inline, for thumbnails and
+/// snippets in the UI. Files over `preview_max_bytes` are refused with 413.
+async fn preview_download(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
-) -> Result<StatusCode, AppError> {
-    let mut settings = state.settings.write();
+) -> Result<Response, AppError> {
+    let record = state
+        .db
+        .get_download(&id)?
+        .ok_or_else(|| AppError::NotFound("Download not found".into()))?;
     
-    if id == "general" {
-        return Err(AppError::BadRequest("Cannot remove default file type".into()));
+    if record.status != DownloadStatus::Completed {
+        return Err(AppError::Conflict("Only completed downloads can be previewed".into()));
     }
     
-    if settings.file_types.remove(&id).is_none() {
-        return Err(AppError::NotFound("File type not found".into()));
+    let mime = mime_guess::from_path(&record.filename).first_or_octet_stream();
+    if !matches!(mime.type_(), mime_guess::mime::TEXT | mime_guess::mime::IMAGE) {
+        return Err(AppError::UnsupportedMediaType(
+            "Only text and image files can be previewed".into(),
+        ));
     }
     
-    config::save(&settings)?;
+    let max_bytes = state.settings.read().preview_max_bytes;
+    let path = record.destination.join(&record.filename);
+    let file = File::open(&path)
+        .await
+        .map_err(|_| AppError::NotFound("Downloaded file no longer exists".into()))?;
     
-    Ok(StatusCode::NO_CONTENT)
+    // Read one byte past the cap so a file that grew since it was recorded
+    // is still caught, without ever holding more than that in memory
+    let mut content = Vec::new();
+    file.take(max_bytes.saturating_add(1))
+        .read_to_end(&mut content)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    if content.len() as u64 > max_bytes {
+        return Err(AppError::PayloadTooLarge(format!(
+            "File is larger than the {} byte preview limit",
+            max_bytes
+        )));
+    }
+    
+    let disposition = format!("inline; filename=\"{}\"", record.filename.replace(['"', '\\'], "_"));
+    Ok((
+        [
+            (axum::http::header::CONTENT_TYPE, mime.to_string()),
+            (axum::http::header::CONTENT_DISPOSITION, disposition),
+            (axum::http::header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            // Downloaded HTML or SVG must not run scripts on our origin
+            (axum::http::header::CONTENT_SECURITY_POLICY, "sandbox".to_string()),
+        ],
+        content,
+    )
+        .into_response())
 }
 
-// ============ Error Handling ============
+/// Get download statistics
+async fn download_stats(
+    State(state): State<Arc<AppState>>,
+) -> Json<DownloadStats> {
+    Json(state.download_manager.stats())
+}
 
-/// Application error type
-#[derive(Debug)]
-pub enum AppError {
-    Internal(String),
-    BadRequest(String),
-    NotFound(String),
+/// A download waiting in the in-memory queue
+#[derive(Debug, Serialize)]
+pub struct QueueEntry {
+    /// 1-based position, matching `AddDownloadResponse::queue_position`
+    pub position: usize,
+    #[serde(flatten)]
+    pub download: DownloadRecord,
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        let (status, message) = match self {
-            AppError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
-            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
-        };
-        
-        let body = Json(serde_json::json!({ "error": message }));
-        (status, body).into_response()
-    }
+/// List the download manager's queue in order. This reads the live queue,
+/// which can be ahead of the `queued` status stored in the database.
+async fn list_queue(
+    State(state): State<Arc<AppState>>,
+) -> Json<Vec<QueueEntry>> {
+    let entries = state
+        .download_manager
+        .queued()
+        .into_iter()
+        .enumerate()
+        .map(|(i, download)| QueueEntry { position: i + 1, download })
+        .collect();
+    Json(entries)
 }
 
-impl From<anyhow::Error> for AppError {
-    fn from(err: anyhow::Error) -> Self {
-        AppError::Internal(err.to_string())
+/// Stop starting queued downloads; running ones carry on
+async fn pause_queue(
+    State(state): State<Arc<AppState>>,
+) -> Json<DownloadStats> {
+    if !state.download_manager.is_paused() {
+        info!("Queue paused");
+        state.download_manager.set_paused(true);
+        state.download_manager.notify_change(download::DownloadChange::resync());
     }
+    Json(state.download_manager.stats())
 }
 
-/// Download file with cancellation support
-async fn download_file_with_cancel(
-    record: &DownloadRecord,
-    progress_tx: &tokio::sync::broadcast::Sender<download::ProgressUpdate>,
-    cancel_rx: &mut tokio::sync::mpsc::Receiver<()>,
-) -> anyhow::Result<()> {
-    let client = reqwest::Client::builder()
-        .user_agent("VibeDownloader/1.0")
-        .build()?;
-    
-    let response = client.get(&record.url).send().await?;
-    
-    if !response.status().is_success() {
-        anyhow::bail!("HTTP error: {}", response.status());
+/// Let the queue advance again, starting downloads for every free slot
+async fn resume_queue(
+    State(state): State<Arc<AppState>>,
+) -> Json<DownloadStats> {
+    if state.download_manager.is_paused() {
+        info!("Queue resumed");
+        state.download_manager.set_paused(false);
+        start_queued(&state);
+        state.download_manager.notify_change(download::DownloadChange::resync());
+    }
+    Json(state.download_manager.stats())
+}
+
+/// Get aggregate statistics over the whole download history
+async fn lifetime_stats(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<LifetimeStats>, AppError> {
+    Ok(Json(state.db.lifetime_stats()?))
+}
+
+// ============ URL Info Endpoint ============
+
+#[derive(Debug, Deserialize)]
+pub struct UrlInfoRequest {
+    pub url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UrlInfoResponse {
+    pub filename: Option<String>,
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+}
+
+/// Get file info from URL via HEAD request, falling back to GET with range if needed.
+/// Uses the download client, so the probe goes through the same proxy and
+/// certificate settings as the download would.
+async fn get_url_info(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<UrlInfoRequest>,
+) -> Json<UrlInfoResponse> {
+    let clients = state.download_manager.http_client();
+    let client = clients.for_url(&req.url);
+    
+    // Try HEAD request first
+    let head_response = client
+        .head(&req.url)
+        .timeout(std::time::Duration::from_secs(15))
+        .send()
+        .await;
+    
+    let (filename, size, content_type) = match head_response {
+        Ok(response) if response.status().is_success() => {
+            let headers = response.headers();
+            let filename = headers
+                .get("content-disposition")
+                .and_then(|v| v.to_str().ok())
+                .and_then(download::parse_content_disposition);
+            let size = headers
+                .get("content-length")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok());
+            let content_type = headers
+                .get("content-type")
+                .and_then(|v| v.to_str().ok())
+                .map(|s| s.split(';').next().unwrap_or(s).trim().to_string());
+            
+            // If HEAD didn't give us a filename, try GET with range
+            if filename.is_none() {
+                match try_get_with_range(client, &req.url).await {
+                    Some((get_filename, get_size, get_content_type)) => {
+                        (get_filename, get_size.or(size), get_content_type.or(content_type))
+                    }
+                    None => (filename, size, content_type)
+                }
+            } else {
+                (filename, size, content_type)
+            }
+        }
+        _ => {
+            // HEAD failed or returned error, try GET with range
+            try_get_with_range(client, &req.url).await
+                .unwrap_or((None, None, None))
+        }
+    };
+    
+    // Fall back to URL path for filename
+    let filename = filename.or_else(|| extract_filename_from_url(&req.url));
+    
+    Json(UrlInfoResponse {
+        filename,
+        size,
+        content_type,
+    })
+}
+
+/// Try to get file info using GET request with Range header (downloads minimal data)
+async fn try_get_with_range(
+    client: &reqwest::Client,
+    url: &str,
+) -> Option<(Option<String>, Option<u64>, Option<String>)> {
+    // Request only first byte to minimize data transfer
+    let response = client
+        .get(url)
+        .header("Range", "bytes=0-0")
+        .timeout(std::time::Duration::from_secs(15))
+        .send()
+        .await
+        .ok()?;
+    
+    if !response.status().is_success() && response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return None;
+    }
+    
+    let headers = response.headers();
+    
+    let filename = headers
+        .get("content-disposition")
+        .and_then(|v| v.to_str().ok())
+        .and_then(download::parse_content_disposition);
+    
+    // For range requests, Content-Range header has full size: "bytes 0-0/12345"
+    let size = response_total_size(response.status(), headers);
+    
+    let content_type = headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.split(';').next().unwrap_or(s).trim().to_string());
+    
+    Some((filename, size, content_type))
+}
+
+/// Filename from the URL path, if it looks like one (has an extension)
+fn extract_filename_from_url(url: &str) -> Option<String> {
+    download::filename_from_url(url).filter(|name| name.contains('.') && !name.starts_with('.'))
+}
+
+/// Build and platform details, for bug reports
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    /// Short commit hash, when built from a git checkout
+    pub git_commit: Option<&'static str>,
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub debug: bool,
+}
+
+/// Get build and platform details
+async fn version_info() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: option_env!("VIBE_GIT_COMMIT"),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        debug: cfg!(debug_assertions),
+    })
+}
+
+/// Where the web UI can be opened
+#[derive(Debug, Serialize)]
+pub struct NetworkInfo {
+    /// Host and port the server is listening on
+    pub host: String,
+    pub port: u16,
+    pub lan_access: bool,
+    /// URL for this machine
+    pub local_url: String,
+    /// This machine's non-loopback, non-link-local addresses
+    pub addresses: Vec<std::net::IpAddr>,
+    /// URLs to open on other devices; empty without `lan_access`
+    pub lan_urls: Vec<String>,
+}
+
+/// Get the address the server is bound to and this machine's LAN addresses
+async fn network_info(State(state): State<Arc<AppState>>) -> Result<Json<NetworkInfo>, AppError> {
+    let server = state.served_at();
+    let addresses = tokio::task::spawn_blocking(super::network::lan_addresses)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let lan_urls = if server.lan_access {
+        super::network::lan_urls_from(server.bind_host(), server.port, &addresses)
+    } else {
+        Vec::new()
+    };
+    
+    Ok(Json(NetworkInfo {
+        host: server.bind_host().to_string(),
+        port: server.port,
+        lan_access: server.lan_access,
+        local_url: server.local_url(),
+        addresses,
+        lan_urls,
+    }))
+}
+
+// ============ Settings Endpoints ============
+
+/// Settings response (excluding sensitive data)
+#[derive(Debug, Serialize)]
+pub struct SettingsResponse {
+    pub server_port: u16,
+    pub server_host: String,
+    pub lan_access: bool,
+    pub read_only: bool,
+    pub max_concurrent_downloads: usize,
+    pub max_connections_per_host: usize,
+    pub max_queue_size: usize,
+    pub alt_speed_bytes_per_sec: u64,
+    pub max_history_records: usize,
+    pub max_retries: u32,
+    pub connect_retries: u32,
+    pub retry_backoff_secs: u64,
+    pub connect_retry_backoff_secs: u64,
+    pub start_on_login: bool,
+    pub start_on_boot: bool,
+    pub start_on_boot_available: bool,
+    pub content_encoding: ContentEncodingMode,
+    pub proxy: Option<String>,
+    pub use_system_proxy: bool,
+    pub partial_suffix: String,
+    pub hide_partial_files: bool,
+    pub cancelled_partial_retention_minutes: u64,
+    pub fsync_on_complete: bool,
+    pub dedupe_by_checksum: bool,
+    pub compute_checksums: bool,
+    pub routing_rules: Vec<RoutingRule>,
+    pub auto_resume_paused_on_start: bool,
+    pub startup_stagger_ms: u64,
+    pub pause_on_metered: bool,
+    pub resume_verify_bytes: u64,
+    pub preview_max_bytes: u64,
+    pub on_conflict: ConflictPolicy,
+    pub idle_shutdown_minutes: u64,
+    pub finished_replay_secs: u64,
+    pub progress_save_interval_secs: u64,
+    pub webhook_url: Option<String>,
+    /// Settings that were asked for but couldn't be applied, e.g. a boot
+    /// service that failed to install; those keep their previous value
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl From<&Settings> for SettingsResponse {
+    fn from(settings: &Settings) -> Self {
+        Self {
+            server_port: settings.server.port,
+            server_host: settings.server.host.clone(),
+            lan_access: settings.server.lan_access,
+            read_only: settings.server.read_only,
+            max_concurrent_downloads: settings.max_concurrent_downloads,
+            max_connections_per_host: settings.max_connections_per_host,
+            max_queue_size: settings.max_queue_size,
+            alt_speed_bytes_per_sec: settings.alt_speed_bytes_per_sec,
+            max_history_records: settings.max_history_records,
+            max_retries: settings.max_retries,
+            connect_retries: settings.connect_retries,
+            retry_backoff_secs: settings.retry_backoff_secs,
+            connect_retry_backoff_secs: settings.connect_retry_backoff_secs,
+            start_on_login: settings.start_on_login,
+            start_on_boot: settings.start_on_boot,
+            start_on_boot_available: cfg!(target_os = "linux"),
+            content_encoding: settings.content_encoding,
+            proxy: settings.proxy.clone(),
+            use_system_proxy: settings.use_system_proxy,
+            partial_suffix: settings.partial_suffix.clone(),
+            hide_partial_files: settings.hide_partial_files,
+            cancelled_partial_retention_minutes: settings.cancelled_partial_retention_minutes,
+            fsync_on_complete: settings.fsync_on_complete,
+            dedupe_by_checksum: settings.dedupe_by_checksum,
+            compute_checksums: settings.compute_checksums,
+            routing_rules: settings.routing_rules.clone(),
+            auto_resume_paused_on_start: settings.auto_resume_paused_on_start,
+            startup_stagger_ms: settings.startup_stagger_ms,
+            pause_on_metered: settings.pause_on_metered,
+            resume_verify_bytes: settings.resume_verify_bytes,
+            preview_max_bytes: settings.preview_max_bytes,
+            on_conflict: settings.on_conflict,
+            idle_shutdown_minutes: settings.idle_shutdown_minutes,
+            finished_replay_secs: settings.finished_replay_secs,
+            progress_save_interval_secs: settings.progress_save_interval_secs,
+            webhook_url: settings.webhook_url.clone(),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+/// Get current settings
+async fn get_settings(
+    State(state): State<Arc<AppState>>,
+) -> Json<SettingsResponse> {
+    let settings = state.settings.read();
+    Json(SettingsResponse::from(&*settings))
+}
+
+/// Outcome of one test notification
+#[derive(Debug, Serialize)]
+pub struct NotificationTestResult {
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/// Outcome of sending a test through each configured notification
+#[derive(Debug, Serialize)]
+pub struct NotificationTestResponse {
+    /// `None` when no webhook is configured
+    pub webhook: Option<NotificationTestResult>,
+}
+
+/// Send a sample event through the configured notifications, the same way
+/// a finished download would, and report how each went
+async fn test_notifications(
+    State(state): State<Arc<AppState>>,
+) -> Json<NotificationTestResponse> {
+    let webhook_url = state.settings.read().webhook_url.clone();
+    
+    let webhook = match webhook_url {
+        Some(url) => {
+            let clients = state.download_manager.http_client();
+            let result = download::send_webhook(clients.for_url(&url), &url, &download::WebhookEvent::test()).await;
+            Some(NotificationTestResult {
+                success: result.is_ok(),
+                error: result.err(),
+            })
+        }
+        None => None,
+    };
+    
+    Json(NotificationTestResponse { webhook })
+}
+
+/// Upper bound for `resume_verify_bytes`; the overlap is held in memory
+const MAX_RESUME_VERIFY_BYTES: u64 = 1024 * 1024;
+
+/// Upper bound for `finished_replay_secs`
+const MAX_FINISHED_REPLAY_SECS: u64 = 3600;
+
+/// Upper bound for `startup_stagger_ms`
+const MAX_STARTUP_STAGGER_MS: u64 = 60_000;
+
+/// Update settings request
+#[derive(Debug, Deserialize)]
+pub struct UpdateSettingsRequest {
+    /// Changing either of these restarts the web server on the new address
+    pub server_port: Option<u16>,
+    pub server_host: Option<String>,
+    pub lan_access: Option<bool>,
+    /// Once on, turning it off again takes the write token like any change
+    pub read_only: Option<bool>,
+    pub max_concurrent_downloads: Option<usize>,
+    pub max_connections_per_host: Option<usize>,
+    pub max_queue_size: Option<usize>,
+    pub alt_speed_bytes_per_sec: Option<u64>,
+    pub max_history_records: Option<usize>,
+    pub max_retries: Option<u32>,
+    pub connect_retries: Option<u32>,
+    pub retry_backoff_secs: Option<u64>,
+    pub connect_retry_backoff_secs: Option<u64>,
+    pub start_on_login: Option<bool>,
+    pub start_on_boot: Option<bool>,
+    pub content_encoding: Option<ContentEncodingMode>,
+    /// `null` removes the proxy
+    #[serde(default, deserialize_with = "present_or_null")]
+    pub proxy: Option<Option<String>>,
+    pub use_system_proxy: Option<bool>,
+    pub partial_suffix: Option<String>,
+    pub hide_partial_files: Option<bool>,
+    pub cancelled_partial_retention_minutes: Option<u64>,
+    pub fsync_on_complete: Option<bool>,
+    pub dedupe_by_checksum: Option<bool>,
+    pub compute_checksums: Option<bool>,
+    pub routing_rules: Option<Vec<RoutingRule>>,
+    pub auto_resume_paused_on_start: Option<bool>,
+    pub startup_stagger_ms: Option<u64>,
+    pub pause_on_metered: Option<bool>,
+    pub resume_verify_bytes: Option<u64>,
+    pub preview_max_bytes: Option<u64>,
+    pub on_conflict: Option<ConflictPolicy>,
+    pub idle_shutdown_minutes: Option<u64>,
+    pub finished_replay_secs: Option<u64>,
+    pub progress_save_interval_secs: Option<u64>,
+    /// `null` turns the webhook off
+    #[serde(default, deserialize_with = "present_or_null")]
+    pub webhook_url: Option<Option<String>>,
+}
+
+/// Update settings
+async fn update_settings(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<UpdateSettingsRequest>,
+) -> Result<Json<SettingsResponse>, AppError> {
+    let (start_on_login, start_on_boot) = (req.start_on_login, req.start_on_boot);
+    let trims_history = req.max_history_records.is_some();
+    apply_settings(&state, req)?;
+    
+    // These only change once the system agrees, so the saved setting
+    // never claims a startup entry that doesn't exist. The system's tools
+    // are waited on without holding the settings.
+    let startup = configure_startup(start_on_login, start_on_boot).await?;
+    let mut settings = state.settings.write();
+    if startup.start_on_login.is_some() || startup.start_on_boot.is_some() {
+        settings.start_on_login = startup.start_on_login.unwrap_or(settings.start_on_login);
+        settings.start_on_boot = startup.start_on_boot.unwrap_or(settings.start_on_boot);
+        config::save(&settings)?;
+    }
+    let mut response = SettingsResponse::from(&*settings);
+    response.warnings = startup.warnings;
+    drop(settings);
+    
+    // A lowered cap applies to the history there already is
+    if trims_history {
+        trim_history(&state);
+    }
+    // A raised limit lets queued downloads start right away
+    start_queued(&state);
+    
+    Ok(Json(response))
+}
+
+/// Validate and save the settings `req` changes, other than the startup
+/// entries, and apply them to the running app. They're checked on a copy,
+/// so a request with any invalid field changes nothing.
+fn apply_settings(state: &AppState, req: UpdateSettingsRequest) -> Result<(), AppError> {
+    let mut current = state.settings.write();
+    let mut settings = current.clone();
+    
+    if let Some(port) = req.server_port {
+        if port == 0 {
+            return Err(AppError::BadRequest("Port must be between 1 and 65535".into()));
+        }
+        settings.server.port = port;
+    }
+    
+    if let Some(host) = req.server_host {
+        let Some(ip) = config::parse_host(&host) else {
+            return Err(AppError::BadRequest(format!("server_host {}", config::SERVER_HOST_RULE)));
+        };
+        settings.server.host = ip.to_string();
+    }
+    
+    if let Some(lan_access) = req.lan_access {
+        settings.server.lan_access = lan_access;
+    }
+    
+    if let Some(read_only) = req.read_only {
+        settings.server.read_only = read_only;
+    }
+    
+    if let Some(max) = req.max_concurrent_downloads {
+        settings.max_concurrent_downloads = max;
+    }
+    
+    if let Some(max) = req.max_connections_per_host {
+        settings.max_connections_per_host = max;
+    }
+    
+    if let Some(max) = req.max_queue_size {
+        settings.max_queue_size = max;
+    }
+    
+    if let Some(limit) = req.alt_speed_bytes_per_sec {
+        if limit == 0 {
+            return Err(AppError::BadRequest("The alternative speed limit must be at least 1 byte per second".into()));
+        }
+        settings.alt_speed_bytes_per_sec = limit;
+    }
+    
+    if let Some(max) = req.max_history_records {
+        settings.max_history_records = max;
+    }
+    
+    // Picked up by downloads started from now on
+    if let Some(retries) = req.max_retries {
+        settings.max_retries = retries;
+    }
+    if let Some(retries) = req.connect_retries {
+        settings.connect_retries = retries;
+    }
+    if let Some(secs) = req.retry_backoff_secs {
+        settings.retry_backoff_secs = secs;
+    }
+    if let Some(secs) = req.connect_retry_backoff_secs {
+        settings.connect_retry_backoff_secs = secs;
+    }
+    
+    let rebuild_client = req.content_encoding.is_some() || req.proxy.is_some() || req.use_system_proxy.is_some();
+    if let Some(mode) = req.content_encoding {
+        settings.content_encoding = mode;
+    }
+    if let Some(proxy) = req.proxy {
+        if !proxy.as_deref().is_none_or(valid_proxy_url) {
+            return Err(AppError::BadRequest("Proxy must be an http(s) URL".into()));
+        }
+        settings.proxy = proxy;
+    }
+    if let Some(use_system_proxy) = req.use_system_proxy {
+        settings.use_system_proxy = use_system_proxy;
+    }
+    
+    if let Some(suffix) = req.partial_suffix {
+        if !valid_partial_suffix(&suffix) {
+            return Err(AppError::BadRequest(format!("Partial suffix {}", PARTIAL_SUFFIX_RULE)));
+        }
+        settings.partial_suffix = suffix;
+    }
+    
+    if let Some(hide) = req.hide_partial_files {
+        settings.hide_partial_files = hide;
+    }
+    
+    if let Some(minutes) = req.cancelled_partial_retention_minutes {
+        settings.cancelled_partial_retention_minutes = minutes;
+    }
+    
+    if let Some(fsync) = req.fsync_on_complete {
+        settings.fsync_on_complete = fsync;
+    }
+    
+    if let Some(dedupe) = req.dedupe_by_checksum {
+        settings.dedupe_by_checksum = dedupe;
+    }
+    
+    if let Some(compute) = req.compute_checksums {
+        settings.compute_checksums = compute;
+    }
+    
+    if let Some(rules) = req.routing_rules {
+        settings.routing_rules = rules;
+        let problems = settings.routing_rule_problems();
+        if !problems.is_empty() {
+            let errors = problems.into_iter().map(|(field, problem)| FieldError::new(&field, problem)).collect();
+            return Err(AppError::InvalidFields(errors));
+        }
+    }
+    
+    if let Some(resume) = req.auto_resume_paused_on_start {
+        settings.auto_resume_paused_on_start = resume;
+    }
+    
+    if let Some(stagger) = req.startup_stagger_ms {
+        if stagger > MAX_STARTUP_STAGGER_MS {
+            return Err(AppError::BadRequest(format!(
+                "Resumed downloads are started at most {} ms apart",
+                MAX_STARTUP_STAGGER_MS
+            )));
+        }
+        settings.startup_stagger_ms = stagger;
+    }
+    
+    if let Some(pause) = req.pause_on_metered {
+        settings.pause_on_metered = pause;
+    }
+    
+    if let Some(bytes) = req.resume_verify_bytes {
+        if bytes > MAX_RESUME_VERIFY_BYTES {
+            return Err(AppError::BadRequest(format!(
+                "Resume verification is limited to {} bytes",
+                MAX_RESUME_VERIFY_BYTES
+            )));
+        }
+        settings.resume_verify_bytes = bytes;
+    }
+    
+    if let Some(bytes) = req.preview_max_bytes {
+        settings.preview_max_bytes = bytes;
+    }
+    
+    if let Some(policy) = req.on_conflict {
+        settings.on_conflict = policy;
+    }
+    
+    if let Some(minutes) = req.idle_shutdown_minutes {
+        settings.idle_shutdown_minutes = minutes;
+    }
+    
+    if let Some(secs) = req.finished_replay_secs {
+        if secs > MAX_FINISHED_REPLAY_SECS {
+            return Err(AppError::BadRequest(format!(
+                "Finished downloads are replayed for at most {} seconds",
+                MAX_FINISHED_REPLAY_SECS
+            )));
+        }
+        settings.finished_replay_secs = secs;
+    }
+    
+    if let Some(secs) = req.progress_save_interval_secs {
+        if secs == 0 {
+            return Err(AppError::BadRequest("Progress save interval must be at least 1 second".into()));
+        }
+        settings.progress_save_interval_secs = secs;
+    }
+    
+    if let Some(webhook_url) = req.webhook_url {
+        if !webhook_url.as_deref().is_none_or(valid_webhook_url) {
+            return Err(AppError::BadRequest("Webhook URL must be an http(s) URL".into()));
+        }
+        settings.webhook_url = webhook_url;
+    }
+    
+    // Decompression and proxies are configured on the shared client
+    let client = rebuild_client.then(|| download::build_http_client(&settings)).transpose()?;
+    
+    // Save to file
+    config::save(&settings)?;
+    
+    let manager = &state.download_manager;
+    manager.set_max_concurrent(settings.max_concurrent_downloads);
+    manager.set_max_connections_per_host(settings.max_connections_per_host);
+    manager.set_max_queue_size(settings.max_queue_size);
+    manager.set_alt_speed_limit(settings.alt_speed_bytes_per_sec);
+    if let Some(client) = client {
+        manager.set_http_client(client);
+    }
+    
+    // The restart waits for this request to finish, so the response still
+    // goes out on the old address
+    let restart = settings.server.port != current.server.port
+        || settings.server.bind_host() != current.server.bind_host();
+    *current = settings;
+    drop(current);
+    if restart {
+        state.restart_server();
+    }
+    
+    Ok(())
+}
+
+const PARTIAL_SUFFIX_RULE: &str = "must be non-empty and contain no path separators";
+
+fn valid_partial_suffix(suffix: &str) -> bool {
+    !suffix.is_empty() && !suffix.contains(['/', '\\'])
+}
+
+fn valid_webhook_url(url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+fn valid_proxy_url(url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+}
+
+/// Every setting as saved in the config file, for editing it whole
+#[derive(Debug, Serialize)]
+pub struct RawConfigResponse {
+    #[serde(flatten)]
+    pub settings: Settings,
+    /// Changes that couldn't be applied, e.g. a startup entry the system refused
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl RawConfigResponse {
+    fn new(settings: &Settings, warnings: Vec<String>) -> Self {
+        let mut settings = settings.clone();
+        // Reading the config mustn't hand out write access
+        settings.server.write_token = None;
+        Self { settings, warnings }
+    }
+}
+
+/// Get the whole configuration, minus the write token
+async fn get_raw_config(
+    State(state): State<Arc<AppState>>,
+) -> Json<RawConfigResponse> {
+    Json(RawConfigResponse::new(&state.settings.read(), Vec::new()))
+}
+
+/// Settings that run programs or weaken security, which a client on the
+/// network could use to take over the machine, so only the config file
+/// changes them. Reports each one `new` changes.
+fn config_file_only_changes(current: &Settings, new: &Settings) -> Vec<FieldError> {
+    let changed = [
+        ("post_complete_command", current.post_complete_command != new.post_complete_command),
+        ("allow_command_hooks", current.allow_command_hooks != new.allow_command_hooks),
+        ("ffmpeg_path", current.ffmpeg_path != new.ffmpeg_path),
+        (
+            "allow_invalid_certs_for_hosts",
+            current.allow_invalid_certs_for_hosts != new.allow_invalid_certs_for_hosts,
+        ),
+        ("client_cert_path", current.client_cert_path != new.client_cert_path),
+        ("client_key_path", current.client_key_path != new.client_key_path),
+        ("custom_ca_cert_path", current.custom_ca_cert_path != new.custom_ca_cert_path),
+        // Left out when reading, so leaving it out keeps it
+        (
+            "server.write_token",
+            new.server.write_token.is_some() && current.server.write_token != new.server.write_token,
+        ),
+    ];
+    changed
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(field, _)| FieldError::new(field, "can only be changed in the config file"))
+        .collect()
+}
+
+/// Check a whole configuration, reporting every invalid setting rather
+/// than only the first
+async fn validate_settings(settings: &Settings) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    
+    if settings.server.port == 0 {
+        errors.push(FieldError::new("server.port", "must be between 1 and 65535"));
+    }
+    if config::parse_host(&settings.server.host).is_none() {
+        errors.push(FieldError::new("server.host", config::SERVER_HOST_RULE));
+    }
+    if settings.max_concurrent_downloads == 0 {
+        errors.push(FieldError::new("max_concurrent_downloads", "must be at least 1"));
+    }
+    if !valid_partial_suffix(&settings.partial_suffix) {
+        errors.push(FieldError::new("partial_suffix", PARTIAL_SUFFIX_RULE));
+    }
+    if settings.resume_verify_bytes > MAX_RESUME_VERIFY_BYTES {
+        errors.push(FieldError::new(
+            "resume_verify_bytes",
+            &format!("must be at most {}", MAX_RESUME_VERIFY_BYTES),
+        ));
+    }
+    if settings.progress_save_interval_secs == 0 {
+        errors.push(FieldError::new("progress_save_interval_secs", "must be at least 1"));
+    }
+    if settings.alt_speed_bytes_per_sec == 0 {
+        errors.push(FieldError::new("alt_speed_bytes_per_sec", "must be at least 1"));
+    }
+    if settings.finished_replay_secs > MAX_FINISHED_REPLAY_SECS {
+        errors.push(FieldError::new(
+            "finished_replay_secs",
+            &format!("must be at most {}", MAX_FINISHED_REPLAY_SECS),
+        ));
+    }
+    if settings.startup_stagger_ms > MAX_STARTUP_STAGGER_MS {
+        errors.push(FieldError::new(
+            "startup_stagger_ms",
+            &format!("must be at most {}", MAX_STARTUP_STAGGER_MS),
+        ));
+    }
+    if !settings.webhook_url.as_deref().is_none_or(valid_webhook_url) {
+        errors.push(FieldError::new("webhook_url", "must be an http(s) URL"));
+    }
+    if !settings.proxy.as_deref().is_none_or(valid_proxy_url) {
+        errors.push(FieldError::new("proxy", "must be an http(s) URL"));
+    }
+    for (field, problem) in settings.routing_rule_problems() {
+        errors.push(FieldError::new(&field, problem));
+    }
+    
+    // Downloads of unknown types fall back to it
+    if !settings.file_types.contains_key("general") {
+        errors.push(FieldError::new("file_types.general", "the default file type is required"));
+    }
+    let mut file_types: Vec<_> = settings.file_types.iter().collect();
+    file_types.sort_by_key(|(id, _)| *id);
+    for (id, file_type) in file_types {
+        if file_type.name.trim().is_empty() {
+            errors.push(FieldError::new(&format!("file_types.{}.name", id), "must not be empty"));
+        }
+        if file_type.post_download_transcode.as_ref().is_some_and(|t| !t.is_valid()) {
+            errors.push(FieldError::new(
+                &format!("file_types.{}.post_download_transcode.format", id),
+                "must be a file extension such as mp3",
+            ));
+        }
+        if !file_type.date_subfolder.as_deref().is_none_or(config::valid_date_subfolder) {
+            errors.push(FieldError::new(
+                &format!("file_types.{}.date_subfolder", id),
+                "must be a relative date format such as %Y/%m/%d",
+            ));
+        }
+        if let Err(e) = check_destination(file_type.destination.clone()).await {
+            errors.push(FieldError::new(&format!("file_types.{}.destination", id), &e.to_string()));
+        }
+    }
+    
+    errors
+}
+
+/// Replace the whole configuration. Nothing changes unless every setting is
+/// valid; then it's saved and applied like `PUT /settings` would.
+async fn replace_raw_config(
+    State(state): State<Arc<AppState>>,
+    Json(mut new): Json<Settings>,
+) -> Result<Json<RawConfigResponse>, AppError> {
+    let current = state.settings.read().clone();
+    let mut errors = config_file_only_changes(&current, &new);
+    errors.extend(validate_settings(&new).await);
+    if !errors.is_empty() {
+        return Err(AppError::InvalidFields(errors));
+    }
+    new.server.write_token = current.server.write_token.clone();
+    
+    // These only change once the system agrees, as with `PUT /settings`
+    let startup = configure_startup(
+        Some(new.start_on_login).filter(|&start| start != current.start_on_login),
+        Some(new.start_on_boot).filter(|&start| start != current.start_on_boot),
+    )
+    .await?;
+    new.start_on_login = startup.start_on_login.unwrap_or(current.start_on_login);
+    new.start_on_boot = startup.start_on_boot.unwrap_or(current.start_on_boot);
+    let warnings = startup.warnings;
+    
+    let client = download::build_http_client(&new)?;
+    config::save(&new)?;
+    
+    let manager = &state.download_manager;
+    manager.set_max_concurrent(new.max_concurrent_downloads);
+    manager.set_max_connections_per_host(new.max_connections_per_host);
+    manager.set_max_queue_size(new.max_queue_size);
+    manager.set_alt_speed_limit(new.alt_speed_bytes_per_sec);
+    manager.set_http_client(client);
+    
+    let restart = new.server.port != current.server.port || new.server.bind_host() != current.server.bind_host();
+    let response = RawConfigResponse::new(&new, warnings);
+    *state.settings.write() = new;
+    if restart {
+        state.restart_server();
+    }
+    trim_history(&state);
+    start_queued(&state);
+    
+    Ok(Json(response))
+}
+
+/// Startup entries the system took, and why any it didn't
+struct StartupChanges {
+    start_on_login: Option<bool>,
+    start_on_boot: Option<bool>,
+    warnings: Vec<String>,
+}
+
+/// Turn the login and boot startup entries on or off where asked. Runs on
+/// the blocking pool, since it waits on tools such as `systemctl`.
+async fn configure_startup(login: Option<bool>, boot: Option<bool>) -> Result<StartupChanges, AppError> {
+    tokio::task::spawn_blocking(move || {
+        let mut warnings = Vec::new();
+        let start_on_login = login.filter(|&start| match configure_auto_launch(start) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!("Failed to configure auto-launch: {}", e);
+                warnings.push(e);
+                false
+            }
+        });
+        #[cfg(target_os = "linux")]
+        let start_on_boot = boot.filter(|&start| match configure_systemd_service(start) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!("Failed to configure systemd service: {}", e);
+                warnings.push(e);
+                false
+            }
+        });
+        #[cfg(not(target_os = "linux"))]
+        let start_on_boot = boot.filter(|&start| {
+            if start {
+                warnings.push("Start on boot is only available on Linux".to_string());
+            }
+            false
+        });
+        StartupChanges { start_on_login, start_on_boot, warnings }
+    })
+    .await
+    .map_err(|e| AppError::Internal(e.to_string()))
+}
+
+/// Configure auto-launch on system startup
+fn configure_auto_launch(enable: bool) -> Result<(), String> {
+    let exe_path = std::env::current_exe()
+        .map_err(|e| format!("Failed to get executable path: {}", e))?;
+    
+    let exe_path_str = exe_path.to_string_lossy().to_string();
+    
+    let auto_launch = AutoLaunchBuilder::new()
+        .set_app_name("Vibe Downloader")
+        .set_app_path(&exe_path_str)
+        .set_use_launch_agent(true) // macOS: use LaunchAgent instead of login items
+        .build()
+        .map_err(|e| format!("Failed to build auto-launch: {}", e))?;
+    
+    if enable {
+        auto_launch.enable().map_err(|e| format!("Failed to enable auto-launch: {}", e))?;
+        info!("Auto-launch enabled");
+    } else {
+        // Only disable if currently enabled
+        if auto_launch.is_enabled().unwrap_or(false) {
+            auto_launch.disable().map_err(|e| format!("Failed to disable auto-launch: {}", e))?;
+            info!("Auto-launch disabled");
+        }
+    }
+    
+    Ok(())
+}
+
+/// Name of the systemd user unit installed for start on boot
+#[cfg(target_os = "linux")]
+const SYSTEMD_UNIT: &str = "vibe-downloader.service";
+
+/// Contents of the systemd user unit that runs `exe`
+#[cfg(target_os = "linux")]
+fn systemd_unit(exe: &std::path::Path) -> String {
+    // Quoted so a path with spaces stays one argument; `%` starts a
+    // specifier and `$` a variable in unit files
+    let exe = exe
+        .to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    
+    format!(
+        r#"[Unit]
+Description=Vibe Downloader - Download Manager with Web UI
+After=network.target
+
+[Service]
+Type=simple
+ExecStart="{}"
+Restart=on-failure
+RestartSec=5
+Environment=DISPLAY=:0
+
+[Install]
+WantedBy=default.target
+"#,
+        exe
+    )
+}
+
+/// Run `systemctl --user` with `args`, failing with its error output if it
+/// can't be run or exits unsuccessfully
+#[cfg(target_os = "linux")]
+fn systemctl(args: &[&str]) -> Result<(), String> {
+    let output = std::process::Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run systemctl: {}", e))?;
+    
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "systemctl --user {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Install and enable, or disable and remove, the systemd user service that
+/// starts the app at boot (Linux only)
+#[cfg(target_os = "linux")]
+fn configure_systemd_service(enable: bool) -> Result<(), String> {
+    use std::process::Command;
+    
+    let exe_path = std::env::current_exe()
+        .map_err(|e| format!("Failed to get executable path: {}", e))?;
+    
+    let service_dir = dirs::config_dir()
+        .ok_or("Could not find config directory")?
+        .join("systemd/user");
+    
+    let service_path = service_dir.join(SYSTEMD_UNIT);
+    
+    if enable {
+        // Create systemd user service directory
+        std::fs::create_dir_all(&service_dir)
+            .map_err(|e| format!("Failed to create systemd directory: {}", e))?;
+        
+        std::fs::write(&service_path, systemd_unit(&exe_path))
+            .map_err(|e| format!("Failed to write service file: {}", e))?;
+        
+        // Reload systemd and enable service; don't leave a unit behind that
+        // systemd wouldn't take
+        if let Err(e) = systemctl(&["daemon-reload"]).and_then(|_| systemctl(&["enable", SYSTEMD_UNIT])) {
+            let _ = std::fs::remove_file(&service_path);
+            return Err(e);
+        }
+        
+        // Enable lingering so service starts at boot without login
+        let user = std::env::var("USER").unwrap_or_else(|_| "root".to_string());
+        let linger_result = Command::new("loginctl")
+            .args(["enable-linger", &user])
+            .output();
+        
+        match linger_result {
+            Ok(output) if output.status.success() => {}
+            // Not fatal: the service still starts on login, and the missing
+            // linger is pointed out at startup
+            Ok(output) => tracing::warn!(
+                "Failed to enable linger (may need sudo): {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => tracing::warn!("Failed to enable linger (may need sudo): {}", e),
+        }
+        
+        info!("Systemd service enabled for boot startup");
+    } else {
+        if !service_path.exists() {
+            return Ok(());
+        }
+        
+        systemctl(&["disable", SYSTEMD_UNIT])?;
+        // Stopping would end this very process when it runs as the service
+        std::fs::remove_file(&service_path)
+            .map_err(|e| format!("Failed to remove service file: {}", e))?;
+        systemctl(&["daemon-reload"])?;
+        
+        info!("Systemd service disabled");
+    }
+    
+    Ok(())
+}
+
+// ============ File Type Endpoints ============
+
+/// A file type with its id, as listed
+#[derive(Debug, Serialize)]
+pub struct FileTypeEntry {
+    pub id: String,
+    #[serde(flatten)]
+    pub config: FileTypeConfig,
+}
+
+/// File types in display order
+fn file_type_entries(settings: &Settings) -> Vec<FileTypeEntry> {
+    settings
+        .file_types_in_order()
+        .into_iter()
+        .map(|(id, config)| FileTypeEntry {
+            id: id.clone(),
+            config: config.clone(),
+        })
+        .collect()
+}
+
+/// List all file types, in display order
+async fn list_file_types(
+    State(state): State<Arc<AppState>>,
+) -> Json<Vec<FileTypeEntry>> {
+    let settings = state.settings.read();
+    Json(file_type_entries(&settings))
+}
+
+/// New display order of the file types
+#[derive(Debug, Deserialize)]
+pub struct ReorderFileTypesRequest {
+    /// Ids in the order to show them; any left out follow, in their
+    /// current order
+    pub ids: Vec<String>,
+}
+
+/// Change the order file types are listed in
+async fn reorder_file_types(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ReorderFileTypesRequest>,
+) -> Result<Json<Vec<FileTypeEntry>>, AppError> {
+    let mut settings = state.settings.write();
+    settings.reorder_file_types(&req.ids).map_err(AppError::BadRequest)?;
+    config::save(&settings)?;
+    
+    Ok(Json(file_type_entries(&settings)))
+}
+
+/// Most entries of a folder read for a listing; the rest of a huge folder
+/// is left out
+const MAX_LISTING_ENTRIES: usize = 10_000;
+
+/// Page size of a listing when none is given, and the largest allowed
+const DEFAULT_LISTING_LIMIT: usize = 100;
+const MAX_LISTING_LIMIT: usize = 1000;
+
+/// Page of a folder listing to return
+#[derive(Debug, Deserialize)]
+pub struct ListingQuery {
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+/// A file or folder in a file type's destination
+#[derive(Debug, Serialize)]
+pub struct ListingEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// Size of files; `None` for folders
+    pub size: Option<u64>,
+    pub modified: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// One page of a file type's destination, sorted by name
+#[derive(Debug, Serialize)]
+pub struct FolderListing {
+    pub path: PathBuf,
+    /// Entries across all pages
+    pub total: usize,
+    /// The folder has more than `MAX_LISTING_ENTRIES` entries, so some
+    /// aren't listed at all
+    pub truncated: bool,
+    pub entries: Vec<ListingEntry>,
+}
+
+/// List what's already in a file type's destination folder. Only the
+/// folder itself is listed, and links pointing outside it are left out.
+async fn file_type_listing(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<ListingQuery>,
+) -> Result<Json<FolderListing>, AppError> {
+    let destination = state
+        .settings
+        .read()
+        .file_types
+        .get(&id)
+        .map(|t| t.destination.clone())
+        .ok_or_else(|| AppError::NotFound(format!("File type '{}' not found", id)))?;
+    let limit = query.limit.unwrap_or(DEFAULT_LISTING_LIMIT).min(MAX_LISTING_LIMIT);
+    
+    let listing = tokio::task::spawn_blocking(move || list_folder(&destination, query.offset, limit))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?
+        .map_err(|e| AppError::Internal(format!("Failed to list folder: {}", e)))?;
+    Ok(Json(listing))
+}
+
+/// Read up to `MAX_LISTING_ENTRIES` entries of `dir` and return those from
+/// `offset`, at most `limit` of them. A folder that doesn't exist yet is
+/// empty. Blocking; run it off the async runtime.
+fn list_folder(dir: &std::path::Path, offset: usize, limit: usize) -> std::io::Result<FolderListing> {
+    let mut listing = FolderListing {
+        path: dir.to_path_buf(),
+        total: 0,
+        truncated: false,
+        entries: Vec::new(),
+    };
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(listing),
+        Err(e) => return Err(e),
+    };
+    let root = dir.canonicalize()?;
+    
+    let mut all = Vec::new();
+    for entry in entries.flatten() {
+        if all.len() == MAX_LISTING_ENTRIES {
+            listing.truncated = true;
+            break;
+        }
+        let Ok(file_type) = entry.file_type() else { continue };
+        // A link is described by what it points at, as long as that's
+        // inside the folder
+        let metadata = if file_type.is_symlink() {
+            match entry.path().canonicalize() {
+                Ok(target) if target.starts_with(&root) => std::fs::metadata(&target),
+                _ => continue,
+            }
+        } else {
+            entry.metadata()
+        };
+        let Ok(metadata) = metadata else { continue };
+        all.push(ListingEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: metadata.is_dir(),
+            size: metadata.is_file().then_some(metadata.len()),
+            modified: metadata.modified().ok().map(chrono::DateTime::from),
+        });
+    }
+    
+    all.sort_by(|a, b| a.name.cmp(&b.name));
+    listing.total = all.len();
+    listing.entries = all.into_iter().skip(offset).take(limit).collect();
+    Ok(listing)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StorageQuery {
+    /// Measure the files really in each folder, which can be slow
+    #[serde(default)]
+    pub walk: bool,
+}
+
+/// Space taken up in a file type's destination folder
+#[derive(Debug, Serialize)]
+pub struct StorageUsage {
+    pub file_type: String,
+    pub destination: PathBuf,
+    /// Size of the completed downloads saved there, by the database
+    pub downloaded_bytes: u64,
+    /// Size of all the files there; only measured when asked to walk
+    pub on_disk_bytes: Option<u64>,
+    /// Free space on the folder's volume, when it could be found
+    pub free_bytes: Option<u64>,
+}
+
+/// How much space each file type's downloads take up, in display order.
+/// Downloads in a subfolder count towards the file type whose destination
+/// is closest above them, so one inside another's folder isn't counted twice.
+async fn storage_usage(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StorageQuery>,
+) -> Result<Json<Vec<StorageUsage>>, AppError> {
+    let folders: Vec<(String, PathBuf)> = state
+        .settings
+        .read()
+        .file_types_in_order()
+        .into_iter()
+        .map(|(id, file_type)| (id.clone(), file_type.destination.clone()))
+        .collect();
+    let sizes = state.db.completed_size_by_destination()?;
+    
+    let usage = tokio::task::spawn_blocking(move || {
+        let mut downloaded: HashMap<&str, u64> = HashMap::new();
+        for (destination, size) in &sizes {
+            if let Some(id) = owning_file_type(&folders, destination) {
+                *downloaded.entry(id).or_default() += size;
+            }
+        }
+        
+        folders
+            .iter()
+            .map(|(id, destination)| {
+                let on_disk_bytes = query.walk.then(|| {
+                    let nested: Vec<PathBuf> = folders
+                        .iter()
+                        .map(|(_, other)| other.clone())
+                        .filter(|other| other != destination && other.starts_with(destination))
+                        .collect();
+                    crate::fs::dir_size(destination, &nested)
+                });
+                StorageUsage {
+                    file_type: id.clone(),
+                    destination: destination.clone(),
+                    downloaded_bytes: downloaded.get(id.as_str()).copied().unwrap_or(0),
+                    on_disk_bytes,
+                    free_bytes: crate::fs::available_space(destination).ok(),
+                }
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|e| AppError::Internal(e.to_string()))?;
+    
+    Ok(Json(usage))
+}
+
+/// The file type whose destination is the nearest folder at or above `path`;
+/// the first in order when several share it
+fn owning_file_type<'a>(folders: &'a [(String, PathBuf)], path: &std::path::Path) -> Option<&'a str> {
+    folders
+        .iter()
+        .filter(|(_, destination)| path.starts_with(destination))
+        .min_by_key(|(_, destination)| std::cmp::Reverse(destination.components().count()))
+        .map(|(id, _)| id.as_str())
+}
+
+/// Downloads a bulk action on a file type applied to
+#[derive(Debug, Serialize)]
+pub struct FileTypeActionResponse {
+    pub ids: Vec<String>,
+}
+
+fn require_file_type(state: &AppState, id: &str) -> Result<(), AppError> {
+    if state.settings.read().file_types.contains_key(id) {
+        Ok(())
+    } else {
+        Err(AppError::NotFound(format!("File type '{}' not found", id)))
+    }
+}
+
+/// Pause every running and queued download of a file type, keeping their
+/// partial files
+async fn pause_file_type(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<FileTypeActionResponse>, AppError> {
+    require_file_type(&state, &id)?;
+    let (active, queued) = state.download_manager.ids_of_file_type(&id);
+    let mut ids = Vec::new();
+    for download in active.into_iter().chain(queued) {
+        if state.download_manager.pause(&download).await {
+            ids.push(download);
+        }
+    }
+    info!("Paused {} {} downloads", ids.len(), id);
+    Ok(Json(FileTypeActionResponse { ids }))
+}
+
+/// Queue every paused download of a file type again, to pick up from its
+/// partial file
+async fn resume_file_type(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<FileTypeActionResponse>, AppError> {
+    require_file_type(&state, &id)?;
+    let settings = state.settings.read().clone();
+    let mut ids = Vec::new();
+    let paused = state
+        .db
+        .get_pending_downloads(true)?
+        .into_iter()
+        .filter(|d| d.status == DownloadStatus::Paused && d.file_type == id);
+    for mut download in paused {
+        download.downloaded_size = reconcile_partial(&settings, &download);
+        state.db.update_progress(&download.id, download.downloaded_size, download.total_size)?;
+        state.db.update_status(&download.id, DownloadStatus::Queued, None)?;
+        download.status = DownloadStatus::Queued;
+        ids.push(download.id.clone());
+        state.download_manager.requeue(download);
+    }
+    start_queued(&state);
+    info!("Resumed {} {} downloads", ids.len(), id);
+    Ok(Json(FileTypeActionResponse { ids }))
+}
+
+/// Cancel every running and queued download of a file type
+async fn cancel_file_type(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<FileTypeActionResponse>, AppError> {
+    require_file_type(&state, &id)?;
+    let (active, queued) = state.download_manager.ids_of_file_type(&id);
+    let mut ids = Vec::new();
+    for download in active {
+        if state.download_manager.cancel(&download, CancelReason::UserRequested).await {
+            ids.push(download);
+        }
+    }
+    for download in queued {
+        if state.download_manager.cancel(&download, CancelReason::UserRequested).await {
+            discard_partial(&state, &download, true).await;
+            ids.push(download);
+        }
+    }
+    info!("Cancelled {} {} downloads", ids.len(), id);
+    Ok(Json(FileTypeActionResponse { ids }))
+}
+
+/// Add file type request
+#[derive(Debug, Deserialize)]
+pub struct AddFileTypeRequest {
+    pub name: String,
+    pub extensions: Vec<String>,
+    pub destination: String,
+    #[serde(default)]
+    pub auto_extract: bool,
+    /// Overrides the global `on_conflict` for this file type
+    pub on_conflict: Option<ConflictPolicy>,
+    pub post_download_transcode: Option<TranscodeConfig>,
+    /// Dated subfolder pattern such as `%Y/%m/%d`
+    pub date_subfolder: Option<String>,
+}
+
+/// Check that downloads can be saved to `dir`, creating it if needed
+async fn check_destination(dir: PathBuf) -> Result<(), AppError> {
+    tokio::task::spawn_blocking(move || crate::fs::ensure_writable_dir(&dir))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?
+        .map_err(|e| AppError::BadRequest(format!("{:#}", e)))
+}
+
+/// Reject a transcode target that isn't a plain extension
+fn check_transcode(transcode: Option<&TranscodeConfig>) -> Result<(), AppError> {
+    match transcode {
+        Some(transcode) if !transcode.is_valid() => Err(AppError::BadRequest(
+            "Transcode format must be a file extension such as mp3".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Reject a dated subfolder pattern that's malformed or leads out of the destination
+fn check_date_subfolder(pattern: Option<&str>) -> Result<(), AppError> {
+    match pattern {
+        Some(pattern) if !config::valid_date_subfolder(pattern) => Err(AppError::BadRequest(
+            "Date subfolder must be a relative date format such as %Y/%m/%d".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Add a new file type
+async fn add_file_type(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<AddFileTypeRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    check_destination(PathBuf::from(&req.destination)).await?;
+    check_transcode(req.post_download_transcode.as_ref())?;
+    check_date_subfolder(req.date_subfolder.as_deref())?;
+    
+    let mut settings = state.settings.write();
+    
+    // Generate unique ID from name + timestamp to allow multiple categories
+    let base_id = req.name.to_lowercase().replace(|c: char| !c.is_alphanumeric(), "-");
+    let mut id = base_id.clone();
+    let mut counter = 1;
+    
+    // If ID exists, append a number to make it unique
+    while settings.file_types.contains_key(&id) {
+        id = format!("{}-{}", base_id, counter);
+        counter += 1;
+    }
+    
+    // New file types go last
+    let order = settings.file_types.values().map(|t| t.order.saturating_add(1)).max().unwrap_or(0);
+    settings.file_types.insert(
+        id.clone(),
+        FileTypeConfig {
+            name: req.name,
+            extensions: req.extensions,
+            destination: PathBuf::from(req.destination),
+            auto_extract: req.auto_extract,
+            on_conflict: req.on_conflict,
+            post_download_transcode: req.post_download_transcode,
+            date_subfolder: req.date_subfolder,
+            order,
+        },
+    );
+    
+    config::save(&settings)?;
+    
+    Ok(Json(serde_json::json!({ "id": id })))
+}
+
+/// Update file type request
+#[derive(Debug, Deserialize)]
+pub struct UpdateFileTypeRequest {
+    pub name: Option<String>,
+    pub extensions: Option<Vec<String>>,
+    pub destination: Option<String>,
+    pub auto_extract: Option<bool>,
+    /// `null` removes the override so the global policy applies again
+    #[serde(default, deserialize_with = "present_or_null")]
+    pub on_conflict: Option<Option<ConflictPolicy>>,
+    /// `null` turns transcoding off
+    #[serde(default, deserialize_with = "present_or_null")]
+    pub post_download_transcode: Option<Option<TranscodeConfig>>,
+    /// `null` saves straight into the destination again
+    #[serde(default, deserialize_with = "present_or_null")]
+    pub date_subfolder: Option<Option<String>>,
+    /// Move the completed downloads of this type from the old destination
+    /// to the new one
+    #[serde(default)]
+    pub move_existing: bool,
+}
+
+/// Response after updating a file type
+#[derive(Debug, Default, Serialize)]
+pub struct UpdateFileTypeResponse {
+    /// Downloads moved to the new destination, with their new location
+    pub moved: Vec<DownloadRecord>,
+    /// Downloads that couldn't be moved and stay where they were
+    pub failed: Vec<FailedMove>,
+}
+
+/// A download `move_existing` couldn't move
+#[derive(Debug, Serialize)]
+pub struct FailedMove {
+    pub id: String,
+    pub path: PathBuf,
+    pub error: String,
+}
+
+/// Deserialize a field where `null` means something: absent stays `None`,
+/// `null` becomes `Some(None)`
+fn present_or_null<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Update an existing file type
+async fn update_file_type(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(req): Json<UpdateFileTypeRequest>,
+) -> Result<Json<UpdateFileTypeResponse>, AppError> {
+    if let Some(destination) = &req.destination {
+        check_destination(PathBuf::from(destination)).await?;
+    }
+    if let Some(transcode) = &req.post_download_transcode {
+        check_transcode(transcode.as_ref())?;
+    }
+    if let Some(pattern) = &req.date_subfolder {
+        check_date_subfolder(pattern.as_deref())?;
+    }
+    
+    // Settings are released before any file is moved
+    let relocation = {
+        let mut settings = state.settings.write();
+        
+        let file_type = settings
+            .file_types
+            .get_mut(&id)
+            .ok_or_else(|| AppError::NotFound("File type not found".into()))?;
+        
+        let relocation = req
+            .destination
+            .as_ref()
+            .filter(|_| req.move_existing)
+            .map(|destination| (file_type.destination.clone(), PathBuf::from(destination)));
+        
+        if let Some(name) = req.name {
+            file_type.name = name;
+        }
+        if let Some(extensions) = req.extensions {
+            file_type.extensions = extensions;
+        }
+        if let Some(destination) = req.destination {
+            file_type.destination = PathBuf::from(destination);
+        }
+        if let Some(auto_extract) = req.auto_extract {
+            file_type.auto_extract = auto_extract;
+        }
+        if let Some(on_conflict) = req.on_conflict {
+            file_type.on_conflict = on_conflict;
+        }
+        if let Some(transcode) = req.post_download_transcode {
+            file_type.post_download_transcode = transcode;
+        }
+        if let Some(pattern) = req.date_subfolder {
+            file_type.date_subfolder = pattern;
+        }
+        
+        config::save(&settings)?;
+        relocation
+    };
+    
+    let response = match relocation {
+        Some((from, to)) if !same_folder(&from, &to) => move_file_type_downloads(&state, &id, &from, &to).await?,
+        _ => UpdateFileTypeResponse::default(),
+    };
+    Ok(Json(response))
+}
+
+/// Move the completed downloads of `file_type` saved under `from` to the
+/// same place under `to`. A download that can't be moved is reported and
+/// left where it is; the rest are still moved.
+async fn move_file_type_downloads(
+    state: &AppState,
+    file_type: &str,
+    from: &std::path::Path,
+    to: &std::path::Path,
+) -> Result<UpdateFileTypeResponse, AppError> {
+    let mut response = UpdateFileTypeResponse::default();
+    let records = state.db.get_all_downloads()?.into_iter().filter(|r| {
+        r.file_type == file_type && r.status == DownloadStatus::Completed && r.destination.starts_with(from)
+    });
+    
+    for mut record in records {
+        let path = record.destination.join(&record.filename);
+        let destination = match record.destination.strip_prefix(from) {
+            Ok(subfolder) => to.join(subfolder),
+            Err(_) => to.to_path_buf(),
+        };
+        let moved = async {
+            anyhow::ensure!(path.is_file(), "{} no longer exists", path.display());
+            tokio::fs::create_dir_all(&destination).await?;
+            relocate_file(state, &mut record, destination).await
+        };
+        match moved.await {
+            Ok(_) => response.moved.push(record),
+            Err(e) => response.failed.push(FailedMove {
+                id: record.id.clone(),
+                path,
+                error: format!("{:#}", e),
+            }),
+        }
+    }
+    
+    if !response.moved.is_empty() {
+        state.download_manager.notify_change(download::DownloadChange::resync());
+    }
+    Ok(response)
+}
+
+/// Remove a file type
+async fn remove_file_type(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let mut settings = state.settings.write();
+    
+    if id == "general" {
+        return Err(AppError::BadRequest("Cannot remove default file type".into()));
+    }
+    
+    if settings.remove_file_type(&id).is_none() {
+        return Err(AppError::NotFound("File type not found".into()));
+    }
+    
+    config::save(&settings)?;
+    
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// ============ Error Handling ============
+
+/// A request field that failed validation
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    /// Path of the field, e.g. `server.port` or `file_types.video.destination`
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Application error type. Sent as `{"error": {"code", "message"}}`, plus
+/// `fields` for `InvalidFields`.
+#[derive(Debug)]
+pub enum AppError {
+    Internal(String),
+    BadRequest(String),
+    NotFound(String),
+    Forbidden(String),
+    Conflict(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    ServiceUnavailable(String),
+    /// One or more fields of the request are invalid
+    InvalidFields(Vec<FieldError>),
+}
+
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::InvalidFields(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+    
+    /// Machine-readable code for clients to act on; part of the API, so
+    /// existing codes must not change
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::Internal(_) => "INTERNAL",
+            AppError::BadRequest(_) => "BAD_REQUEST",
+            AppError::NotFound(_) => "NOT_FOUND",
+            AppError::Forbidden(_) => "FORBIDDEN",
+            AppError::Conflict(_) => "CONFLICT",
+            AppError::PayloadTooLarge(_) => "PAYLOAD_TOO_LARGE",
+            AppError::UnsupportedMediaType(_) => "UNSUPPORTED_MEDIA_TYPE",
+            AppError::ServiceUnavailable(_) => "SERVICE_UNAVAILABLE",
+            AppError::InvalidFields(_) => "INVALID_FIELDS",
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        let mut error = serde_json::json!({ "code": self.code(), "message": self.to_string() });
+        if let AppError::InvalidFields(fields) = &self {
+            error["fields"] = serde_json::json!(fields);
+        }
+        (self.status(), Json(serde_json::json!({ "error": error }))).into_response()
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::Internal(msg)
+            | AppError::BadRequest(msg)
+            | AppError::NotFound(msg)
+            | AppError::Forbidden(msg)
+            | AppError::Conflict(msg)
+            | AppError::PayloadTooLarge(msg)
+            | AppError::UnsupportedMediaType(msg)
+            | AppError::ServiceUnavailable(msg) => f.write_str(msg),
+            AppError::InvalidFields(fields) => {
+                let fields: Vec<String> = fields.iter().map(|e| format!("{} {}", e.field, e.message)).collect();
+                write!(f, "Invalid fields: {}", fields.join("; "))
+            }
+        }
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        AppError::Internal(err.to_string())
+    }
+}
+
+/// Build the request for a download: a GET, or a POST with its body and
+/// content type. `${VIBE_...}` placeholders in its headers and query
+/// parameters are expanded here, so the resolved values never reach the
+/// database; one naming an unset or disallowed variable fails the request.
+fn download_request(
+    record: &DownloadRecord,
+    client: &reqwest::Client,
+    settings: &Settings,
+) -> anyhow::Result<reqwest::RequestBuilder> {
+    let method = match record.method {
+        RequestMethod::Get => reqwest::Method::GET,
+        RequestMethod::Post => reqwest::Method::POST,
+    };
+    let mut request = client.request(method, &record.url);
+    
+    // reqwest appends these to any query already in the URL
+    if !record.query_params.is_empty() {
+        let query = record
+            .query_params
+            .iter()
+            .map(|(name, value)| Ok((name.as_str(), download::expand_env(value)?)))
+            .collect::<Result<Vec<_>, download::EnvVarError>>()?;
+        request = request.query(&query);
+    }
+    for (name, value) in &record.headers {
+        let mut value = reqwest::header::HeaderValue::from_str(&download::expand_env(value)?)
+            .map_err(|_| anyhow::anyhow!("Invalid value for header {} after expansion", name))?;
+        // Keeps likely secrets out of debug output
+        value.set_sensitive(true);
+        request = request.header(name.as_str(), value);
+    }
+    if let Some(accept) = &record.accept {
+        request = request.header(reqwest::header::ACCEPT, accept);
+    }
+    if let Some(content_type) = &record.content_type {
+        request = request.header(reqwest::header::CONTENT_TYPE, content_type);
+    }
+    if let Some(body) = &record.body {
+        request = request.body(body.clone());
+    }
+    if keeps_encoding(settings, record) {
+        // Hint that we want the bytes as-is; if a server compresses anyway
+        // the encoded bytes are stored and still match Content-Length.
+        request = request.header(reqwest::header::ACCEPT_ENCODING, "identity");
+    }
+    
+    Ok(request)
+}
+
+/// The URL a response came from, with the values of query parameters
+/// expanded from `${VAR}` placeholders put back as the placeholders, so the
+/// resolved values aren't stored or reported
+fn final_url(response: &reqwest::Response, record: &DownloadRecord) -> String {
+    let mut url = response.url().clone();
+    let templates: Vec<&(String, String)> = record.query_params.iter().filter(|(_, value)| value.contains("${")).collect();
+    if templates.is_empty() || url.query().is_none() {
+        return url.to_string();
+    }
+    
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| {
+            let value = match templates.iter().find(|(template, _)| *template == name) {
+                Some((_, template)) => template.clone(),
+                None => value.into_owned(),
+            };
+            (name.into_owned(), value)
+        })
+        .collect();
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+    url.to_string()
+}
+
+/// Whether a download is saved as the bytes the server holds rather than
+/// decompressed. Besides `content_encoding = "original"`, that's the case for
+/// byte ranges, which only make sense for the stored bytes, and for files
+/// that are compressed themselves: a `.tar.gz` a server sends with
+/// `Content-Encoding: gzip` would otherwise be saved as a bare tar under its
+/// `.tar.gz` name.
+fn keeps_encoding(settings: &Settings, record: &DownloadRecord) -> bool {
+    settings.content_encoding == ContentEncodingMode::Original
+        || record.range_start.is_some()
+        || download::is_precompressed(&record.filename)
+}
+
+/// Headers that identify a version of a file, for asking the server later
+/// whether it changed
+#[derive(Debug, Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    fn from_headers(headers: &reqwest::header::HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+        }
+    }
+}
+
+/// How far past its expected size a download may go before it's worth a
+/// warning. It completes either way.
+const EXPECTED_SIZE_TOLERANCE_PERCENT: u64 = 1;
+
+/// Whether `downloaded` bytes go past `expected` by more than the tolerance.
+/// Worked out in u128, so small sizes aren't rounded down to no tolerance
+/// and the largest don't overflow.
+fn beyond_expected_size(downloaded: u64, expected: u64) -> bool {
+    u128::from(downloaded) * 100 > u128::from(expected) * u128::from(100 + EXPECTED_SIZE_TOLERANCE_PERCENT)
+}
+
+/// A partial file the server agreed to continue
+struct ResumedDownload {
+    stream: futures_util::stream::BoxStream<'static, reqwest::Result<axum::body::Bytes>>,
+    /// URL the response came from after redirects
+    url: String,
+    validators: Validators,
+    total_size: Option<u64>,
+    /// Bytes already in the partial file
+    offset: u64,
+    /// Bytes received past the verified overlap, not yet written
+    leftover: axum::body::Bytes,
+}
+
+/// What a successful download produced
+#[derive(Debug)]
+struct FinishedDownload {
+    /// Size of the finished file
+    size: u64,
+    /// Name it was saved under
+    filename: String,
+    /// URL the content finally came from, after any redirects
+    final_url: String,
+    validators: Validators,
+    /// The server said the content hadn't changed, so nothing was downloaded
+    not_modified: bool,
+    /// Digest of the file, when it was verified or `compute_checksums` is on
+    checksum: Option<String>,
+}
+
+/// Parse `Content-Range: bytes first-last/total` into `(first, last, total)`
+fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    let (first, last) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+    // A range ending before it starts is malformed
+    (first <= last).then_some((first, last, total))
+}
+
+/// `Range` header value asking for a download's bytes from `from` on.
+/// For a download of only part of a file, `from` counts from the start of
+/// that part and the request stops at its end.
+fn range_header(record: &DownloadRecord, from: u64) -> String {
+    let start = record.range_start.unwrap_or(0) + from;
+    match record.range_end {
+        Some(end) => format!("bytes={}-{}", start, end),
+        None => format!("bytes={}-", start),
+    }
+}
+
+/// Size of the part of the file a ranged download asked for, read from the
+/// answer to a request made with `range_header(record, from)`. Fails if the
+/// server sent something other than that part.
+fn requested_range_size(
+    record: &DownloadRecord,
+    from: u64,
+    status: StatusCode,
+    headers: &reqwest::header::HeaderMap,
+) -> anyhow::Result<u64> {
+    let range_start = record.range_start.unwrap_or(0);
+    let range = headers
+        .get(reqwest::header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_content_range);
+    match range {
+        Some((first, last, _)) if status == StatusCode::PARTIAL_CONTENT && Some(first) == range_start.checked_add(from) => {
+            // `first <= last`, so only the + 1 can overflow
+            last.checked_add(1)
+                .map(|end| end - range_start)
+                .ok_or_else(|| anyhow::anyhow!("The server sent a range past the largest file size"))
+        }
+        _ => anyhow::bail!("The server doesn't support byte ranges, so part of the file can't be downloaded"),
+    }
+}
+
+/// Full size of the resource a response is part of. A `Content-Range`
+/// carries it after the slash, `*` meaning the server doesn't know; a 206
+/// without one has a `Content-Length` covering only the range, so it isn't used.
+fn response_total_size(status: StatusCode, headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    let range = headers
+        .get(reqwest::header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_content_range);
+    match range {
+        Some((_, _, total)) => total,
+        None if status == StatusCode::PARTIAL_CONTENT => None,
+        None => headers
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()),
+    }
+}
+
+/// Try to continue a partial file left by an interrupted attempt.
+///
+/// With `resume_verify_bytes` set, the request backs up that many bytes and
+/// they must match the end of the partial file; a mismatch means the partial
+/// is corrupt. Returns `None` whenever the download has to start over.
+async fn resume_partial(
+    record: &DownloadRecord,
+    client: &reqwest::Client,
+    settings: &Settings,
+    temp_path: &std::path::Path,
+) -> anyhow::Result<Option<ResumedDownload>> {
+    // Ranges address the encoded bytes, which we only store when responses
+    // aren't being decompressed
+    if !keeps_encoding(settings, record) {
+        return Ok(None);
+    }
+    // Few servers honour a range on a POST, and asking costs another
+    // request the server acts on, so these start over
+    if record.method == RequestMethod::Post {
+        return Ok(None);
+    }
+    let existing = match tokio::fs::metadata(temp_path).await {
+        Ok(meta) if meta.len() > 0 => meta.len(),
+        _ => return Ok(None),
+    };
+    
+    let overlap = settings.resume_verify_bytes.min(existing);
+    let start = existing - overlap;
+    
+    let response = download_request(record, client, settings)?
+        .header(reqwest::header::RANGE, range_header(record, start))
+        .send()
+        .await?;
+    
+    let total_size = if record.range_start.is_some() {
+        // Starting over would hit the same refusal, so fail outright
+        Some(requested_range_size(record, start, response.status(), response.headers())?)
+    } else {
+        let range = response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_content_range);
+        match range {
+            Some((first, _, total)) if response.status() == StatusCode::PARTIAL_CONTENT && first == start => total,
+            _ => {
+                info!("Server can't resume {}, starting over", record.filename);
+                return Ok(None);
+            }
+        }
+    };
+    
+    let url = final_url(&response, record);
+    let validators = Validators::from_headers(response.headers());
+    let mut stream = response.bytes_stream().boxed();
+    let mut fetched = Vec::new();
+    while (fetched.len() as u64) < overlap {
+        match stream.next().await {
+            Some(chunk) => fetched.extend_from_slice(&chunk?),
+            None => break,
+        }
+    }
+    
+    if overlap > 0 {
+        let mut tail = vec![0u8; overlap as usize];
+        let mut file = File::open(temp_path).await?;
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        file.read_exact(&mut tail).await?;
+        
+        if fetched.get(..tail.len()) != Some(&tail[..]) {
+            tracing::warn!("Partial file for {} doesn't match the server, starting over", record.filename);
+            return Ok(None);
+        }
+    }
+    
+    Ok(Some(ResumedDownload {
+        stream,
+        url,
+        validators,
+        total_size,
+        offset: existing,
+        leftover: axum::body::Bytes::from(fetched).slice(overlap as usize..),
+    }))
+}
+
+/// Download a record, retrying failures that may not happen again (see
+/// `download::retry_class`). `on_retry` hears about each failure that
+/// will be retried and how long until the next attempt.
+#[allow(clippy::too_many_arguments)]
+async fn download_with_retries(
+    record: &DownloadRecord,
+    clients: &download::HttpClients,
+    settings: &Settings,
+    policy: &download::RetryPolicy,
+    progress_tx: &tokio::sync::broadcast::Sender<download::ProgressUpdate>,
+    cancel_rx: &mut tokio::sync::mpsc::Receiver<CancelReason>,
+    speed_limit: &download::SpeedLimit,
+    filenames: &download::FilenameReservations,
+    final_name: impl Fn() -> Option<String>,
+    mut on_retry: impl FnMut(&anyhow::Error, std::time::Duration),
+) -> anyhow::Result<FinishedDownload> {
+    let mut retries = download::RetryState::default();
+    loop {
+        let result = download_file_with_cancel(
+            record,
+            clients,
+            settings,
+            progress_tx,
+            cancel_rx,
+            speed_limit,
+            filenames,
+            &final_name,
+        )
+        .await;
+        
+        let Err(e) = &result else { return result };
+        let Some(delay) = retries.next_delay(policy, e) else {
+            // Out of retries, so a partial kept for the next attempt goes
+            if download::retry_class(e) != download::RetryClass::Fatal {
+                let _ = tokio::fs::remove_file(settings.partial_path(&record.destination, &record.filename)).await;
+            }
+            return result;
+        };
+        on_retry(e, delay);
+        
+        // Stay cancellable while waiting. A partial file kept for resuming
+        // goes, as it would mid-transfer, unless this is a
+        // shutdown or a pause.
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            reason = cancel_rx.recv() => {
+                let reason = reason.unwrap_or(CancelReason::UserRequested);
+                if !reason.keeps_partial() {
+                    discard_cancelled_partial(settings, record, reason).await;
+                }
+                return Err(download::Cancelled(reason).into());
+            }
+        }
+    }
+}
+
+/// Download from the record's URL, falling back to each of its mirrors in
+/// turn. Cancellation stops at once; any other failure moves on to the next
+/// source, and the last failure is returned if none succeed.
+#[allow(clippy::too_many_arguments)]
+async fn download_file_with_cancel(
+    record: &DownloadRecord,
+    clients: &download::HttpClients,
+    settings: &Settings,
+    progress_tx: &tokio::sync::broadcast::Sender<download::ProgressUpdate>,
+    cancel_rx: &mut tokio::sync::mpsc::Receiver<CancelReason>,
+    speed_limit: &download::SpeedLimit,
+    filenames: &download::FilenameReservations,
+    final_name: impl FnOnce() -> Option<String>,
+) -> anyhow::Result<FinishedDownload> {
+    let mut final_name = Some(final_name);
+    let mut source = &record.url;
+    let mut result = download_from_source(
+        record,
+        clients.for_download(&record.url, keeps_encoding(settings, record)),
+        settings,
+        progress_tx,
+        cancel_rx,
+        speed_limit,
+        filenames,
+        || final_name.take().and_then(|f| f()),
+    )
+    .await;
+    
+    for mirror in &record.mirrors {
+        match &result {
+            Err(e) if !e.is::<download::Cancelled>() => {
+                tracing::warn!("{} failed from {} ({}), trying {}", record.filename, source, e, mirror);
+            }
+            _ => break,
+        }
+        
+        source = mirror;
+        // The headers may hold credentials meant for the original host only
+        let headers = if same_host(&record.url, mirror) { record.headers.clone() } else { Vec::new() };
+        let attempt = DownloadRecord { url: mirror.clone(), headers, ..record.clone() };
+        result = download_from_source(
+            &attempt,
+            clients.for_download(mirror, keeps_encoding(settings, record)),
+            settings,
+            progress_tx,
+            cancel_rx,
+            speed_limit,
+            filenames,
+            || final_name.take().and_then(|f| f()),
+        )
+        .await;
+    }
+    
+    result
+}
+
+/// Whether two URLs point at the same host
+fn same_host(a: &str, b: &str) -> bool {
+    let host = |url: &str| url::Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_string));
+    host(a).is_some_and(|a| Some(a) == host(b))
+}
+
+/// Download a record from its `url` alone, with cancellation support.
+/// `final_name` is asked just before the partial file is renamed, so a rename
+/// made while downloading still applies.
+#[allow(clippy::too_many_arguments)]
+async fn download_from_source(
+    record: &DownloadRecord,
+    client: &reqwest::Client,
+    settings: &Settings,
+    progress_tx: &tokio::sync::broadcast::Sender<download::ProgressUpdate>,
+    cancel_rx: &mut tokio::sync::mpsc::Receiver<CancelReason>,
+    speed_limit: &download::SpeedLimit,
+    filenames: &download::FilenameReservations,
+    final_name: impl FnOnce() -> Option<String>,
+) -> anyhow::Result<FinishedDownload> {
+    // Ensure destination directory exists
+    let destination = record.destination.clone();
+    tokio::task::spawn_blocking(move || crate::fs::create_dir(&destination)).await??;
+    
+    // Write to a partial file while downloading
+    let temp_path = settings.partial_path(&record.destination, &record.filename);
+    
+    let (mut stream, total_size, resumed_from, mut file, final_url, validators) =
+        match resume_partial(record, client, settings, &temp_path).await? {
+            Some(resumed) => {
+                info!("Resuming {} from byte {}", record.filename, resumed.offset);
+                let mut file = tokio::fs::OpenOptions::new().append(true).open(&temp_path).await?;
+                file.write_all(&resumed.leftover).await?;
+                let offset = resumed.offset + resumed.leftover.len() as u64;
+                (resumed.stream, resumed.total_size, offset, file, resumed.url, resumed.validators)
+            }
+            None => {
+                let mut request = download_request(record, client, settings)?;
+                if record.range_start.is_some() {
+                    request = request.header(reqwest::header::RANGE, range_header(record, 0));
+                }
+                // Until it completes, a download's validators are the ones
+                // copied from the earlier download of the same URL
+                if record.skip_if_unmodified {
+                    if let Some(etag) = &record.etag {
+                        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+                    }
+                    if let Some(last_modified) = &record.last_modified {
+                        request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+                    }
+                }
+                let response = request.send().await?;
+                
+                if response.status() == StatusCode::NOT_MODIFIED && record.skip_if_unmodified {
+                    info!("{} hasn't changed, skipping the download", record.filename);
+                    let size = tokio::fs::metadata(record.destination.join(&record.filename))
+                        .await
+                        .map(|meta| meta.len())
+                        .unwrap_or(0);
+                    // A 304 may bring fresh validators; otherwise the old ones still hold
+                    let fresh = Validators::from_headers(response.headers());
+                    return Ok(FinishedDownload {
+                        size,
+                        filename: record.filename.clone(),
+                        final_url: final_url(&response, record),
+                        validators: Validators {
+                            etag: fresh.etag.or_else(|| record.etag.clone()),
+                            last_modified: fresh.last_modified.or_else(|| record.last_modified.clone()),
+                        },
+                        not_modified: true,
+                        checksum: None,
+                    });
+                }
+                
+                if !response.status().is_success() {
+                    return Err(download::HttpStatusError(response.status()).into());
+                }
+                
+                let total_size = if record.range_start.is_some() {
+                    Some(requested_range_size(record, 0, response.status(), response.headers())?)
+                } else if response.status() == StatusCode::PARTIAL_CONTENT {
+                    // Some servers answer a plain GET with a 206 for the whole
+                    // file, often without Content-Length; the size is then only
+                    // in Content-Range
+                    let range = response
+                        .headers()
+                        .get(reqwest::header::CONTENT_RANGE)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(parse_content_range);
+                    match range {
+                        // It counts encoded bytes, which are only what's stored
+                        // when responses aren't decompressed
+                        Some((0, _, total)) if keeps_encoding(settings, record) => total,
+                        Some((0, _, _)) => None,
+                        _ => anyhow::bail!("Server sent part of the file when the whole was requested"),
+                    }
+                } else {
+                    // When reqwest decodes the body it drops Content-Length, so this is
+                    // None rather than a compressed size that would skew the percentage
+                    response.content_length()
+                };
+                let final_url = final_url(&response, record);
+                let validators = Validators::from_headers(response.headers());
+                let file = File::create(&temp_path).await?;
+                (response.bytes_stream().boxed(), total_size, 0, file, final_url, validators)
+            }
+        };
+    // Without a size from the server, progress goes by the one the download
+    // was added with
+    let expected_size = if total_size.is_none() { record.total_size } else { None };
+    let total_size = total_size.or(expected_size);
+    
+    let expected_checksum = record.checksum.as_deref().and_then(download::Checksum::parse);
+    let hash_algorithm = match &expected_checksum {
+        Some(expected) => Some(expected.algorithm),
+        None if settings.compute_checksums => Some(download::HashAlgorithm::Sha256),
+        None => None,
+    };
+    // A resumed download's earlier bytes are only on disk, so it's hashed
+    // from the file once complete instead
+    let mut hasher = hash_algorithm.filter(|_| resumed_from == 0).map(download::StreamHasher::new);
+    
+    let mut downloaded = resumed_from;
+    let mut throttle = download::Throttle::for_download(speed_limit);
+    let start_time = std::time::Instant::now();
+    let mut last_update = std::time::Instant::now();
+    
+    loop {
+        tokio::select! {
+            // Check for cancellation
+            reason = cancel_rx.recv() => {
+                let reason = reason.unwrap_or(CancelReason::UserRequested);
+                // All of it on disk, so its length is what was downloaded
+                let _ = file.flush().await;
+                drop(file);
+                // Keep the partial file across a shutdown or pause so it can be resumed
+                if !reason.keeps_partial() {
+                    discard_cancelled_partial(settings, record, reason).await;
+                }
+                return Err(download::Cancelled(reason).into());
+            }
+            // Process next chunk, once the speed limit allows it
+            chunk = async {
+                throttle.wait().await;
+                stream.next().await
+            } => {
+                match chunk {
+                    Some(Ok(bytes)) => {
+                        throttle.consume(bytes.len() as u64);
+                        if let Err(e) = file.write_all(&bytes).await {
+                            drop(file);
+                            let _ = tokio::fs::remove_file(&temp_path).await;
+                            if e.kind() == std::io::ErrorKind::StorageFull {
+                                return Err(download::Cancelled(CancelReason::DiskFull).into());
+                            }
+                            return Err(e.into());
+                        }
+                        if let Some(hasher) = &mut hasher {
+                            hasher.update(&bytes);
+                        }
+                        downloaded += bytes.len() as u64;
+                        
+                        // Send progress every 200ms
+                        if last_update.elapsed().as_millis() >= 200 {
+                            let elapsed = start_time.elapsed().as_secs_f64();
+                            let session = downloaded - resumed_from;
+                            let speed = if elapsed > 0.0 { (session as f64 / elapsed) as u64 } else { 0 };
+                            
+                            let _ = progress_tx.send(download::ProgressUpdate {
+                                id: record.id.clone(),
+                                downloaded,
+                                total: total_size,
+                                indeterminate: total_size.is_none(),
+                                speed,
+                                status: DownloadStatus::Downloading,
+                                error: None,
+                                started_at: None,
+                            });
+                            last_update = std::time::Instant::now();
+                        }
+                    }
+                    Some(Err(e)) => {
+                        drop(file);
+                        let message = format!("Download error: {}", e);
+                        let err = anyhow::Error::new(e).context(message);
+                        // A dropped connection is retried, resuming from
+                        // what's on disk; anything else starts clean
+                        if download::retry_class(&err) == download::RetryClass::Fatal {
+                            let _ = tokio::fs::remove_file(&temp_path).await;
+                        }
+                        return Err(err);
+                    }
+                    None => break, // Stream ended
+                }
+            }
+        }
+    }
+    
+    file.flush().await?;
+    if settings.fsync_on_complete {
+        file.sync_all().await?;
+    }
+    drop(file);
+    
+    if let Some(expected) = expected_size {
+        if beyond_expected_size(downloaded, expected) {
+            tracing::warn!(
+                "{} is {} bytes, more than the {} bytes expected",
+                record.filename,
+                downloaded,
+                expected
+            );
+        }
+    }
+    
+    let checksum = match (hasher, hash_algorithm) {
+        (Some(hasher), _) => Some(hasher.finish()),
+        (None, Some(algorithm)) => {
+            let path = temp_path.clone();
+            let digest = tokio::task::spawn_blocking(move || download::file_digest(&path, algorithm)).await??;
+            Some(download::Checksum { algorithm, digest })
+        }
+        (None, None) => None,
+    };
+    if let (Some(expected), Some(actual)) = (&expected_checksum, &checksum) {
+        if actual.digest != expected.digest {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(download::ChecksumMismatch {
+                expected: expected.to_string(),
+                actual: actual.to_string(),
+            }
+            .into());
+        }
+    }
+    
+    // Rename from the partial file to the final filename
+    // Held until the file has its name, so a download finishing alongside
+    // can't pick the same one
+    let filename = final_name().unwrap_or_else(|| record.filename.clone());
+    let reserved = filenames.reserve(&record.destination, &filename, settings.conflict_policy(&record.file_type));
+    let filename = reserved.filename().to_string();
+    tokio::fs::rename(&temp_path, record.destination.join(&filename)).await?;
+    
+    // The rename itself is only durable once the folder is synced (Unix)
+    #[cfg(unix)]
+    if settings.fsync_on_complete {
+        File::open(&record.destination).await?.sync_all().await?;
+    }
+    
+    Ok(FinishedDownload {
+        size: downloaded,
+        filename,
+        final_url,
+        validators,
+        not_modified: false,
+        checksum: checksum.map(|c| c.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{Body, Bytes};
+    use axum::http::header;
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use tokio::sync::{broadcast, mpsc};
+    
+    const PAYLOAD_LEN: usize = 64 * 1024;
+    const SLOW_CHUNK: usize = 1024;
+    const SLOW_DELAY: Duration = Duration::from_millis(25);
+    
+    /// Deterministic test payload
+    fn payload() -> Vec<u8> {
+        (0..PAYLOAD_LEN).map(|i| (i % 251) as u8).collect()
+    }
+    
+    /// Payload with Content-Length and Accept-Ranges
+    async fn serve_sized() -> Response {
+        (
+            [(header::ACCEPT_RANGES, "bytes")],
+            Body::from(payload()),
+        )
+            .into_response()
+    }
+    
+    /// Payload streamed without Content-Length
+    async fn serve_unsized() -> Response {
+        let chunks: Vec<Result<Bytes, Infallible>> = payload()
+            .chunks(4096)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        Body::from_stream(futures_util::stream::iter(chunks)).into_response()
+    }
+    
+    /// Payload trickled out slowly, like a speed-limited server
+    async fn serve_slow() -> Response {
+        let chunks: Vec<Bytes> = payload()
+            .chunks(SLOW_CHUNK)
+            .map(Bytes::copy_from_slice)
+            .collect();
+        let stream = futures_util::stream::iter(chunks).then(|chunk| async move {
+            tokio::time::sleep(SLOW_DELAY).await;
+            Ok::<_, Infallible>(chunk)
+        });
+        (
+            [(header::CONTENT_LENGTH, PAYLOAD_LEN.to_string())],
+            Body::from_stream(stream),
+        )
+            .into_response()
+    }
+    
+    /// Payload served with `Range` support (`bytes=N-` and `bytes=N-M`)
+    async fn serve_ranged(headers: axum::http::HeaderMap) -> Response {
+        let body = payload();
+        let range = headers
+            .get(header::RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes="))
+            .and_then(|v| v.split_once('-'));
+        let start = range.and_then(|(start, _)| start.parse::<usize>().ok());
+        let last = range
+            .and_then(|(_, end)| end.parse::<usize>().ok())
+            .map_or(body.len() - 1, |end| end.min(body.len() - 1));
+        match start {
+            Some(start) if start < body.len() => (
+                StatusCode::PARTIAL_CONTENT,
+                [(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, last, body.len()),
+                )],
+                body[start..=last].to_vec(),
+            )
+                .into_response(),
+            Some(_) => StatusCode::RANGE_NOT_SATISFIABLE.into_response(),
+            None => body.into_response(),
+        }
+    }
+    
+    /// Slow 206 replies with `Content-Range` but no `Content-Length`, even to
+    /// requests without `Range`. `?total=unknown` sends `*` as the total.
+    async fn serve_partial_unsized(
+        headers: axum::http::HeaderMap,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> Response {
+        let start = headers
+            .get(header::RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes="))
+            .and_then(|v| v.trim_end_matches('-').parse::<usize>().ok())
+            .unwrap_or(0);
+        let total = match query.get("total").map(String::as_str) {
+            Some("unknown") => "*".to_string(),
+            _ => PAYLOAD_LEN.to_string(),
+        };
+        let chunks: Vec<Bytes> = payload()[start..]
+            .chunks(SLOW_CHUNK * 4)
+            .map(Bytes::copy_from_slice)
+            .collect();
+        let stream = futures_util::stream::iter(chunks).then(|chunk| async move {
+            tokio::time::sleep(SLOW_DELAY).await;
+            Ok::<_, Infallible>(chunk)
+        });
+        (
+            StatusCode::PARTIAL_CONTENT,
+            [(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, PAYLOAD_LEN - 1, total))],
+            Body::from_stream(stream),
+        )
+            .into_response()
+    }
+    
+    /// Echo the request's query string and Accept header back as the body
+    async fn serve_echo(uri: axum::http::Uri, headers: axum::http::HeaderMap) -> String {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        format!("{}\n{}", uri.query().unwrap_or(""), accept)
+    }
+    
+    /// The payload gzipped, as a server would hold a `.gz` file
+    fn gzipped_payload() -> Vec<u8> {
+        use std::io::Write;
+        
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&payload()).unwrap();
+        encoder.finish().unwrap()
+    }
+    
+    /// Gzipped payload, always labelled `Content-Encoding: gzip` whatever the
+    /// client asked for, like a server that mistakes `.gz` files for ones it
+    /// compressed itself
+    async fn serve_gzipped() -> Response {
+        ([(header::CONTENT_ENCODING, "gzip")], Body::from(gzipped_payload())).into_response()
+    }
+    
+    /// Echo a POST's Content-Type and body back as the body
+    async fn serve_post_echo(headers: axum::http::HeaderMap, body: String) -> String {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        format!("{}\n{}", content_type, body)
+    }
+    
+    /// Payload with an `ETag`, answering 304 when it's sent back
+    async fn serve_versioned(headers: axum::http::HeaderMap) -> Response {
+        const ETAG: &str = "\"v1\"";
+        if headers.get(header::IF_NONE_MATCH).is_some_and(|v| v == ETAG) {
+            return StatusCode::NOT_MODIFIED.into_response();
+        }
+        (
+            [
+                (header::ETAG, ETAG),
+                (header::LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ],
+            Body::from(payload()),
+        )
+            .into_response()
+    }
+    
+    /// Routes of the mock file server
+    fn mock_app() -> Router {
+        Router::new()
+            .route("/sized.bin", get(serve_sized))
+            .route("/unsized.bin", get(serve_unsized))
+            .route("/slow.bin", get(serve_slow))
+            .route("/ranged.bin", get(serve_ranged))
+            .route("/partial-unsized.bin", get(serve_partial_unsized))
+            .route("/missing.bin", get(|| async { StatusCode::NOT_FOUND }))
+            .route("/moved.bin", get(|| async { axum::response::Redirect::temporary("/sized.bin") }))
+            .route("/echo.txt", get(serve_echo))
+            .route("/gzipped/{name}", get(serve_gzipped))
+            .route("/post-echo.txt", post(serve_post_echo))
+            .route("/versioned.bin", get(serve_versioned))
+    }
+    
+    /// Serve the mock routes on an already bound listener
+    fn serve_mock(listener: tokio::net::TcpListener) {
+        tokio::spawn(async move {
+            axum::serve(listener, mock_app()).await.unwrap();
+        });
+    }
+    
+    /// Start a mock file server, returning its base URL
+    async fn mock_server() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve_mock(listener);
+        format!("http://{}", addr)
+    }
+    
+    fn record(url: String, dir: &std::path::Path) -> DownloadRecord {
+        let filename = download::extract_filename(&url, None);
+        DownloadRecord::new(url, filename, "general".into(), dir.to_path_buf())
+    }
+    
+    fn client(settings: &Settings) -> download::HttpClients {
+        download::build_http_client(settings).unwrap()
+    }
+    
+    fn unlimited() -> download::SpeedLimit {
+        download::SpeedLimit::default()
+    }
+    
+    /// Run a download to completion (or failure) without cancelling
+    async fn run(record: &DownloadRecord) -> anyhow::Result<u64> {
+        run_with(record, &Settings::default()).await
+    }
+    
+    async fn run_with(record: &DownloadRecord, settings: &Settings) -> anyhow::Result<u64> {
+        let (progress_tx, _) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        download_file_with_cancel(record, &client(settings), settings, &progress_tx, &mut cancel_rx, &unlimited(), &Default::default(), || None)
+            .await
+            .map(|finished| finished.size)
+    }
+    
+    /// Names of all files left in a directory
+    fn files_in(dir: &std::path::Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+    
+    #[tokio::test]
+    async fn downloads_sized_payload_and_renames_part_file() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/sized.bin", base), dir.path());
+        
+        let written = run(&record).await.unwrap();
+        
+        assert_eq!(written, PAYLOAD_LEN as u64);
+        assert_eq!(std::fs::read(dir.path().join("sized.bin")).unwrap(), payload());
+        assert_eq!(files_in(dir.path()), vec!["sized.bin"]);
+    }
+    
+    #[tokio::test]
+    async fn downloads_payload_without_content_length() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/unsized.bin", base), dir.path());
+        
+        let written = run(&record).await.unwrap();
+        
+        assert_eq!(written, PAYLOAD_LEN as u64);
+        assert_eq!(std::fs::read(dir.path().join("unsized.bin")).unwrap(), payload());
+    }
+    
+    #[tokio::test]
+    async fn expected_size_stands_in_for_a_missing_content_length() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = record(format!("{}/unsized.bin", base), dir.path());
+        // Smaller than the payload, which only warns
+        record.total_size = Some(PAYLOAD_LEN as u64 / 2);
+        
+        let settings = Settings::default();
+        let (progress_tx, mut progress_rx) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        let finished = download_file_with_cancel(&record, &client(&settings), &settings, &progress_tx, &mut cancel_rx, &unlimited(), &Default::default(), || None)
+            .await
+            .unwrap();
+        
+        assert_eq!(finished.size, PAYLOAD_LEN as u64);
+        while let Ok(update) = progress_rx.try_recv() {
+            assert_eq!(update.total, record.total_size);
+            assert!(!update.indeterminate);
+        }
+    }
+    
+    #[tokio::test]
+    async fn fsync_on_complete_still_finishes() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/sized.bin", base), dir.path());
+        let settings = Settings {
+            fsync_on_complete: true,
+            ..Settings::default()
+        };
+        
+        assert_eq!(run_with(&record, &settings).await.unwrap(), PAYLOAD_LEN as u64);
+        assert_eq!(files_in(dir.path()), vec!["sized.bin"]);
+    }
+    
+    #[tokio::test]
+    async fn reports_progress_while_downloading() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/slow.bin", base), dir.path());
+        let settings = Settings::default();
+        let (progress_tx, mut progress_rx) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        
+        download_file_with_cancel(&record, &client(&settings), &settings, &progress_tx, &mut cancel_rx, &unlimited(), &Default::default(), || None)
+            .await
+            .unwrap();
+        
+        let update = progress_rx.try_recv().expect("no progress update sent");
+        assert_eq!(update.id, record.id);
+        assert_eq!(update.total, Some(PAYLOAD_LEN as u64));
+        assert!(!update.indeterminate);
+        assert!(update.downloaded > 0 && update.downloaded <= PAYLOAD_LEN as u64);
+    }
+    
+    #[tokio::test]
+    async fn cancel_removes_partial_file() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/slow.bin", base), dir.path());
+        let settings = Settings::default();
+        let client = client(&settings);
+        let (progress_tx, _) = broadcast::channel(100);
+        let (cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        let limit = unlimited();
+        let filenames = download::FilenameReservations::default();
+        
+        let cancel = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            cancel_tx.send(CancelReason::UserRequested).await.unwrap();
+        };
+        let (result, _) = tokio::join!(
+            download_file_with_cancel(&record, &client, &settings, &progress_tx, &mut cancel_rx, &limit, &filenames, || None),
+            cancel,
+        );
+        
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<download::Cancelled>(),
+            Some(download::Cancelled(CancelReason::UserRequested))
+        ));
+        assert!(files_in(dir.path()).is_empty());
+    }
+    
+    #[tokio::test]
+    async fn metered_pause_keeps_partial_file() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/slow.bin", base), dir.path());
+        let settings = Settings::default();
+        let client = client(&settings);
+        let (progress_tx, _) = broadcast::channel(100);
+        let (cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        let limit = unlimited();
+        let filenames = download::FilenameReservations::default();
+        
+        let pause = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            cancel_tx.send(CancelReason::Metered).await.unwrap();
+        };
+        let (result, _) = tokio::join!(
+            download_file_with_cancel(&record, &client, &settings, &progress_tx, &mut cancel_rx, &limit, &filenames, || None),
+            pause,
+        );
+        
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<download::Cancelled>(),
+            Some(download::Cancelled(CancelReason::Metered))
+        ));
+        assert_eq!(files_in(dir.path()), vec![format!("{}.part", record.filename)]);
+    }
+    
+    #[tokio::test]
+    async fn cancel_leaves_existing_final_file_alone() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("slow.bin"), b"older copy").unwrap();
+        let record = record(format!("{}/slow.bin", base), dir.path());
+        let settings = Settings::default();
+        let client = client(&settings);
+        let (progress_tx, _) = broadcast::channel(100);
+        let (cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        let limit = unlimited();
+        let filenames = download::FilenameReservations::default();
+        
+        let cancel = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            cancel_tx.send(CancelReason::UserRequested).await.unwrap();
+        };
+        let (result, _) = tokio::join!(
+            download_file_with_cancel(&record, &client, &settings, &progress_tx, &mut cancel_rx, &limit, &filenames, || None),
+            cancel,
+        );
+        
+        assert!(result.unwrap_err().is::<download::Cancelled>());
+        assert_eq!(files_in(dir.path()), vec!["slow.bin"]);
+        assert_eq!(std::fs::read(dir.path().join("slow.bin")).unwrap(), b"older copy");
+    }
+    
+    #[tokio::test]
+    async fn configured_partial_suffix_is_used_while_downloading() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/slow.bin", base), dir.path());
+        let settings = Settings {
+            partial_suffix: ".download".into(),
+            hide_partial_files: true,
+            ..Settings::default()
+        };
+        let client = client(&settings);
+        let (progress_tx, _) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        let limit = unlimited();
+        let filenames = download::FilenameReservations::default();
+        
+        let observe = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            files_in(dir.path())
+        };
+        let (result, during) = tokio::join!(
+            download_file_with_cancel(&record, &client, &settings, &progress_tx, &mut cancel_rx, &limit, &filenames, || None),
+            observe,
+        );
+        
+        result.unwrap();
+        assert_eq!(during, vec![".slow.bin.download"]);
+        assert_eq!(files_in(dir.path()), vec!["slow.bin"]);
+    }
+    
+    #[tokio::test]
+    async fn stale_partial_file_is_replaced_not_appended() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/sized.bin", base), dir.path());
+        std::fs::write(dir.path().join("sized.bin.part"), b"left over from a crash").unwrap();
+        
+        run(&record).await.unwrap();
+        
+        assert_eq!(std::fs::read(dir.path().join("sized.bin")).unwrap(), payload());
+        assert_eq!(files_in(dir.path()), vec!["sized.bin"]);
+    }
+    
+    /// Download with default settings, returning the progress updates sent
+    async fn run_collecting_progress(record: &DownloadRecord) -> Vec<download::ProgressUpdate> {
+        let settings = Settings::default();
+        let (progress_tx, mut progress_rx) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        
+        download_file_with_cancel(record, &client(&settings), &settings, &progress_tx, &mut cancel_rx, &unlimited(), &Default::default(), || None)
+            .await
+            .unwrap();
+        
+        std::iter::from_fn(|| progress_rx.try_recv().ok()).collect()
+    }
+    
+    #[tokio::test]
+    async fn total_comes_from_content_range_without_content_length() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/partial-unsized.bin", base), dir.path());
+        
+        let updates = run_collecting_progress(&record).await;
+        
+        assert!(!updates.is_empty());
+        assert!(updates.iter().all(|u| u.total == Some(PAYLOAD_LEN as u64) && !u.indeterminate));
+        assert_eq!(std::fs::read(dir.path().join("partial-unsized.bin")).unwrap(), payload());
+    }
+    
+    #[tokio::test]
+    async fn unknown_content_range_total_resumes_as_indeterminate() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/partial-unsized.bin?total=unknown", base), dir.path());
+        std::fs::write(dir.path().join("partial-unsized.bin.part"), &payload()[..10_000]).unwrap();
+        
+        let updates = run_collecting_progress(&record).await;
+        
+        assert!(!updates.is_empty());
+        assert!(updates.iter().all(|u| u.total.is_none() && u.indeterminate));
+        assert!(updates.iter().all(|u| u.downloaded > 10_000));
+        assert_eq!(std::fs::read(dir.path().join("partial-unsized.bin")).unwrap(), payload());
+    }
+    
+    #[tokio::test]
+    async fn resume_appends_to_existing_partial() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/ranged.bin", base), dir.path());
+        // Zeros rather than the real prefix, to show the partial was kept
+        std::fs::write(dir.path().join("ranged.bin.part"), vec![0u8; 10_000]).unwrap();
+        
+        let written = run(&record).await.unwrap();
+        
+        let mut expected = vec![0u8; 10_000];
+        expected.extend_from_slice(&payload()[10_000..]);
+        assert_eq!(written, PAYLOAD_LEN as u64);
+        assert_eq!(std::fs::read(dir.path().join("ranged.bin")).unwrap(), expected);
+    }
+    
+    #[tokio::test]
+    async fn verified_resume_keeps_partial_with_matching_tail() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/ranged.bin", base), dir.path());
+        let mut partial = vec![0u8; 9_000];
+        partial.extend_from_slice(&payload()[9_000..10_000]);
+        std::fs::write(dir.path().join("ranged.bin.part"), &partial).unwrap();
+        let settings = Settings { resume_verify_bytes: 1_000, ..Settings::default() };
+        
+        run_with(&record, &settings).await.unwrap();
+        
+        let mut expected = partial;
+        expected.extend_from_slice(&payload()[10_000..]);
+        assert_eq!(std::fs::read(dir.path().join("ranged.bin")).unwrap(), expected);
+    }
+    
+    #[tokio::test]
+    async fn verified_resume_restarts_on_mismatched_tail() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/ranged.bin", base), dir.path());
+        let mut partial = payload()[..10_000].to_vec();
+        partial[9_990..].fill(0xff);
+        std::fs::write(dir.path().join("ranged.bin.part"), &partial).unwrap();
+        let settings = Settings { resume_verify_bytes: 1_000, ..Settings::default() };
+        
+        run_with(&record, &settings).await.unwrap();
+        
+        assert_eq!(std::fs::read(dir.path().join("ranged.bin")).unwrap(), payload());
+        assert_eq!(files_in(dir.path()), vec!["ranged.bin"]);
+    }
+    
+    #[tokio::test]
+    async fn byte_range_downloads_only_that_slice() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = record(format!("{}/ranged.bin", base), dir.path());
+        record.range_start = Some(1000);
+        record.range_end = Some(2999);
+        
+        assert_eq!(run(&record).await.unwrap(), 2000);
+        assert_eq!(std::fs::read(dir.path().join("ranged.bin")).unwrap(), &payload()[1000..3000]);
+    }
+    
+    #[tokio::test]
+    async fn byte_range_resumes_within_the_slice() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = record(format!("{}/ranged.bin", base), dir.path());
+        record.range_start = Some(1000);
+        let settings = Settings {
+            resume_verify_bytes: 100,
+            ..Settings::default()
+        };
+        std::fs::write(settings.partial_path(dir.path(), "ranged.bin"), &payload()[1000..1500]).unwrap();
+        
+        assert_eq!(run_with(&record, &settings).await.unwrap(), (PAYLOAD_LEN - 1000) as u64);
+        assert_eq!(std::fs::read(dir.path().join("ranged.bin")).unwrap(), &payload()[1000..]);
+    }
+    
+    #[tokio::test]
+    async fn byte_range_fails_when_server_ignores_it() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = record(format!("{}/sized.bin", base), dir.path());
+        record.range_start = Some(0);
+        record.range_end = Some(99);
+        
+        let err = run(&record).await.unwrap_err();
+        assert!(err.to_string().contains("doesn't support byte ranges"), "{}", err);
+        assert!(files_in(dir.path()).is_empty());
+    }
+    
+    #[tokio::test]
+    async fn rename_while_downloading_sets_final_name() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/sized.bin", base), dir.path());
+        let settings = Settings::default();
+        let (progress_tx, _) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        
+        let finished = download_file_with_cancel(
+            &record,
+            &client(&settings),
+            &settings,
+            &progress_tx,
+            &mut cancel_rx,
+            &unlimited(),
+            &Default::default(),
+            || Some("renamed.bin".into()),
+        )
+        .await
+        .unwrap();
+        
+        assert_eq!(finished.filename, "renamed.bin");
+        assert_eq!(files_in(dir.path()), vec!["renamed.bin"]);
+    }
+    
+    #[tokio::test]
+    async fn downloads_finishing_together_get_distinct_names() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings {
+            on_conflict: ConflictPolicy::Rename,
+            ..Settings::default()
+        };
+        let client = client(&settings);
+        let filenames = download::FilenameReservations::default();
+        let (progress_tx, _) = broadcast::channel(100);
+        
+        // Separate partial files, both renamed to the same name while running
+        let finish = |name: &str| {
+            let mut record = record(format!("{}/sized.bin", base), dir.path());
+            record.filename = name.to_string();
+            let (client, settings, progress_tx, filenames) = (&client, &settings, &progress_tx, &filenames);
+            async move {
+                let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+                download_file_with_cancel(
+                    &record,
+                    client,
+                    settings,
+                    progress_tx,
+                    &mut cancel_rx,
+                    &unlimited(),
+                    filenames,
+                    || Some("same.bin".into()),
+                )
+                .await
+                .unwrap()
+                .filename
+            }
+        };
+        let (first, second) = tokio::join!(finish("a.bin"), finish("b.bin"));
+        
+        assert_ne!(first, second);
+        assert_eq!(files_in(dir.path()), vec!["same (2).bin", "same.bin"]);
+        assert_eq!(std::fs::read(dir.path().join("same (2).bin")).unwrap(), payload());
+    }
+    
+    #[tokio::test]
+    async fn reports_final_url_after_redirect() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/moved.bin", base), dir.path());
+        let settings = Settings::default();
+        let (progress_tx, _) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        
+        let finished = download_file_with_cancel(
+            &record,
+            &client(&settings),
+            &settings,
+            &progress_tx,
+            &mut cancel_rx,
+            &unlimited(),
+            &Default::default(),
+            || None,
+        )
+        .await
+        .unwrap();
+        
+        assert_eq!(finished.final_url, format!("{}/sized.bin", base));
+        assert_eq!(std::fs::read(dir.path().join("moved.bin")).unwrap(), payload());
+    }
+    
+    #[tokio::test]
+    async fn expanded_query_params_stay_out_of_the_final_url() {
+        std::env::set_var("VIBE_TEST_URL_TOKEN", "s3cret");
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = record(format!("{}/sized.bin", base), dir.path());
+        record.query_params = vec![("token".into(), "${VIBE_TEST_URL_TOKEN}".into()), ("page".into(), "2".into())];
+        let settings = Settings::default();
+        let (progress_tx, _) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        
+        let finished = download_file_with_cancel(
+            &record,
+            &client(&settings),
+            &settings,
+            &progress_tx,
+            &mut cancel_rx,
+            &unlimited(),
+            &Default::default(),
+            || None,
+        )
+        .await
+        .unwrap();
+        
+        assert!(!finished.final_url.contains("s3cret"));
+        assert_eq!(finished.final_url, format!("{}/sized.bin?token=%24%7BVIBE_TEST_URL_TOKEN%7D&page=2", base));
+    }
+    
+    #[tokio::test]
+    async fn unmodified_content_is_not_downloaded_again() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::default();
+        let (progress_tx, _) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        let mut finish = async |record: &DownloadRecord| {
+            download_file_with_cancel(
+                record,
+                &client(&settings),
+                &settings,
+                &progress_tx,
+                &mut cancel_rx,
+                &unlimited(),
+                &Default::default(),
+                || None,
+            )
+            .await
+            .unwrap()
+        };
+        
+        let mut record = record(format!("{}/versioned.bin", base), dir.path());
+        let first = finish(&record).await;
+        assert!(!first.not_modified);
+        assert_eq!(first.validators.etag.as_deref(), Some("\"v1\""));
+        
+        // Same version: nothing is transferred and the file stays as it was
+        record.skip_if_unmodified = true;
+        record.etag = first.validators.etag;
+        record.last_modified = first.validators.last_modified;
+        let second = finish(&record).await;
+        assert!(second.not_modified);
+        assert_eq!(second.size, PAYLOAD_LEN as u64);
+        assert_eq!(second.validators.last_modified.as_deref(), Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(files_in(dir.path()), vec!["versioned.bin"]);
+        
+        // A different version is downloaded as usual
+        record.etag = Some("\"v0\"".into());
+        let third = finish(&record).await;
+        assert!(!third.not_modified);
+        assert_eq!(third.size, PAYLOAD_LEN as u64);
+    }
+    
+    #[tokio::test]
+    async fn speed_limit_throttles_and_can_be_lifted_while_running() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/sized.bin", base), dir.path());
+        let settings = Settings::default();
+        let client = client(&settings);
+        let (progress_tx, _) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        // At 16 KiB/s the 64 KiB payload would take about four seconds
+        let limit = download::SpeedLimit::new(16 * 1024);
+        let filenames = download::FilenameReservations::default();
+        
+        let lift = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            limit.own.store(0, std::sync::atomic::Ordering::Relaxed);
+        };
+        let started = std::time::Instant::now();
+        let (result, _) = tokio::join!(
+            download_file_with_cancel(&record, &client, &settings, &progress_tx, &mut cancel_rx, &limit, &filenames, || None),
+            lift,
+        );
+        
+        result.unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(250), "not throttled: {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "limit not lifted: {:?}", elapsed);
+    }
+    
+    #[tokio::test]
+    async fn query_params_are_merged_and_accept_is_sent() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = record(format!("{}/echo.txt?page=2", base), dir.path());
+        record.filename = "echo.txt".into();
+        record.accept = Some("application/octet-stream".into());
+        record.query_params = vec![("token".into(), "a b".into())];
+        
+        run(&record).await.unwrap();
+        
+        let body = std::fs::read_to_string(dir.path().join("echo.txt")).unwrap();
+        assert_eq!(body, "page=2&token=a+b\napplication/octet-stream");
     }
     
-    let total_size = response.content_length();
-    
-    // Ensure destination directory exists
-    tokio::fs::create_dir_all(&record.destination).await?;
-    
-    // Use .part extension while downloading
-    let final_path = record.destination.join(&record.filename);
-    let temp_path = record.destination.join(format!("{}.part", &record.filename));
-    let mut file = File::create(&temp_path).await?;
+    #[tokio::test]
+    async fn headers_are_only_sent_to_a_mirror_on_the_same_host() {
+        let base = mock_server().await;
+        let other_host = base.replace("127.0.0.1", "localhost");
+        let dir = tempfile::tempdir().unwrap();
+        for (mirror_base, sent) in [(&base, true), (&other_host, false)] {
+            let mut record = record(format!("{}/missing.bin", base), dir.path());
+            record.filename = "echo.txt".into();
+            record.headers = vec![("Accept".into(), "text/x-secret".into())];
+            record.mirrors = vec![format!("{}/echo.txt", mirror_base)];
+            
+            run(&record).await.unwrap();
+            
+            let body = std::fs::read_to_string(dir.path().join("echo.txt")).unwrap();
+            assert_eq!(body.contains("text/x-secret"), sent, "{}", mirror_base);
+            std::fs::remove_file(dir.path().join("echo.txt")).unwrap();
+        }
+    }
     
-    let mut stream = response.bytes_stream();
-    let mut downloaded: u64 = 0;
-    let start_time = std::time::Instant::now();
-    let mut last_update = std::time::Instant::now();
+    #[tokio::test]
+    async fn unset_env_vars_in_headers_fail_the_download() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = record(format!("{}/echo.txt", base), dir.path());
+        record.headers = vec![("Authorization".into(), "Bearer ${VIBE_TEST_UNSET_TOKEN}".into())];
+        
+        let err = run(&record).await.unwrap_err();
+        assert_eq!(err.to_string(), "unresolved env var ${VIBE_TEST_UNSET_TOKEN}");
+        assert!(files_in(dir.path()).is_empty());
+    }
     
-    loop {
-        tokio::select! {
-            // Check for cancellation
-            _ = cancel_rx.recv() => {
-                // Clean up partial file
-                drop(file);
-                let _ = tokio::fs::remove_file(&temp_path).await;
-                anyhow::bail!("Download cancelled");
-            }
-            // Process next chunk
-            chunk = stream.next() => {
-                match chunk {
-                    Some(Ok(bytes)) => {
-                        file.write_all(&bytes).await?;
-                        downloaded += bytes.len() as u64;
-                        
-                        // Send progress every 200ms
-                        if last_update.elapsed().as_millis() >= 200 {
-                            let elapsed = start_time.elapsed().as_secs_f64();
-                            let speed = if elapsed > 0.0 { (downloaded as f64 / elapsed) as u64 } else { 0 };
-                            
-                            let _ = progress_tx.send(download::ProgressUpdate {
-                                id: record.id.clone(),
-                                downloaded,
-                                total: total_size,
-                                speed,
-                                status: DownloadStatus::Downloading,
-                                error: None,
-                            });
-                            last_update = std::time::Instant::now();
-                        }
-                    }
-                    Some(Err(e)) => {
-                        // Clean up on error
-                        drop(file);
-                        let _ = tokio::fs::remove_file(&temp_path).await;
-                        anyhow::bail!("Download error: {}", e);
+    #[tokio::test]
+    async fn post_downloads_send_their_body_and_start_over() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = record(format!("{}/post-echo.txt", base), dir.path());
+        record.method = RequestMethod::Post;
+        record.body = Some(r#"{"file":"report"}"#.into());
+        record.content_type = Some("application/json".into());
+        // Resumable settings, but a POST isn't resumed from its partial file
+        let settings = Settings {
+            content_encoding: ContentEncodingMode::Original,
+            ..Settings::default()
+        };
+        std::fs::write(settings.partial_path(dir.path(), "post-echo.txt"), "stale").unwrap();
+        
+        run_with(&record, &settings).await.unwrap();
+        
+        let body = std::fs::read_to_string(dir.path().join("post-echo.txt")).unwrap();
+        assert_eq!(body, "application/json\n{\"file\":\"report\"}");
+    }
+    
+    #[tokio::test]
+    async fn http_error_fails_without_creating_files() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/missing.bin", base), dir.path());
+        
+        let err = run(&record).await.unwrap_err();
+        
+        assert!(err.to_string().contains("404"));
+        assert_eq!(download::classify_error(&err), crate::db::DownloadErrorKind::HttpStatus);
+        assert!(files_in(dir.path()).is_empty());
+    }
+    
+    #[tokio::test]
+    async fn unprefixed_env_vars_are_refused_when_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.file_types.get_mut("general").unwrap().destination = dir.path().to_path_buf();
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = Arc::new(AppState::new(settings, db).unwrap());
+        let add = |headers: Vec<(String, String)>, query_params: Vec<(String, String)>| AddDownloadRequest {
+            url: "http://example.com/a.bin".into(),
+            file_type: "general".into(),
+            headers: Some(headers),
+            query_params: Some(query_params),
+            ..Default::default()
+        };
+        
+        let secret = || vec![("key".to_string(), "${AWS_SECRET_ACCESS_KEY}".to_string())];
+        for req in [add(secret(), Vec::new()), add(Vec::new(), secret())] {
+            let err = create_download(&state, req, None).await.unwrap_err();
+            assert!(matches!(err, AppError::BadRequest(_)));
+            assert!(err.to_string().contains("AWS_SECRET_ACCESS_KEY"));
+        }
+        assert!(state.db.get_pending_downloads(true).unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn file_in_place_of_the_destination_is_reported() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("downloads");
+        std::fs::write(&file, b"not a folder").unwrap();
+        
+        // Refused when added
+        let mut settings = Settings::default();
+        settings.file_types.get_mut("general").unwrap().destination = file.clone();
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = Arc::new(AppState::new(settings, db).unwrap());
+        let req = AddDownloadRequest {
+            url: format!("{}/sized.bin", base),
+            file_type: "general".into(),
+            ..Default::default()
+        };
+        let err = create_download(&state, req, None).await.unwrap_err();
+        assert_eq!(err.to_string(), format!("destination is not a directory: {}", file.display()));
+        
+        // And typed when it only turns up once the download runs
+        let record = record(format!("{}/sized.bin", base), &file);
+        let err = run(&record).await.unwrap_err();
+        assert_eq!(download::classify_error(&err), crate::db::DownloadErrorKind::NotADirectory);
+        assert_eq!(std::fs::read(&file).unwrap(), b"not a folder");
+    }
+    
+    #[test]
+    fn expected_size_tolerance_is_a_share_of_the_size() {
+        assert!(!beyond_expected_size(50, 50));
+        assert!(beyond_expected_size(51, 50));
+        assert!(!beyond_expected_size(10_100, 10_000));
+        assert!(beyond_expected_size(10_101, 10_000));
+        assert!(!beyond_expected_size(u64::MAX, u64::MAX - 1));
+        assert!(beyond_expected_size(u64::MAX, u64::MAX / 2));
+    }
+    
+    #[tokio::test]
+    async fn impossible_byte_ranges_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = Arc::new(AppState::new(Settings::default(), db).unwrap());
+        let add = |start, end| {
+            create_download(
+                &state,
+                AddDownloadRequest {
+                    url: "http://localhost/file.bin".into(),
+                    range_start: Some(start),
+                    range_end: Some(end),
+                    ..Default::default()
+                },
+                None,
+            )
+        };
+        assert!(matches!(add(10, 9).await, Err(AppError::BadRequest(_))));
+        assert!(matches!(add(0, u64::MAX).await, Err(AppError::BadRequest(_))));
+        assert!(state.db.get_all_downloads().unwrap().is_empty());
+        
+        // Nor taken from a server
+        assert_eq!(parse_content_range("bytes 10-9/100"), None);
+        let mut record = record("http://localhost/file.bin".into(), dir.path());
+        record.range_start = Some(u64::MAX - 1);
+        let mut headers = reqwest::header::HeaderMap::new();
+        let range = format!("bytes {}-{}/*", u64::MAX - 1, u64::MAX);
+        headers.insert(reqwest::header::CONTENT_RANGE, range.parse().unwrap());
+        assert!(requested_range_size(&record, 0, StatusCode::PARTIAL_CONTENT, &headers).is_err());
+    }
+    
+    #[test]
+    fn reconcile_trusts_partial_file_within_total() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::default();
+        let mut record = record("http://localhost/file.bin".into(), dir.path());
+        record.downloaded_size = 500;
+        record.total_size = Some(1_000);
+        
+        // No partial: nothing to resume, whatever the record says
+        assert_eq!(reconcile_partial(&settings, &record), 0);
+        
+        // The record is behind (or ahead of) the file: the shorter wins, and
+        // the file is cut back to it
+        let partial = settings.partial_path(dir.path(), "file.bin");
+        std::fs::write(&partial, vec![0u8; 800]).unwrap();
+        assert_eq!(reconcile_partial(&settings, &record), 500);
+        assert_eq!(std::fs::metadata(&partial).unwrap().len(), 500);
+        std::fs::write(&partial, vec![0u8; 200]).unwrap();
+        assert_eq!(reconcile_partial(&settings, &record), 200);
+        assert_eq!(std::fs::metadata(&partial).unwrap().len(), 200);
+        
+        // Longer than the whole download: corrupt, so it's discarded
+        std::fs::write(&partial, vec![0u8; 1_001]).unwrap();
+        assert_eq!(reconcile_partial(&settings, &record), 0);
+        assert!(!partial.exists());
+    }
+    
+    /// Retry quickly so tests don't wait on real backoffs
+    fn fast_retries() -> download::RetryPolicy {
+        download::RetryPolicy {
+            max_retries: 2,
+            connect_retries: 5,
+            backoff: Duration::from_millis(10),
+            connect_backoff: Duration::from_millis(10),
+        }
+    }
+    
+    #[tokio::test]
+    async fn retries_connect_failures_until_server_is_up() {
+        // Reserve a port with nothing listening on it yet
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("http://{}/sized.bin", addr), dir.path());
+        let settings = Settings::default();
+        let (progress_tx, _) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        
+        let mut failures = 0;
+        let finished = download_with_retries(
+            &record,
+            &client(&settings),
+            &settings,
+            &fast_retries(),
+            &progress_tx,
+            &mut cancel_rx,
+            &unlimited(),
+            &Default::default(),
+            || None,
+            |e, _| {
+                assert_eq!(download::retry_class(e), download::RetryClass::Connect);
+                failures += 1;
+                // The server comes up after the third failed connect
+                if failures == 3 {
+                    let listener = std::net::TcpListener::bind(addr).unwrap();
+                    listener.set_nonblocking(true).unwrap();
+                    serve_mock(tokio::net::TcpListener::from_std(listener).unwrap());
+                }
+            },
+        )
+        .await
+        .unwrap();
+        
+        assert_eq!(failures, 3);
+        assert_eq!(finished.size, PAYLOAD_LEN as u64);
+        assert_eq!(std::fs::read(dir.path().join("sized.bin")).unwrap(), payload());
+    }
+    
+    #[tokio::test]
+    async fn cancel_while_waiting_to_retry_removes_partial() {
+        // Nothing listens on this port, so every attempt fails to connect
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("http://{}/sized.bin", addr), dir.path());
+        let settings = Settings::default();
+        // Left by an earlier session, to resume from
+        std::fs::write(settings.partial_path(dir.path(), "sized.bin"), &payload()[..1000]).unwrap();
+        let policy = download::RetryPolicy {
+            connect_backoff: Duration::from_secs(60),
+            ..fast_retries()
+        };
+        let (progress_tx, _) = broadcast::channel(100);
+        let (cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        
+        let err = download_with_retries(
+            &record,
+            &client(&settings),
+            &settings,
+            &policy,
+            &progress_tx,
+            &mut cancel_rx,
+            &unlimited(),
+            &Default::default(),
+            || None,
+            |_, _| cancel_tx.try_send(CancelReason::UserRequested).unwrap(),
+        )
+        .await
+        .unwrap_err();
+        
+        assert!(err.is::<download::Cancelled>());
+        assert!(files_in(dir.path()).is_empty());
+    }
+    
+    #[tokio::test]
+    async fn retry_after_a_dropped_connection_resumes_the_partial() {
+        const DROPPED_AT: usize = PAYLOAD_LEN / 2;
+        // The first answer breaks off halfway; later ones honour the range
+        let ranges = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let app = Router::new().route(
+            "/flaky.bin",
+            get({
+                let ranges = Arc::clone(&ranges);
+                move |headers: axum::http::HeaderMap| async move {
+                    let range = headers.get(header::RANGE).map(|v| v.to_str().unwrap().to_string());
+                    let first = range.is_none();
+                    ranges.lock().push(range);
+                    if !first {
+                        return serve_ranged(headers).await;
                     }
-                    None => break, // Stream ended
+                    let head = futures_util::stream::iter([Ok(Bytes::copy_from_slice(&payload()[..DROPPED_AT]))]);
+                    // Once the first half has gone out
+                    let drop = futures_util::stream::once(async {
+                        tokio::time::sleep(SLOW_DELAY).await;
+                        Err(std::io::Error::other("connection dropped"))
+                    });
+                    (
+                        [(header::CONTENT_LENGTH, PAYLOAD_LEN.to_string())],
+                        Body::from_stream(head.chain(drop)),
+                    )
+                        .into_response()
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("http://{}/flaky.bin", addr), dir.path());
+        let settings = Settings::default();
+        let (progress_tx, _) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        
+        let mut failures = 0;
+        let finished = download_with_retries(
+            &record,
+            &client(&settings),
+            &settings,
+            &fast_retries(),
+            &progress_tx,
+            &mut cancel_rx,
+            &unlimited(),
+            &Default::default(),
+            || None,
+            |_, _| failures += 1,
+        )
+        .await
+        .unwrap();
+        
+        assert_eq!(failures, 1);
+        assert_eq!(*ranges.lock(), [None, Some(format!("bytes={}-", DROPPED_AT))]);
+        assert_eq!(finished.size, PAYLOAD_LEN as u64);
+        assert_eq!(std::fs::read(dir.path().join("flaky.bin")).unwrap(), payload());
+    }
+    
+    #[test]
+    fn an_invalid_setting_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = AppState::new(Settings::default(), db).unwrap();
+        let before = state.download_manager.stats().max_concurrent;
+        
+        let req = serde_json::from_value(serde_json::json!({
+            "max_concurrent_downloads": before + 4,
+            "alt_speed_bytes_per_sec": 1234,
+            "webhook_url": "ftp://example.com/hook",
+        }))
+        .unwrap();
+        assert!(matches!(apply_settings(&state, req), Err(AppError::BadRequest(_))));
+        
+        let settings = state.settings.read();
+        assert_eq!(settings.max_concurrent_downloads, Settings::default().max_concurrent_downloads);
+        assert_eq!(settings.alt_speed_bytes_per_sec, Settings::default().alt_speed_bytes_per_sec);
+        assert_eq!(settings.webhook_url, None);
+        assert_eq!(state.download_manager.stats().max_concurrent, before);
+    }
+    
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn systemd_unit_quotes_the_executable() {
+        let unit = systemd_unit(std::path::Path::new("/opt/My Apps/100%/$HOME/vibe-downloader"));
+        assert!(unit.contains("ExecStart=\"/opt/My Apps/100%%/$$HOME/vibe-downloader\"\n"));
+        assert!(unit.contains("WantedBy=default.target"));
+    }
+    
+    #[tokio::test]
+    async fn batch_removal_deletes_records_and_unshared_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = Arc::new(AppState::new(Settings::default(), db).unwrap());
+        
+        let completed = |name: &str| {
+            let mut record = record(format!("http://example.com/{}", name), dir.path());
+            record.status = DownloadStatus::Completed;
+            std::fs::write(dir.path().join(name), b"data").unwrap();
+            state.db.insert_download(&record).unwrap();
+            record
+        };
+        let (a, b) = (completed("a.bin"), completed("b.bin"));
+        // A later download that came back not modified, sharing a.bin
+        let mut again = a.clone();
+        again.id = uuid::Uuid::new_v4().to_string();
+        state.db.insert_download(&again).unwrap();
+        
+        let req = RemoveDownloadsRequest {
+            ids: vec![b.id.clone(), "missing".into(), a.id.clone(), b.id.clone()],
+            delete_files: true,
+        };
+        let Json(results) = remove_downloads(State(state.clone()), Json(req)).await.unwrap();
+        
+        let ids: Vec<_> = results.iter().map(|r| (r.id.as_str(), r.removed)).collect();
+        assert_eq!(ids, [(b.id.as_str(), true), ("missing", false), (a.id.as_str(), true)]);
+        assert_eq!(results[0].files_deleted, [dir.path().join("b.bin")]);
+        assert!(results[2].files_deleted.is_empty());
+        assert!(!dir.path().join("b.bin").exists());
+        assert!(dir.path().join("a.bin").exists());
+        let left: Vec<_> = state.db.get_all_downloads().unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(left, [again.id]);
+    }
+    
+    #[tokio::test]
+    async fn completed_file_is_linked_into_other_file_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let (videos, archive) = (dir.path().join("videos"), dir.path().join("archive"));
+        std::fs::create_dir(&videos).unwrap();
+        let mut settings = Settings::default();
+        settings.file_types.get_mut("general").unwrap().destination = videos.clone();
+        settings.file_types.get_mut("video").unwrap().destination = archive.clone();
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = Arc::new(AppState::new(settings, db).unwrap());
+        
+        let mut source = record("http://example.com/clip.mp4".into(), &videos);
+        source.status = DownloadStatus::Completed;
+        source.total_size = Some(4);
+        source.link_file_types = vec!["video".into(), "missing".into()];
+        std::fs::write(videos.join("clip.mp4"), b"clip").unwrap();
+        state.db.insert_download(&source).unwrap();
+        
+        link_copies(&state, &source).await;
+        
+        assert_eq!(std::fs::read(archive.join("clip.mp4")).unwrap(), b"clip");
+        assert_eq!(std::fs::read(videos.join("clip.mp4")).unwrap(), b"clip");
+        let downloads = state.db.get_all_downloads().unwrap();
+        let copy = downloads.iter().find(|d| d.id != source.id).unwrap();
+        assert_eq!(copy.linked_from.as_deref(), Some(source.id.as_str()));
+        assert_eq!(copy.status, DownloadStatus::Completed);
+        assert_eq!(copy.destination, archive);
+        // The file type that doesn't exist is noted on the source
+        assert_eq!(state.db.get_download_errors(&source.id).unwrap().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn duplicate_download_shares_the_earlier_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        std::fs::create_dir(&first).unwrap();
+        std::fs::create_dir(&second).unwrap();
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = Arc::new(AppState::new(Settings::default(), db).unwrap());
+        
+        let checksum = "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let completed = |folder: &std::path::Path| {
+            let mut download = record("http://example.com/abc.txt".into(), folder);
+            download.status = DownloadStatus::Completed;
+            download.checksum = Some(checksum.into());
+            std::fs::write(folder.join("abc.txt"), b"abc").unwrap();
+            state.db.insert_download(&download).unwrap();
+            download
+        };
+        let (original, duplicate) = (completed(&first), completed(&second));
+        
+        deduplicate(&state, &duplicate).await;
+        
+        let shared = state.db.get_download(&duplicate.id).unwrap().unwrap();
+        assert_eq!(shared.shares_file_with.as_deref(), Some(original.id.as_str()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |folder: &std::path::Path| std::fs::metadata(folder.join("abc.txt")).unwrap().ino();
+            assert_eq!(inode(&first), inode(&second));
+        }
+        assert_eq!(std::fs::read_dir(&second).unwrap().count(), 1);
+        
+        // Forgetting the original leaves the duplicate's file in place
+        state.db.delete_download(&original.id).unwrap();
+        std::fs::remove_file(first.join("abc.txt")).unwrap();
+        assert_eq!(std::fs::read(second.join("abc.txt")).unwrap(), b"abc");
+        assert_eq!(state.db.get_download(&duplicate.id).unwrap().unwrap().shares_file_with, None);
+    }
+    
+    #[tokio::test]
+    async fn file_type_downloads_move_to_the_new_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        std::fs::create_dir_all(old.join("sub")).unwrap();
+        std::fs::create_dir(&new).unwrap();
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = Arc::new(AppState::new(Settings::default(), db).unwrap());
+        
+        let completed = |name: &str, folder: &std::path::Path, file_type: &str| {
+            let mut download = record(format!("http://example.com/{}", name), folder);
+            download.file_type = file_type.into();
+            download.status = DownloadStatus::Completed;
+            state.db.insert_download(&download).unwrap();
+            download
+        };
+        let top = completed("a.mp4", &old, "video");
+        let nested = completed("b.mp4", &old.join("sub"), "video");
+        let gone = completed("c.mp4", &old, "video");
+        let other = completed("d.zip", &old, "archive");
+        for (download, folder) in [(&top, &old), (&nested, &old.join("sub")), (&other, &old)] {
+            std::fs::write(folder.join(&download.filename), b"data").unwrap();
+        }
+        
+        let response = move_file_type_downloads(&state, "video", &old, &new).await.unwrap();
+        
+        let mut moved: Vec<&str> = response.moved.iter().map(|d| d.id.as_str()).collect();
+        moved.sort();
+        let mut expected = vec![top.id.as_str(), nested.id.as_str()];
+        expected.sort();
+        assert_eq!(moved, expected);
+        assert_eq!(response.failed.len(), 1);
+        assert_eq!(response.failed[0].id, gone.id);
+        
+        assert!(new.join("a.mp4").is_file());
+        assert!(new.join("sub/b.mp4").is_file());
+        assert!(old.join("d.zip").is_file());
+        assert_eq!(state.db.get_download(&nested.id).unwrap().unwrap().destination, new.join("sub"));
+        assert_eq!(state.db.get_download(&gone.id).unwrap().unwrap().destination, old);
+    }
+    
+    #[tokio::test]
+    async fn list_files_are_read_line_by_line() {
+        let text = "\u{feff}# mirrors\r\nhttps://example.com/a.iso\r\n\r\n  http://example.com/b.zip  \nftp://example.com/c\nnot a url\n";
+        let entries = parse_url_list(text);
+        let lines: Vec<(usize, &str, bool)> = entries.iter().map(|(n, url, parsed)| (*n, url.as_str(), parsed.is_ok())).collect();
+        assert_eq!(
+            lines,
+            vec![
+                (2, "https://example.com/a.iso", true),
+                (4, "http://example.com/b.zip", true),
+                (5, "ftp://example.com/c", false),
+                (6, "not a url", false),
+            ]
+        );
+        
+        let upload = |body: &'static str| {
+            let request = axum::http::Request::builder()
+                .header("content-type", "multipart/form-data; boundary=\"XyZ\"")
+                .body(axum::body::Body::from(body))
+                .unwrap();
+            async { multipart_file(Multipart::from_request(request, &()).await.unwrap()).await.unwrap() }
+        };
+        let body = concat!(
+            "--XyZ\r\nContent-Disposition: form-data; name=\"file_type\"\r\n\r\nvideo\r\n",
+            "--XyZ\r\nContent-Disposition: form-data; name=\"list\"; filename=\"urls.txt\"\r\n",
+            "Content-Type: text/plain\r\n\r\nhttps://example.com/a.iso\r\n\r\n",
+            "--XyZ--\r\n",
+        );
+        assert_eq!(upload(body).await.as_deref(), Some(&b"https://example.com/a.iso\r\n"[..]));
+        assert_eq!(upload("--XyZ--\r\n").await, None);
+    }
+    
+    #[tokio::test]
+    async fn renamed_download_resumes_its_partial_after_a_restart() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let downloads = dir.path().join("downloads");
+        let db_path = dir.path().join("test.db");
+        let settings = Settings::default();
+        let partial = settings.partial_path(&downloads, "partial-unsized.bin");
+        
+        let state = Arc::new(AppState::new(settings.clone(), crate::db::Database::open(&db_path, &Default::default()).unwrap()).unwrap());
+        let download = record(format!("{}/partial-unsized.bin", base), &downloads);
+        let id = download.id.clone();
+        state.db.insert_download(&download).unwrap();
+        resume_incomplete_downloads(Arc::clone(&state));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while std::fs::metadata(&partial).map_or(0, |m| m.len()) == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        
+        let renamed = rename_download(
+            State(Arc::clone(&state)),
+            Path(id.clone()),
+            Json(RenameDownloadRequest { filename: "renamed.bin".into() }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(renamed.filename, "partial-unsized.bin");
+        assert_eq!(renamed.rename_to.as_deref(), Some("renamed.bin"));
+        
+        // Shut down partway
+        state.download_manager.cancel(&id, CancelReason::Shutdown).await;
+        while state.download_manager.is_active(&id) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(partial.exists());
+        
+        let state = Arc::new(AppState::new(settings, crate::db::Database::open(&db_path, &Default::default()).unwrap()).unwrap());
+        resume_incomplete_downloads(Arc::clone(&state));
+        // The partial was found under the old name
+        assert!(state.db.get_download(&id).unwrap().unwrap().downloaded_size > 0);
+        
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while state.db.get_download(&id).unwrap().unwrap().status != DownloadStatus::Completed {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let finished = state.db.get_download(&id).unwrap().unwrap();
+        assert_eq!(finished.filename, "renamed.bin");
+        assert_eq!(finished.rename_to, None);
+        assert_eq!(files_in(&downloads), vec!["renamed.bin"]);
+        assert_eq!(std::fs::read(downloads.join("renamed.bin")).unwrap(), payload());
+    }
+    
+    #[tokio::test]
+    async fn resumed_downloads_start_staggered() {
+        const STAGGER: Duration = Duration::from_millis(200);
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let downloads = dir.path().join("downloads");
+        let settings = Settings {
+            max_concurrent_downloads: 4,
+            startup_stagger_ms: STAGGER.as_millis() as u64,
+            ..Default::default()
+        };
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = Arc::new(AppState::new(settings, db).unwrap());
+        
+        let add = |name: &str| {
+            let mut download = record(format!("{}/sized.bin?{}", base, name), &downloads);
+            download.filename = format!("{}.bin", name);
+            state.db.insert_download(&download).unwrap();
+            download
+        };
+        let resumed: Vec<String> = ["a", "b", "c"].into_iter().map(|name| add(name).id).collect();
+        resume_incomplete_downloads(Arc::clone(&state));
+        
+        // One added by the user meanwhile starts straight away
+        let added = add("added");
+        let slot = state.download_manager.try_start(&added.url).unwrap();
+        start_download(Arc::clone(&state), added.clone(), slot);
+        
+        let started_at = |id: &str| {
+            let record = state.db.get_download(id).unwrap().unwrap();
+            (record.status == DownloadStatus::Completed).then_some(record.started_at).flatten()
+        };
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while resumed.iter().chain([&added.id]).any(|id| started_at(id).is_none()) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        
+        let starts: Vec<_> = resumed.iter().map(|id| started_at(id).unwrap()).collect();
+        assert!(started_at(&added.id).unwrap() < starts[1]);
+        // In their order, a stagger apart give or take scheduling
+        for pair in starts.windows(2) {
+            assert!(pair[1] - pair[0] >= chrono::Duration::from_std(STAGGER / 2).unwrap(), "{:?}", starts);
+        }
+    }
+    
+    #[tokio::test]
+    async fn resumed_downloads_respect_the_concurrency_limit() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let downloads = dir.path().join("downloads");
+        let settings = Settings {
+            max_concurrent_downloads: 2,
+            ..Default::default()
+        };
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = Arc::new(AppState::new(settings, db).unwrap());
+        
+        // Left running, queued and cancelled by a shutdown in the last session
+        let ids: Vec<String> = (0..6)
+            .map(|n| {
+                let mut download = record(format!("{}/sized.bin?n={}", base, n), &downloads);
+                download.filename = format!("{}.bin", n);
+                state.db.insert_download(&download).unwrap();
+                match n % 3 {
+                    0 => state.db.update_status(&download.id, DownloadStatus::Downloading, None).unwrap(),
+                    1 => state.db.update_status(&download.id, DownloadStatus::Queued, None).unwrap(),
+                    _ => state.db.mark_cancelled(&download.id, CancelReason::Shutdown).unwrap(),
                 }
+                download.id
+            })
+            .collect();
+        
+        resume_incomplete_downloads(Arc::clone(&state));
+        let manager = &state.download_manager;
+        assert_eq!(manager.active_count(), 2);
+        assert_eq!(manager.slots_in_use(), 2);
+        assert_eq!(manager.queue_len(), 4);
+        
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                assert!(manager.active_count() <= 2);
+                let done = ids
+                    .iter()
+                    .all(|id| state.db.get_download(id).unwrap().unwrap().status == DownloadStatus::Completed);
+                if done && manager.active_count() == 0 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(files_in(&downloads).len(), 6);
+    }
+    
+    #[tokio::test]
+    async fn cancelled_partial_can_be_restored_from_the_trash() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let settings = Settings {
+            cancelled_partial_retention_minutes: 60,
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::new(settings.clone(), db).unwrap());
+        // Nothing starts, so the restored partial stays put
+        state.download_manager.set_queue_paused(true);
+        
+        let mut download = record("http://example.com/big.iso".into(), dir.path());
+        download.status = DownloadStatus::Queued;
+        // As reconciled with its partial when it was resumed on startup
+        download.downloaded_size = 4;
+        state.db.insert_download(&download).unwrap();
+        let partial = settings.partial_path(dir.path(), "big.iso");
+        std::fs::write(&partial, b"half").unwrap();
+        
+        discard_partial(&state, &download.id, true).await;
+        state.db.update_status(&download.id, DownloadStatus::Cancelled, None).unwrap();
+        assert!(!partial.exists());
+        assert!(trash_path(dir.path(), &download.id).exists());
+        
+        let Json(restored) = restore_download(State(Arc::clone(&state)), Path(download.id.clone())).await.unwrap();
+        assert_eq!(restored.status, DownloadStatus::Queued);
+        assert_eq!(restored.downloaded_size, 4);
+        assert_eq!(std::fs::read(&partial).unwrap(), b"half");
+        assert_eq!(state.download_manager.queue_len(), 1);
+        
+        // Only cancelled downloads, and only while the partial is kept
+        let again = restore_download(State(Arc::clone(&state)), Path(download.id.clone())).await;
+        assert!(matches!(again, Err(AppError::Conflict(_))));
+    }
+    
+    // On several threads the download stops on its own time, as it would
+    // outside tests
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn removing_a_running_download_leaves_nothing_in_the_trash() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let downloads = dir.path().join("downloads");
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let settings = Settings {
+            cancelled_partial_retention_minutes: 60,
+            ..Default::default()
+        };
+        let partial = settings.partial_path(&downloads, "slow.bin");
+        let state = Arc::new(AppState::new(settings, db).unwrap());
+        let download = record(format!("{}/slow.bin", base), &downloads);
+        let id = download.id.clone();
+        state.db.insert_download(&download).unwrap();
+        resume_incomplete_downloads(Arc::clone(&state));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while std::fs::metadata(&partial).map_or(0, |m| m.len()) == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
             }
+        })
+        .await
+        .unwrap();
+        
+        let response = remove_download(State(Arc::clone(&state)), Path(id.clone())).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        // Stopped by the time the removal returns, partial file dealt with
+        assert!(!state.download_manager.is_active(&id));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!trash_path(&downloads, &id).exists());
+        assert!(!partial.exists());
+    }
+    
+    #[test]
+    fn folder_listing_is_paged_and_stays_inside_the_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().join("videos");
+        std::fs::create_dir_all(folder.join("season 1")).unwrap();
+        std::fs::write(folder.join("b.mkv"), b"bb").unwrap();
+        std::fs::write(folder.join("a.mkv"), b"a").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), b"s").unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path().join("secret.txt"), folder.join("escape")).unwrap();
+            std::os::unix::fs::symlink(folder.join("a.mkv"), folder.join("c.mkv")).unwrap();
+        }
+        
+        let listing = list_folder(&folder, 0, 2).unwrap();
+        let names: Vec<&str> = listing.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["a.mkv", "b.mkv"]);
+        assert_eq!(listing.entries[1].size, Some(2));
+        assert!(!listing.truncated);
+        
+        let rest = list_folder(&folder, 2, 10).unwrap();
+        let names: Vec<&str> = rest.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names.last(), Some(&"season 1"));
+        assert!(rest.entries.last().unwrap().is_dir);
+        // The link inside the folder is listed, the one out of it isn't
+        #[cfg(unix)]
+        assert_eq!(names, ["c.mkv", "season 1"]);
+        assert_eq!(rest.total, listing.total);
+        
+        assert_eq!(list_folder(&dir.path().join("missing"), 0, 10).unwrap().total, 0);
+    }
+    
+    #[test]
+    fn date_subfolders_are_found_for_the_trash_sweep() {
+        let dir = tempfile::tempdir().unwrap();
+        for day in ["2026/10/15", "2026/10/16", "2025/12/31"] {
+            std::fs::create_dir_all(dir.path().join(day)).unwrap();
         }
+        std::fs::create_dir_all(dir.path().join(TRASH_DIR).join("x").join("y")).unwrap();
+        std::fs::write(dir.path().join("2026").join("notes.txt"), b"").unwrap();
+        
+        let mut found = subfolders_at_depth(dir.path(), 3);
+        found.sort();
+        let expected: Vec<PathBuf> = ["2025/12/31", "2026/10/15", "2026/10/16"].iter().map(|day| dir.path().join(day)).collect();
+        assert_eq!(found, expected);
+        assert_eq!(subfolders_at_depth(dir.path(), 0), vec![dir.path().to_path_buf()]);
     }
     
-    file.flush().await?;
-    drop(file);
+    #[test]
+    fn trash_sweep_removes_only_expired_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let trash = dir.path().join(TRASH_DIR);
+        std::fs::create_dir(&trash).unwrap();
+        std::fs::write(trash.join("a.part"), b"a").unwrap();
+        let folders = vec![dir.path().to_path_buf(), dir.path().to_path_buf()];
+        
+        assert_eq!(sweep_trash(&folders, std::time::Duration::from_secs(3600)), 0);
+        assert!(trash.join("a.part").exists());
+        
+        assert_eq!(sweep_trash(&folders, std::time::Duration::ZERO), 1);
+        // The emptied trash folder goes too
+        assert!(!trash.exists());
+    }
     
-    // Rename from .part to final filename
-    tokio::fs::rename(&temp_path, &final_path).await?;
+    #[tokio::test]
+    async fn existing_files_are_registered_after_checking_their_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = Arc::new(AppState::new(Settings::default(), db).unwrap());
+        let path = dir.path().join("movie.mkv");
+        std::fs::write(&path, b"abc").unwrap();
+        // SHA-256 of "abc"
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let request = |path: &std::path::Path, checksum: &str| RegisterDownloadRequest {
+            path: path.to_path_buf(),
+            url: "https://example.com/movie.mkv".into(),
+            file_type: None,
+            checksum: Some(checksum.into()),
+        };
+        
+        let wrong = format!("sha256:{}", "0".repeat(64));
+        let mismatch = register_download(State(Arc::clone(&state)), Json(request(&path, &wrong))).await;
+        assert!(matches!(mismatch, Err(AppError::BadRequest(message)) if message.contains("mismatch")));
+        let missing = register_download(State(Arc::clone(&state)), Json(request(&dir.path().join("gone.mkv"), digest))).await;
+        assert!(matches!(missing, Err(AppError::NotFound(_))));
+        assert!(state.db.get_all_downloads().unwrap().is_empty());
+        
+        let Json(record) = register_download(State(Arc::clone(&state)), Json(request(&path, digest))).await.unwrap();
+        assert_eq!(record.status, DownloadStatus::Completed);
+        assert_eq!(record.file_type, "video");
+        assert_eq!(record.destination.join(&record.filename), path);
+        assert_eq!(record.total_size, Some(3));
+        assert_eq!(record.checksum, Some(format!("sha256:{}", digest)));
+        assert!(record.completed_at.is_some());
+        
+        let again = register_download(State(Arc::clone(&state)), Json(request(&path, digest))).await;
+        assert!(matches!(again, Err(AppError::Conflict(_))));
+    }
     
-    Ok(())
+    #[tokio::test]
+    async fn download_running_past_its_deadline_is_cancelled() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let db = crate::db::Database::open(&dir.path().join("test.db"), &Default::default()).unwrap();
+        let state = Arc::new(AppState::new(Settings::default(), db).unwrap());
+        let downloads = dir.path().join("downloads");
+        // The slow payload takes well over a second
+        let mut record = record(format!("{}/slow.bin", base), &downloads);
+        record.max_duration_secs = Some(1);
+        state.db.insert_download(&record).unwrap();
+        
+        let slot = state.download_manager.try_start(&record.url).unwrap();
+        start_download(Arc::clone(&state), record.clone(), slot);
+        let finished = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let current = state.db.get_download(&record.id).unwrap().unwrap();
+                if current.status == DownloadStatus::Cancelled {
+                    return current;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        
+        assert_eq!(finished.cancel_reason, Some(CancelReason::Timeout));
+        assert_eq!(finished.error_kind, Some(crate::db::DownloadErrorKind::DeadlineExceeded));
+        assert!(files_in(&downloads).is_empty());
+    }
+    
+    #[tokio::test]
+    async fn replacement_config_is_checked_field_by_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut current = Settings::default();
+        for file_type in current.file_types.values_mut() {
+            file_type.destination = dir.path().join("downloads");
+        }
+        assert!(validate_settings(&current).await.is_empty());
+        
+        let mut new = current.clone();
+        new.server.port = 0;
+        new.server.host = "localhost".into();
+        new.partial_suffix = "/x".into();
+        new.file_types.get_mut("video").unwrap().destination = dir.path().join("file").join("sub");
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+        new.allow_command_hooks = true;
+        // Read back without it, which keeps it
+        current.server.write_token = Some("secret".into());
+        
+        let mut fields: Vec<String> = validate_settings(&new).await.into_iter().map(|e| e.field).collect();
+        fields.extend(config_file_only_changes(&current, &new).into_iter().map(|e| e.field));
+        assert_eq!(
+            fields,
+            ["server.port", "server.host", "partial_suffix", "file_types.video.destination", "allow_command_hooks"]
+        );
+    }
+    
+    #[tokio::test]
+    async fn errors_carry_a_code_and_message() {
+        let response = AppError::PayloadTooLarge("too big".into()).into_response();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "PAYLOAD_TOO_LARGE");
+        assert_eq!(body["error"]["message"], "too big");
+    }
+    
+    #[tokio::test]
+    async fn not_found_fails_without_retrying() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/missing.bin", base), dir.path());
+        let settings = Settings::default();
+        let (progress_tx, _) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        
+        let result = download_with_retries(
+            &record,
+            &client(&settings),
+            &settings,
+            &fast_retries(),
+            &progress_tx,
+            &mut cancel_rx,
+            &unlimited(),
+            &Default::default(),
+            || None,
+            |e, _| panic!("retried a fatal error: {}", e),
+        )
+        .await;
+        
+        assert!(result.unwrap_err().is::<download::HttpStatusError>());
+    }
+    
+    #[tokio::test]
+    async fn falls_back_to_mirror_when_url_fails() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = record(format!("{}/missing.bin", base), dir.path());
+        record.mirrors = vec![format!("{}/sized.bin", base)];
+        
+        run(&record).await.unwrap();
+        
+        assert_eq!(std::fs::read(dir.path().join("missing.bin")).unwrap(), payload());
+    }
+    
+    #[tokio::test]
+    async fn matching_checksum_completes() {
+        use sha2::{Digest, Sha256};
+        
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = record(format!("{}/sized.bin", base), dir.path());
+        let digest: String = Sha256::digest(payload()).iter().map(|b| format!("{:02x}", b)).collect();
+        record.checksum = Some(format!("sha256:{}", digest));
+        
+        run(&record).await.unwrap();
+        
+        assert_eq!(files_in(dir.path()), vec!["sized.bin"]);
+    }
+    
+    #[tokio::test]
+    async fn content_encoding_mode_decides_what_is_stored() {
+        let base = mock_server().await;
+        for (mode, expected) in [
+            (ContentEncodingMode::Original, gzipped_payload()),
+            (ContentEncodingMode::Decompress, payload()),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let settings = Settings { content_encoding: mode, ..Settings::default() };
+            
+            let written = run_with(&record(format!("{}/gzipped/page.txt", base), dir.path()), &settings).await.unwrap();
+            
+            assert_eq!(written, expected.len() as u64, "{:?}", mode);
+            assert_eq!(std::fs::read(dir.path().join("page.txt")).unwrap(), expected, "{:?}", mode);
+        }
+    }
+    
+    #[tokio::test]
+    async fn only_stored_bytes_as_sent_are_resumed() {
+        let base = mock_server().await;
+        // Zeros rather than the real prefix, to show whether the partial was kept
+        let mut resumed = vec![0u8; 10_000];
+        resumed.extend_from_slice(&payload()[10_000..]);
+        for (mode, expected) in [
+            (ContentEncodingMode::Original, resumed),
+            // Offsets into decoded bytes mean nothing to the server
+            (ContentEncodingMode::Decompress, payload()),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let settings = Settings { content_encoding: mode, ..Settings::default() };
+            std::fs::write(settings.partial_path(dir.path(), "ranged.bin"), vec![0u8; 10_000]).unwrap();
+            
+            run_with(&record(format!("{}/ranged.bin", base), dir.path()), &settings).await.unwrap();
+            
+            assert_eq!(std::fs::read(dir.path().join("ranged.bin")).unwrap(), expected, "{:?}", mode);
+        }
+    }
+    
+    #[tokio::test]
+    async fn compressed_files_are_not_decompressed_in_transit() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings {
+            content_encoding: ContentEncodingMode::Decompress,
+            ..Settings::default()
+        };
+        
+        run_with(&record(format!("{}/gzipped/backup.tar.gz", base), dir.path()), &settings).await.unwrap();
+        run_with(&record(format!("{}/gzipped/page.txt", base), dir.path()), &settings).await.unwrap();
+        
+        assert_eq!(std::fs::read(dir.path().join("backup.tar.gz")).unwrap(), gzipped_payload());
+        assert_eq!(std::fs::read(dir.path().join("page.txt")).unwrap(), payload());
+    }
+    
+    #[test]
+    fn storage_counts_towards_the_nearest_file_type() {
+        let folders = vec![
+            ("general".to_string(), PathBuf::from("/dl")),
+            ("video".to_string(), PathBuf::from("/dl/Videos")),
+            ("clips".to_string(), PathBuf::from("/dl/Videos")),
+        ];
+        
+        assert_eq!(owning_file_type(&folders, std::path::Path::new("/dl")), Some("general"));
+        assert_eq!(owning_file_type(&folders, std::path::Path::new("/dl/2026/10")), Some("general"));
+        assert_eq!(owning_file_type(&folders, std::path::Path::new("/dl/Videos/2026")), Some("video"));
+        assert_eq!(owning_file_type(&folders, std::path::Path::new("/elsewhere")), None);
+    }
+    
+    #[tokio::test]
+    async fn checksum_is_computed_when_enabled() {
+        use sha2::{Digest, Sha256};
+        
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(format!("{}/sized.bin", base), dir.path());
+        let settings = Settings {
+            compute_checksums: true,
+            ..Settings::default()
+        };
+        let (progress_tx, _) = broadcast::channel(100);
+        let (_cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        
+        let finished = download_file_with_cancel(&record, &client(&settings), &settings, &progress_tx, &mut cancel_rx, &unlimited(), &Default::default(), || None)
+            .await
+            .unwrap();
+        
+        let digest: String = Sha256::digest(payload()).iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(finished.checksum, Some(format!("sha256:{}", digest)));
+    }
+    
+    #[tokio::test]
+    async fn checksum_mismatch_fails_and_discards_file() {
+        let base = mock_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut record = record(format!("{}/sized.bin", base), dir.path());
+        record.checksum = Some(format!("sha256:{}", "0".repeat(64)));
+        
+        let err = run(&record).await.unwrap_err();
+        
+        assert_eq!(download::classify_error(&err), crate::db::DownloadErrorKind::ChecksumMismatch);
+        assert!(files_in(dir.path()).is_empty());
+    }
 }
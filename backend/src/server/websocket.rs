@@ -1,41 +1,137 @@
 //! WebSocket handler for real-time progress updates
 
+use crate::db::DownloadRecord;
+use crate::download::{DownloadChange, ProgressUpdate, QueueSnapshot};
 use crate::AppState;
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        Query, State,
     },
     response::IntoResponse,
 };
 use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
 use std::sync::Arc;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
 use tracing::{error, info};
 
+/// What a WebSocket client wants to be sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WsEvents {
+    /// Every `ProgressUpdate`, several a second per running download
+    #[default]
+    Progress,
+    /// Only a `DownloadChange` when a download is added, removed or changes
+    /// status, as a cue to refetch the list
+    Changes,
+    /// A `QueueSnapshot` on connecting and whenever the queue's order changes
+    Queue,
+}
+
+/// The broadcasts a client's `WsEvents` are made from
+enum Feed {
+    Progress(Receiver<ProgressUpdate>),
+    /// Progress updates for status changes, plus added and removed downloads
+    Changes(Receiver<ProgressUpdate>, Receiver<DownloadChange>),
+    Queue(Receiver<QueueSnapshot>),
+}
+
+/// Most finished downloads replayed to a connecting client
+const MAX_FINISHED_REPLAY: usize = 50;
+
+/// Query parameters of the WebSocket endpoint
+#[derive(Debug, Deserialize)]
+pub struct WsQuery {
+    #[serde(default)]
+    pub events: WsEvents,
+}
+
 /// WebSocket upgrade handler
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
+    Query(query): Query<WsQuery>,
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    ws.on_upgrade(move |socket| handle_socket(socket, state, query.events))
 }
 
 /// Handle WebSocket connection
-async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
+async fn handle_socket(socket: WebSocket, state: Arc<AppState>, events: WsEvents) {
     let (mut sender, mut receiver) = socket.split();
     
-    // Subscribe to progress updates
-    let mut progress_rx = state.download_manager.subscribe();
+    // Subscribe to just what this client is sent; a receiver nobody reads
+    // would still fill up and lag
+    let manager = state.download_manager.clone();
+    let feed = match events {
+        WsEvents::Progress => Feed::Progress(manager.subscribe()),
+        WsEvents::Changes => Feed::Changes(manager.subscribe(), manager.subscribe_changes()),
+        WsEvents::Queue => Feed::Queue(manager.subscribe_queue()),
+    };
     
     info!("WebSocket client connected");
     
+    let replay = match events {
+        WsEvents::Progress => finished_replay(&state),
+        _ => Vec::new(),
+    };
+    
     // Spawn task to forward progress updates to client
     let send_task = tokio::spawn(async move {
-        while let Ok(update) = progress_rx.recv().await {
-            let msg = serde_json::to_string(&update).unwrap_or_default();
-            if sender.send(Message::Text(msg.into())).await.is_err() {
-                break;
+        match feed {
+            Feed::Progress(mut progress_rx) => {
+                for update in replay {
+                    let msg = serde_json::to_string(&update).unwrap_or_default();
+                    if sender.send(Message::Text(msg.into())).await.is_err() {
+                        return;
+                    }
+                }
+                while let Ok(update) = progress_rx.recv().await {
+                    let msg = serde_json::to_string(&update).unwrap_or_default();
+                    if sender.send(Message::Text(msg.into())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Feed::Queue(mut queue_rx) => {
+                let mut snapshot = manager.queue_snapshot();
+                loop {
+                    let msg = serde_json::to_string(&snapshot).unwrap_or_default();
+                    if sender.send(Message::Text(msg.into())).await.is_err() {
+                        break;
+                    }
+                    snapshot = match queue_rx.recv().await {
+                        Ok(snapshot) => snapshot,
+                        // Only the latest order matters
+                        Err(RecvError::Lagged(_)) => manager.queue_snapshot(),
+                        Err(RecvError::Closed) => break,
+                    };
+                }
             }
+            Feed::Changes(mut progress_rx, mut changes_rx) => loop {
+                let change = tokio::select! {
+                    update = progress_rx.recv() => match update {
+                        Ok(update) => match DownloadChange::from_progress(&update) {
+                            Some(change) => change,
+                            None => continue,
+                        },
+                        // Missed some, so the client can't know what changed
+                        Err(RecvError::Lagged(_)) => DownloadChange::resync(),
+                        Err(RecvError::Closed) => break,
+                    },
+                    change = changes_rx.recv() => match change {
+                        Ok(change) => change,
+                        Err(RecvError::Lagged(_)) => DownloadChange::resync(),
+                        Err(RecvError::Closed) => break,
+                    },
+                };
+                
+                let msg = serde_json::to_string(&change).unwrap_or_default();
+                if sender.send(Message::Text(msg.into())).await.is_err() {
+                    break;
+                }
+            },
         }
     });
     
@@ -61,3 +157,35 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
     // Cancel the send task when client disconnects
     send_task.abort();
 }
+
+/// Final updates of the downloads that finished within
+/// `finished_replay_secs`, for a client that may have missed them
+fn finished_replay(state: &AppState) -> Vec<ProgressUpdate> {
+    let secs = state.settings.read().finished_replay_secs;
+    if secs == 0 {
+        return Vec::new();
+    }
+    
+    let since = chrono::Utc::now() - chrono::Duration::seconds(secs as i64);
+    match state.db.finished_since(since, MAX_FINISHED_REPLAY) {
+        Ok(downloads) => downloads.iter().map(final_update).collect(),
+        Err(e) => {
+            error!("Failed to load finished downloads: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// The update a finished download's last one amounted to
+fn final_update(download: &DownloadRecord) -> ProgressUpdate {
+    ProgressUpdate {
+        id: download.id.clone(),
+        downloaded: download.downloaded_size,
+        total: download.total_size,
+        indeterminate: download.total_size.is_none() && !download.status.is_finished(),
+        speed: 0,
+        status: download.status,
+        error: download.error_message.clone(),
+        started_at: None,
+    }
+}
@@ -1,10 +1,11 @@
 //! System tray module for background running
 
+use crate::db::CancelReason;
 use crate::AppState;
 use anyhow::Result;
 use std::sync::Arc;
 use tray_icon::{
-    menu::{Menu, MenuItem},
+    menu::{CheckMenuItem, Menu, MenuItem},
     TrayIconBuilder,
 };
 use tracing::info;
@@ -22,12 +23,15 @@ pub fn run(state: Arc<AppState>) -> Result<()> {
     let menu = Menu::new();
     
     let open_item = MenuItem::new("Open Web UI", true, None);
+    let speed_item = CheckMenuItem::new(speed_limit_label(false), true, false, None);
     let quit_item = MenuItem::new("Quit", true, None);
     
     let open_id = open_item.id().clone();
+    let speed_id = speed_item.id().clone();
     let quit_id = quit_item.id().clone();
     
     menu.append(&open_item)?;
+    menu.append(&speed_item)?;
     menu.append(&quit_item)?;
     
     // Create tray icon
@@ -41,26 +45,66 @@ pub fn run(state: Arc<AppState>) -> Result<()> {
     
     info!("System tray initialized");
     
-    // Get server URL for opening
-    let port = state.settings.read().server.port;
-    let url = format!("http://localhost:{}", port);
-    
     // Event loop
     let event_loop = tray_icon::menu::MenuEvent::receiver();
     
     loop {
         if let Ok(event) = event_loop.recv() {
             if event.id == open_id {
+                // Read it each time, the port can change while running
+                let url = state.served_at().local_url();
                 info!("Opening web UI: {}", url);
                 let _ = open::that(&url);
+            } else if event.id == speed_id {
+                let manager = &state.download_manager;
+                let enabled = !manager.is_alt_speed();
+                manager.set_alt_speed(enabled);
+                info!(
+                    "Alternative speed limit {}",
+                    if enabled { "enabled" } else { "disabled" }
+                );
+                // The menu may have toggled the check mark itself; make
+                // sure it matches
+                speed_item.set_checked(enabled);
+                speed_item.set_text(speed_limit_label(enabled));
             } else if event.id == quit_id {
                 info!("Quit requested from tray menu");
+                shutdown_downloads(&state);
                 std::process::exit(0);
             }
         }
     }
 }
 
+/// Text of the menu item that toggles the alternative speed limit
+fn speed_limit_label(enabled: bool) -> &'static str {
+    if enabled {
+        "Disable Speed Limit"
+    } else {
+        "Enable Speed Limit"
+    }
+}
+
+/// Stop running downloads so they're recorded as interrupted by the
+/// shutdown (and resumed on next start) rather than left mid-write. The
+/// queue is paused first, so the slots they free don't start others.
+fn shutdown_downloads(state: &AppState) {
+    let manager = &state.download_manager;
+    manager.set_paused(true);
+    if manager.active_count() == 0 {
+        return;
+    }
+    
+    info!("Stopping {} active download(s)", manager.active_count());
+    manager.cancel_all_active(CancelReason::Shutdown);
+    
+    // Give the download tasks a moment to clean up and record their status
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(3);
+    while manager.active_count() > 0 && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
+
 /// Load the tray icon
 fn load_icon() -> Result<tray_icon::Icon> {
     // Create a simple colored icon programmatically
@@ -19,7 +19,7 @@ function App() {
   // Fetch downloads to compute stats from actual data
   const { data: downloads } = useQuery({
     queryKey: ['downloads'],
-    queryFn: api.getDownloads,
+    queryFn: () => api.getDownloads(),
   })
   
   // Compute stats from downloads list
@@ -1,25 +1,75 @@
 import type {
+  ApiErrorBody,
+  ApiErrorCode,
+  FieldError,
+  RawConfig,
   DownloadRecord,
+  DownloadError,
+  SpeedStats,
   DownloadStats,
-  FileTypeConfig,
+  LifetimeStats,
+  QueueEntry,
+  ConflictPolicy,
+  FileTypeEntry,
   Settings,
+  TranscodeConfig,
   AddDownloadRequest,
   AddDownloadResponse,
+  AddBatchResponse,
+  ListFileResponse,
+  RemovedDownload,
+  BatchProgress,
+  ListDownloadsParams,
+  MoveDownloadResponse,
+  UpdateFileTypeResponse,
+  NotificationTestResponse,
+  ValidateDownloadResponse,
+  VersionInfo,
+  NetworkInfo,
+  FolderListing,
+  StorageUsage,
 } from '../types'
 
 const BASE_URL = '/api'
 
+/** localStorage key holding the token that allows changes on a read-only server */
+export const WRITE_TOKEN_KEY = 'vibe-write-token'
+
+/** Error response from the API. `code` is stable; match on it, not on the message. */
+export class ApiError extends Error {
+  constructor(
+    public status: number,
+    public code: ApiErrorCode | 'UNKNOWN',
+    message: string,
+    public fields: FieldError[] = [],
+  ) {
+    super(message)
+    this.name = 'ApiError'
+  }
+}
+
 async function fetchJson<T>(url: string, options?: RequestInit): Promise<T> {
+  const headers: Record<string, string> = {
+    'Content-Type': 'application/json',
+  }
+  const writeToken = localStorage.getItem(WRITE_TOKEN_KEY)
+  if (writeToken) {
+    headers.Authorization = `Bearer ${writeToken}`
+  }
+  
   const response = await fetch(`${BASE_URL}${url}`, {
-    headers: {
-      'Content-Type': 'application/json',
-    },
+    headers,
     ...options,
   })
   
   if (!response.ok) {
-    const error = await response.json().catch(() => ({ error: 'Unknown error' }))
-    throw new Error(error.error || `HTTP ${response.status}`)
+    const body: Partial<ApiErrorBody> = await response.json().catch(() => ({}))
+    throw new ApiError(
+      response.status,
+      body.error?.code ?? 'UNKNOWN',
+      body.error?.message || `HTTP ${response.status}`,
+      body.error?.fields,
+    )
   }
   
   // Handle empty responses (204 No Content)
@@ -32,7 +82,12 @@ async function fetchJson<T>(url: string, options?: RequestInit): Promise<T> {
 
 export const api = {
   // Downloads
-  getDownloads: () => fetchJson<DownloadRecord[]>('/downloads'),
+  getDownloads: (params: ListDownloadsParams = {}) => {
+    const query = new URLSearchParams(
+      Object.entries(params).filter(([, value]) => value !== undefined)
+    ).toString()
+    return fetchJson<DownloadRecord[]>(query ? `/downloads?${query}` : '/downloads')
+  },
   
   addDownload: (data: AddDownloadRequest) =>
     fetchJson<AddDownloadResponse>('/downloads', {
@@ -40,14 +95,95 @@ export const api = {
       body: JSON.stringify(data),
     }),
   
+  addBatch: (downloads: AddDownloadRequest[]) =>
+    fetchJson<AddBatchResponse>('/downloads/batch', {
+      method: 'POST',
+      body: JSON.stringify({ downloads }),
+    }),
+  
+  /** Add the URLs of a list file (one per line, `#` comments) as a batch */
+  importListFile: (text: string, fileType?: string) =>
+    fetchJson<ListFileResponse>(
+      fileType ? `/downloads/from-file?file_type=${encodeURIComponent(fileType)}` : '/downloads/from-file',
+      { method: 'POST', body: text },
+    ),
+  
+  /** Track a file downloaded some other way as a completed download */
+  registerDownload: (data: { path: string; url: string; file_type?: string; checksum?: string }) =>
+    fetchJson<DownloadRecord>('/downloads/register', {
+      method: 'POST',
+      body: JSON.stringify(data),
+    }),
+  
+  getBatch: (batchId: string) => fetchJson<BatchProgress>(`/batches/${batchId}`),
+  
+  validateDownload: (data: Pick<AddDownloadRequest, 'url' | 'accept' | 'query_params'>) =>
+    fetchJson<ValidateDownloadResponse>('/downloads/validate', {
+      method: 'POST',
+      body: JSON.stringify(data),
+    }),
+  
   removeDownload: (id: string) =>
     fetchJson<void>(`/downloads/${id}`, { method: 'DELETE' }),
   
+  removeDownloads: (ids: string[], deleteFiles = false) =>
+    fetchJson<RemovedDownload[]>('/downloads/delete', {
+      method: 'POST',
+      body: JSON.stringify({ ids, delete_files: deleteFiles }),
+    }),
+  
   cancelDownload: (id: string) =>
     fetchJson<void>(`/downloads/${id}/cancel`, { method: 'POST' }),
   
+  restoreDownload: (id: string) =>
+    fetchJson<DownloadRecord>(`/downloads/${id}/restore`, { method: 'POST' }),
+  
+  previewUrl: (id: string) => `${BASE_URL}/downloads/${id}/preview`,
+  
+  renameDownload: (id: string, filename: string) =>
+    fetchJson<DownloadRecord>(`/downloads/${id}/filename`, {
+      method: 'PUT',
+      body: JSON.stringify({ filename }),
+    }),
+  
+  moveDownload: (id: string, destination: string) =>
+    fetchJson<MoveDownloadResponse>(`/downloads/${id}/move`, {
+      method: 'POST',
+      body: JSON.stringify({ destination }),
+    }),
+  
+  setSpeedLimit: (id: string, bytesPerSec: number) =>
+    fetchJson<DownloadRecord>(`/downloads/${id}/speed-limit`, {
+      method: 'PUT',
+      body: JSON.stringify({ bytes_per_sec: bytesPerSec }),
+    }),
+  
+  clearCompleted: (includeCancelled = false) =>
+    fetchJson<{ removed: number }>(
+      `/downloads/clear-completed?include_cancelled=${includeCancelled}`,
+      { method: 'POST' }
+    ),
+  
+  getDownloadErrors: (id: string) =>
+    fetchJson<DownloadError[]>(`/downloads/${id}/errors`),
+  
+  getSpeedStats: (id: string) =>
+    fetchJson<SpeedStats>(`/downloads/${id}/speed-stats`),
+  
   getDownloadStats: () => fetchJson<DownloadStats>('/downloads/stats'),
   
+  getLifetimeStats: () => fetchJson<LifetimeStats>('/stats/lifetime'),
+  
+  getQueue: () => fetchJson<QueueEntry[]>('/queue'),
+  
+  pauseQueue: () => fetchJson<DownloadStats>('/queue/pause', { method: 'POST' }),
+  
+  resumeQueue: () => fetchJson<DownloadStats>('/queue/resume', { method: 'POST' }),
+  
+  getVersion: () => fetchJson<VersionInfo>('/version'),
+  
+  getNetworkInfo: () => fetchJson<NetworkInfo>('/network-info'),
+  
   // Settings
   getSettings: () => fetchJson<Settings>('/settings'),
   
@@ -57,13 +193,34 @@ export const api = {
       body: JSON.stringify(data),
     }),
   
+  getRawConfig: () => fetchJson<RawConfig>('/config/raw'),
+  
+  replaceRawConfig: (config: RawConfig) =>
+    fetchJson<RawConfig>('/config/raw', {
+      method: 'PUT',
+      body: JSON.stringify(config),
+    }),
+  
+  testNotifications: () =>
+    fetchJson<NotificationTestResponse>('/notifications/test', { method: 'POST' }),
+  
   // File Types
-  getFileTypes: () => fetchJson<Record<string, FileTypeConfig>>('/file-types'),
+  getFileTypes: () => fetchJson<FileTypeEntry[]>('/file-types'),
+  
+  reorderFileTypes: (ids: string[]) =>
+    fetchJson<FileTypeEntry[]>('/file-types/order', {
+      method: 'PUT',
+      body: JSON.stringify({ ids }),
+    }),
   
   addFileType: (data: {
     name: string
     extensions: string[]
     destination: string
+    auto_extract?: boolean
+    on_conflict?: ConflictPolicy
+    post_download_transcode?: TranscodeConfig
+    date_subfolder?: string
   }) =>
     fetchJson<{ id: string }>('/file-types', {
       method: 'POST',
@@ -72,9 +229,18 @@ export const api = {
   
   updateFileType: (
     id: string,
-    data: Partial<{ name: string; extensions: string[]; destination: string }>
+    data: Partial<{
+      name: string
+      extensions: string[]
+      destination: string
+      auto_extract: boolean
+      on_conflict: ConflictPolicy | null
+      post_download_transcode: TranscodeConfig | null
+      date_subfolder: string | null
+      move_existing: boolean
+    }>
   ) =>
-    fetchJson<void>(`/file-types/${id}`, {
+    fetchJson<UpdateFileTypeResponse>(`/file-types/${id}`, {
       method: 'PUT',
       body: JSON.stringify(data),
     }),
@@ -82,6 +248,20 @@ export const api = {
   removeFileType: (id: string) =>
     fetchJson<void>(`/file-types/${id}`, { method: 'DELETE' }),
   
+  pauseFileType: (id: string) =>
+    fetchJson<{ ids: string[] }>(`/file-types/${id}/pause-all`, { method: 'POST' }),
+  
+  resumeFileType: (id: string) =>
+    fetchJson<{ ids: string[] }>(`/file-types/${id}/resume-all`, { method: 'POST' }),
+  
+  cancelFileType: (id: string) =>
+    fetchJson<{ ids: string[] }>(`/file-types/${id}/cancel-all`, { method: 'POST' }),
+  
+  getFileTypeListing: (id: string, offset = 0, limit = 100) =>
+    fetchJson<FolderListing>(`/file-types/${id}/listing?offset=${offset}&limit=${limit}`),
+  
+  getStorageUsage: (walk = false) => fetchJson<StorageUsage[]>(`/storage?walk=${walk}`),
+  
   // URL utilities
   getUrlInfo: (url: string) =>
     fetchJson<{ filename: string | null; size: number | null; content_type: string | null }>('/url-info', {
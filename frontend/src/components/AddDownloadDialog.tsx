@@ -42,22 +42,22 @@ function rememberFileTypeForExtension(filename: string, fileType: string) {
 /** Auto-detect file type based on filename extension */
 function detectFileTypeFromFilename(
   filename: string,
-  fileTypes: Record<string, { name: string; extensions: string[]; destination: string }> | undefined
+  fileTypes: { id: string; name: string; extensions: string[]; destination: string }[] | undefined
 ): string {
   if (!fileTypes || !filename) return 'general'
   
   // First check user's history for this extension
   const remembered = getRememberedFileType(filename)
-  if (remembered && fileTypes[remembered]) {
+  if (remembered && fileTypes.some(t => t.id === remembered)) {
     return remembered
   }
   
   // Fall back to extension matching
   const ext = filename.split('.').pop()?.toLowerCase()
   if (ext) {
-    for (const [id, config] of Object.entries(fileTypes)) {
+    for (const config of fileTypes) {
       if (config.extensions.some(e => e.toLowerCase() === ext)) {
-        return id
+        return config.id
       }
     }
   }
@@ -222,8 +222,8 @@ export function AddDownloadDialog({ onClose }: AddDownloadDialogProps) {
               className="w-full px-3 py-3 text-base border border-slate-300 dark:border-slate-700 rounded-lg bg-white dark:bg-slate-800 text-slate-800 dark:text-white focus:outline-none focus:ring-2 focus:ring-primary-500"
             >
               {fileTypes &&
-                Object.entries(fileTypes).map(([id, config]) => (
-                  <option key={id} value={id}>
+                fileTypes.map((config) => (
+                  <option key={config.id} value={config.id}>
                     {config.name} — {config.destination}
                   </option>
                 ))}
@@ -18,7 +18,7 @@ export function DownloadList() {
   
   const { data: downloads, isLoading, error } = useQuery({
     queryKey: ['downloads'],
-    queryFn: api.getDownloads,
+    queryFn: () => api.getDownloads(),
     refetchInterval: 10000, // Refresh every 10s as backup
   })
 
@@ -110,7 +110,7 @@ function DownloadItem({ download, onRemove, onCancel }: DownloadItemProps) {
           <div className="flex items-center gap-2">
             <statusConfig.icon className={`w-4 h-4 flex-shrink-0 ${statusConfig.color}`} />
             <h3 className="font-medium text-slate-800 dark:text-white truncate text-sm sm:text-base">
-              {download.filename}
+              {download.rename_to ?? download.filename}
             </h3>
           </div>
           
@@ -120,25 +120,51 @@ function DownloadItem({ download, onRemove, onCancel }: DownloadItemProps) {
           
           <div className="flex flex-wrap items-center gap-2 sm:gap-4 mt-2 text-xs text-slate-500 dark:text-slate-500">
             <span className="capitalize bg-slate-100 dark:bg-slate-800 px-2 py-0.5 rounded">{download.file_type}</span>
+            {download.linked_from && (
+              <span className="bg-slate-100 dark:bg-slate-800 px-2 py-0.5 rounded" title="Linked from another download, not downloaded again">
+                Linked
+              </span>
+            )}
+            {download.shares_file_with && (
+              <span className="bg-slate-100 dark:bg-slate-800 px-2 py-0.5 rounded" title="Same checksum as an earlier download, so it shares that file">
+                Deduplicated
+              </span>
+            )}
             <span>{formatBytes(download.downloaded_size)}{download.total_size ? ` / ${formatBytes(download.total_size)}` : ''}</span>
             <span className={statusConfig.color}>{statusConfig.label}</span>
+            {download.duration_secs !== null && (
+              <span title={`Active for ${formatDuration(download.active_secs)}`}>
+                in {formatDuration(download.duration_secs)}
+              </span>
+            )}
           </div>
           
           {/* Progress bar */}
           {(download.status === 'downloading' || download.status === 'queued') && (
             <div className="mt-3">
               <div className="h-1.5 sm:h-2 bg-slate-200 dark:bg-slate-800 rounded-full overflow-hidden">
-                <div
-                  className="h-full bg-primary-500 transition-all duration-300"
-                  style={{ width: `${progress}%` }}
-                />
+                {download.status === 'downloading' && !download.total_size ? (
+                  // Unknown size: show activity instead of a stuck 0% bar
+                  <div className="h-full w-full bg-primary-500/60 animate-pulse" />
+                ) : (
+                  <div
+                    className="h-full bg-primary-500 transition-all duration-300"
+                    style={{ width: `${progress}%` }}
+                  />
+                )}
               </div>
             </div>
           )}
           
-          {/* Error message */}
+          {/* Error message (or why it was cancelled) */}
           {download.error_message && (
-            <p className="text-xs sm:text-sm text-red-500 mt-2">{download.error_message}</p>
+            <p
+              className={`text-xs sm:text-sm mt-2 ${
+                download.cancel_reason ? 'text-slate-500 dark:text-slate-400' : 'text-red-500'
+              }`}
+            >
+              {download.error_message}
+            </p>
           )}
         </div>
 
@@ -197,3 +223,14 @@ function formatBytes(bytes: number): string {
   
   return `${parseFloat((bytes / Math.pow(k, i)).toFixed(2))} ${sizes[i]}`
 }
+
+function formatDuration(secs: number): string {
+  if (secs < 60) return `${secs < 10 ? secs.toFixed(1) : Math.round(secs)}s`
+  
+  const total = Math.round(secs)
+  const h = Math.floor(total / 3600)
+  const m = Math.floor((total % 3600) / 60)
+  const s = total % 60
+  
+  return h > 0 ? `${h}h ${m}m` : `${m}m ${s}s`
+}
@@ -1,6 +1,6 @@
 import { useState } from 'react'
 import { useQuery, useMutation, useQueryClient } from '@tanstack/react-query'
-import { Save, Plus, Trash2, Loader2, Folder } from 'lucide-react'
+import { Save, Plus, Trash2, Loader2, Folder, Send } from 'lucide-react'
 import toast from 'react-hot-toast'
 import { api } from '../api/client'
 
@@ -19,28 +19,54 @@ export function SettingsPanel() {
 
   const updateSettingsMutation = useMutation({
     mutationFn: api.updateSettings,
-    onSuccess: () => {
+    onSuccess: (saved) => {
       queryClient.invalidateQueries({ queryKey: ['settings'] })
-      toast.success('Settings saved')
+      if (saved.warnings?.length) {
+        saved.warnings.forEach((warning) => toast.error(warning))
+      } else {
+        toast.success('Settings saved')
+      }
     },
     onError: (err: Error) => {
       toast.error(`Failed to save settings: ${err.message}`)
     },
   })
 
+  const testNotificationsMutation = useMutation({
+    mutationFn: api.testNotifications,
+    onSuccess: ({ webhook }) => {
+      if (!webhook) {
+        toast.error('No webhook is configured')
+      } else if (webhook.success) {
+        toast.success('Test webhook sent')
+      } else {
+        toast.error(webhook.error ?? 'Test webhook failed')
+      }
+    },
+    onError: (err: Error) => {
+      toast.error(`Failed to send test: ${err.message}`)
+    },
+  })
+
   const [maxConcurrent, setMaxConcurrent] = useState<number | null>(null)
   const [startOnLogin, setStartOnLogin] = useState<boolean | null>(null)
   const [startOnBoot, setStartOnBoot] = useState<boolean | null>(null)
+  const [pauseOnMetered, setPauseOnMetered] = useState<boolean | null>(null)
+  const [webhookUrl, setWebhookUrl] = useState<string | null>(null)
 
   const currentMaxConcurrent = maxConcurrent ?? settings?.max_concurrent_downloads ?? 3
   const currentStartOnLogin = startOnLogin ?? settings?.start_on_login ?? false
   const currentStartOnBoot = startOnBoot ?? settings?.start_on_boot ?? false
+  const currentPauseOnMetered = pauseOnMetered ?? settings?.pause_on_metered ?? false
+  const currentWebhookUrl = webhookUrl ?? settings?.webhook_url ?? ''
 
   const handleSaveSettings = () => {
     updateSettingsMutation.mutate({
       max_concurrent_downloads: currentMaxConcurrent,
       start_on_login: currentStartOnLogin,
       start_on_boot: currentStartOnBoot,
+      pause_on_metered: currentPauseOnMetered,
+      webhook_url: currentWebhookUrl.trim() || null,
     })
   }
 
@@ -120,6 +146,59 @@ export function SettingsPanel() {
             </div>
           )}
 
+          {/* Pause on Metered Connections */}
+          <div className="flex items-start gap-3 py-2">
+            <input
+              type="checkbox"
+              id="pauseOnMetered"
+              checked={currentPauseOnMetered}
+              onChange={(e) => setPauseOnMetered(e.target.checked)}
+              className="w-5 h-5 text-primary-500 rounded focus:ring-primary-500 mt-0.5"
+            />
+            <div>
+              <label
+                htmlFor="pauseOnMetered"
+                className="text-sm font-medium text-slate-700 dark:text-slate-300"
+              >
+                Pause downloads on metered connections
+              </label>
+              <p className="text-xs text-slate-500 dark:text-slate-500 mt-1">
+                Resumes once the connection is unmetered. Windows, or Linux with NetworkManager.
+              </p>
+            </div>
+          </div>
+
+          {/* Webhook */}
+          <div>
+            <label className="block text-sm font-medium text-slate-700 dark:text-slate-300 mb-2">
+              Webhook URL
+            </label>
+            <div className="flex flex-col sm:flex-row gap-2">
+              <input
+                type="url"
+                placeholder="https://example.com/hooks/downloads"
+                value={currentWebhookUrl}
+                onChange={(e) => setWebhookUrl(e.target.value)}
+                className="w-full px-3 py-3 sm:py-2 border border-slate-300 dark:border-slate-700 rounded-lg bg-white dark:bg-slate-800 text-slate-800 dark:text-white focus:outline-none focus:ring-2 focus:ring-primary-500 text-base"
+              />
+              <button
+                onClick={() => testNotificationsMutation.mutate()}
+                disabled={testNotificationsMutation.isPending || !settings?.webhook_url}
+                className="flex items-center justify-center gap-2 px-4 py-3 sm:py-2 border border-slate-300 dark:border-slate-700 text-slate-700 dark:text-slate-300 rounded-lg hover:bg-slate-50 dark:hover:bg-slate-800 transition-colors disabled:opacity-50 font-medium whitespace-nowrap"
+              >
+                {testNotificationsMutation.isPending ? (
+                  <Loader2 className="w-4 h-4 animate-spin" />
+                ) : (
+                  <Send className="w-4 h-4" />
+                )}
+                Send test
+              </button>
+            </div>
+            <p className="text-xs text-slate-500 dark:text-slate-500 mt-2">
+              Gets a JSON POST when a download completes or fails. The test uses the saved URL.
+            </p>
+          </div>
+
           <button
             onClick={handleSaveSettings}
             disabled={updateSettingsMutation.isPending}
@@ -146,8 +225,8 @@ export function SettingsPanel() {
 
         <div className="space-y-2">
           {fileTypes &&
-            Object.entries(fileTypes).map(([id, config]) => (
-              <FileTypeItem key={id} id={id} config={config} />
+            fileTypes.map((config) => (
+              <FileTypeItem key={config.id} id={config.id} config={config} />
             ))}
         </div>
       </section>
@@ -24,6 +24,13 @@ export function useWebSocket() {
       try {
         const update: ProgressUpdate = JSON.parse(event.data)
         
+        // A download added elsewhere (another device, a script) isn't in
+        // the list yet; fetch it rather than dropping its updates
+        const cached = queryClient.getQueryData<any[]>(['downloads'])
+        if (cached && !cached.some((download) => download.id === update.id)) {
+          queryClient.invalidateQueries({ queryKey: ['downloads'] })
+        }
+        
         // Update the downloads cache with new progress
         queryClient.setQueryData<any[]>(['downloads'], (oldData) => {
           if (!oldData) return oldData
@@ -36,6 +43,7 @@ export function useWebSocket() {
                 total_size: update.total,
                 status: update.status,
                 error_message: update.error,
+                started_at: update.started_at ?? download.started_at,
               }
             }
             return download
@@ -13,6 +13,80 @@ export interface DownloadRecord {
   created_at: string
   started_at: string | null
   completed_at: string | null
+  accept: string | null
+  query_params: [string, string][]
+  /** Values may hold `${VIBE_...}` placeholders, expanded when the request is sent */
+  headers: [string, string][]
+  cancel_reason: CancelReason | null
+  error_kind: DownloadErrorKind | null
+  extracted_path: string | null
+  speed_limit: number | null
+  mirrors: string[]
+  checksum: string | null
+  final_url: string | null
+  batch_id: string | null
+  skip_if_unmodified: boolean
+  etag: string | null
+  last_modified: string | null
+  /** Completed without downloading because the content hadn't changed */
+  not_modified: boolean
+  /** Set when only part of the file was requested; `range_end` is inclusive */
+  range_start: number | null
+  range_end: number | null
+  /** File types whose folders also get the finished file */
+  link_file_types: string[]
+  /** Id of the download this file was linked or copied from, instead of being downloaded */
+  linked_from: string | null
+  shares_file_with: string | null
+  /** Name it will be saved under once finished, when renamed after it started */
+  rename_to: string | null
+  /** File the download was converted into by its file type's transcode setting */
+  transcoded_path: string | null
+  /** Seconds spent transferring, summed over every run */
+  active_secs: number
+  /** Seconds from the last start to completion; only set once completed */
+  duration_secs: number | null
+  method: RequestMethod
+  /** Sent with a `POST` download */
+  body: string | null
+  content_type: string | null
+  /** A run taking longer than this is cancelled */
+  max_duration_secs: number | null
+}
+
+export type RequestMethod = 'GET' | 'POST'
+
+export type DownloadErrorKind =
+  | 'network'
+  | 'timeout'
+  | 'http_status'
+  | 'disk_full'
+  | 'not_a_directory'
+  | 'checksum_mismatch'
+  | 'deadline_exceeded'
+  | 'cancelled'
+  | 'other'
+
+export type CancelReason =
+  | 'user_requested'
+  | 'shutdown'
+  | 'disk_full'
+  | 'metered'
+  | 'timeout'
+  | 'paused'
+
+export interface DownloadError {
+  message: string
+  occurred_at: string
+}
+
+/** Spread of a download's sampled speeds in bytes/s; null with too few samples */
+export interface SpeedStats {
+  samples: number
+  min: number | null
+  median: number | null
+  p95: number | null
+  max: number | null
 }
 
 export type DownloadStatus = 
@@ -24,42 +98,326 @@ export type DownloadStatus =
   | 'failed'
   | 'cancelled'
 
+export interface QueueEntry extends DownloadRecord {
+  position: number
+}
+
 export interface DownloadStats {
   active: number
   queued: number
   max_concurrent: number
+  /** Nothing starts, whether paused by hand or on a metered connection */
+  queue_paused: boolean
+  /** Paused by hand with pauseQueue */
+  paused: boolean
+  /** Bytes saved by downloads completed since the app started */
+  total_bytes: number
+}
+
+export interface StatusTotals {
+  count: number
+  bytes: number
+}
+
+export interface LifetimeStats {
+  total_downloads: number
+  total_bytes: number
+  by_status: Partial<Record<DownloadStatus, StatusTotals>>
+}
+
+export type SortColumn =
+  | 'created_at'
+  | 'started_at'
+  | 'completed_at'
+  | 'filename'
+  | 'total_size'
+  | 'status'
+
+export interface ListDownloadsParams {
+  /** YYYY-MM-DD (UTC) or an RFC 3339 time */
+  from?: string
+  /** YYYY-MM-DD includes that whole day */
+  to?: string
+  sort?: SortColumn
+  order?: 'asc' | 'desc'
+}
+
+export interface BatchProgress {
+  batch_id: string
+  total_downloads: number
+  downloaded_bytes: number
+  total_bytes: number
+  unknown_sizes: number
+  by_status: Partial<Record<DownloadStatus, number>>
 }
 
 export interface FileTypeConfig {
   name: string
   extensions: string[]
   destination: string
+  auto_extract: boolean
+  on_conflict: ConflictPolicy | null
+  /** Convert completed downloads with ffmpeg (needs `ffmpeg_path` in the config file) */
+  post_download_transcode: TranscodeConfig | null
+  /** Dated subfolder of `destination` such as `%Y/%m/%d`, picked when a download starts */
+  date_subfolder: string | null
+  /** Position in the list, lowest first */
+  order: number
+}
+
+/** A file type as listed, in display order */
+export interface FileTypeEntry extends FileTypeConfig {
+  id: string
+}
+
+export interface TranscodeConfig {
+  /** Target extension, e.g. `mp3` */
+  format: string
+  delete_original: boolean
 }
 
 export interface Settings {
   server_port: number
+  /** IP address to bind to while `lan_access` is on (0.0.0.0 = every interface) */
+  server_host: string
+  lan_access: boolean
+  /** Changes need the write token (see `WRITE_TOKEN_KEY` in the API client) */
+  read_only: boolean
   max_concurrent_downloads: number
+  max_connections_per_host: number
+  max_queue_size: number
+  /** Combined cap in bytes per second while the tray's speed limit is on */
+  alt_speed_bytes_per_sec: number
+  /** Finished records kept before the oldest are deleted (0 = keep all) */
+  max_history_records: number
+  max_retries: number
+  connect_retries: number
+  retry_backoff_secs: number
+  connect_retry_backoff_secs: number
   start_on_login: boolean
   start_on_boot: boolean
   start_on_boot_available: boolean
+  content_encoding: ContentEncodingMode
+  /** Proxy for all downloads; overrides the proxy environment variables */
+  proxy: string | null
+  /** Without `proxy`, honour HTTP_PROXY / HTTPS_PROXY / ALL_PROXY / NO_PROXY */
+  use_system_proxy: boolean
+  partial_suffix: string
+  hide_partial_files: boolean
+  cancelled_partial_retention_minutes: number
+  fsync_on_complete: boolean
+  dedupe_by_checksum: boolean
+  compute_checksums: boolean
+  routing_rules: RoutingRule[]
+  auto_resume_paused_on_start: boolean
+  startup_stagger_ms: number
+  pause_on_metered: boolean
+  resume_verify_bytes: number
+  preview_max_bytes: number
+  on_conflict: ConflictPolicy
+  idle_shutdown_minutes: number
+  /** Downloads finished this recently are replayed to WebSocket clients on connecting */
+  finished_replay_secs: number
+  progress_save_interval_secs: number
+  webhook_url: string | null
+  /** Only in update responses: settings that couldn't be applied */
+  warnings?: string[]
 }
 
+export type ContentEncodingMode = 'original' | 'decompress'
+
+export type ConflictPolicy = 'overwrite' | 'rename'
+
 export interface ProgressUpdate {
   id: string
   downloaded: number
   total: number | null
+  indeterminate: boolean
   speed: number
   status: DownloadStatus
   error: string | null
+  started_at: string | null
+}
+
+/** Sent on `/ws?events=changes` instead of progress updates */
+export interface DownloadChange {
+  /** Absent for `resync` */
+  id?: string
+  change: 'added' | 'removed' | 'status_changed' | 'resync'
+}
+
+/** Sent on `/ws?events=queue` on connecting and whenever the queue's order changes */
+export interface QueueSnapshot {
+  /** Next in line first */
+  queue: { id: string; position: number; eta_secs: number | null }[]
 }
 
 export interface AddDownloadRequest {
   url: string
-  file_type: string
+  /** Picked by the routing rules, then the extension, when left out */
+  file_type?: string
   filename?: string
+  accept?: string
+  query_params?: [string, string][]
+  /** Extra request headers; `${VIBE_...}` in a value (or a query parameter) is read from the server's environment */
+  headers?: [string, string][]
+  speed_limit?: number
+  mirrors?: string[]
+  checksum?: string
+  /** Skip the transfer if the URL's content hasn't changed since it was last downloaded */
+  skip_if_unmodified?: boolean
+  /** Download only bytes `range_start` (default 0) through `range_end` (inclusive, default the end) */
+  range_start?: number
+  range_end?: number
+  /** Also link the finished file into these file types' folders */
+  link_file_types?: string[]
+  method?: RequestMethod
+  /** Only allowed with `POST` */
+  body?: string
+  content_type?: string
+  /** Cancel a run that takes longer than this many seconds */
+  max_duration_secs?: number
+  /** Size of the file if known, for progress when the server doesn't send one */
+  expected_size?: number
+}
+
+export interface ValidateDownloadResponse {
+  reachable: boolean
+  status_code: number | null
+  content_length: number | null
+  suggested_filename: string
+  suggested_file_type: string
+  supports_resume: boolean
+  error: string | null
 }
 
 export interface AddDownloadResponse {
   id: string
   queued: boolean
+  queue_position: number | null
+  estimated_wait_secs: number | null
+}
+
+export interface AddBatchResponse {
+  batch_id: string
+  added: AddDownloadResponse[]
+  errors: { index: number; error: string }[]
+}
+
+export interface RemovedDownload {
+  id: string
+  removed: boolean
+  /** Files deleted from disk with `delete_files` */
+  files_deleted: string[]
+  error?: string
+}
+
+export interface ListFileResponse {
+  batch_id: string
+  /** One per URL line; `download` when it was added, `error` otherwise */
+  lines: { line: number; url: string; download?: AddDownloadResponse; error?: string }[]
+}
+
+export interface NotificationTestResult {
+  success: boolean
+  error: string | null
+}
+
+export interface NotificationTestResponse {
+  /** Null when no webhook is configured */
+  webhook: NotificationTestResult | null
+}
+
+export interface MoveDownloadResponse {
+  /** Where the file is now */
+  path: string
+  download: DownloadRecord
+}
+
+export interface UpdateFileTypeResponse {
+  /** Downloads moved to the new destination, when `move_existing` was set */
+  moved: DownloadRecord[]
+  /** Downloads that couldn't be moved and stay where they were */
+  failed: { id: string; path: string; error: string }[]
+}
+
+export interface RoutingRule {
+  /** Glob matched against the whole URL or just its host */
+  url_pattern: string
+  file_type: string
+}
+
+export interface FolderListing {
+  path: string
+  /** Entries across all pages */
+  total: number
+  /** The folder is too big to list in full */
+  truncated: boolean
+  entries: {
+    name: string
+    is_dir: boolean
+    /** Size of files; null for folders */
+    size: number | null
+    modified: string | null
+  }[]
+}
+
+export interface StorageUsage {
+  file_type: string
+  destination: string
+  /** Size of the completed downloads saved there, by the database */
+  downloaded_bytes: number
+  /** Size of all the files there; only measured with `walk` */
+  on_disk_bytes: number | null
+  /** Free space on the folder's volume */
+  free_bytes: number | null
+}
+
+export interface VersionInfo {
+  version: string
+  git_commit: string | null
+  os: string
+  arch: string
+  debug: boolean
+}
+
+export interface NetworkInfo {
+  host: string
+  port: number
+  lan_access: boolean
+  local_url: string
+  addresses: string[]
+  lan_urls: string[]
+}
+
+export type ApiErrorCode =
+  | 'INTERNAL'
+  | 'BAD_REQUEST'
+  | 'NOT_FOUND'
+  | 'FORBIDDEN'
+  | 'CONFLICT'
+  | 'PAYLOAD_TOO_LARGE'
+  | 'UNSUPPORTED_MEDIA_TYPE'
+  | 'SERVICE_UNAVAILABLE'
+  | 'INVALID_FIELDS'
+
+export interface FieldError {
+  /** Path of the field, e.g. `server.port` */
+  field: string
+  message: string
+}
+
+/** Body of every API error response */
+export interface ApiErrorBody {
+  error: {
+    code: ApiErrorCode
+    message: string
+    /** Set for `INVALID_FIELDS` */
+    fields?: FieldError[]
+  }
+}
+
+/** The whole config file as JSON, as read and replaced through `/config/raw` */
+export type RawConfig = Record<string, unknown> & {
+  warnings?: string[]
 }